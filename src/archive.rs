@@ -0,0 +1,454 @@
+//! Packaging a creator's downloads into a single zip or tar.zst file.
+//!
+//! Files are streamed straight into the archive so there's never a second copy on disk.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::{debug, info};
+use serde::Serialize;
+use serde_json::json;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::collection::PostCollection;
+use crate::dedup::downloaded_files;
+use crate::download::{
+    collect_files, converted_relative_path, download_relative_path, DownloadOptions, METADATA_DIR,
+};
+use crate::errors::KemonoError;
+use crate::{KemonoClient, Post};
+
+/// The listing of posts at the root of the archive
+pub static INDEX_FILENAME: &str = "index.json";
+
+/// What kind of archive to write
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    #[default]
+    Zip,
+    /// A tarball compressed with zstd
+    TarZst,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "zip" => Ok(ArchiveFormat::Zip),
+            "tar.zst" | "tar-zst" | "tzst" => Ok(ArchiveFormat::TarZst),
+            _ => Err(format!(
+                "Invalid archive format '{}', expected zip or tar.zst",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for ArchiveFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveFormat::Zip => write!(f, "zip"),
+            ArchiveFormat::TarZst => write!(f, "tar.zst"),
+        }
+    }
+}
+
+/// Which files go in the archive, everything if nothing's set
+#[derive(Clone, Debug, Default)]
+pub struct ArchiveFilter {
+    /// Only include files with these extensions, compared case-insensitively
+    pub extensions: Vec<String>,
+    /// Only include posts published on or after this date, eg `2023-01-01`
+    pub since: Option<String>,
+    /// Only include posts published on or before this date
+    pub until: Option<String>,
+}
+
+impl ArchiveFilter {
+    fn has_dates(&self) -> bool {
+        self.since.is_some() || self.until.is_some()
+    }
+
+    fn matches_extension(&self, path: &Path) -> bool {
+        if self.extensions.is_empty() {
+            return true;
+        }
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        self.extensions.iter().any(|wanted| {
+            wanted
+                .trim_start_matches('.')
+                .eq_ignore_ascii_case(&extension)
+        })
+    }
+}
+
+/// What ended up in the archive
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ArchiveSummary {
+    pub output: PathBuf,
+    pub posts: usize,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Loads the stored post metadata, skipping anything which doesn't parse
+pub fn read_metadata_posts(metadata_dir: &Path) -> Result<Vec<Post>, KemonoError> {
+    if !metadata_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let entries = metadata_dir.read_dir().map_err(|err| {
+        KemonoError::from(format!(
+            "Failed to read {}: {:?}",
+            metadata_dir.display(),
+            err
+        ))
+    })?;
+    let mut posts = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') || !name.ends_with(".json") {
+            continue;
+        }
+        match std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Post>(&contents).ok())
+        {
+            Some(post) => posts.push(post),
+            None => debug!("Skipping unparseable metadata {}", path.display()),
+        }
+    }
+    Ok(posts)
+}
+
+/// The name of a file inside the archive, always `/`-separated
+fn archive_name(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+enum ArchiveWriter {
+    Zip(Box<ZipWriter<File>>),
+    TarZst(tar::Builder<zstd::Encoder<'static, File>>),
+}
+
+impl ArchiveWriter {
+    fn new(format: ArchiveFormat, file: File) -> Result<Self, KemonoError> {
+        Ok(match format {
+            ArchiveFormat::Zip => ArchiveWriter::Zip(Box::new(ZipWriter::new(file))),
+            ArchiveFormat::TarZst => ArchiveWriter::TarZst(tar::Builder::new(
+                zstd::Encoder::new(file, 0).map_err(KemonoError::from_stringable)?,
+            )),
+        })
+    }
+
+    fn add_file(&mut self, name: &str, path: &Path, size: u64) -> Result<(), KemonoError> {
+        let err = |err: String| {
+            KemonoError::from(format!(
+                "Failed to add {} to the archive: {}",
+                path.display(),
+                err
+            ))
+        };
+        match self {
+            ArchiveWriter::Zip(zip) => {
+                // media is already compressed, so don't waste time on it
+                let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Stored)
+                    .large_file(size >= u32::MAX as u64);
+                zip.start_file(name, options)
+                    .map_err(|e| err(e.to_string()))?;
+                let mut file = File::open(path).map_err(|e| err(e.to_string()))?;
+                std::io::copy(&mut file, zip.as_mut()).map_err(|e| err(e.to_string()))?;
+            }
+            ArchiveWriter::TarZst(tar) => tar
+                .append_path_with_name(path, name)
+                .map_err(|e| err(e.to_string()))?,
+        }
+        Ok(())
+    }
+
+    fn add_bytes(&mut self, name: &str, data: &[u8]) -> Result<(), KemonoError> {
+        let err = |err: String| {
+            KemonoError::from(format!("Failed to add {} to the archive: {}", name, err))
+        };
+        match self {
+            ArchiveWriter::Zip(zip) => {
+                let options =
+                    SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+                zip.start_file(name, options)
+                    .map_err(|e| err(e.to_string()))?;
+                zip.write_all(data).map_err(|e| err(e.to_string()))?;
+            }
+            ArchiveWriter::TarZst(tar) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(
+                    std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default(),
+                );
+                header.set_cksum();
+                tar.append_data(&mut header, name, data)
+                    .map_err(|e| err(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), KemonoError> {
+        let err = |e: String| KemonoError::from(format!("Failed to finish the archive: {}", e));
+        match self {
+            ArchiveWriter::Zip(zip) => {
+                zip.finish().map_err(|e| err(e.to_string()))?;
+            }
+            ArchiveWriter::TarZst(tar) => {
+                tar.into_inner()
+                    .map_err(|e| err(e.to_string()))?
+                    .finish()
+                    .map_err(|e| err(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a creator/service's downloads and metadata to `output`, with an `index.json` listing the posts
+pub fn export_archive(
+    client: &KemonoClient,
+    options: &DownloadOptions,
+    output: &Path,
+    format: ArchiveFormat,
+    filter: &ArchiveFilter,
+    force: bool,
+) -> Result<ArchiveSummary, KemonoError> {
+    if output.exists() && !force {
+        return Err(KemonoError::Configuration(format!(
+            "{} already exists, use --force to overwrite it",
+            output.display()
+        )));
+    }
+    let target_dir = options.target_dir(client);
+    if !target_dir.is_dir() {
+        return Err(KemonoError::from(format!(
+            "Nothing downloaded for {}/{} in {}",
+            options.service,
+            options.creator,
+            target_dir.display()
+        )));
+    }
+    let metadata_dir = options.metadata_dir(client);
+
+    let mut posts = PostCollection::from(read_metadata_posts(&metadata_dir)?);
+    if filter.has_dates() {
+        posts = posts.filter_by_date_range(
+            filter.since.as_deref().unwrap_or_default(),
+            filter.until.as_deref().unwrap_or_default(),
+        );
+    }
+    posts.sort_by_published();
+
+    // which post each file belongs to, for the index
+    let mut owners: BTreeMap<PathBuf, String> = BTreeMap::new();
+    for (post, attachment) in collect_files(&posts) {
+        let Ok(relative_path) = download_relative_path(&post, &attachment, options) else {
+            continue;
+        };
+        let converted = converted_relative_path(&post, &attachment, options)
+            .ok()
+            .flatten();
+        for name in std::iter::once(relative_path).chain(converted) {
+            owners.insert(target_dir.join(format!("{}.nfo", name)), post.id.clone());
+            owners.insert(target_dir.join(name), post.id.clone());
+        }
+    }
+
+    let output_path = std::path::absolute(output).unwrap_or(output.to_path_buf());
+    let mut files: Vec<PathBuf> = downloaded_files(&target_dir)?
+        .into_iter()
+        .filter(|path| path != &output_path)
+        // files we can't tie to a post can't be in a date range
+        .filter(|path| !filter.has_dates() || owners.contains_key(path))
+        .filter(|path| filter.matches_extension(path))
+        .collect();
+    files.sort();
+
+    let file = File::create(output).map_err(|err| {
+        KemonoError::from(format!("Failed to create {}: {:?}", output.display(), err))
+    })?;
+    let mut writer = ArchiveWriter::new(format, file)?;
+    let mut summary = ArchiveSummary {
+        output: output.to_path_buf(),
+        posts: posts.len(),
+        ..Default::default()
+    };
+
+    let mut post_files: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for path in files.iter() {
+        let Ok(relative) = path.strip_prefix(&target_dir) else {
+            continue;
+        };
+        let name = archive_name(relative);
+        let size = path.metadata().map(|m| m.len()).unwrap_or_default();
+        writer.add_file(&name, path, size)?;
+        summary.files += 1;
+        summary.bytes += size;
+        if let Some(post_id) = owners.get(path) {
+            post_files.entry(post_id).or_default().push(name);
+        }
+    }
+
+    for post in posts.iter() {
+        let metadata_file = metadata_dir.join(format!("{}.json", post.id));
+        if metadata_file.is_file() {
+            writer.add_file(
+                &format!("{}/{}.json", METADATA_DIR, post.id),
+                &metadata_file,
+                0,
+            )?;
+        }
+    }
+
+    let index: Vec<serde_json::Value> = posts
+        .iter()
+        .map(|post| {
+            json!({
+                "id": post.id,
+                "title": post.title,
+                "published": post.published,
+                "files": post_files.get(post.id.as_str()).cloned().unwrap_or_default(),
+            })
+        })
+        .collect();
+    writer.add_bytes(INDEX_FILENAME, &serde_json::to_vec_pretty(&index)?)?;
+    writer.finish()?;
+
+    info!(
+        "Exported {} files from {} posts to {}",
+        summary.files,
+        summary.posts,
+        output.display()
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_format_from_str() {
+        assert_eq!(ArchiveFormat::from_str("ZIP"), Ok(ArchiveFormat::Zip));
+        assert_eq!(
+            ArchiveFormat::from_str("tar.zst"),
+            Ok(ArchiveFormat::TarZst)
+        );
+        assert!(ArchiveFormat::from_str("rar").is_err());
+    }
+
+    #[test]
+    fn test_export_archive() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let base_dir = tmp.path().join("archive");
+        let client = KemonoClient::new("example.com", Some(base_dir.display().to_string()));
+        let options = DownloadOptions::new("patreon", "12345");
+        let target_dir = options.target_dir(&client);
+        let metadata_dir = options.metadata_dir(&client);
+        std::fs::create_dir_all(&metadata_dir).expect("Failed to create test dir");
+
+        let posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        let post = &posts[0];
+        std::fs::write(
+            metadata_dir.join(format!("{}.json", post.id)),
+            serde_json::to_string(post).expect("Failed to serialize"),
+        )
+        .expect("Failed to write");
+        let (_, attachment) = collect_files(std::slice::from_ref(post))
+            .pop()
+            .expect("No attachments in test post");
+        let relative_path = download_relative_path(post, &attachment, &options)
+            .expect("Failed to name test attachment");
+        std::fs::write(target_dir.join(&relative_path), "hello").expect("Failed to write");
+        std::fs::write(target_dir.join("unrelated.txt"), "hello").expect("Failed to write");
+
+        let output = base_dir.join("creator.zip");
+        let filter = ArchiveFilter::default();
+        let first = export_archive(
+            &client,
+            &options,
+            &output,
+            ArchiveFormat::Zip,
+            &filter,
+            false,
+        );
+        let refused = export_archive(
+            &client,
+            &options,
+            &output,
+            ArchiveFormat::Zip,
+            &filter,
+            false,
+        );
+        let names: Vec<String> = zip::ZipArchive::new(File::open(&output).expect("No archive"))
+            .expect("Failed to read archive")
+            .file_names()
+            .map(|name| name.expect("Bad name").to_string())
+            .collect();
+
+        let tar_output = base_dir.join("creator.tar.zst");
+        let dated = ArchiveFilter {
+            since: Some("2100-01-01".to_string()),
+            ..Default::default()
+        };
+        let tar_summary = export_archive(
+            &client,
+            &options,
+            &tar_output,
+            ArchiveFormat::TarZst,
+            &dated,
+            false,
+        );
+        let tar_names: Vec<String> = tar::Archive::new(
+            zstd::Decoder::new(File::open(&tar_output).expect("No archive"))
+                .expect("Failed to decompress"),
+        )
+        .entries()
+        .expect("Failed to read archive")
+        .map(|entry| {
+            entry
+                .expect("Bad entry")
+                .path()
+                .expect("Bad path")
+                .display()
+                .to_string()
+        })
+        .collect();
+
+        let first = first.expect("Failed to export");
+        assert_eq!(first.files, 2);
+        assert_eq!(first.posts, 1);
+        assert!(refused.is_err());
+        assert!(names.contains(&relative_path));
+        assert!(names.contains(&"unrelated.txt".to_string()));
+        assert!(names.contains(&format!("metadata/{}.json", post.id)));
+        assert!(names.contains(&INDEX_FILENAME.to_string()));
+
+        assert_eq!(tar_summary.expect("Failed to export").files, 0);
+        assert_eq!(tar_names, vec![INDEX_FILENAME.to_string()]);
+    }
+}
@@ -0,0 +1,318 @@
+//! A list of posts with the filtering and aggregation the commands keep needing.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Attachment, Post};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct PostCollection(Vec<Post>);
+
+impl PostCollection {
+    /// Posts which have `tag`, ignoring case
+    pub fn filter_by_tag(&self, tag: &str) -> PostCollection {
+        self.filter(|post| {
+            post.tags
+                .iter()
+                .flatten()
+                .any(|post_tag| post_tag.eq_ignore_ascii_case(tag.trim()))
+        })
+    }
+
+    /// Posts published between `from` and `to` inclusive, either can be empty to leave that end open.
+    ///
+    /// The bounds can be as precise as you like, `2023`, `2023-10-20` or `2023-10-20T14:05:01` all work.
+    pub fn filter_by_date_range(&self, from: &str, to: &str) -> PostCollection {
+        self.filter(|post| {
+            let published = post.published.as_str();
+            let truncated = |bound: &str| &published[..bound.len().min(published.len())];
+            (from.is_empty() || truncated(from) >= from) && (to.is_empty() || truncated(to) <= to)
+        })
+    }
+
+    /// Number of named files across all the posts, including each post's main file
+    pub fn total_attachment_count(&self) -> usize {
+        self.0
+            .iter()
+            .map(|post| named_attachments(post).len())
+            .sum()
+    }
+
+    /// Count of files by lowercased extension, files without one are counted under `(none)`
+    pub fn extensions(&self) -> HashMap<String, usize> {
+        let mut stats = PostStats::default();
+        self.0.iter().for_each(|post| stats.add(post));
+        stats.extensions
+    }
+
+    /// The posts without their content, for when that's all that's needed
+    pub fn to_summaries(&self) -> Vec<PostSummary> {
+        self.0.iter().map(PostSummary::from).collect()
+    }
+
+    /// Oldest first
+    pub fn sort_by_published(&mut self) {
+        self.0.sort_by(|a, b| a.published.cmp(&b.published));
+    }
+
+    pub fn into_inner(self) -> Vec<Post> {
+        self.0
+    }
+
+    fn filter<F>(&self, predicate: F) -> PostCollection
+    where
+        F: Fn(&Post) -> bool,
+    {
+        PostCollection(
+            self.0
+                .iter()
+                .filter(|post| predicate(post))
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+impl From<Vec<Post>> for PostCollection {
+    fn from(posts: Vec<Post>) -> Self {
+        PostCollection(posts)
+    }
+}
+
+impl Deref for PostCollection {
+    type Target = [Post];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl IntoIterator for PostCollection {
+    type Item = Post;
+    type IntoIter = std::vec::IntoIter<Post>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+/// The parts of a post which counting and date checks need, without the content and embeds
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PostSummary {
+    pub id: String,
+    pub published: String,
+    pub service: String,
+    pub user: String,
+    /// Named files including the post's main file
+    pub attachment_count: usize,
+}
+
+impl From<&Post> for PostSummary {
+    fn from(post: &Post) -> Self {
+        PostSummary {
+            id: post.id.clone(),
+            published: post.published.clone(),
+            service: post.service.clone(),
+            user: post.user.clone(),
+            attachment_count: named_attachments(post).len(),
+        }
+    }
+}
+
+impl From<Post> for PostSummary {
+    fn from(post: Post) -> Self {
+        let attachment_count = named_attachments(&post).len();
+        PostSummary {
+            id: post.id,
+            published: post.published,
+            service: post.service,
+            user: post.user,
+            attachment_count,
+        }
+    }
+}
+
+/// Running totals over posts, for when they're streamed rather than collected
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct PostStats {
+    pub post_count: usize,
+    /// Files including each post's main file
+    pub file_count: usize,
+    /// Count of files by lowercased extension, files without one are counted under `(none)`
+    pub extensions: HashMap<String, usize>,
+    /// Posts by the year they were published, eg `2023`
+    pub posts_per_year: BTreeMap<String, usize>,
+    /// Posts by the month they were published, eg `2023-10`
+    pub posts_per_month: BTreeMap<String, usize>,
+    /// Bytes across the files whose size is known, see [PostStats::add_sizes]
+    pub total_size: u64,
+    /// Bytes by lowercased extension, like `extensions`
+    pub size_by_extension: BTreeMap<String, u64>,
+    /// Files whose size isn't known
+    pub unknown_sizes: usize,
+}
+
+impl PostStats {
+    pub fn add(&mut self, post: &Post) {
+        let attachments = named_attachments(post);
+        self.count(&post.published, attachments.len());
+        for attachment in attachments {
+            let ext = attachment
+                .extension()
+                .unwrap_or_else(|| "(none)".to_string());
+            *self.extensions.entry(ext).or_insert(0) += 1;
+        }
+    }
+
+    /// Counts a post by its summary, everything but the extensions and sizes which need its attachments
+    pub fn add_summary(&mut self, summary: &PostSummary) {
+        self.count(&summary.published, summary.attachment_count);
+    }
+
+    fn count(&mut self, published: &str, attachment_count: usize) {
+        self.post_count += 1;
+        self.file_count += attachment_count;
+        if let Some(year) = published.get(..4) {
+            *self.posts_per_year.entry(year.to_string()).or_insert(0) += 1;
+        }
+        if let Some(month) = published.get(..7) {
+            *self.posts_per_month.entry(month.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    /// Adds the sizes of a post's files, from `sizes` by attachment path
+    pub fn add_sizes(&mut self, post: &Post, sizes: &HashMap<String, u64>) {
+        for attachment in post.all_attachments() {
+            if attachment.name.is_none() {
+                continue;
+            }
+            let Some(size) = attachment.path.as_deref().and_then(|path| sizes.get(path)) else {
+                self.unknown_sizes += 1;
+                continue;
+            };
+            let ext = attachment
+                .extension()
+                .unwrap_or_else(|| "(none)".to_string());
+            self.total_size += size;
+            *self.size_by_extension.entry(ext).or_insert(0) += size;
+        }
+    }
+}
+
+/// The post's files which have a name, the ones the stats count
+fn named_attachments(post: &Post) -> Vec<&Attachment> {
+    post.all_attachments()
+        .into_iter()
+        .filter(|attachment| attachment.name.is_some())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_collection() -> PostCollection {
+        let mut posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        posts.truncate(3);
+        posts[0].published = "2023-10-20T14:05:01".to_string();
+        posts[0].tags = Some(vec!["Comic".to_string()]);
+        posts[1].published = "2023-07-30T23:37:29".to_string();
+        posts[1].tags = None;
+        posts[2].published = "2022-12-31T23:59:59".to_string();
+        posts[2].tags = Some(vec!["sketch".to_string(), "comic".to_string()]);
+        posts.into()
+    }
+
+    #[test]
+    fn test_filters() {
+        let posts = test_collection();
+        assert_eq!(posts.filter_by_tag("comic").len(), 2);
+        assert_eq!(posts.filter_by_tag("nope").len(), 0);
+        assert_eq!(posts.filter_by_date_range("2023", "").len(), 2);
+        assert_eq!(posts.filter_by_date_range("", "2023-07-30").len(), 2);
+        assert_eq!(posts.filter_by_date_range("2023-07", "2023-07").len(), 1);
+        assert_eq!(posts.filter_by_date_range("", "").len(), 3);
+    }
+
+    #[test]
+    fn test_aggregates() {
+        let mut posts = test_collection();
+        let total = posts.total_attachment_count();
+        assert_eq!(posts.extensions().values().sum::<usize>(), total);
+        let mut stats = PostStats::default();
+        posts.iter().for_each(|post| stats.add(post));
+        assert_eq!((stats.post_count, stats.file_count), (3, total));
+        assert_eq!(stats.extensions, posts.extensions());
+        assert_eq!(
+            stats.posts_per_year,
+            [("2022".to_string(), 1), ("2023".to_string(), 2)].into()
+        );
+        assert_eq!(stats.posts_per_month.get("2023-07"), Some(&1));
+        assert_eq!(stats.posts_per_month.len(), 3);
+
+        // sizes are only known for the files we've been told about
+        let mut sizes = HashMap::new();
+        let first: Vec<&crate::Attachment> = posts[0].all_attachments();
+        for attachment in first.iter() {
+            sizes.insert(attachment.path.clone().expect("No path"), 100);
+        }
+        assert_eq!(posts[0].total_size(&sizes), Some(100 * first.len() as u64));
+        assert_eq!(posts[0].total_size(&HashMap::new()), None);
+        posts.iter().for_each(|post| stats.add_sizes(post, &sizes));
+        assert_eq!(stats.total_size, 100 * first.len() as u64);
+        assert_eq!(
+            stats.size_by_extension.values().sum::<u64>(),
+            stats.total_size
+        );
+        assert_eq!(stats.unknown_sizes, total - first.len());
+
+        let summaries = posts.to_summaries();
+        assert_eq!(summaries.len(), 3);
+        assert_eq!(summaries[0], PostSummary::from(posts[0].clone()));
+        assert_eq!(summaries[0].published, posts[0].published);
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|summary| summary.attachment_count)
+                .sum::<usize>(),
+            total
+        );
+        let mut from_summaries = PostStats::default();
+        summaries
+            .iter()
+            .for_each(|summary| from_summaries.add_summary(summary));
+        assert_eq!(from_summaries.post_count, stats.post_count);
+        assert_eq!(from_summaries.posts_per_month, stats.posts_per_month);
+
+        posts.sort_by_published();
+        assert_eq!(posts[0].published, "2022-12-31T23:59:59");
+        assert_eq!(posts.into_inner().len(), 3);
+    }
+
+    #[test]
+    fn test_unnamed_attachments_not_counted() {
+        let mut posts = test_collection();
+        let total = posts.total_attachment_count();
+        posts.0[0]
+            .attachments
+            .get_or_insert_with(Default::default)
+            .insert(crate::Attachment {
+                name: None,
+                path: Some("/ab/cd/unnamed.png".to_string()),
+            });
+        assert_eq!(posts.total_attachment_count(), total);
+        let mut stats = PostStats::default();
+        posts.iter().for_each(|post| stats.add(post));
+        assert_eq!(stats.file_count, total);
+        assert_eq!(stats.extensions.values().sum::<usize>(), stats.file_count);
+        assert_eq!(
+            PostSummary::from(&posts[0]).attachment_count,
+            named_attachments(&posts[0]).len()
+        );
+        assert_eq!(posts.into_inner().len(), 3);
+    }
+}
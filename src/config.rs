@@ -0,0 +1,543 @@
+//! TOML configuration file support.
+//!
+//! Without `--config`, `kemono.toml` in the current directory is used, then
+//! `~/.config/kemono/config.toml`. Command line options and environment variables take precedence.
+//!
+//! ```toml
+//! hostname = "kemono.example"
+//! download_path = "/archive"
+//! threads = 4
+//! mkvs = true
+//!
+//! [creators]
+//! patreon = ["12345"]
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::content::ContentFormat;
+use crate::errors::KemonoError;
+use crate::layout::Layout;
+use crate::mtime::MtimeSource;
+use crate::platform::Platform;
+use crate::util::FilenameTemplate;
+use crate::{DEFAULT_DOWNLOAD_PATH, DEFAULT_THREADS};
+
+/// The config file looked for in the current directory
+pub static CONFIG_FILENAME: &str = "kemono.toml";
+
+/// What `config init` writes, everything is commented out so it changes nothing until edited
+pub static CONFIG_TEMPLATE: &str = r#"# kemono configuration, command line options and KEMONO_* environment variables take precedence
+
+# hostname = "kemono.su"
+# platform = "kemono"
+# download_path = "./download"
+# metadata_path = "/archive/metadata"
+# data_hosts = ["n1.kemono.su", "n2.kemono.su"]
+# proxy = "http://proxy:3128"
+# timeout = 30
+# threads = 2
+# per_creator_threads = 1
+# cache_ttl = 300
+# strict = false
+# cookies_file = "./cookies.json"
+
+# mkvs = true
+# layout = "creator-service"
+# post_dirs = false
+# filename_template = "{published}-{name}"
+# content_format = "markdown"
+# mtime = "published"
+# inline_media = false
+# thumbnails = false
+# dedup = false
+# manifest = false
+# ignore_edits = false
+# suspect_threshold = 1024
+# exec_after = "echo $KEMONO_FILE"
+# notify_webhook = "https://discord.com/api/webhooks/..."
+
+# creators for update to fetch even if they haven't been downloaded yet, by service
+# [creators]
+# patreon = ["12345", "67890"]
+"#;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KemonoConfig {
+    pub hostname: Option<String>,
+    pub download_path: Option<String>,
+    pub threads: Option<usize>,
+    pub proxy: Option<String>,
+    /// Request timeout in seconds
+    pub timeout: Option<u64>,
+    /// Where to store cookies between runs
+    pub cookies_file: Option<String>,
+    pub platform: Option<String>,
+    pub metadata_path: Option<String>,
+    pub data_hosts: Option<Vec<String>>,
+    pub per_creator_threads: Option<usize>,
+    pub cache_ttl: Option<u64>,
+    pub strict: Option<bool>,
+    pub mkvs: Option<bool>,
+    pub layout: Option<String>,
+    pub post_dirs: Option<bool>,
+    pub filename_template: Option<String>,
+    pub content_format: Option<String>,
+    pub mtime: Option<String>,
+    pub inline_media: Option<bool>,
+    pub thumbnails: Option<bool>,
+    pub dedup: Option<bool>,
+    pub manifest: Option<bool>,
+    pub ignore_edits: Option<bool>,
+    pub suspect_threshold: Option<u64>,
+    pub exec_after: Option<String>,
+    /// Where to POST a [crate::notify::NotificationPayload] when new files are downloaded
+    pub notify_webhook: Option<String>,
+    /// Creator IDs by service, which `update` fetches even if there's no directory for them yet
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub creators: BTreeMap<String, Vec<String>>,
+}
+
+impl KemonoConfig {
+    /// Load and validate a config file
+    pub fn from_file(path: &Path) -> Result<Self, KemonoError> {
+        let contents = std::fs::read_to_string(path).map_err(|err| {
+            KemonoError::from(format!(
+                "Failed to read config {}: {:?}",
+                path.display(),
+                err
+            ))
+        })?;
+        let config: Self = toml::from_str(&contents).map_err(|err| {
+            KemonoError::from(format!(
+                "Failed to parse config {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The config file to use when there's no `--config`, if there is one
+    pub fn find() -> Option<PathBuf> {
+        Self::find_in(
+            &std::env::current_dir().ok()?,
+            std::env::var_os("HOME").map(PathBuf::from).as_deref(),
+        )
+    }
+
+    /// `kemono.toml` in `cwd`, then `.config/kemono/config.toml` under `home`
+    pub fn find_in(cwd: &Path, home: Option<&Path>) -> Option<PathBuf> {
+        std::iter::once(cwd.join(CONFIG_FILENAME))
+            .chain(home.map(|home| home.join(".config").join("kemono").join("config.toml")))
+            .find(|path| path.is_file())
+    }
+
+    /// Writes [CONFIG_TEMPLATE] to `path`, refusing to replace an existing file unless `force` is set
+    pub fn write_template(path: &Path, force: bool) -> Result<(), KemonoError> {
+        if path.exists() && !force {
+            return Err(KemonoError::from(format!(
+                "{} already exists, use --force to overwrite it",
+                path.display()
+            )));
+        }
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)
+                .map_err(|err| format!("Failed to create {}: {:?}", parent.display(), err))?;
+        }
+        std::fs::write(path, CONFIG_TEMPLATE).map_err(|err| {
+            KemonoError::from(format!("Failed to write {}: {:?}", path.display(), err))
+        })
+    }
+
+    /// The service/creator pairs in `[creators]`
+    pub fn creator_pairs(&self) -> Vec<(String, String)> {
+        self.creators
+            .iter()
+            .flat_map(|(service, creators)| {
+                creators
+                    .iter()
+                    .map(move |creator| (service.clone(), creator.clone()))
+            })
+            .collect()
+    }
+
+    /// Build a config entirely from `KEMONO_*` environment variables, filling in defaults for anything optional
+    pub fn from_env() -> Result<Self, KemonoError> {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    /// Build a config from whatever `get_var` returns for each `KEMONO_*` variable
+    pub fn from_vars<F>(get_var: F) -> Result<Self, KemonoError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let get_var = |key: &str| get_var(key).filter(|value| !value.trim().is_empty());
+        let parse_number = |key: &str| -> Result<Option<u64>, KemonoError> {
+            get_var(key)
+                .map(|value| {
+                    value.trim().parse::<u64>().map_err(|err| {
+                        KemonoError::Configuration(format!(
+                            "{} should be a number, got {:?}: {}",
+                            key, value, err
+                        ))
+                    })
+                })
+                .transpose()
+        };
+
+        let hostname = get_var("KEMONO_HOSTNAME");
+        if hostname.is_none() {
+            return Err(KemonoError::Configuration(
+                "Missing required environment variables: KEMONO_HOSTNAME".to_string(),
+            ));
+        }
+
+        let config = Self {
+            hostname,
+            download_path: Some(
+                get_var("KEMONO_DOWNLOAD_PATH").unwrap_or(DEFAULT_DOWNLOAD_PATH.to_string()),
+            ),
+            threads: Some(
+                parse_number("KEMONO_THREADS")?
+                    .map(|n| n as usize)
+                    .unwrap_or(DEFAULT_THREADS),
+            ),
+            proxy: get_var("KEMONO_PROXY"),
+            timeout: parse_number("KEMONO_TIMEOUT")?,
+            cookies_file: get_var("KEMONO_COOKIES_FILE"),
+            ..Default::default()
+        };
+        config
+            .validate()
+            .map_err(|err| KemonoError::Configuration(err.to_string()))?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), KemonoError> {
+        if let Some(hostname) = &self.hostname {
+            if hostname.trim().is_empty() {
+                return Err(KemonoError::from_stringable("hostname can't be empty"));
+            }
+        }
+        if self.threads == Some(0) {
+            return Err(KemonoError::from_stringable(
+                "threads must be greater than 0",
+            ));
+        }
+        if self.timeout == Some(0) {
+            return Err(KemonoError::from_stringable(
+                "timeout must be greater than 0 seconds",
+            ));
+        }
+        if self.per_creator_threads == Some(0) {
+            return Err(KemonoError::from_stringable(
+                "per_creator_threads must be greater than 0",
+            ));
+        }
+        if let Some(proxy) = &self.proxy {
+            reqwest::Proxy::all(proxy)?;
+        }
+        if let Some(webhook) = &self.notify_webhook {
+            reqwest::Url::parse(webhook)
+                .map_err(|err| KemonoError::Configuration(format!("notify_webhook: {}", err)))?;
+        }
+        check_parses::<Platform>("platform", &self.platform)?;
+        check_parses::<Layout>("layout", &self.layout)?;
+        check_parses::<FilenameTemplate>("filename_template", &self.filename_template)?;
+        check_parses::<ContentFormat>("content_format", &self.content_format)?;
+        check_parses::<MtimeSource>("mtime", &self.mtime)?;
+        for (service, creator) in self.creator_pairs() {
+            if service.trim().is_empty() || creator.trim().is_empty() {
+                return Err(KemonoError::from(format!(
+                    "creators needs both a service and a creator, got '{}' = '{}'",
+                    service, creator
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge another config over this one, anything set in `other` takes precedence
+    pub fn merge(self, other: KemonoConfig) -> Self {
+        Self {
+            hostname: other.hostname.or(self.hostname),
+            download_path: other.download_path.or(self.download_path),
+            threads: other.threads.or(self.threads),
+            proxy: other.proxy.or(self.proxy),
+            timeout: other.timeout.or(self.timeout),
+            cookies_file: other.cookies_file.or(self.cookies_file),
+            platform: other.platform.or(self.platform),
+            metadata_path: other.metadata_path.or(self.metadata_path),
+            data_hosts: other.data_hosts.or(self.data_hosts),
+            per_creator_threads: other.per_creator_threads.or(self.per_creator_threads),
+            cache_ttl: other.cache_ttl.or(self.cache_ttl),
+            strict: other.strict.or(self.strict),
+            mkvs: other.mkvs.or(self.mkvs),
+            layout: other.layout.or(self.layout),
+            post_dirs: other.post_dirs.or(self.post_dirs),
+            filename_template: other.filename_template.or(self.filename_template),
+            content_format: other.content_format.or(self.content_format),
+            mtime: other.mtime.or(self.mtime),
+            inline_media: other.inline_media.or(self.inline_media),
+            thumbnails: other.thumbnails.or(self.thumbnails),
+            dedup: other.dedup.or(self.dedup),
+            manifest: other.manifest.or(self.manifest),
+            ignore_edits: other.ignore_edits.or(self.ignore_edits),
+            suspect_threshold: other.suspect_threshold.or(self.suspect_threshold),
+            exec_after: other.exec_after.or(self.exec_after),
+            notify_webhook: other.notify_webhook.or(self.notify_webhook),
+            creators: match other.creators.is_empty() {
+                true => self.creators,
+                false => other.creators,
+            },
+        }
+    }
+}
+
+/// Reads a list of `service creator` lines, for `update --targets`.
+///
+/// Blank lines and anything after a `#` are ignored. Lines which aren't a service and a creator
+/// are logged and skipped rather than failing the whole list.
+pub fn read_targets(path: &Path) -> Result<Vec<(String, String)>, KemonoError> {
+    let contents = std::fs::read_to_string(path).map_err(|err| {
+        KemonoError::from(format!(
+            "Failed to read targets {}: {:?}",
+            path.display(),
+            err
+        ))
+    })?;
+    Ok(parse_targets(&contents, &path.display().to_string()))
+}
+
+/// The `service creator` pairs in a targets list, `source` is only used for logging
+fn parse_targets(contents: &str, source: &str) -> Vec<(String, String)> {
+    let mut targets = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [service, creator] => targets.push((service.to_string(), creator.to_string())),
+            _ => warn!(
+                "Skipping line {} of {}, expected 'service creator': {}",
+                number + 1,
+                source,
+                line
+            ),
+        }
+    }
+    targets
+}
+
+/// Fails if `value` is set but isn't a valid `T`
+fn check_parses<T: FromStr<Err = String>>(
+    name: &str,
+    value: &Option<String>,
+) -> Result<(), KemonoError> {
+    match value.as_deref().map(T::from_str) {
+        Some(Err(err)) => Err(KemonoError::Configuration(format!("{}: {}", name, err))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_from_file() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = tmp.path().join("config.toml");
+        std::fs::write(
+            &path,
+            r#"
+hostname = "kemono.example"
+threads = 4
+timeout = 60
+
+[creators]
+patreon = ["12345"]
+fanbox = ["67890"]
+"#,
+        )
+        .expect("Failed to write config");
+        let config = KemonoConfig::from_file(&path);
+
+        let config = config.expect("Failed to load config");
+        assert_eq!(config.hostname, Some("kemono.example".to_string()));
+        assert_eq!(config.threads, Some(4));
+        assert_eq!(config.creator_pairs().len(), 2);
+        // [creators] is the only way to list them
+        assert!(toml::from_str::<KemonoConfig>(
+            "[[target]]\nservice = \"patreon\"\ncreator = \"12345\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_config_validate() {
+        let config: KemonoConfig = toml::from_str("threads = 0").expect("Failed to parse");
+        assert!(config.validate().is_err());
+        let config: KemonoConfig =
+            toml::from_str("[creators]\npatreon = [\"\"]").expect("Failed to parse");
+        assert!(config.validate().is_err());
+        assert!(toml::from_str::<KemonoConfig>("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn test_config_from_vars() {
+        let vars = |pairs: &[(&str, &str)]| -> std::collections::HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+
+        let env = vars(&[
+            ("KEMONO_HOSTNAME", "kemono.example"),
+            ("KEMONO_THREADS", "4"),
+        ]);
+        let config =
+            KemonoConfig::from_vars(|key| env.get(key).cloned()).expect("Failed to load config");
+        assert_eq!(config.hostname, Some("kemono.example".to_string()));
+        assert_eq!(config.threads, Some(4));
+        assert_eq!(
+            config.download_path,
+            Some(DEFAULT_DOWNLOAD_PATH.to_string())
+        );
+        assert_eq!(config.timeout, None);
+
+        let env = vars(&[("KEMONO_THREADS", "4")]);
+        match KemonoConfig::from_vars(|key| env.get(key).cloned()) {
+            Err(KemonoError::Configuration(msg)) => assert!(msg.contains("KEMONO_HOSTNAME")),
+            other => panic!("Expected a configuration error, got {:?}", other),
+        }
+
+        let env = vars(&[
+            ("KEMONO_HOSTNAME", "kemono.example"),
+            ("KEMONO_TIMEOUT", "soon"),
+        ]);
+        assert!(matches!(
+            KemonoConfig::from_vars(|key| env.get(key).cloned()),
+            Err(KemonoError::Configuration(_))
+        ));
+    }
+
+    #[test]
+    fn test_config_creators() {
+        let config: KemonoConfig = toml::from_str(
+            "mkvs = true\nlayout = \"service-creator\"\n[creators]\npatreon = [\"1\", \"2\"]\nfanbox = [\"3\"]",
+        )
+        .expect("Failed to parse");
+        config.validate().expect("Failed to validate");
+        assert_eq!(config.mkvs, Some(true));
+        assert_eq!(
+            config.creator_pairs(),
+            vec![
+                ("fanbox".to_string(), "3".to_string()),
+                ("patreon".to_string(), "1".to_string()),
+                ("patreon".to_string(), "2".to_string()),
+            ]
+        );
+        let config: KemonoConfig =
+            toml::from_str("layout = \"sideways\"").expect("Failed to parse");
+        assert!(matches!(
+            config.validate(),
+            Err(KemonoError::Configuration(_))
+        ));
+    }
+
+    #[test]
+    fn test_config_template() {
+        // the template on its own changes nothing
+        let config: KemonoConfig = toml::from_str(CONFIG_TEMPLATE).expect("Failed to parse");
+        assert_eq!(config, KemonoConfig::default());
+        // and every example in it is valid once uncommented
+        let uncommented: String = CONFIG_TEMPLATE
+            .lines()
+            .filter_map(|line| line.strip_prefix("# "))
+            .filter(|line| line.starts_with('[') || line.contains(" = "))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let config: KemonoConfig = toml::from_str(&uncommented).expect("Failed to parse");
+        config.validate().expect("Failed to validate");
+        assert_eq!(config.creator_pairs().len(), 2);
+    }
+
+    #[test]
+    fn test_config_find() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = tmp.path().join("config-find");
+        let (cwd, home) = (dir.join("cwd"), dir.join("home"));
+        let user_config = home.join(".config").join("kemono").join("config.toml");
+        std::fs::create_dir_all(&cwd).expect("Failed to create test dir");
+        std::fs::create_dir_all(user_config.parent().expect("No parent"))
+            .expect("Failed to create test dir");
+
+        assert_eq!(KemonoConfig::find_in(&cwd, Some(&home)), None);
+        std::fs::write(&user_config, "").expect("Failed to write");
+        assert_eq!(
+            KemonoConfig::find_in(&cwd, Some(&home)),
+            Some(user_config.clone())
+        );
+        std::fs::write(cwd.join(CONFIG_FILENAME), "").expect("Failed to write");
+        assert_eq!(
+            KemonoConfig::find_in(&cwd, Some(&home)),
+            Some(cwd.join(CONFIG_FILENAME))
+        );
+        assert_eq!(KemonoConfig::find_in(&home, None), None);
+
+        let written = dir.join("new").join(CONFIG_FILENAME);
+        KemonoConfig::write_template(&written, false).expect("Failed to write template");
+        assert_eq!(
+            KemonoConfig::from_file(&written).expect("Failed to load template"),
+            KemonoConfig::default()
+        );
+        assert!(KemonoConfig::write_template(&written, false).is_err());
+        KemonoConfig::write_template(&written, true).expect("Failed to overwrite template");
+    }
+
+    #[test]
+    fn test_parse_targets() {
+        let targets = parse_targets(
+            "# my subscriptions\npatreon 12345\n\n  fanbox   67890  # the good one\nonlyfans\npatreon 1 2\n",
+            "test",
+        );
+        assert_eq!(
+            targets,
+            vec![
+                ("patreon".to_string(), "12345".to_string()),
+                ("fanbox".to_string(), "67890".to_string()),
+            ]
+        );
+        assert!(read_targets(Path::new("/nonexistent/targets.txt")).is_err());
+    }
+
+    #[test]
+    fn test_config_merge() {
+        let file = KemonoConfig {
+            hostname: Some("file.example".to_string()),
+            threads: Some(4),
+            ..Default::default()
+        };
+        let cli = KemonoConfig {
+            threads: Some(8),
+            ..Default::default()
+        };
+        let merged = file.merge(cli);
+        assert_eq!(merged.hostname, Some("file.example".to_string()));
+        assert_eq!(merged.threads, Some(8));
+    }
+}
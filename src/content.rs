@@ -0,0 +1,403 @@
+//! Rendering of post content into standalone HTML or Markdown files.
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use reqwest::Url;
+
+use crate::errors::KemonoError;
+use crate::util::write_atomic;
+use crate::{Attachment, Post};
+
+/// Name of the directory post content files are written to
+pub static CONTENT_DIR: &str = "content";
+
+/// Which format to save a post's content in, if at all
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ContentFormat {
+    Html,
+    Markdown,
+    #[default]
+    None,
+}
+
+impl ContentFormat {
+    /// The file extension used for this format
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            ContentFormat::Html => Some("html"),
+            ContentFormat::Markdown => Some("md"),
+            ContentFormat::None => None,
+        }
+    }
+}
+
+impl FromStr for ContentFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "html" => Ok(ContentFormat::Html),
+            "markdown" | "md" => Ok(ContentFormat::Markdown),
+            "none" => Ok(ContentFormat::None),
+            _ => Err(format!(
+                "Invalid content format '{}', expected html, markdown or none",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for ContentFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContentFormat::Html => write!(f, "html"),
+            ContentFormat::Markdown => write!(f, "markdown"),
+            ContentFormat::None => write!(f, "none"),
+        }
+    }
+}
+
+/// Escape the characters that matter when dropping text into HTML
+pub fn escape_html(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn decode_entities(input: &str) -> String {
+    input
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Pull an attribute value out of the inside of a tag, eg `a href="foo"`
+fn get_attribute(tag: &str, name: &str) -> Option<String> {
+    let needle = format!("{}=", name);
+    let start = tag.find(&needle)? + needle.len();
+    let rest = &tag[start..];
+    let quote = rest.chars().next()?;
+    if quote == '"' || quote == '\'' {
+        let rest = &rest[1..];
+        rest.find(quote).map(|end| decode_entities(&rest[..end]))
+    } else {
+        rest.split_whitespace()
+            .next()
+            .map(|value| decode_entities(value.trim_end_matches('/')))
+    }
+}
+
+/// Returns the path of a media reference if it's on one of `hosts`, relative references are always on the host
+fn media_path(reference: &str, hosts: &[String]) -> Option<String> {
+    let path = match Url::parse(reference) {
+        Ok(url) => {
+            let host = url.host_str()?;
+            let known = hosts
+                .iter()
+                .any(|known| host == known || host.ends_with(&format!(".{}", known)));
+            if !known {
+                return None;
+            }
+            url.path().to_string()
+        }
+        // relative URL
+        Err(_) if reference.starts_with('/') && !reference.starts_with("//") => reference
+            .split(['?', '#'])
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        Err(_) => return None,
+    };
+    // only uploaded files, not links to other posts or pages
+    let is_media = path.starts_with("/data/")
+        || Path::new(&path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .is_some_and(|stem| stem.len() == 64 && stem.chars().all(|c| c.is_ascii_hexdigit()));
+    is_media.then_some(path)
+}
+
+/// Finds the media a post's content references with `<img src>` and `<a href>`, skipping anything not on `hosts`.
+///
+/// Returns the reference as it appears in the HTML along with an attachment to download it.
+pub fn inline_media(html: &str, hosts: &[String]) -> Vec<(String, Attachment)> {
+    let mut media: Vec<(String, Attachment)> = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = rest[start + 1..end].trim();
+        rest = &rest[end + 1..];
+
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        let reference = match name.as_str() {
+            "img" => get_attribute(tag, "src"),
+            "a" => get_attribute(tag, "href"),
+            _ => None,
+        };
+        let Some(reference) = reference else {
+            continue;
+        };
+        let Some(path) = media_path(&reference, hosts) else {
+            continue;
+        };
+        if media
+            .iter()
+            .any(|(_, attachment)| attachment.path.as_ref() == Some(&path))
+        {
+            continue;
+        }
+        let name = path.rsplit('/').next().unwrap_or_default().to_string();
+        media.push((
+            reference,
+            Attachment {
+                name: Some(name),
+                path: Some(path),
+            },
+        ));
+    }
+    media
+}
+
+/// Points references in the HTML at local files, `replacements` is pairs of (reference, local path)
+pub fn rewrite_media(html: &str, replacements: &[(String, String)]) -> String {
+    let mut html = html.to_string();
+    for (reference, local) in replacements {
+        // the reference was decoded, so it might be escaped in the source
+        for reference in [reference.clone(), reference.replace('&', "&amp;")] {
+            for quote in ['"', '\''] {
+                html = html.replace(
+                    &format!("={}{}{}", quote, reference, quote),
+                    &format!("={}{}{}", quote, escape_html(local), quote),
+                );
+            }
+        }
+    }
+    html
+}
+
+/// Wrap the post content in a minimal HTML page, with the title and published date
+pub fn post_to_html(post: &Post) -> String {
+    let title = escape_html(&post.title);
+    let published = escape_html(&post.published);
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n<p><time datetime=\"{}\">{}</time></p>\n{}\n</body>\n</html>\n",
+        title,
+        title,
+        published,
+        published,
+        post.content.clone().unwrap_or_default()
+    )
+}
+
+/// A very simple HTML to Markdown conversion, handling p, br, a, img and strong/em, other tags are dropped
+///
+/// ```
+/// use kemono::content::html_to_markdown;
+/// assert_eq!(
+///     html_to_markdown("<p>Hello <strong>there</strong></p><p><a href=\"https://example.com\">link</a></p>"),
+///     "Hello **there**\n\n[link](https://example.com)"
+/// );
+/// ```
+pub fn html_to_markdown(html: &str) -> String {
+    let mut output = String::new();
+    let mut links: Vec<Option<String>> = Vec::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        output.push_str(&decode_entities(&rest[..start]));
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => {
+                // not actually a tag, keep the text
+                output.push_str(&decode_entities(&rest[start..]));
+                rest = "";
+                break;
+            }
+        };
+        let tag = rest[start + 1..end].trim();
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        match (name.as_str(), closing) {
+            ("p", true) => output.push_str("\n\n"),
+            ("br", _) => output.push('\n'),
+            ("strong", _) | ("b", _) => output.push_str("**"),
+            ("em", _) | ("i", _) => output.push('*'),
+            ("a", false) => {
+                let href = get_attribute(tag, "href");
+                if href.is_some() {
+                    output.push('[');
+                }
+                links.push(href);
+            }
+            ("a", true) => {
+                if let Some(Some(href)) = links.pop() {
+                    output.push_str(&format!("]({})", href));
+                }
+            }
+            ("img", false) => {
+                if let Some(src) = get_attribute(tag, "src") {
+                    output.push_str(&format!(
+                        "![{}]({})",
+                        get_attribute(tag, "alt").unwrap_or_default(),
+                        src
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    output.push_str(&decode_entities(rest));
+    output.trim().to_string()
+}
+
+/// Strips the tags out of HTML, keeping paragraph and line breaks
+pub fn html_to_text(html: &str) -> String {
+    let mut output = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        output.push_str(&decode_entities(&rest[..start]));
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = rest[start + 1..end].trim().to_lowercase();
+        rest = &rest[end + 1..];
+        if tag == "/p" {
+            output.push_str("\n\n");
+        } else if tag.starts_with("br") {
+            output.push('\n');
+        }
+    }
+    output.push_str(&decode_entities(rest));
+    output.trim().to_string()
+}
+
+/// Writes the post's content to `content/{published}-{id}.{ext}` under `target_dir`, returns the path if a file was written
+pub fn write_post_content(
+    format: ContentFormat,
+    target_dir: &std::path::Path,
+    post: &Post,
+) -> Result<Option<PathBuf>, KemonoError> {
+    let extension = match format.extension() {
+        Some(extension) => extension,
+        None => return Ok(None),
+    };
+    let content = post.content.clone().unwrap_or_default();
+    if content.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let filepath = target_dir.join(CONTENT_DIR).join(format!(
+        "{}-{}.{}",
+        post.published.replace(':', "-"),
+        post.id,
+        extension
+    ));
+    if filepath.exists() {
+        return Ok(None);
+    }
+
+    let body = match format {
+        ContentFormat::Html => post_to_html(post),
+        ContentFormat::Markdown => format!("# {}\n\n{}\n", post.title, html_to_markdown(&content)),
+        ContentFormat::None => return Ok(None),
+    };
+
+    if let Some(parent) = filepath.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    }
+    write_atomic(&filepath, body)
+        .map_err(|err| KemonoError::from(format!("Failed to write post content: {:?}", err)))?;
+    Ok(Some(filepath))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html_to_markdown() {
+        assert_eq!(
+            html_to_markdown("<p>one<br/>two</p><p><em>three</em> &amp; <b>four</b></p>"),
+            "one\ntwo\n\n*three* & **four**"
+        );
+        assert_eq!(
+            html_to_markdown("<img src=\"/foo.png\" alt=\"a foo\">"),
+            "![a foo](/foo.png)"
+        );
+        assert_eq!(html_to_markdown("<a>no link</a>"), "no link");
+    }
+
+    #[test]
+    fn test_inline_media() {
+        let hosts = vec!["kemono.example".to_string()];
+        let html = concat!(
+            "<p><img src=\"/data/aa/bb/one.jpg\"></p>",
+            "<a href=\"https://n1.kemono.example/data/cc/dd/two.png?f=two.png\">two</a>",
+            "<img src='https://elsewhere.example/data/three.jpg'>",
+            "<a href=\"/patreon/user/12345/post/1\">another post</a>",
+            "<img src=\"/data/aa/bb/one.jpg\">",
+        );
+        let media = inline_media(html, &hosts);
+        assert_eq!(media.len(), 2);
+        assert_eq!(media[0].1.path, Some("/data/aa/bb/one.jpg".to_string()));
+        assert_eq!(media[1].1.name, Some("two.png".to_string()));
+        assert_eq!(media[1].1.path, Some("/data/cc/dd/two.png".to_string()));
+
+        let rewritten = rewrite_media(html, &[(media[0].0.clone(), "../2023-one.jpg".to_string())]);
+        assert!(rewritten.starts_with("<p><img src=\"../2023-one.jpg\"></p>"));
+        assert!(!rewritten.contains("\"/data/aa/bb/one.jpg\""));
+    }
+
+    #[test]
+    fn test_post_to_html() {
+        let mut post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        post.title = "Fish & chips".to_string();
+        post.content = Some("<p>hello</p>".to_string());
+        let html = post_to_html(&post);
+        assert!(html.contains("<title>Fish &amp; chips</title>"));
+        assert!(html.contains(&format!("<time datetime=\"{}\">", post.published)));
+        assert!(html.contains("<p>hello</p>"));
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        assert_eq!(
+            html_to_text("<p>one<br/>two</p><p><em>three</em> &amp; <a href=\"/x\">four</a></p>"),
+            "one\ntwo\n\nthree & four"
+        );
+    }
+
+    #[test]
+    fn test_content_format_from_str() {
+        assert_eq!(ContentFormat::from_str("HTML"), Ok(ContentFormat::Html));
+        assert_eq!(ContentFormat::from_str("md"), Ok(ContentFormat::Markdown));
+        assert_eq!(ContentFormat::from_str("none"), Ok(ContentFormat::None));
+        assert!(ContentFormat::from_str("pdf").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! Keeps the login session in a file between runs, so cron jobs don't have to log in every time.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use log::debug;
+use reqwest::cookie::CookieStore;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::KemonoError;
+use crate::util::part_path;
+use crate::KemonoClient;
+
+/// A cookie as it's stored in `--cookie-file`.
+///
+/// The jar only hands back names and values, so expiry isn't kept, an expired session shows up
+/// when the site rejects it.
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct SavedCookie {
+    /// The host the cookie's sent to, with the port if it's not the default
+    pub domain: String,
+    pub name: String,
+    pub value: String,
+}
+
+impl KemonoClient {
+    /// The cookies the jar sends to the site
+    pub fn saved_cookies(&self) -> Result<Vec<SavedCookie>, KemonoError> {
+        let url = Url::parse(&self.host_url()).map_err(KemonoError::from_stringable)?;
+        let Some(header) = self.cookies.cookies(&url) else {
+            return Ok(Vec::new());
+        };
+        let header = header.to_str().map_err(KemonoError::from_stringable)?;
+        Ok(header
+            .split(';')
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .map(|(name, value)| SavedCookie {
+                domain: self.hostname.clone(),
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect())
+    }
+
+    /// Writes the site's cookies to `path` as JSON, only readable by the current user
+    pub fn save_cookies(&self, path: &Path) -> Result<(), KemonoError> {
+        let cookies = serde_json::to_vec_pretty(&self.saved_cookies()?)?;
+        let part_path = part_path(path);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let res = options
+            .open(&part_path)
+            .and_then(|mut file| {
+                // the mode only applies to new files, so tighten up one left behind
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+                }
+                file.write_all(&cookies)?;
+                file.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&part_path, path));
+        if let Err(err) = res {
+            let _ = std::fs::remove_file(&part_path);
+            return Err(KemonoError::from(format!(
+                "Failed to save cookies to {}: {:?}",
+                path.display(),
+                err
+            )));
+        }
+        debug!("Saved cookies to {}", path.display());
+        Ok(())
+    }
+
+    /// Loads cookies saved by [KemonoClient::save_cookies] into the jar, returning how many there
+    /// were, a missing file isn't an error
+    pub fn load_cookies(&self, path: &Path) -> Result<usize, KemonoError> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => {
+                return Err(KemonoError::from(format!(
+                    "Failed to read cookies from {}: {:?}",
+                    path.display(),
+                    err
+                )))
+            }
+        };
+        let cookies: Vec<SavedCookie> = serde_json::from_slice(&contents).map_err(|err| {
+            KemonoError::Configuration(format!(
+                "Failed to parse cookies from {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        for cookie in cookies.iter() {
+            let url = Url::parse(&format!("{}://{}", self.scheme, cookie.domain))
+                .map_err(KemonoError::from_stringable)?;
+            self.cookies
+                .add_cookie_str(&format!("{}={}; Path=/", cookie.name, cookie.value), &url);
+        }
+        debug!("Loaded {} cookies from {}", cookies.len(), path.display());
+        Ok(cookies.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load_cookies() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = tmp.path().join("cookies");
+        std::fs::create_dir_all(&dir).expect("Failed to create test dir");
+        let path = dir.join("cookies.json");
+
+        let client = KemonoClient::new("example.com", None);
+        assert_eq!(client.load_cookies(&path).expect("Missing file is fine"), 0);
+        client
+            .set_session_token("abc123")
+            .expect("Failed to set session");
+        client.save_cookies(&path).expect("Failed to save cookies");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path)
+                .expect("Failed to stat cookies")
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let loaded = KemonoClient::new("example.com", None);
+        assert!(!loaded.has_session());
+        assert_eq!(loaded.load_cookies(&path).expect("Failed to load"), 1);
+        assert!(loaded.has_session());
+        assert_eq!(
+            loaded.saved_cookies().expect("Failed to list cookies"),
+            vec![SavedCookie {
+                domain: "example.com".to_string(),
+                name: "session".to_string(),
+                value: "abc123".to_string(),
+            }]
+        );
+
+        std::fs::write(&path, "not json").expect("Failed to write bad cookies");
+        assert!(matches!(
+            loaded.load_cookies(&path),
+            Err(KemonoError::Configuration(_))
+        ));
+    }
+}
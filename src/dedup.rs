@@ -0,0 +1,230 @@
+//! Content-addressed storage, so files which show up in multiple posts are only stored once.
+//!
+//! Objects live in `{download_path}/.objects/ab/cd/{sha256}` and the per-post files are links to them.
+
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+use serde::Serialize;
+
+use crate::content::CONTENT_DIR;
+use crate::download::{METADATA_DIR, PART_EXTENSION};
+use crate::errors::KemonoError;
+use crate::failures::FAILURES_FILENAME;
+use crate::layout::Layout;
+use crate::manifest::{sha256_file, MANIFEST_FILENAME, SHA256SUMS_FILENAME};
+
+pub static OBJECTS_DIR: &str = ".objects";
+
+/// How a file ended up linked to its object
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LinkKind {
+    Hardlink,
+    Symlink,
+    Copy,
+}
+
+/// Where the object for a given hash lives
+pub fn object_path(base_dir: &Path, hash: &str) -> PathBuf {
+    let mut path = base_dir.join(OBJECTS_DIR);
+    if hash.len() >= 4 {
+        path = path.join(&hash[0..2]).join(&hash[2..4]);
+    }
+    path.join(hash)
+}
+
+/// Links `target` to `object`, falling back to a symlink and then a copy if the filesystem doesn't support hardlinks
+pub fn link_or_copy(object: &Path, target: &Path) -> Result<LinkKind, KemonoError> {
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    }
+    if std::fs::hard_link(object, target).is_ok() {
+        return Ok(LinkKind::Hardlink);
+    }
+    #[cfg(unix)]
+    {
+        let absolute = object.canonicalize().unwrap_or(object.to_path_buf());
+        if std::os::unix::fs::symlink(absolute, target).is_ok() {
+            return Ok(LinkKind::Symlink);
+        }
+    }
+    std::fs::copy(object, target).map_err(|err| {
+        KemonoError::from(format!(
+            "Failed to copy {} to {}: {:?}",
+            object.display(),
+            target.display(),
+            err
+        ))
+    })?;
+    Ok(LinkKind::Copy)
+}
+
+/// Returns true if both paths point at the same file on disk
+fn same_file(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let (Ok(a), Ok(b)) = (std::fs::metadata(a), std::fs::metadata(b)) {
+            return a.dev() == b.dev() && a.ino() == b.ino();
+        }
+    }
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Moves `file` into the object store (unless it's already there) and replaces it with a link.
+///
+/// Returns true if the object already existed, meaning `file` was a duplicate.
+pub fn store_object(base_dir: &Path, hash: &str, file: &Path) -> Result<bool, KemonoError> {
+    let object = object_path(base_dir, hash);
+    if object.exists() {
+        if same_file(&object, file) {
+            return Ok(false);
+        }
+        std::fs::remove_file(file).map_err(|err| {
+            KemonoError::from(format!("Failed to remove {}: {:?}", file.display(), err))
+        })?;
+        link_or_copy(&object, file)?;
+        return Ok(true);
+    }
+
+    if let Some(parent) = object.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    }
+    if std::fs::rename(file, &object).is_err() {
+        // probably on a different filesystem
+        std::fs::copy(file, &object).map_err(|err| {
+            KemonoError::from(format!(
+                "Failed to copy {} into the object store: {:?}",
+                file.display(),
+                err
+            ))
+        })?;
+        std::fs::remove_file(file).map_err(|err| {
+            KemonoError::from(format!("Failed to remove {}: {:?}", file.display(), err))
+        })?;
+    }
+    link_or_copy(&object, file)?;
+    Ok(false)
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DedupSummary {
+    pub files: usize,
+    pub duplicates: usize,
+    pub bytes_saved: u64,
+}
+
+/// Files in a creator/service directory which are ours rather than downloads
+fn is_bookkeeping(name: &str) -> bool {
+    name.starts_with('.')
+        || name.ends_with(&format!(".{}", PART_EXTENSION))
+        || [MANIFEST_FILENAME, SHA256SUMS_FILENAME, FAILURES_FILENAME].contains(&name)
+}
+
+/// Downloaded files in a creator/service directory, at the top level or one level down if they're in post directories
+pub fn downloaded_files(dir: &Path) -> Result<Vec<PathBuf>, KemonoError> {
+    let read_dir = |path: &Path| {
+        path.read_dir().map_err(|err| {
+            KemonoError::from(format!("Failed to read {}: {:?}", path.display(), err))
+        })
+    };
+
+    let mut entries = Vec::new();
+    for entry in read_dir(dir)?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = entry.path();
+        if path.is_dir() {
+            if !name.starts_with('.') && name != METADATA_DIR && name != CONTENT_DIR {
+                entries.extend(read_dir(&path)?.flatten());
+            }
+        } else {
+            entries.push(entry);
+        }
+    }
+    Ok(entries
+        .into_iter()
+        .filter(|entry| !is_bookkeeping(&entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect())
+}
+
+/// Walks an existing archive, hashing every downloaded file and converting duplicates into links to the object store
+pub fn dedup_archive(base_dir: &Path) -> Result<DedupSummary, KemonoError> {
+    let mut summary = DedupSummary::default();
+
+    for dir in Layout::default().walk(base_dir) {
+        for path in downloaded_files(&dir.path)? {
+            let size = path.metadata().map(|m| m.len()).unwrap_or_default();
+            let hash = sha256_file(&path)?;
+            summary.files += 1;
+            if store_object(base_dir, &hash, &path)? {
+                debug!("{} is a duplicate of {}", path.display(), hash);
+                summary.duplicates += 1;
+                summary.bytes_saved += size;
+            }
+        }
+    }
+    info!(
+        "Deduplicated {} of {} files, saved {} bytes",
+        summary.duplicates, summary.files, summary.bytes_saved
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_path() {
+        assert_eq!(
+            object_path(Path::new("/download"), "abcdef"),
+            PathBuf::from("/download/.objects/ab/cd/abcdef")
+        );
+    }
+
+    #[test]
+    fn test_dedup_archive() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let base_dir = tmp.path().join("dedup");
+        let first = base_dir.join("12345").join("patreon");
+        let second = base_dir.join("67890").join("fanbox");
+        std::fs::create_dir_all(&first).expect("Failed to create test dir");
+        std::fs::create_dir_all(&second).expect("Failed to create test dir");
+        std::fs::write(first.join("a.jpg"), "hello").expect("Failed to write");
+        std::fs::write(first.join("b.jpg"), "hello").expect("Failed to write");
+        std::fs::write(second.join("c.jpg"), "hello").expect("Failed to write");
+        std::fs::write(second.join("d.jpg"), "different").expect("Failed to write");
+        std::fs::write(first.join(MANIFEST_FILENAME), "[]").expect("Failed to write");
+        // in a post directory
+        std::fs::create_dir_all(first.join("2023-01-01-1-post")).expect("Failed to create dir");
+        std::fs::write(first.join("2023-01-01-1-post").join("e.jpg"), "hello")
+            .expect("Failed to write");
+        std::fs::create_dir_all(first.join(METADATA_DIR)).expect("Failed to create dir");
+        std::fs::write(first.join(METADATA_DIR).join("1.json"), "hello").expect("Failed to write");
+
+        let summary = dedup_archive(&base_dir);
+        // running it again shouldn't find anything new
+        let again = dedup_archive(&base_dir);
+        let contents = std::fs::read_to_string(second.join("c.jpg"));
+        let object_exists = object_path(
+            &base_dir,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+        )
+        .exists();
+
+        let summary = summary.expect("Failed to dedup");
+        assert_eq!(summary.files, 5);
+        assert_eq!(summary.duplicates, 3);
+        assert_eq!(summary.bytes_saved, 15);
+        assert_eq!(again.expect("Failed to dedup").duplicates, 0);
+        assert_eq!(contents.expect("Failed to read"), "hello");
+        assert!(object_exists);
+    }
+}
@@ -0,0 +1,112 @@
+//! Archived Discord servers, which are channels of messages rather than a creator's posts.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Attachment, Embed, Post};
+
+/// The service name the site uses for Discord servers
+pub static DISCORD_SERVICE: &str = "discord";
+
+/// A Discord server, the server ID is what's used as the creator ID
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct DiscordServer {
+    pub id: String,
+    pub channels: Vec<DiscordChannel>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct DiscordChannel {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct DiscordAuthor {
+    pub id: String,
+    #[serde(default)]
+    pub username: String,
+}
+
+/// A message in a channel
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct DiscordMessage {
+    pub id: String,
+    #[serde(default)]
+    pub author: DiscordAuthor,
+    pub server: String,
+    pub channel: String,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub added: String,
+    #[serde(default)]
+    pub published: String,
+    pub edited: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+impl DiscordMessage {
+    /// Turns the message into a post so it can be downloaded like any other, titled with the channel's name
+    pub fn into_post(self, channel: &DiscordChannel) -> Post {
+        Post {
+            id: self.id,
+            user: self.server,
+            service: DISCORD_SERVICE.to_string(),
+            title: channel.name.clone(),
+            content: Some(self.content),
+            embed: Embed::default(),
+            shared_file: None,
+            file: Attachment {
+                name: None,
+                path: None,
+            },
+            added: self.added,
+            published: self.published,
+            edited: self.edited,
+            poll: None,
+            captions: None,
+            tags: None,
+            attachments: match self.attachments.is_empty() {
+                true => None,
+                false => Some(self.attachments.into_iter().collect::<HashSet<_>>()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_into_post() {
+        let message: DiscordMessage = serde_json::from_value(serde_json::json!({
+            "id": "111",
+            "author": {"id": "222", "username": "someone", "avatar": null},
+            "server": "333",
+            "channel": "444",
+            "content": "hello",
+            "added": "2023-01-02T00:00:00",
+            "published": "2023-01-01T00:00:00",
+            "edited": null,
+            "embeds": [],
+            "mentions": [],
+            "attachments": [{"name": "a.png", "path": "/aa/bb/a.png"}]
+        }))
+        .expect("Failed to deserialize message");
+        let channel = DiscordChannel {
+            id: "444".to_string(),
+            name: "art".to_string(),
+        };
+
+        let post = message.into_post(&channel);
+        assert_eq!(post.service, DISCORD_SERVICE);
+        assert_eq!(post.user, "333");
+        assert_eq!(post.title, "art");
+        assert_eq!(post.edited, None);
+        assert_eq!(crate::download::collect_files(&[post]).len(), 1);
+    }
+}
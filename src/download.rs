@@ -0,0 +1,2635 @@
+//! The download engine, walks a creator's posts and pulls down metadata and attachments.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::TryStreamExt;
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{StatusCode, Url};
+use serde_json::json;
+
+use crate::content::{inline_media, rewrite_media, write_post_content, ContentFormat};
+use crate::dedup::{link_or_copy, object_path, store_object};
+use crate::discord::DISCORD_SERVICE;
+use crate::errors::KemonoError;
+use crate::export::{write_captions, write_nfo, write_post_markdown, VIDEO_EXTENSIONS};
+use crate::failures::{failures_path, read_failures, write_failures, Failure};
+use crate::filter::{FilenameFilter, TagFilter};
+use crate::hooks::{run_hooks, DownloadHook, HookEvent};
+use crate::layout::{sanitize_name, Layout};
+use crate::manifest::{
+    checked_sha256, hash_from_path, read_manifest, sha256sums_writer, write_manifest, Validator,
+    Validators,
+};
+use crate::mtime::{set_file_times, MtimeSource};
+use crate::output::print_action;
+use crate::state::SyncState;
+use crate::summary::{DownloadCounters, DownloadOutcome, DownloadSummary};
+use crate::util::{
+    get_canonical_filename, get_converted_filename, part_path, write_atomic, FilenameTemplate,
+};
+use crate::{Attachment, ConvertedExtensions, DirectMessage, KemonoClient, Post, DEFAULT_THREADS};
+
+/// Name of the per creator/service metadata directory, unless there's a separate metadata path
+pub static METADATA_DIR: &str = "metadata";
+
+/// Extension for files which are still being downloaded
+pub static PART_EXTENSION: &str = "part";
+
+/// Where `--thumbnails` puts the thumbnails, under the creator/service directory
+pub static THUMBS_DIR: &str = "thumbs";
+
+/// Where files from shared file posts go, under the creator/service directory
+pub static SHARED_DIR: &str = "shared";
+
+/// Where `include_dms` saves the creator's DMs, in the metadata directory
+pub static DMS_FILE: &str = "dms.json";
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask running downloads to stop, in-flight files are finished but nothing new is started
+pub fn request_stop() {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Forgets a [request_stop] so later downloads in the process run, returning whether there was one
+pub fn clear_stop() -> bool {
+    STOP_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Options controlling a download run for a single creator/service
+#[derive(Clone, Debug)]
+pub struct DownloadOptions {
+    pub service: String,
+    pub creator: String,
+    /// Number of concurrent downloads
+    pub threads: usize,
+    /// Which attachments to download by name
+    pub filename: FilenameFilter,
+    /// If the "original" file is an mp4 or m4v then we might have a mkv file and that's OK
+    pub mkvs: bool,
+    /// Which extensions to look for instead of the original when `mkvs` is set
+    pub converted_extensions: ConvertedExtensions,
+    /// Overrides the client's download path if set
+    pub download_path: Option<String>,
+    /// Save each post's content as a standalone file in this format
+    pub content_format: ContentFormat,
+    /// Only handle posts newer than this post ID
+    pub since_post_id: Option<String>,
+    /// Only fetch posts newer than the high-water mark in the sync state file
+    pub incremental: bool,
+    /// Don't refresh the stored metadata when a post has been edited
+    pub ignore_edits: bool,
+    /// Append newly downloaded files to a `SHA256SUMS` file
+    pub sha256sums: bool,
+    /// Store files once in the content-addressed object store and link to them
+    pub dedup: bool,
+    /// Which of the post's dates to set as the mtime of downloaded files
+    pub mtime: MtimeSource,
+    /// Caps how many of `threads` a single creator can use
+    pub per_creator_threads: Option<usize>,
+    /// Also download media referenced in the post content which isn't in the attachments
+    pub inline_media: bool,
+    /// Point the saved content files at the downloaded inline media
+    pub rewrite_media: bool,
+    /// Write a Markdown version of each post next to its metadata
+    pub export_markdown: bool,
+    /// How creator/service directories are laid out
+    pub layout: Layout,
+    /// The creator's display name, for layouts which use it
+    pub creator_name: Option<String>,
+    /// Keep metadata under this root instead of in a `metadata` directory next to the downloads
+    pub metadata_path: Option<String>,
+    /// Write an NFO sidecar next to each video attachment
+    pub nfo: bool,
+    /// Put each post's files in their own `{published}-{id}-{title}` directory
+    pub post_dirs: bool,
+    /// Files smaller than this many bytes are treated as broken and downloaded again, empty files always are
+    pub suspect_threshold: Option<u64>,
+    /// Fetch the creator's icon and banner into their directory
+    pub profile_images: bool,
+    /// Don't write per-post metadata or the sync state, so there's no metadata directory at all.
+    ///
+    /// That means `incremental` has nothing to go on, and [retry_failed] can't rebuild the posts.
+    pub no_metadata: bool,
+    /// Write the per-post metadata but don't download any attachments, refreshing what's already stored
+    pub metadata_only: bool,
+    /// Run this for each successfully downloaded file
+    pub exec_after: Option<DownloadHook>,
+    /// How many `exec_after` hooks can run at once
+    pub hook_concurrency: usize,
+    /// Download image thumbnails into [THUMBS_DIR] instead of the originals
+    pub thumbnails: bool,
+    /// Download files again even if they're already there
+    pub force: bool,
+    /// Only handle this post
+    pub post_id: Option<String>,
+    /// Only handle posts with these tags
+    pub tags: TagFilter,
+    /// How downloaded files are named
+    pub filename_template: FilenameTemplate,
+    /// Save the creator's DMs to [DMS_FILE]
+    pub include_dms: bool,
+    /// Skip this many of the newest posts
+    pub offset: usize,
+    /// Stop after this many posts
+    pub max_posts: Option<usize>,
+}
+
+impl DownloadOptions {
+    pub fn new(service: &str, creator: &str) -> Self {
+        Self {
+            service: service.to_string(),
+            creator: creator.to_string(),
+            threads: DEFAULT_THREADS,
+            filename: FilenameFilter::default(),
+            mkvs: false,
+            converted_extensions: ConvertedExtensions::default(),
+            download_path: None,
+            content_format: ContentFormat::None,
+            since_post_id: None,
+            incremental: false,
+            ignore_edits: false,
+            sha256sums: false,
+            dedup: false,
+            mtime: MtimeSource::default(),
+            per_creator_threads: None,
+            inline_media: false,
+            rewrite_media: false,
+            export_markdown: false,
+            layout: Layout::default(),
+            creator_name: None,
+            metadata_path: None,
+            nfo: false,
+            post_dirs: false,
+            suspect_threshold: None,
+            profile_images: true,
+            no_metadata: false,
+            metadata_only: false,
+            exec_after: None,
+            hook_concurrency: 1,
+            thumbnails: false,
+            force: false,
+            post_id: None,
+            tags: TagFilter::default(),
+            filename_template: FilenameTemplate::default(),
+            include_dms: false,
+            offset: 0,
+            max_posts: None,
+        }
+    }
+
+    /// Returns the base download path, using our download path if it's set
+    pub fn base_dir(&self, client: &KemonoClient) -> PathBuf {
+        PathBuf::from(
+            self.download_path
+                .clone()
+                .unwrap_or(client.get_base_download_path()),
+        )
+    }
+
+    /// Returns the creator/service directory under the base path, based on the layout
+    pub fn target_dir(&self, client: &KemonoClient) -> PathBuf {
+        let base_dir = self.base_dir(client);
+        let relative = self.layout.relative_dir(
+            &base_dir,
+            &self.service,
+            &self.creator,
+            self.creator_name.as_deref(),
+        );
+        base_dir.join(relative)
+    }
+
+    /// Where the post metadata and sync state go, `{metadata_path}/{layout dirs}` if it's set, otherwise `metadata` in the target dir
+    pub fn metadata_dir(&self, client: &KemonoClient) -> PathBuf {
+        match &self.metadata_path {
+            Some(metadata_path) => PathBuf::from(metadata_path).join(self.layout.relative_dir(
+                &self.base_dir(client),
+                &self.service,
+                &self.creator,
+                self.creator_name.as_deref(),
+            )),
+            None => self.target_dir(client).join(METADATA_DIR),
+        }
+    }
+
+    /// Fills in the creator's name if the layout needs it, from an existing directory or the API
+    pub async fn resolve_creator_name(&self, client: &KemonoClient) -> Self {
+        let mut options = self.clone();
+        if !self.layout.needs_name() || self.creator_name.is_some() {
+            return options;
+        }
+        options.creator_name =
+            match self
+                .layout
+                .find_existing(&self.base_dir(client), &self.service, &self.creator)
+            {
+                Some(existing) => existing.name,
+                None => match client.creator_name(&self.service, &self.creator).await {
+                    Ok(name) => Some(name),
+                    Err(err) => {
+                        warn!(
+                            "Couldn't get the name of {}/{}, using the ID: {:?}",
+                            self.service, self.creator, err
+                        );
+                        None
+                    }
+                },
+            };
+        options
+    }
+
+    /// Whether only some of the creator's posts are being handled, because of `offset` or `max_posts`
+    pub fn windowed(&self) -> bool {
+        self.offset > 0 || self.max_posts.is_some()
+    }
+
+    /// How many threads to download `files` attachments with, there's no point having more threads than files
+    pub fn worker_threads(&self, files: usize) -> usize {
+        self.threads
+            .min(self.per_creator_threads.unwrap_or(usize::MAX))
+            .min(files)
+            .max(1)
+    }
+
+    /// Returns false if the filename filter is set and the attachment doesn't match it
+    pub fn matches_filter(&self, attachment: &Attachment) -> bool {
+        match &attachment.name {
+            Some(name) => self.filename.matches(name),
+            None => true,
+        }
+    }
+}
+
+/// Pulls the list of (post, attachment) pairs to download, including the post's main file
+pub fn collect_files(posts: &[Post]) -> Vec<(Post, Attachment)> {
+    posts
+        .iter()
+        .flat_map(|post| {
+            post.all_attachments()
+                .into_iter()
+                .map(|attachment| (post.clone(), attachment.clone()))
+        })
+        .collect()
+}
+
+/// The hosts whose media we'll download from post content, the main host and any data hosts
+fn media_hosts(client: &KemonoClient) -> Vec<String> {
+    std::iter::once(client.host_url())
+        .chain(client.data_host_urls())
+        .filter_map(|url| Url::parse(&url).ok()?.host_str().map(str::to_string))
+        .collect()
+}
+
+/// Media referenced in the post content which isn't one of its attachments
+pub fn collect_inline_media(client: &KemonoClient, post: &Post) -> Vec<(String, Attachment)> {
+    let known = post.all_attachments();
+    inline_media(
+        post.content.as_deref().unwrap_or_default(),
+        &media_hosts(client),
+    )
+    .into_iter()
+    .filter(|(_, attachment)| !known.iter().any(|known| known.path == attachment.path))
+    .collect()
+}
+
+/// The extension to save an image as, from its content type
+fn image_extension(content_type: &str) -> Option<String> {
+    let subtype = content_type
+        .split(';')
+        .next()?
+        .trim()
+        .strip_prefix("image/")?
+        .split('+')
+        .next()?
+        .to_lowercase();
+    match subtype.as_str() {
+        "" => None,
+        "jpeg" => Some("jpg".to_string()),
+        _ => Some(subtype),
+    }
+}
+
+/// The creator's icon and banner, which are saved as `{stem}.{ext}`
+pub static PROFILE_IMAGES: [&str; 2] = ["icon", "banner"];
+
+/// Returns true if the filename is one of the [PROFILE_IMAGES]
+pub fn is_profile_image(name: &str) -> bool {
+    name.split_once('.')
+        .is_some_and(|(stem, _)| PROFILE_IMAGES.contains(&stem))
+}
+
+/// Adds the creator's DMs to [DMS_FILE] in the metadata directory, returns how many weren't there already.
+///
+/// Messages which have since been removed upstream are kept.
+pub async fn save_dms(
+    client: &KemonoClient,
+    options: &DownloadOptions,
+) -> Result<usize, KemonoError> {
+    let fetched = client.dms(&options.service, &options.creator).await?;
+    let metadata_dir = options.metadata_dir(client);
+    let path = metadata_dir.join(DMS_FILE);
+    let mut dms: Vec<DirectMessage> = match std::fs::read_to_string(&path) {
+        Ok(data) => serde_json::from_str(&data).map_err(|err| {
+            KemonoError::from(format!("Failed to parse {}: {:?}", path.display(), err))
+        })?,
+        Err(_) => Vec::new(),
+    };
+    let before = dms.len();
+    for dm in fetched {
+        if !dms.iter().any(|existing| existing.hash == dm.hash) {
+            dms.push(dm);
+        }
+    }
+    let added = dms.len() - before;
+    if added > 0 {
+        dms.sort_by(|a, b| {
+            (a.published.as_ref().or(a.added.as_ref()))
+                .cmp(&b.published.as_ref().or(b.added.as_ref()))
+        });
+        std::fs::create_dir_all(&metadata_dir)
+            .map_err(|err| format!("Failed to create {}: {:?}", metadata_dir.display(), err))?;
+        write_atomic(&path, serde_json::to_string_pretty(&dms)?).map_err(|err| {
+            KemonoError::from(format!("Failed to write {}: {:?}", path.display(), err))
+        })?;
+    }
+    Ok(added)
+}
+
+/// Fetches the creator's `icon.{ext}` and `banner.{ext}` into `target_dir` if they're not already there, returns how many were saved.
+///
+/// Not every creator has them, so a 404 is ignored.
+pub async fn download_profile_images(
+    client: &KemonoClient,
+    options: &DownloadOptions,
+) -> Result<usize, KemonoError> {
+    let target_dir = options.target_dir(client);
+    let existing: Vec<String> = match target_dir.read_dir() {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let session = client.new_async_session()?;
+    let mut saved = 0;
+    for stem in PROFILE_IMAGES {
+        if existing
+            .iter()
+            .any(|name| name.split_once('.').map(|(s, _)| s) == Some(stem))
+        {
+            continue;
+        }
+        let url = match stem {
+            "icon" => client.icon_url(&options.service, &options.creator)?,
+            _ => client.banner_url(&options.service, &options.creator)?,
+        };
+        let response = session.get(url.clone()).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            debug!("No {} for {}/{}", stem, options.service, options.creator);
+            continue;
+        }
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(KemonoError::RateLimited);
+        }
+        let response = response.error_for_status()?;
+        let extension = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(image_extension);
+        let Some(extension) = extension else {
+            debug!("{} didn't return an image, skipping it", url);
+            continue;
+        };
+        let bytes = response.bytes().await?;
+        std::fs::create_dir_all(&target_dir)
+            .map_err(|err| format!("Failed to create {}: {:?}", target_dir.display(), err))?;
+        let path = target_dir.join(format!("{}.{}", stem, extension));
+        write_atomic(&path, &bytes).map_err(|err| {
+            KemonoError::from(format!("Failed to write {}: {:?}", path.display(), err))
+        })?;
+        saved += 1;
+    }
+    Ok(saved)
+}
+
+/// What happened when we checked a post against its stored metadata
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MetadataStatus {
+    /// We hadn't seen this post before
+    New,
+    Unchanged,
+    /// The post changed upstream, includes any attachments which weren't in the stored version
+    Edited(Vec<Attachment>),
+}
+
+/// Returns true if the post looks like it's changed since `stored` was saved
+pub fn post_changed(stored: &Post, post: &Post) -> bool {
+    stored.edited != post.edited
+        || stored.added != post.added
+        || stored.file != post.file
+        || stored.attachments != post.attachments
+        || stored.embed != post.embed
+        || stored.poll != post.poll
+        || stored.captions != post.captions
+}
+
+/// Attachments in `post` which aren't in `stored`
+pub fn new_attachments(stored: &Post, post: &Post) -> Vec<Attachment> {
+    let stored_files: Vec<(Post, Attachment)> = collect_files(std::slice::from_ref(stored));
+    collect_files(std::slice::from_ref(post))
+        .into_iter()
+        .map(|(_, attachment)| attachment)
+        .filter(|attachment| {
+            !stored_files
+                .iter()
+                .any(|(_, stored_attachment)| stored_attachment.path == attachment.path)
+        })
+        .collect()
+}
+
+/// Writes the post's JSON to `metadata/{id}.json` if it's new, or if it's been edited and we're not ignoring edits.
+///
+/// Every post is [MetadataStatus::New] if `no_metadata` is set, as there's nothing to compare against.
+pub fn write_post_metadata(
+    options: &DownloadOptions,
+    client: &KemonoClient,
+    post: &Post,
+) -> Result<MetadataStatus, KemonoError> {
+    if options.no_metadata {
+        return Ok(MetadataStatus::New);
+    }
+    let post_data_filepath = options
+        .metadata_dir(client)
+        .join(format!("{}.json", post.id));
+
+    let status = match post_data_filepath.exists() {
+        false => MetadataStatus::New,
+        true if options.ignore_edits => MetadataStatus::Unchanged,
+        true => {
+            let stored = std::fs::read_to_string(&post_data_filepath)
+                .ok()
+                .and_then(|contents| serde_json::from_str::<Post>(&contents).ok());
+            match stored {
+                Some(stored) if !post_changed(&stored, post) => MetadataStatus::Unchanged,
+                Some(stored) => MetadataStatus::Edited(new_attachments(&stored, post)),
+                // couldn't parse what's there, so replace it
+                None => MetadataStatus::Edited(Vec::new()),
+            }
+        }
+    };
+    // refreshing what's stored is the point of --metadata-only
+    if status == MetadataStatus::Unchanged && !options.metadata_only {
+        return Ok(status);
+    }
+
+    if let Some(parent) = post_data_filepath.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    }
+    write_atomic(&post_data_filepath, serde_json::to_string_pretty(post)?)
+        .map_err(|err| KemonoError::from(format!("Failed to write post data: {:?}", err)))?;
+    set_file_times(&post_data_filepath, options.mtime, post);
+    Ok(status)
+}
+
+/// Longest a post's title can be in its directory name
+static POST_DIR_TITLE_LENGTH: usize = 50;
+
+/// The per-post directory name, `{published}-{id}-{title}` with the title sanitized and truncated
+pub fn post_dir_name(post: &Post) -> String {
+    let title: String = sanitize_name(&post.title)
+        .chars()
+        .take(POST_DIR_TITLE_LENGTH)
+        .collect();
+    let title = title.trim();
+    let dir_name = format!("{}-{}", post.published.replace(':', "-"), post.id);
+    match title.is_empty() {
+        true => dir_name,
+        false => format!("{}-{}", dir_name, title),
+    }
+}
+
+/// Where an attachment goes relative to the creator/service directory, named by [get_canonical_filename]
+/// and in its post's directory if `post_dirs` is set.
+///
+/// Shared file posts go in [SHARED_DIR] instead, so they're not buried among the dated attachments.
+pub fn download_relative_path(
+    post: &Post,
+    attachment: &Attachment,
+    options: &DownloadOptions,
+) -> Result<String, KemonoError> {
+    Ok(place_filename(
+        post,
+        options,
+        get_canonical_filename(post, attachment, options)?,
+    ))
+}
+
+/// Where a remuxed copy of an attachment would be, named by [get_converted_filename], or None if
+/// its extension isn't one that gets converted
+pub fn converted_relative_path(
+    post: &Post,
+    attachment: &Attachment,
+    options: &DownloadOptions,
+) -> Result<Option<String>, KemonoError> {
+    Ok(get_converted_filename(post, attachment, options)?
+        .map(|filename| place_filename(post, options, filename)))
+}
+
+/// Puts a filename in the directory its post's files go in
+fn place_filename(post: &Post, options: &DownloadOptions, filename: String) -> String {
+    if post.shared_file == Some(true) {
+        return format!("{}/{}", SHARED_DIR, filename);
+    }
+    match options.post_dirs {
+        true => format!("{}/{}", post_dir_name(post), filename),
+        false => filename,
+    }
+}
+
+/// The URL to download an attachment from, on the preferred data host
+pub fn attachment_url(client: &KemonoClient, attachment: &Attachment) -> Result<Url, KemonoError> {
+    attachment_urls(client, attachment)?
+        .into_iter()
+        .next()
+        .ok_or(KemonoError::from_stringable("No data hosts configured"))
+}
+
+/// The URLs to try downloading an attachment from, one per data host in order of preference
+pub fn attachment_urls(
+    client: &KemonoClient,
+    attachment: &Attachment,
+) -> Result<Vec<Url>, KemonoError> {
+    let mut attachment_path = attachment.path.clone().unwrap_or_default();
+    if !attachment_path.starts_with('/') {
+        attachment_path = format!("/{}", attachment_path);
+    }
+    client
+        .data_host_urls()
+        .iter()
+        .map(|host| {
+            Url::from_str(&format!("{}{}", host, attachment_path))
+                .map_err(KemonoError::from_stringable)
+        })
+        .collect()
+}
+
+/// Whether it's worth trying another data host after this error
+fn try_next_host(err: &reqwest::Error) -> bool {
+    match err.status() {
+        Some(status) => status == StatusCode::FORBIDDEN || status.is_server_error(),
+        None => err.is_connect() || err.is_timeout(),
+    }
+}
+
+/// Requests the attachment from each data host in turn, until one works
+///
+/// With a `validator` the request is conditional, so the response might be a `304 Not Modified`.
+fn fetch_attachment(
+    client: &mut KemonoClient,
+    attachment: &Attachment,
+    validator: Option<&Validator>,
+) -> Result<reqwest::blocking::Response, KemonoError> {
+    if client.session.is_none() {
+        client.new_session()?;
+    }
+    let session = client
+        .session
+        .clone()
+        .ok_or(KemonoError::from_stringable("No session available"))?;
+
+    let urls = attachment_urls(client, attachment)?;
+    let mut last_error = None;
+    for url in urls {
+        let host = url.origin().ascii_serialization();
+        let mut request = session.get(url);
+        if let Some(etag) = validator.and_then(|validator| validator.etag.as_deref()) {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) =
+            validator.and_then(|validator| validator.last_modified.as_deref())
+        {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        match request
+            .send()
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(response) => {
+                client.prefer_data_host(&host);
+                return Ok(response);
+            }
+            Err(err) if try_next_host(&err) => {
+                warn!("Failed to download {} from {}: {:?}", attachment, host, err);
+                last_error = Some(err);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Err(match last_error {
+        Some(err) => err.into(),
+        None => KemonoError::from_stringable("No data hosts configured"),
+    })
+}
+
+/// Files with these extensions should never start with `<`, so if a small one does it's an error page
+static SNIFF_EXTENSIONS: [&str; 3] = ["zip", "rar", "7z"];
+/// Only files smaller than this get sniffed, error pages are small
+static SNIFF_LIMIT: u64 = 64 * 1024;
+
+/// Why a downloaded file looks broken, if it does: it's empty, smaller than `threshold`, or an HTML error page saved as a video or archive
+pub fn suspect_reason(path: &Path, threshold: Option<u64>) -> Option<String> {
+    let size = path.metadata().ok()?.len();
+    if size == 0 {
+        return Some("it's empty".to_string());
+    }
+    if threshold.is_some_and(|threshold| size < threshold) {
+        return Some(format!("it's only {} bytes", size));
+    }
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if size < SNIFF_LIMIT
+        && (VIDEO_EXTENSIONS.contains(&extension.as_str())
+            || SNIFF_EXTENSIONS.contains(&extension.as_str()))
+    {
+        let mut start = [0u8; 512];
+        let read = File::open(path)
+            .and_then(|mut file| file.read(&mut start))
+            .ok()?;
+        if start[..read].trim_ascii_start().starts_with(b"<") {
+            return Some("it looks like an HTML page".to_string());
+        }
+    }
+    None
+}
+
+/// download a given file
+///
+/// If the file was downloaded before under another name, `validators` are used to ask the server
+/// whether it's changed, and if it hasn't the old file's linked into place.
+pub fn download_content(
+    options: &DownloadOptions,
+    client: &mut KemonoClient,
+    post: &Post,
+    attachment: &Attachment,
+    validators: &Validators,
+) -> Result<DownloadOutcome, KemonoError> {
+    if !attachment.is_complete() {
+        return Err(KemonoError::from(format!(
+            "Attachment is missing a name or path! {}",
+            attachment
+        )));
+    }
+    let relative_path = download_relative_path(post, attachment, options)?;
+    let target_dir = options.target_dir(client);
+    let download_path = target_dir.join(&relative_path);
+    // if we've already got the original there's no point getting a thumbnail of it
+    if options.thumbnails && (options.force || !download_path.exists()) {
+        if let Some(outcome) = download_thumbnail(options, client, post, attachment)? {
+            return Ok(outcome);
+        }
+    }
+    // check
+    let mut repairing = match options.force {
+        true => false,
+        false => match needs_download(&download_path, options.suspect_threshold) {
+            Some(repairing) => repairing,
+            None => return Ok(DownloadOutcome::Exists),
+        },
+    };
+
+    if let Some(converted) = converted_relative_path(post, attachment, options)?
+        .filter(|_| options.mkvs && !options.force)
+    {
+        let full_mkv_path = target_dir.join(converted);
+        if full_mkv_path.exists() {
+            if let Some(reason) = suspect_reason(&full_mkv_path, options.suspect_threshold) {
+                // the mkv is what's broken, so it's no good as a stand-in
+                warn!(
+                    "Ignoring {} as it looks broken: {}",
+                    full_mkv_path.display(),
+                    reason
+                );
+                repairing = true;
+            }
+        }
+        if full_mkv_path.exists() && !repairing {
+            debug!(
+                "Skipping mkv {} because it already exists",
+                full_mkv_path.display()
+            );
+            return Ok(DownloadOutcome::Exists);
+        } else {
+            debug!("Couldn't find mkv {}", full_mkv_path.display());
+        }
+    }
+
+    // if we've already got the bytes in the object store, just link to them
+    let object_hash = match options.dedup {
+        true => hash_from_path(attachment),
+        false => None,
+    };
+    // a broken file might be a link to a broken object, so don't link it straight back
+    if let Some(hash) = object_hash
+        .as_ref()
+        .filter(|_| !options.force && !repairing)
+    {
+        let object = object_path(&options.base_dir(client), hash);
+        if object.exists() {
+            debug!(
+                "Linking {} to existing object {}",
+                download_path.display(),
+                object.display()
+            );
+            link_or_copy(&object, &download_path)?;
+            return Ok(DownloadOutcome::Linked);
+        }
+    }
+
+    let url = attachment_url(client, attachment)?;
+    print_action(
+        "download",
+        json!({
+            "filename": download_path.display().to_string(),
+            "url": url.to_string(),
+        }),
+    );
+
+    // only worth asking if we've still got the file somewhere else
+    let validator = attachment
+        .path
+        .as_deref()
+        .and_then(|path| validators.get(path))
+        .filter(|validator| {
+            !repairing
+                && !options.force
+                && validator.filename != relative_path
+                && target_dir.join(&validator.filename).is_file()
+        });
+    let response = fetch_attachment(client, attachment, validator.as_ref())?;
+    if let Some(validator) = validator.filter(|_| response.status() == StatusCode::NOT_MODIFIED) {
+        debug!(
+            "{} hasn't changed since it was saved as {}, linking it",
+            attachment, validator.filename
+        );
+        link_or_copy(&target_dir.join(&validator.filename), &download_path)?;
+        return Ok(DownloadOutcome::Linked);
+    }
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+    let size = save_response(response, &download_path)?;
+    set_file_times(&download_path, options.mtime, post);
+    if let (Some(path), true) = (
+        attachment.path.as_deref(),
+        etag.is_some() || last_modified.is_some(),
+    ) {
+        validators.insert(
+            path,
+            Validator {
+                filename: relative_path,
+                etag,
+                last_modified,
+            },
+        );
+    }
+    if let Some(hash) = &object_hash {
+        let object = object_path(&options.base_dir(client), hash);
+        // what's in the store could be what's broken
+        if (options.force || repairing) && object.exists() {
+            std::fs::remove_file(&object).map_err(|err| {
+                KemonoError::from(format!("Failed to remove {}: {:?}", object.display(), err))
+            })?;
+        }
+        store_object(&options.base_dir(client), hash, &download_path)?;
+    }
+    Ok(match repairing {
+        true => DownloadOutcome::Repaired(size),
+        false => DownloadOutcome::Downloaded(size),
+    })
+}
+
+/// Whether `path` needs downloading, None if it's already there, true if it's there but broken
+///
+/// A broken file's left where it is until the new download replaces it, in case that fails.
+fn needs_download(path: &Path, threshold: Option<u64>) -> Option<bool> {
+    if !path.exists() {
+        return Some(false);
+    }
+    match suspect_reason(path, threshold) {
+        Some(reason) => {
+            warn!(
+                "Downloading {} again as it looks broken: {}",
+                path.display(),
+                reason
+            );
+            Some(true)
+        }
+        None => {
+            debug!("Skipping {} because it already exists", path.display());
+            None
+        }
+    }
+}
+
+/// Writes to a `.part` file next to `download_path` first, so an interrupted download never looks complete
+fn save_response(
+    mut response: reqwest::blocking::Response,
+    download_path: &Path,
+) -> Result<u64, KemonoError> {
+    if let Some(parent) = download_path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    }
+    let part_path = part_path(download_path);
+    let mut part_file = File::create(&part_path).map_err(|err| {
+        KemonoError::from(format!(
+            "Failed to create {}: {:?}",
+            part_path.display(),
+            err
+        ))
+    })?;
+    let size = response.copy_to(&mut part_file)?;
+    part_file
+        .sync_all()
+        .map_err(|err| KemonoError::from(format!("Failed to write image data: {:?}", err)))?;
+    std::fs::rename(&part_path, download_path).map_err(|err| {
+        KemonoError::from(format!(
+            "Failed to move {} into place: {:?}",
+            part_path.display(),
+            err
+        ))
+    })?;
+    Ok(size)
+}
+
+/// Removes the `.part` files left in `dir` by runs which didn't finish, returns how many there were.
+///
+/// Only call this when nothing else is downloading into `dir`, or their files will go missing.
+pub fn remove_partial_files(dir: &Path) -> Result<usize, KemonoError> {
+    let mut removed = 0;
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match dir.read_dir() {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(KemonoError::from(format!(
+                    "Failed to read {}: {:?}",
+                    dir.display(),
+                    err
+                )))
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => dirs.push(path),
+                Ok(file_type)
+                    if file_type.is_file()
+                        && path.extension().is_some_and(|ext| ext == PART_EXTENSION) =>
+                {
+                    debug!("Removing partial file {}", path.display());
+                    std::fs::remove_file(&path).map_err(|err| {
+                        KemonoError::from(format!("Failed to remove {}: {:?}", path.display(), err))
+                    })?;
+                    removed += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Where the thumbnail of an attachment goes, if it's got one
+pub fn thumbnail_path(
+    target_dir: &Path,
+    post: &Post,
+    attachment: &Attachment,
+    options: &DownloadOptions,
+) -> Result<PathBuf, KemonoError> {
+    Ok(target_dir
+        .join(THUMBS_DIR)
+        .join(download_relative_path(post, attachment, options)?))
+}
+
+/// Where an attachment ended up, its thumbnail if we're downloading those and got one
+pub fn local_path(
+    options: &DownloadOptions,
+    target_dir: &Path,
+    post: &Post,
+    attachment: &Attachment,
+) -> Result<PathBuf, KemonoError> {
+    let thumbnail = thumbnail_path(target_dir, post, attachment, options)?;
+    Ok(match options.thumbnails && thumbnail.exists() {
+        true => thumbnail,
+        false => target_dir.join(download_relative_path(post, attachment, options)?),
+    })
+}
+
+/// Downloads the attachment's thumbnail into [THUMBS_DIR], None if it isn't an image or the
+/// site hasn't got a thumbnail, so the caller can get the original instead
+fn download_thumbnail(
+    options: &DownloadOptions,
+    client: &mut KemonoClient,
+    post: &Post,
+    attachment: &Attachment,
+) -> Result<Option<DownloadOutcome>, KemonoError> {
+    let Some(url) = attachment.thumbnail_url(client) else {
+        return Ok(None);
+    };
+    let download_path = thumbnail_path(&options.target_dir(client), post, attachment, options)?;
+    let repairing = match options.force {
+        true => false,
+        false => match needs_download(&download_path, options.suspect_threshold) {
+            Some(repairing) => repairing,
+            None => return Ok(Some(DownloadOutcome::Exists)),
+        },
+    };
+
+    if client.session.is_none() {
+        client.new_session()?;
+    }
+    let session = client
+        .session
+        .clone()
+        .ok_or(KemonoError::from_stringable("No session available"))?;
+    let response = session.get(url.clone()).send()?;
+    if response.status() == StatusCode::NOT_FOUND {
+        debug!("No thumbnail for {}, downloading the original", attachment);
+        return Ok(None);
+    }
+    let response = response.error_for_status()?;
+    print_action(
+        "download",
+        json!({
+            "filename": download_path.display().to_string(),
+            "url": url.to_string(),
+        }),
+    );
+    let size = save_response(response, &download_path)?;
+    set_file_times(&download_path, options.mtime, post);
+    Ok(Some(match repairing {
+        true => DownloadOutcome::Repaired(size),
+        false => DownloadOutcome::Downloaded(size),
+    }))
+}
+
+/// The `SHA256SUMS` entry for a downloaded file, hashed from what's on disk so a corrupt file can't get a good hash
+fn sha256sum_entry(
+    target_dir: &Path,
+    post: &Post,
+    attachment: &Attachment,
+    options: &DownloadOptions,
+) -> Result<(String, String), KemonoError> {
+    let filename = download_relative_path(post, attachment, options)?;
+    let hash = checked_sha256(&target_dir.join(&filename), attachment)?;
+    Ok((hash, filename))
+}
+
+/// What [download_files] got through
+#[derive(Debug, Default)]
+pub struct DownloadedFiles {
+    /// The files which failed, including ones from before it stopped early
+    pub failures: Vec<Failure>,
+    /// Why it stopped before trying every file, eg [KemonoError::Interrupted]
+    pub stopped: Option<KemonoError>,
+}
+
+/// Downloads a list of files in parallel, recording what happened in `counters` and returning the ones which failed.
+///
+/// If the server starts rate limiting us, or [request_stop] is called, the downloads in flight finish and the rest
+/// are skipped, with [DownloadedFiles::stopped] set to [KemonoError::RateLimitedAfter] or [KemonoError::Interrupted].
+pub fn download_files(
+    client: &KemonoClient,
+    options: &DownloadOptions,
+    files: &[(Post, Attachment)],
+    counters: &DownloadCounters,
+    validators: &Validators,
+) -> Result<DownloadedFiles, KemonoError> {
+    // build a threadpool for rayon so we don't get rate limited
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.worker_threads(files.len()))
+        .build()
+        .map_err(KemonoError::from_stringable)?;
+
+    // rayon can't cancel the rest of the downloads, so they check this before starting
+    let rate_limited = AtomicBool::new(false);
+    let finished = AtomicUsize::new(0);
+
+    let target_dir = options.target_dir(client);
+    let (sums_sender, sums_receiver) = std::sync::mpsc::channel::<(String, String)>();
+    // bounded so downloads wait for the hooks rather than queueing up forever
+    let (hook_sender, hook_receiver) =
+        std::sync::mpsc::sync_channel::<HookEvent>(options.hook_concurrency.max(1));
+
+    let res = std::thread::scope(|scope| {
+        let sums_writer = options
+            .sha256sums
+            .then(|| scope.spawn(|| sha256sums_writer(&target_dir, sums_receiver)));
+        let hook_runner = options.exec_after.as_ref().map(|hook| {
+            scope.spawn(|| {
+                run_hooks(
+                    hook,
+                    hook_receiver,
+                    options.hook_concurrency,
+                    &counters.hooks_failed,
+                )
+            })
+        });
+        let res = pool.install(|| {
+            files
+                .par_iter()
+                .map(|(post, attachment)| {
+                    if rate_limited.load(Ordering::SeqCst) {
+                        return Err(KemonoError::RateLimited);
+                    }
+                    if stop_requested() {
+                        return Err(KemonoError::Interrupted);
+                    }
+                    if !options.matches_filter(attachment) {
+                        debug!(
+                            "Skipping {} as doesn't match {}",
+                            attachment, options.filename
+                        );
+                        counters.filtered();
+                        return Ok(None);
+                    }
+                    let mut client = client.clone();
+
+                    let err = match download_content(
+                        options,
+                        &mut client,
+                        post,
+                        attachment,
+                        validators,
+                    ) {
+                        Ok(outcome) => {
+                            counters.record(outcome);
+                            let file_path = match local_path(options, &target_dir, post, attachment)
+                            {
+                                Ok(file_path) => file_path,
+                                Err(err) => {
+                                    error!("Failed to find {}: {:?}", attachment, err);
+                                    return Ok(None);
+                                }
+                            };
+                            if matches!(
+                                outcome,
+                                DownloadOutcome::Downloaded(_) | DownloadOutcome::Repaired(_)
+                            ) {
+                                if let Some(name) = file_path.file_name() {
+                                    counters.new_file(&name.to_string_lossy());
+                                }
+                                if let Ok(relative) = file_path.strip_prefix(&target_dir) {
+                                    counters.written(&relative.to_string_lossy());
+                                }
+                            }
+                            // SHA256SUMS is for the originals
+                            let is_thumbnail = file_path.starts_with(target_dir.join(THUMBS_DIR));
+                            if options.nfo {
+                                if let Err(err) = write_nfo(&file_path, post, attachment) {
+                                    error!("Failed to write NFO for {}: {:?}", attachment, err);
+                                }
+                            }
+                            if let Err(err) = write_captions(&file_path, post, attachment) {
+                                error!("Failed to write captions for {}: {:?}", attachment, err);
+                            }
+                            if options.exec_after.is_some()
+                                && matches!(
+                                    outcome,
+                                    DownloadOutcome::Downloaded(_)
+                                        | DownloadOutcome::Repaired(_)
+                                        | DownloadOutcome::Linked
+                                )
+                            {
+                                let event = HookEvent::new(
+                                    file_path,
+                                    post,
+                                    &options.service,
+                                    &options.creator,
+                                );
+                                if let Err(err) = hook_sender.send(event) {
+                                    error!("Failed to queue hook for {}: {:?}", attachment, err);
+                                }
+                            }
+                            if options.sha256sums
+                                && !is_thumbnail
+                                && matches!(
+                                    outcome,
+                                    DownloadOutcome::Downloaded(_) | DownloadOutcome::Repaired(_)
+                                )
+                            {
+                                match sha256sum_entry(&target_dir, post, attachment, options) {
+                                    Ok(entry) => {
+                                        if let Err(err) = sums_sender.send(entry) {
+                                            error!("Failed to queue SHA256SUMS entry: {:?}", err);
+                                        }
+                                    }
+                                    Err(err) => error!("Failed to hash {}: {:?}", attachment, err),
+                                }
+                            }
+                            return Ok(None);
+                        }
+                        Err(KemonoError::RateLimited) => {
+                            rate_limited.store(true, Ordering::SeqCst);
+                            return Err(KemonoError::RateLimited);
+                        }
+                        Err(KemonoError::Reqwest(req_error))
+                            if req_error.status().map(|s| s.as_u16()) == Some(429) =>
+                        {
+                            error!("Got rate limited, bailing for now!");
+                            rate_limited.store(true, Ordering::SeqCst);
+                            return Err(KemonoError::RateLimited);
+                        }
+                        Err(err) => err,
+                    };
+                    error!(
+                        creator = options.creator.as_str(), service = options.service.as_str();
+                        "Failed to download {} {:?}", attachment, err
+                    );
+                    Ok(Some(Failure {
+                        post_id: post.id.clone(),
+                        attachment: attachment.clone(),
+                        url: attachment_url(&client, attachment)
+                            .map(|url| url.to_string())
+                            .unwrap_or_default(),
+                        error: err.to_string(),
+                    }))
+                })
+                .inspect(|res| {
+                    if res.is_ok() {
+                        finished.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+                // keep going so the failures from before a stop aren't lost
+                .collect::<Vec<Result<Option<Failure>, _>>>()
+        });
+        // hang up so the writer and hooks finish
+        drop(sums_sender);
+        drop(hook_sender);
+        if let Some(hook_runner) = hook_runner {
+            if hook_runner.join().is_err() {
+                error!("Hook runner panicked");
+            }
+        }
+        if let Some(sums_writer) = sums_writer {
+            match sums_writer.join() {
+                Ok(Ok(written)) => debug!("Added {} entries to SHA256SUMS", written),
+                Ok(Err(err)) => error!("Failed to write SHA256SUMS: {:?}", err),
+                Err(_) => error!("SHA256SUMS writer panicked"),
+            }
+        }
+        res
+    });
+    let mut downloaded = DownloadedFiles::default();
+    for res in res {
+        match res {
+            Ok(failure) => downloaded.failures.extend(failure),
+            // a rate limit trumps a stop, the caller needs to back off
+            Err(KemonoError::RateLimited) => {
+                downloaded.stopped = Some(KemonoError::RateLimitedAfter {
+                    downloaded: counters.downloaded.load(Ordering::Relaxed),
+                    remaining: files.len() - finished.load(Ordering::Relaxed),
+                });
+            }
+            Err(err) => {
+                downloaded.stopped.get_or_insert(err);
+            }
+        }
+    }
+    Ok(downloaded)
+}
+
+/// Runs [download_files] on tokio's blocking threads, so other creators and Ctrl+C handling
+/// aren't held up while it works through the files
+async fn spawn_download_files(
+    client: &KemonoClient,
+    options: &DownloadOptions,
+    files: Arc<Vec<(Post, Attachment)>>,
+    validators: Arc<Validators>,
+) -> Result<(DownloadedFiles, DownloadCounters), KemonoError> {
+    let (client, options) = (client.clone(), options.clone());
+    tokio::task::spawn_blocking(move || {
+        let counters = DownloadCounters::default();
+        download_files(&client, &options, &files, &counters, &validators)
+            .map(|downloaded| (downloaded, counters))
+    })
+    .await
+    .map_err(|err| KemonoError::from(format!("Download task failed: {:?}", err)))?
+}
+
+/// The posts a download works on, from [select_posts]
+#[derive(Debug, Default)]
+pub struct SelectedPosts {
+    pub posts: Vec<Post>,
+    /// Only posts newer than the last sync or `since_post_id` were fetched
+    pub incremental: bool,
+    /// How many posts were fetched, before `post_id` and the tag filter
+    pub fetched: usize,
+}
+
+/// Fetches the posts a download of `options` would look at, from the Discord server, the post
+/// window, `since_post_id` or the sync state, then applies `post_id` and the tag filter
+pub async fn select_posts(
+    client: &mut KemonoClient,
+    options: &DownloadOptions,
+) -> Result<SelectedPosts, KemonoError> {
+    let state = SyncState::load(&options.metadata_dir(client))?.unwrap_or_default();
+    let (mut posts, incremental) = match (&options.since_post_id, &state.newest_published) {
+        // discord servers are channels of messages, there's no paging through them by date
+        _ if options.service == DISCORD_SERVICE => {
+            (client.discord_server_posts(&options.creator).await?, false)
+        }
+        _ if options.windowed() => (
+            client
+                .posts_window(
+                    &options.service,
+                    &options.creator,
+                    options.offset,
+                    options.max_posts,
+                )
+                .try_collect()
+                .await?,
+            false,
+        ),
+        (Some(post_id), _) => (
+            client
+                .posts_after_id(&options.service, &options.creator, post_id)
+                .await?,
+            true,
+        ),
+        (None, Some(published)) if options.incremental => {
+            debug!(
+                "Fetching posts for {}/{} published after {}",
+                options.service, options.creator, published
+            );
+            (
+                client
+                    .posts_since(&options.service, &options.creator, published)
+                    .await?,
+                true,
+            )
+        }
+        _ => (
+            client.all_posts(&options.service, &options.creator).await?,
+            false,
+        ),
+    };
+    let fetched = posts.len();
+    if let Some(post_id) = &options.post_id {
+        posts.retain(|post| post.id == *post_id);
+        if posts.is_empty() && !incremental {
+            return Err(KemonoError::from(format!(
+                "Post {} not found for {}/{}",
+                post_id, options.service, options.creator
+            )));
+        }
+    }
+    if !options.tags.is_empty() {
+        posts.retain(|post| options.tags.matches(post));
+    }
+    Ok(SelectedPosts {
+        posts,
+        incremental,
+        fetched,
+    })
+}
+
+/// Download everything for a given creator/service
+pub async fn download(
+    client: &mut KemonoClient,
+    options: &DownloadOptions,
+) -> Result<DownloadSummary, KemonoError> {
+    let start = Instant::now();
+    if options.no_metadata && options.metadata_only {
+        return Err(KemonoError::Configuration(
+            "no_metadata and metadata_only can't both be set".to_string(),
+        ));
+    }
+    let options = &options.resolve_creator_name(client).await;
+    let mut summary = DownloadSummary::new(&options.service, &options.creator);
+    let target_dir = options.target_dir(client);
+    let metadata_dir = options.metadata_dir(client);
+    let mut state = SyncState::load(&metadata_dir)?.unwrap_or_default();
+
+    let SelectedPosts {
+        posts: all_posts,
+        incremental,
+        fetched,
+    } = select_posts(client, options).await?;
+    if all_posts.is_empty() && fetched > 0 && !options.tags.is_empty() {
+        info!(
+            "No posts {} for {}/{}",
+            options.tags, options.service, options.creator
+        );
+        summary.previous_failures = read_failures(&target_dir)?.len();
+        summary.finish(start.elapsed());
+        return Ok(summary);
+    }
+    // posts the tag filter or window skipped haven't been synced, so they can't move the high-water mark
+    let synced: &[Post] = match options.tags.is_empty() && !options.windowed() {
+        true => &all_posts,
+        false => &[],
+    };
+    if all_posts.is_empty() {
+        if incremental {
+            info!(
+                creator = options.creator.as_str(), service = options.service.as_str();
+                "No new posts for {}/{}", options.service, options.creator
+            );
+            state.update(synced);
+            if !options.no_metadata {
+                state.save(&metadata_dir)?;
+            }
+            summary.previous_failures = read_failures(&target_dir)?.len();
+            summary.finish(start.elapsed());
+            return Ok(summary);
+        }
+        return Err(KemonoError::from(format!(
+            "No posts found for {}/{}",
+            options.service, options.creator
+        )));
+    }
+
+    if options.profile_images && options.service != DISCORD_SERVICE {
+        match download_profile_images(client, options).await {
+            Ok(saved) if saved > 0 => info!(
+                "Saved {} profile images for {}/{}",
+                saved, options.service, options.creator
+            ),
+            Ok(_) => {}
+            Err(KemonoError::RateLimited) => return Err(KemonoError::RateLimited),
+            Err(err) => warn!(
+                "Failed to get profile images for {}/{}: {:?}",
+                options.service, options.creator, err
+            ),
+        }
+    }
+
+    if options.include_dms && options.service != DISCORD_SERVICE {
+        match save_dms(client, options).await {
+            Ok(saved) if saved > 0 => info!(
+                "Saved {} new DMs for {}/{}",
+                saved, options.service, options.creator
+            ),
+            Ok(_) => {}
+            Err(KemonoError::RateLimited) => return Err(KemonoError::RateLimited),
+            Err(err) => warn!(
+                "Failed to get DMs for {}/{}: {:?}",
+                options.service, options.creator, err
+            ),
+        }
+    }
+
+    let mut inline_files: Vec<(Post, Attachment)> = Vec::new();
+    for post in all_posts.iter() {
+        let status = write_post_metadata(options, client, post)?;
+        if let MetadataStatus::Edited(new_files) = &status {
+            info!(
+                "Post {} was edited, {} new attachments",
+                post.id,
+                new_files.len()
+            );
+        }
+        if options.export_markdown
+            && !options.no_metadata
+            && (status != MetadataStatus::Unchanged
+                || !metadata_dir.join(format!("{}.md", post.id)).exists())
+        {
+            let markdown_path = write_post_markdown(&metadata_dir, &target_dir, post, options)?;
+            set_file_times(&markdown_path, options.mtime, post);
+        }
+        let media = match options.inline_media {
+            true => collect_inline_media(client, post),
+            false => Vec::new(),
+        };
+        let content_post = match options.rewrite_media && !media.is_empty() {
+            true => {
+                let replacements: Vec<(String, String)> = media
+                    .iter()
+                    .map(|(reference, attachment)| {
+                        // content files are in a subdirectory of the downloads
+                        Ok((
+                            reference.clone(),
+                            format!("../{}", download_relative_path(post, attachment, options)?),
+                        ))
+                    })
+                    .collect::<Result<_, KemonoError>>()?;
+                let mut rewritten = post.clone();
+                rewritten.content = post
+                    .content
+                    .as_deref()
+                    .map(|content| rewrite_media(content, &replacements));
+                rewritten
+            }
+            false => post.clone(),
+        };
+        if let Some(content_path) =
+            write_post_content(options.content_format, &target_dir, &content_post)?
+        {
+            set_file_times(&content_path, options.mtime, post);
+        }
+        inline_files.extend(
+            media
+                .into_iter()
+                .map(|(_, attachment)| (post.clone(), attachment)),
+        );
+    }
+    let mut files = collect_files(&all_posts);
+    if !inline_files.is_empty() {
+        info!(
+            creator = options.creator.as_str(), service = options.service.as_str();
+            "Found {} inline media files", inline_files.len()
+        );
+        files.extend(inline_files);
+    }
+
+    info!(
+        creator = options.creator.as_str(), service = options.service.as_str();
+        "Found {} objects", files.len()
+    );
+    summary.posts = all_posts.len();
+    summary.attachments = files.len();
+
+    if options.metadata_only {
+        info!(
+            "Only saving metadata for {}/{}, skipping downloads",
+            options.service, options.creator
+        );
+        state.update(synced);
+        state.save(&metadata_dir)?;
+        summary.previous_failures = read_failures(&target_dir)?.len();
+        summary.finish(start.elapsed());
+        return Ok(summary);
+    }
+
+    let validators = Arc::new(Validators::from_manifest(&read_manifest(&target_dir)?));
+    let (downloaded, counters) =
+        spawn_download_files(client, options, Arc::new(files), validators.clone()).await?;
+    let mut rate_limited = None;
+    let failures = match downloaded {
+        // the downloads in flight have finished, wrap up what we've got
+        DownloadedFiles {
+            failures,
+            stopped: Some(KemonoError::Interrupted),
+        } => {
+            warn!(
+                creator = options.creator.as_str(), service = options.service.as_str();
+                "Stopped early for {}/{}, the rest will be downloaded next time",
+                options.service, options.creator
+            );
+            summary.interrupted = true;
+            failures
+        }
+        // keep what was done before the site stopped us, and report it with the error
+        DownloadedFiles {
+            failures,
+            stopped: Some(err @ KemonoError::RateLimitedAfter { .. }),
+        } => {
+            rate_limited = Some(err);
+            failures
+        }
+        DownloadedFiles {
+            stopped: Some(err), ..
+        } => return Err(err),
+        DownloadedFiles { failures, .. } => failures,
+    };
+    summary.add_counters(&counters);
+    // keep the previous failures for posts we didn't look at this time, or all of them if we stopped early
+    let previous: Vec<Failure> = read_failures(&target_dir)?
+        .into_iter()
+        .filter(|failure| {
+            (summary.interrupted
+                || rate_limited.is_some()
+                || !all_posts.iter().any(|post| post.id == failure.post_id))
+                && !failures.iter().any(|new| {
+                    new.post_id == failure.post_id && new.attachment == failure.attachment
+                })
+        })
+        .collect();
+    summary.previous_failures = previous.len();
+    let all_failures: Vec<Failure> = failures.iter().chain(previous.iter()).cloned().collect();
+    write_failures(&target_dir, &all_failures)?;
+    if !all_failures.is_empty() {
+        info!(
+            creator = options.creator.as_str(), service = options.service.as_str();
+            "{} downloads failed for {}/{}, see {}",
+            all_failures.len(),
+            options.service,
+            options.creator,
+            failures_path(&target_dir).display()
+        );
+    }
+
+    write_manifest(
+        &target_dir,
+        options,
+        &all_posts,
+        &validators,
+        &counters.written_files(),
+    )?;
+    if let Some(err) = rate_limited {
+        error!(
+            creator = options.creator.as_str(), service = options.service.as_str();
+            "{} for {}/{}", err, options.service, options.creator
+        );
+        return Err(err);
+    }
+
+    // the posts we didn't get to need looking at again next time
+    if !summary.interrupted {
+        state.update(synced);
+    }
+    if !options.no_metadata {
+        state.save(&metadata_dir)?;
+    }
+    summary.failures = failures;
+    summary.finish(start.elapsed());
+    Ok(summary)
+}
+
+/// Retries the downloads listed in the creator/service's failures file
+pub async fn retry_failed(
+    client: &KemonoClient,
+    options: &DownloadOptions,
+) -> Result<DownloadSummary, KemonoError> {
+    let start = Instant::now();
+    if options.no_metadata {
+        return Err(KemonoError::Configuration(
+            "Retrying failed downloads needs the post metadata, so can't be done with no_metadata"
+                .to_string(),
+        ));
+    }
+    // only look for the named directory once, rather than for every file
+    let mut options = options.clone();
+    if options.layout.needs_name() && options.creator_name.is_none() {
+        options.creator_name = options
+            .layout
+            .find_existing(
+                &options.base_dir(client),
+                &options.service,
+                &options.creator,
+            )
+            .and_then(|existing| existing.name);
+    }
+    let options = &options;
+    let mut summary = DownloadSummary::new(&options.service, &options.creator);
+    let target_dir = options.target_dir(client);
+    let previous = read_failures(&target_dir)?;
+    info!(
+        "Retrying {} failed downloads for {}/{}",
+        previous.len(),
+        options.service,
+        options.creator
+    );
+
+    let mut failures = Vec::new();
+    let mut files = Vec::new();
+    for failure in previous {
+        // we need the post to work out where the file goes
+        let post = std::fs::read_to_string(
+            options
+                .metadata_dir(client)
+                .join(format!("{}.json", failure.post_id)),
+        )
+        .ok()
+        .and_then(|contents| serde_json::from_str::<Post>(&contents).ok());
+        match post {
+            Some(post) => files.push((post, failure.attachment)),
+            None => {
+                error!("Couldn't load metadata for post {}", failure.post_id);
+                failures.push(failure);
+            }
+        }
+    }
+
+    summary.attachments = files.len();
+    let validators = Arc::new(Validators::from_manifest(&read_manifest(&target_dir)?));
+    let files = Arc::new(files);
+    let (downloaded, counters) =
+        spawn_download_files(client, options, files.clone(), validators).await?;
+    failures.extend(downloaded.failures);
+    match downloaded.stopped {
+        // we can't tell which of the rest were tried, so they're all kept for next time
+        Some(KemonoError::Interrupted) => {
+            summary.interrupted = true;
+            for (post, attachment) in files.iter() {
+                if !failures
+                    .iter()
+                    .any(|failure| failure.post_id == post.id && failure.attachment == *attachment)
+                {
+                    failures.push(Failure {
+                        post_id: post.id.clone(),
+                        url: attachment_url(client, attachment)
+                            .map(|url| url.to_string())
+                            .unwrap_or_default(),
+                        error: KemonoError::Interrupted.to_string(),
+                        attachment: attachment.clone(),
+                    });
+                }
+            }
+        }
+        Some(err) => return Err(err),
+        None => {}
+    }
+    summary.add_counters(&counters);
+    write_failures(&target_dir, &failures)?;
+    info!(
+        "{} downloads still failing for {}/{}",
+        failures.len(),
+        options.service,
+        options.creator
+    );
+    summary.failures = failures;
+    summary.finish(start.elapsed());
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_files() {
+        let posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        let files = collect_files(&posts);
+        let expected = posts
+            .iter()
+            .map(|post| post.all_attachments().len())
+            .sum::<usize>();
+        assert_eq!(files.len(), expected);
+    }
+
+    #[test]
+    fn test_matches_filter() {
+        let attachment = Attachment {
+            name: Some("foo.jpg".to_string()),
+            path: Some("/foo.jpg".to_string()),
+        };
+        let mut options = DownloadOptions::new("patreon", "12345");
+        assert!(options.matches_filter(&attachment));
+        options.filename.contains = vec!["foo".to_string()];
+        assert!(options.matches_filter(&attachment));
+        options.filename.contains = vec!["bar".to_string()];
+        assert!(!options.matches_filter(&attachment));
+    }
+
+    #[test]
+    fn test_download_relative_path() {
+        let mut post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        post.id = "42".to_string();
+        post.published = "2023-10-20T14:05:01".to_string();
+        post.title = format!("A/B {}", "x".repeat(100));
+        let attachment = Attachment {
+            name: Some("foo.jpg".to_string()),
+            path: Some("/aa/bb/foo.jpg".to_string()),
+        };
+        let mut options = DownloadOptions::new("patreon", "12345");
+        let relative_path = |post: &Post, attachment: &Attachment, options: &DownloadOptions| {
+            download_relative_path(post, attachment, options).expect("Failed to get path")
+        };
+        assert_eq!(
+            relative_path(&post, &attachment, &options),
+            "2023-10-20T14-05-01-foo.jpg"
+        );
+        options.post_dirs = true;
+        assert_eq!(
+            relative_path(&post, &attachment, &options),
+            format!(
+                "2023-10-20T14-05-01-42-A_B {}/2023-10-20T14-05-01-foo.jpg",
+                "x".repeat(46)
+            )
+        );
+        post.title = " ".to_string();
+        assert_eq!(post_dir_name(&post), "2023-10-20T14-05-01-42");
+        post.shared_file = Some(true);
+        post.title = "My Brush: Pack".to_string();
+        post.file = attachment.clone();
+        let other = Attachment {
+            name: Some("bar.png".to_string()),
+            path: Some("/aa/bb/bar.png".to_string()),
+        };
+        assert_eq!(
+            relative_path(&post, &attachment, &options),
+            "shared/2023-10-20T14-05-01-My Brush_ Pack.jpg"
+        );
+        options.post_dirs = false;
+        assert_eq!(
+            relative_path(&post, &other, &options),
+            "shared/2023-10-20T14-05-01-bar.png"
+        );
+    }
+
+    #[test]
+    fn test_metadata_dir() {
+        let client = KemonoClient::new("kemono.example", Some("/download".to_string()));
+        let mut options = DownloadOptions::new("patreon", "12345");
+        assert_eq!(
+            options.metadata_dir(&client),
+            PathBuf::from("/download/12345/patreon/metadata")
+        );
+        options.metadata_path = Some("/metadata".to_string());
+        options.layout = Layout::ServiceCreator;
+        assert_eq!(
+            options.metadata_dir(&client),
+            PathBuf::from("/metadata/patreon/12345")
+        );
+    }
+
+    #[test]
+    fn test_worker_threads() {
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.threads = 8;
+        assert_eq!(options.worker_threads(100), 8);
+        assert_eq!(options.worker_threads(3), 3);
+        assert_eq!(options.worker_threads(0), 1);
+        options.per_creator_threads = Some(4);
+        assert_eq!(options.worker_threads(100), 4);
+        options.per_creator_threads = Some(16);
+        assert_eq!(options.worker_threads(100), 8);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_data_host_fallback() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let broken = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(403))
+            .expect(1)
+            .mount(&broken)
+            .await;
+        let working = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/aa/bb/foo.jpg"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(2)
+            .mount(&working)
+            .await;
+
+        let mut client = KemonoClient::new("kemono.example", None)
+            .with_data_hosts(vec![broken.uri(), working.uri()]);
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("datahost");
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+        let mut post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let attachment = Attachment {
+            name: Some("foo.jpg".to_string()),
+            path: Some("/aa/bb/foo.jpg".to_string()),
+        };
+
+        let res = tokio::task::spawn_blocking(move || {
+            let validators = Validators::default();
+            let first = download_content(&options, &mut client, &post, &attachment, &validators);
+            // the working host should be tried first from now on
+            post.published = "2000-01-01T00:00:00".to_string();
+            let second = download_content(&options, &mut client, &post, &attachment, &validators);
+            (first, second, client.data_host_urls())
+        })
+        .await
+        .expect("Download thread panicked");
+
+        let (first, second, hosts) = res;
+        assert_eq!(
+            first.expect("Failed to download"),
+            DownloadOutcome::Downloaded(5)
+        );
+        assert_eq!(
+            second.expect("Failed to download"),
+            DownloadOutcome::Downloaded(5)
+        );
+        assert_eq!(hosts[0], working.uri());
+    }
+
+    #[test]
+    fn test_edited_post() {
+        let posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        let original = posts[0].clone();
+        let mut edited = original.clone();
+        let new_file = Attachment {
+            name: Some("new.jpg".to_string()),
+            path: Some("/aa/bb/new.jpg".to_string()),
+        };
+        edited
+            .attachments
+            .get_or_insert_with(Default::default)
+            .insert(new_file.clone());
+
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("edited");
+        let client = KemonoClient::new("example.com", None);
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+
+        let first = write_post_metadata(&options, &client, &original);
+        let unchanged = write_post_metadata(&options, &client, &original);
+        options.ignore_edits = true;
+        let ignored = write_post_metadata(&options, &client, &edited);
+        options.ignore_edits = false;
+        let changed = write_post_metadata(&options, &client, &edited);
+
+        assert_eq!(first.expect("Failed to write"), MetadataStatus::New);
+        assert_eq!(
+            unchanged.expect("Failed to check"),
+            MetadataStatus::Unchanged
+        );
+        assert_eq!(ignored.expect("Failed to check"), MetadataStatus::Unchanged);
+        assert_eq!(
+            changed.expect("Failed to check"),
+            MetadataStatus::Edited(vec![new_file.clone()])
+        );
+        assert!(collect_files(&[edited])
+            .iter()
+            .any(|(_, attachment)| attachment == &new_file));
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed_no_metadata() {
+        let client = KemonoClient::new("example.com", None);
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.no_metadata = true;
+        assert!(matches!(
+            retry_failed(&client, &options).await,
+            Err(KemonoError::Configuration(_))
+        ));
+    }
+
+    #[test]
+    fn test_no_metadata() {
+        let posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("no-metadata");
+        let client = KemonoClient::new("example.com", None);
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+        options.no_metadata = true;
+
+        let status = write_post_metadata(&options, &client, &posts[0]);
+        assert_eq!(status.expect("Failed to check"), MetadataStatus::New);
+        assert!(!options.metadata_dir(&client).exists());
+    }
+
+    #[test]
+    fn test_metadata_only_refreshes() {
+        let posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("metadata-only");
+        let client = KemonoClient::new("example.com", None);
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+        options.ignore_edits = true;
+        let metadata_path = options
+            .metadata_dir(&client)
+            .join(format!("{}.json", posts[0].id));
+
+        let first = write_post_metadata(&options, &client, &posts[0]);
+        std::fs::write(&metadata_path, "stale").expect("Failed to write");
+        let skipped = write_post_metadata(&options, &client, &posts[0]);
+        let stale = std::fs::read_to_string(&metadata_path);
+        options.metadata_only = true;
+        let refreshed = write_post_metadata(&options, &client, &posts[0]);
+        let contents = std::fs::read_to_string(&metadata_path);
+
+        assert_eq!(first.expect("Failed to write"), MetadataStatus::New);
+        assert_eq!(skipped.expect("Failed to check"), MetadataStatus::Unchanged);
+        assert_eq!(stale.expect("Failed to read"), "stale");
+        assert_eq!(
+            refreshed.expect("Failed to write"),
+            MetadataStatus::Unchanged
+        );
+        let stored: Post =
+            serde_json::from_str(&contents.expect("Failed to read")).expect("Failed to parse");
+        assert_eq!(stored, posts[0]);
+    }
+
+    #[test]
+    fn test_image_extension() {
+        assert_eq!(image_extension("image/jpeg"), Some("jpg".to_string()));
+        assert_eq!(
+            image_extension("image/PNG; charset=binary"),
+            Some("png".to_string())
+        );
+        assert_eq!(image_extension("image/svg+xml"), Some("svg".to_string()));
+        assert_eq!(image_extension("text/html"), None);
+    }
+
+    #[test]
+    fn test_remove_partial_files() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = tmp.path().join("partial");
+        let post_dir = dir.join("12345").join("patreon").join("post");
+        std::fs::create_dir_all(&post_dir).expect("Failed to create test dir");
+        for name in ["a.jpg", "a.jpg.part", "partial.txt"] {
+            std::fs::write(post_dir.join(name), "hello").expect("Failed to write");
+        }
+        std::fs::write(dir.join("b.json.part"), "hello").expect("Failed to write");
+
+        assert_eq!(remove_partial_files(&dir).expect("Failed to clean up"), 2);
+        assert!(post_dir.join("a.jpg").exists());
+        assert!(post_dir.join("partial.txt").exists());
+        assert!(!post_dir.join("a.jpg.part").exists());
+        assert_eq!(remove_partial_files(&dir).expect("Failed to clean up"), 0);
+        assert_eq!(
+            remove_partial_files(&dir.join("nope")).expect("Failed to clean up"),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_dms() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let dm = |hash: &str, published: &str| json!({"hash": hash, "user": "12345", "service": "patreon", "content": hash, "published": published});
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345/dms"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!([dm("b", "2023-02-01T00:00:00")])),
+            )
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345/dms"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                dm("b", "2023-02-01T00:00:00"),
+                dm("a", "2023-01-01T00:00:00")
+            ])))
+            .mount(&server)
+            .await;
+
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("dms");
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+
+        let first = save_dms(&client, &options).await;
+        let second = save_dms(&client, &options).await;
+        let third = save_dms(&client, &options).await;
+        let saved = std::fs::read_to_string(options.metadata_dir(&client).join(DMS_FILE));
+
+        assert_eq!(first.expect("Failed to save DMs"), 1);
+        assert_eq!(second.expect("Failed to save DMs"), 1);
+        assert_eq!(third.expect("Failed to save DMs"), 0);
+        let saved: Vec<DirectMessage> =
+            serde_json::from_str(&saved.expect("No DMs saved")).expect("Failed to parse DMs");
+        let hashes: Vec<&str> = saved.iter().map(|dm| dm.hash.as_str()).collect();
+        assert_eq!(hashes, vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_download_profile_images() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/icons/patreon/12345"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", "image/png")
+                    .set_body_bytes(b"png".to_vec()),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/banners/patreon/12345"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("profile");
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+
+        let first = download_profile_images(&client, &options).await;
+        // the icon's already there, so only the banner gets asked for again
+        let second = download_profile_images(&client, &options).await;
+        let icon = std::fs::read(options.target_dir(&client).join("icon.png"));
+
+        assert_eq!(first.expect("Failed to get profile images"), 1);
+        assert_eq!(second.expect("Failed to get profile images"), 0);
+        assert_eq!(icon.expect("No icon"), b"png");
+    }
+
+    #[test]
+    fn test_suspect_reason() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = tmp.path().join("suspect");
+        std::fs::create_dir_all(&dir).expect("Failed to create test dir");
+        let write = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).expect("Failed to write");
+            path
+        };
+        let empty = suspect_reason(&write("empty.jpg", ""), None);
+        let small = suspect_reason(&write("small.jpg", "hello"), Some(10));
+        let error_page = suspect_reason(&write("video.mp4", "\n <html>Error</html>"), None);
+        let error_zip = suspect_reason(&write("files.ZIP", "<!DOCTYPE html>"), None);
+        // an image starting with < isn't something we can judge
+        let image = suspect_reason(&write("image.svg", "<svg></svg>"), None);
+        let fine = suspect_reason(&write("video.mkv", "\x1aE"), Some(1));
+
+        assert!(empty.is_some());
+        assert!(small.is_some());
+        assert!(error_page.is_some());
+        assert!(error_zip.is_some());
+        assert!(image.is_none());
+        assert!(fine.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_repair_empty_file() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/aa/bb/foo.jpg"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("repair");
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+        let post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let attachment = Attachment {
+            name: Some("foo.jpg".to_string()),
+            path: Some("/aa/bb/foo.jpg".to_string()),
+        };
+        let file_path = options.target_dir(&client).join(
+            download_relative_path(&post, &attachment, &options).expect("Failed to get path"),
+        );
+        std::fs::create_dir_all(file_path.parent().unwrap()).expect("Failed to create dir");
+        std::fs::write(&file_path, "").expect("Failed to write");
+
+        let res = tokio::task::spawn_blocking(move || {
+            let validators = Validators::default();
+            let first = download_content(&options, &mut client, &post, &attachment, &validators);
+            let second = download_content(&options, &mut client, &post, &attachment, &validators);
+            (first, second)
+        })
+        .await
+        .expect("Download thread panicked");
+        let contents = std::fs::read_to_string(&file_path);
+
+        assert_eq!(
+            res.0.expect("Failed to download"),
+            DownloadOutcome::Repaired(5)
+        );
+        assert_eq!(res.1.expect("Failed to check"), DownloadOutcome::Exists);
+        assert_eq!(contents.expect("Failed to read"), "hello");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_repair_with_dedup() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let hash = "a".repeat(64);
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(format!("/aa/aa/{}.jpg", hash)))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(tmp.path().display().to_string());
+        options.dedup = true;
+        let post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let attachment = Attachment {
+            name: Some("foo.jpg".to_string()),
+            path: Some(format!("/aa/aa/{}.jpg", hash)),
+        };
+        let file_path = options.target_dir(&client).join(
+            download_relative_path(&post, &attachment, &options).expect("Failed to get path"),
+        );
+        // the broken file's linked to a broken object
+        let object = object_path(&options.base_dir(&client), &hash);
+        std::fs::create_dir_all(object.parent().unwrap()).expect("Failed to create dir");
+        std::fs::write(&object, "").expect("Failed to write");
+        link_or_copy(&object, &file_path).expect("Failed to link");
+
+        let res = tokio::task::spawn_blocking(move || {
+            download_content(
+                &options,
+                &mut client,
+                &post,
+                &attachment,
+                &Validators::default(),
+            )
+        })
+        .await
+        .expect("Download thread panicked");
+
+        assert_eq!(res.expect("Failed to repair"), DownloadOutcome::Repaired(5));
+        assert_eq!(
+            std::fs::read_to_string(&file_path).expect("Failed to read"),
+            "hello"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&object).expect("Failed to read object"),
+            "hello"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failed_repair_keeps_file() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/aa/bb/foo.jpg"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(tmp.path().display().to_string());
+        options.suspect_threshold = Some(10);
+        let post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let attachment = Attachment {
+            name: Some("foo.jpg".to_string()),
+            path: Some("/aa/bb/foo.jpg".to_string()),
+        };
+        let file_path = options.target_dir(&client).join(
+            download_relative_path(&post, &attachment, &options).expect("Failed to get path"),
+        );
+        std::fs::create_dir_all(file_path.parent().unwrap()).expect("Failed to create dir");
+        std::fs::write(&file_path, "small").expect("Failed to write");
+
+        let res = tokio::task::spawn_blocking(move || {
+            download_content(
+                &options,
+                &mut client,
+                &post,
+                &attachment,
+                &Validators::default(),
+            )
+        })
+        .await
+        .expect("Download thread panicked");
+
+        assert!(res.is_err());
+        assert_eq!(
+            std::fs::read_to_string(&file_path).expect("Failed to read"),
+            "small"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_incremental_download() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mut posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        posts.truncate(2);
+        for (post, published) in posts
+            .iter_mut()
+            .zip(["2024-02-01T00:00:00", "2024-01-01T00:00:00"])
+        {
+            post.published = published.to_string();
+            post.file = Attachment {
+                name: None,
+                path: None,
+            };
+            post.attachments = None;
+        }
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345"))
+            .and(query_param("o", "0"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&posts))
+            .expect(2)
+            .mount(&server)
+            .await;
+        // only the full scan gets this far
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345"))
+            .and(query_param("o", "50"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Vec::<Post>::new()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        // both runs need to ask the server
+        client.cache_ttl = None;
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("incremental");
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+        options.profile_images = false;
+        let metadata_dir = options.metadata_dir(&client);
+        SyncState {
+            newest_post_id: Some(posts[1].id.clone()),
+            newest_published: Some(posts[1].published.clone()),
+            last_run: 0,
+        }
+        .save(&metadata_dir)
+        .expect("Failed to save sync state");
+        // a failure from an earlier run, for the post the incremental run doesn't look at
+        let previous = Failure {
+            post_id: posts[1].id.clone(),
+            attachment: Attachment {
+                name: Some("foo.jpg".to_string()),
+                path: Some("/aa/bb/foo.jpg".to_string()),
+            },
+            url: "http://example.com/aa/bb/foo.jpg".to_string(),
+            error: "500".to_string(),
+        };
+        write_failures(&options.target_dir(&client), &[previous])
+            .expect("Failed to write failures");
+
+        options.incremental = true;
+        let incremental = download(&mut client, &options).await;
+        let state = SyncState::load(&metadata_dir);
+        options.incremental = false;
+        let full = download(&mut client, &options).await;
+
+        // it's still recorded, but it isn't one of this run's failures
+        let incremental = incremental.expect("Failed to download");
+        assert_eq!(incremental.posts, 1);
+        assert_eq!(incremental.failed, 0);
+        assert_eq!(incremental.previous_failures, 1);
+        let state = state
+            .expect("Failed to load sync state")
+            .expect("No sync state");
+        assert_eq!(state.newest_post_id, Some(posts[0].id.clone()));
+        assert_eq!(full.expect("Failed to download").posts, 2);
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_thumbnails() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // the thumbnails are on another host, so send everything through the mock server as a proxy
+        let server = MockServer::start().await;
+        for (file, status, body) in [
+            ("/thumbnail/data/aa/bb/foo.jpg", 200, "thumb"),
+            ("/thumbnail/data/aa/bb/bar.png", 404, ""),
+            ("/aa/bb/bar.png", 200, "original"),
+            ("/aa/bb/baz.mp4", 200, "video"),
+        ] {
+            Mock::given(method("GET"))
+                .and(path(file))
+                .respond_with(ResponseTemplate::new(status).set_body_string(body))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let mut client = KemonoClient::new("example.com", None)
+            .with_proxy(&server.uri())
+            .expect("Failed to set proxy");
+        client.scheme = "http".to_string();
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("thumbnails");
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+        options.thumbnails = true;
+        let target_dir = options.target_dir(&client);
+        let post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let attachments: Vec<Attachment> = ["foo.jpg", "bar.png", "baz.mp4"]
+            .into_iter()
+            .map(|name| Attachment {
+                name: Some(name.to_string()),
+                path: Some(format!("/aa/bb/{}", name)),
+            })
+            .collect();
+
+        let res = tokio::task::spawn_blocking(move || {
+            let validators = Validators::default();
+            attachments
+                .iter()
+                .map(|attachment| {
+                    let outcome =
+                        download_content(&options, &mut client, &post, attachment, &validators);
+                    let path = local_path(&options, &target_dir, &post, attachment)
+                        .expect("Failed to get path");
+                    (outcome, path.strip_prefix(&target_dir).map(PathBuf::from))
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .expect("Download thread panicked");
+
+        let res: Vec<(DownloadOutcome, PathBuf)> = res
+            .into_iter()
+            .map(|(outcome, path)| {
+                (
+                    outcome.expect("Failed to download"),
+                    path.expect("Downloaded outside the target dir"),
+                )
+            })
+            .collect();
+        assert_eq!(res[0].0, DownloadOutcome::Downloaded(5));
+        assert!(res[0].1.starts_with(THUMBS_DIR));
+        // no thumbnail, and not an image, so they're the originals
+        assert_eq!(res[1].0, DownloadOutcome::Downloaded(8));
+        assert!(!res[1].1.starts_with(THUMBS_DIR));
+        assert_eq!(res[2].0, DownloadOutcome::Downloaded(5));
+        assert!(!res[2].1.starts_with(THUMBS_DIR));
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_conditional_download() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/aa/bb/foo.jpg"))
+            .and(header("If-None-Match", "\"abc\""))
+            .respond_with(ResponseTemplate::new(304))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/aa/bb/foo.jpg"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"abc\"")
+                    .set_body_string("hello"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("conditional");
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+        let target_dir = options.target_dir(&client);
+        let post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let attachment = Attachment {
+            name: Some("foo.jpg".to_string()),
+            path: Some("/aa/bb/foo.jpg".to_string()),
+        };
+        let file_path = target_dir.join(
+            download_relative_path(&post, &attachment, &options).expect("Failed to get path"),
+        );
+
+        let res = tokio::task::spawn_blocking(move || {
+            let validators = Validators::default();
+            let first = download_content(&options, &mut client, &post, &attachment, &validators);
+            // the same file under a different name, eg after changing the layout
+            options.post_dirs = true;
+            let second = download_content(&options, &mut client, &post, &attachment, &validators);
+            let renamed = options.target_dir(&client).join(
+                download_relative_path(&post, &attachment, &options).expect("Failed to get path"),
+            );
+            (first, second, validators.get("/aa/bb/foo.jpg"), renamed)
+        })
+        .await
+        .expect("Download thread panicked");
+        let renamed = std::fs::read_to_string(&res.3);
+
+        assert_eq!(
+            res.0.expect("Failed to download"),
+            DownloadOutcome::Downloaded(5)
+        );
+        let validator = res.2.expect("No validator saved");
+        assert_eq!(validator.etag, Some("\"abc\"".to_string()));
+        assert_eq!(target_dir.join(validator.filename), file_path);
+        assert_eq!(res.1.expect("Failed to check"), DownloadOutcome::Linked);
+        assert_eq!(renamed.expect("Failed to read"), "hello");
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_force_download() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/aa/bb/foo.jpg"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("fresh"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("force");
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+        let post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let attachment = Attachment {
+            name: Some("foo.jpg".to_string()),
+            path: Some("/aa/bb/foo.jpg".to_string()),
+        };
+        let file_path = options.target_dir(&client).join(
+            download_relative_path(&post, &attachment, &options).expect("Failed to get path"),
+        );
+        std::fs::create_dir_all(file_path.parent().unwrap()).expect("Failed to create dir");
+        std::fs::write(&file_path, "rotted").expect("Failed to write");
+
+        let res = tokio::task::spawn_blocking(move || {
+            let validators = Validators::default();
+            let skipped = download_content(&options, &mut client, &post, &attachment, &validators);
+            options.force = true;
+            let forced = download_content(&options, &mut client, &post, &attachment, &validators);
+            (skipped, forced)
+        })
+        .await
+        .expect("Download thread panicked");
+        let contents = std::fs::read_to_string(&file_path);
+
+        assert_eq!(res.0.expect("Failed to check"), DownloadOutcome::Exists);
+        assert_eq!(
+            res.1.expect("Failed to download"),
+            DownloadOutcome::Downloaded(5)
+        );
+        assert_eq!(contents.expect("Failed to read"), "fresh");
+    }
+
+    #[tokio::test]
+    async fn test_select_posts() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let template: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let page: Vec<Post> = (0..50)
+            .map(|i| Post {
+                id: i.to_string(),
+                ..template.clone()
+            })
+            .collect();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345"))
+            .and(query_param("o", "0"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page))
+            .mount(&server)
+            .await;
+
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.offset = 2;
+        options.max_posts = Some(3);
+        let selected = select_posts(&mut client, &options)
+            .await
+            .expect("Failed to select posts");
+        let ids: Vec<String> = selected.posts.into_iter().map(|post| post.id).collect();
+        assert_eq!(ids, ["2", "3", "4"]);
+        assert!(!selected.incremental);
+
+        options.post_id = Some("3".to_string());
+        let selected = select_posts(&mut client, &options)
+            .await
+            .expect("Failed to select posts");
+        assert_eq!(selected.fetched, 3);
+        assert_eq!(selected.posts.len(), 1);
+        options.post_id = Some("40".to_string());
+        assert!(select_posts(&mut client, &options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_downloads() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        // the first file fails, the third's rate limited, so the last two are never started
+        for (index, status, expected) in [
+            (0, 500, 1),
+            (1, 200, 1),
+            (2, 429, 1),
+            (3, 200, 0),
+            (4, 200, 0),
+        ] {
+            Mock::given(method("GET"))
+                .and(path(format!("/aa/bb/file{}.jpg", index)))
+                .respond_with(ResponseTemplate::new(status).set_body_string("hello"))
+                .expect(expected)
+                .mount(&server)
+                .await;
+        }
+
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("ratelimit");
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.download_path = Some(download_path.display().to_string());
+        options.threads = 1;
+        let post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let files: Vec<(Post, Attachment)> = (0..5)
+            .map(|index| {
+                (
+                    post.clone(),
+                    Attachment {
+                        name: Some(format!("file{}.jpg", index)),
+                        path: Some(format!("/aa/bb/file{}.jpg", index)),
+                    },
+                )
+            })
+            .collect();
+
+        let res = tokio::task::spawn_blocking(move || {
+            download_files(
+                &client,
+                &options,
+                &files,
+                &DownloadCounters::default(),
+                &Validators::default(),
+            )
+        })
+        .await
+        .expect("Download thread panicked");
+
+        let downloaded = res.expect("Failed to download");
+        // the failure from before the rate limit is kept
+        assert_eq!(downloaded.failures.len(), 1);
+        assert_eq!(
+            downloaded.failures[0].attachment.name,
+            Some("file0.jpg".to_string())
+        );
+        match downloaded.stopped {
+            Some(err @ KemonoError::RateLimitedAfter { .. }) => {
+                assert!(err.is_rate_limited());
+                assert_eq!(
+                    err.to_string(),
+                    "Rate limited after downloading 1 files, 3 left for next time"
+                );
+            }
+            stopped => panic!("Expected to be rate limited, got {:?}", stopped),
+        }
+    }
+}
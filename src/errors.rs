@@ -4,7 +4,27 @@ pub enum KemonoError {
     Generic(String),
     SerdeJson(serde_json::Error),
     RateLimited,
-    GetPostsError(String),
+    /// Rate limited part way through a creator's downloads, the rest are left for next time
+    RateLimitedAfter {
+        downloaded: usize,
+        remaining: usize,
+    },
+    /// Getting a page of a creator's posts failed
+    GetPostsError {
+        service: String,
+        creator: String,
+        offset: usize,
+        source: Box<KemonoError>,
+    },
+    Configuration(String),
+    /// A stop was requested part way through
+    Interrupted,
+    /// The request needs us to be logged in
+    Unauthorized,
+    /// The site rejected the session token, it needs replacing
+    SessionExpired,
+    /// The server didn't tell us something we needed, so the caller should fall back to another way
+    Unknown,
 }
 
 impl core::fmt::Display for KemonoError {
@@ -14,7 +34,31 @@ impl core::fmt::Display for KemonoError {
             KemonoError::Generic(e) => write!(f, "Generic error: {}", e),
             KemonoError::SerdeJson(e) => write!(f, "SerdeJson error: {}", e),
             KemonoError::RateLimited => write!(f, "Rate limited"),
-            KemonoError::GetPostsError(e) => write!(f, "Error getting posts: {}", e),
+            KemonoError::RateLimitedAfter {
+                downloaded,
+                remaining,
+            } => write!(
+                f,
+                "Rate limited after downloading {} files, {} left for next time",
+                downloaded, remaining
+            ),
+            KemonoError::GetPostsError {
+                service,
+                creator,
+                offset,
+                source,
+            } => write!(
+                f,
+                "Failed to get posts for {}/{} at offset {}: {}",
+                service, creator, offset, source
+            ),
+            KemonoError::Configuration(e) => write!(f, "Configuration error: {}", e),
+            KemonoError::Interrupted => write!(f, "Interrupted"),
+            KemonoError::Unauthorized => write!(f, "Not logged in"),
+            KemonoError::SessionExpired => {
+                write!(f, "Session expired, log in again for a new session token")
+            }
+            KemonoError::Unknown => write!(f, "Unknown"),
         }
     }
 }
@@ -29,6 +73,32 @@ impl KemonoError {
     pub fn from_stringable(e: impl ToString) -> Self {
         KemonoError::Generic(e.to_string())
     }
+
+    /// Whether the site's rate limiting us, with or without a count of what got done first
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(
+            self,
+            KemonoError::RateLimited | KemonoError::RateLimitedAfter { .. }
+        )
+    }
+
+    /// Adds which creator's posts we were getting to `source`.
+    ///
+    /// Rate limits and interruptions are passed through as they are, callers match on those.
+    pub fn get_posts(service: &str, creator: &str, offset: usize, source: KemonoError) -> Self {
+        match source {
+            KemonoError::RateLimited
+            | KemonoError::RateLimitedAfter { .. }
+            | KemonoError::Interrupted
+            | KemonoError::GetPostsError { .. } => source,
+            source => KemonoError::GetPostsError {
+                service: service.to_string(),
+                creator: creator.to_string(),
+                offset,
+                source: Box::new(source),
+            },
+        }
+    }
 }
 
 impl From<String> for KemonoError {
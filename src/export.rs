@@ -0,0 +1,346 @@
+//! Exporting posts as standalone documents, alongside their metadata.
+
+use std::path::{Path, PathBuf};
+
+use crate::content::{escape_html, html_to_markdown, html_to_text};
+use crate::download::{download_relative_path, DownloadOptions, METADATA_DIR};
+use crate::errors::KemonoError;
+use crate::util::write_atomic;
+use crate::{Attachment, Post};
+
+/// Renders a post as a Markdown document, with the title, published date, content, attachments and embed.
+///
+/// Attachments link to the downloaded files, relative to the `metadata` directory.
+pub fn post_to_markdown(post: &Post, options: &DownloadOptions) -> String {
+    render_markdown(post, "..", options)
+}
+
+/// Percent-encodes the characters which would end or break a Markdown link target
+fn link_target(target: &str) -> String {
+    target
+        .chars()
+        .map(|c| match c {
+            '%' | ' ' | '(' | ')' | '<' | '>' => format!("%{:02X}", c as u32),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+/// Renders the Markdown with attachment links under `media_prefix`
+fn render_markdown(post: &Post, media_prefix: &str, options: &DownloadOptions) -> String {
+    let mut output = format!("# {}\n\nPublished: {}\n", post.title, post.published);
+
+    let content = html_to_markdown(post.content.as_deref().unwrap_or_default());
+    if !content.is_empty() {
+        output.push_str(&format!("\n{}\n", content));
+    }
+
+    let attachments = post.all_attachments();
+    if !attachments.is_empty() {
+        output.push_str("\n## Attachments\n\n");
+        for attachment in attachments {
+            let name = attachment.name.as_deref().unwrap_or_default();
+            // there's nothing to link to if it couldn't be named
+            match download_relative_path(post, attachment, options) {
+                Ok(path) => output.push_str(&format!(
+                    "- [{}]({})\n",
+                    name,
+                    link_target(&format!("{}/{}", media_prefix, path))
+                )),
+                Err(_) => output.push_str(&format!("- {}\n", name)),
+            }
+        }
+    }
+
+    if let Some(poll) = &post.poll {
+        output.push_str("\n## Poll\n\n");
+        if !poll.title.is_empty() {
+            output.push_str(&format!("{}\n\n", poll.title));
+        }
+        for choice in poll.choices.iter() {
+            output.push_str(&format!("- {} ({} votes)\n", choice.text, choice.votes));
+        }
+    }
+
+    if let Some(url) = &post.embed.url {
+        output.push_str(&format!(
+            "\n## Embed\n\n[{}]({})\n",
+            post.embed.subject.as_deref().unwrap_or(url),
+            url
+        ));
+    }
+    output
+}
+
+/// Writes `{id}.md` in `metadata_dir`, linking to the downloads in `target_dir`. Returns the path written.
+pub fn write_post_markdown(
+    metadata_dir: &Path,
+    target_dir: &Path,
+    post: &Post,
+    options: &DownloadOptions,
+) -> Result<PathBuf, KemonoError> {
+    let filepath = metadata_dir.join(format!("{}.md", post.id));
+    // relative links only work if the metadata is in the usual place
+    let media_prefix = match metadata_dir == target_dir.join(METADATA_DIR) {
+        true => "..".to_string(),
+        false => target_dir.display().to_string(),
+    };
+    if let Some(parent) = filepath.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    }
+    write_atomic(&filepath, render_markdown(post, &media_prefix, options))
+        .map_err(|err| KemonoError::from(format!("Failed to write post markdown: {:?}", err)))?;
+    Ok(filepath)
+}
+
+/// Extensions which get an NFO sidecar
+pub static VIDEO_EXTENSIONS: [&str; 9] = [
+    "mp4", "m4v", "mkv", "webm", "mov", "avi", "wmv", "flv", "mpg",
+];
+
+/// Returns true if the attachment looks like a video, based on its extension
+pub fn is_video(attachment: &Attachment) -> bool {
+    attachment
+        .extension()
+        .is_some_and(|ext| VIDEO_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Renders a Kodi/Jellyfin style NFO describing a post's video attachment
+pub fn post_to_nfo(post: &Post, attachment: &Attachment) -> String {
+    let title = match post
+        .all_attachments()
+        .iter()
+        .filter(|a| is_video(a))
+        .count()
+    {
+        0 | 1 => post.title.clone(),
+        // tell multiple videos from the same post apart
+        _ => format!(
+            "{} - {}",
+            post.title,
+            attachment.name.as_deref().unwrap_or_default()
+        ),
+    };
+    // NFO dates are "YYYY-MM-DD HH:MM:SS"
+    let date_added = post
+        .published
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .replace('T', " ");
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<movie>\n  <title>{}</title>\n  <plot>{}</plot>\n  <dateadded>{}</dateadded>\n  <creator>{}</creator>\n</movie>\n",
+        escape_html(&title),
+        escape_html(&html_to_text(post.content.as_deref().unwrap_or_default())),
+        escape_html(&date_added),
+        escape_html(&post.user),
+    )
+}
+
+/// Writes `{file_path}.nfo` next to a downloaded video attachment if it isn't there already, returns the path if one was written
+pub fn write_nfo(
+    file_path: &Path,
+    post: &Post,
+    attachment: &Attachment,
+) -> Result<Option<PathBuf>, KemonoError> {
+    if !is_video(attachment) {
+        return Ok(None);
+    }
+    let mut filepath = file_path.as_os_str().to_owned();
+    filepath.push(".nfo");
+    let filepath = PathBuf::from(filepath);
+    if filepath.exists() {
+        return Ok(None);
+    }
+    if let Some(parent) = filepath.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    }
+    write_atomic(&filepath, post_to_nfo(post, attachment))
+        .map_err(|err| KemonoError::from(format!("Failed to write NFO: {:?}", err)))?;
+    Ok(Some(filepath))
+}
+
+/// The extension for a post's captions sidecar, `vtt` if they're WebVTT and `txt` otherwise, None if there aren't any
+pub fn captions_extension(post: &Post) -> Option<&'static str> {
+    let first = post.captions.as_ref()?.first()?;
+    match first.trim_start().starts_with("WEBVTT") {
+        true => Some("vtt"),
+        false => Some("txt"),
+    }
+}
+
+/// Writes the post's captions to `{file_path}.vtt` or `{file_path}.txt` next to a downloaded video, returns the path if it was written
+pub fn write_captions(
+    file_path: &Path,
+    post: &Post,
+    attachment: &Attachment,
+) -> Result<Option<PathBuf>, KemonoError> {
+    let (Some(captions), Some(extension)) = (&post.captions, captions_extension(post)) else {
+        return Ok(None);
+    };
+    if !is_video(attachment) {
+        return Ok(None);
+    }
+    let mut filepath = file_path.as_os_str().to_owned();
+    filepath.push(format!(".{}", extension));
+    let filepath = PathBuf::from(filepath);
+    let contents = format!("{}\n", captions.join("\n\n"));
+    // they're rewritten if the post's captions are edited
+    if std::fs::read_to_string(&filepath).is_ok_and(|existing| existing == contents) {
+        return Ok(None);
+    }
+    if let Some(parent) = filepath.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    }
+    write_atomic(&filepath, contents)
+        .map_err(|err| KemonoError::from(format!("Failed to write captions: {:?}", err)))?;
+    Ok(Some(filepath))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Embed, Poll, PollChoice};
+
+    #[test]
+    fn test_post_to_nfo() {
+        let mut post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        post.title = "Fish & chips".to_string();
+        post.published = "2023-10-20T14:05:01".to_string();
+        post.content = Some("<p>A <b>video</b></p>".to_string());
+        let video = Attachment {
+            name: Some("clip.MP4".to_string()),
+            path: Some("/aa/bb/clip.mp4".to_string()),
+        };
+        let image = Attachment {
+            name: Some("cover.jpg".to_string()),
+            path: Some("/aa/bb/cover.jpg".to_string()),
+        };
+        assert!(is_video(&video));
+        assert!(!is_video(&image));
+
+        let nfo = post_to_nfo(&post, &video);
+        assert!(nfo.contains("<title>Fish &amp; chips</title>"));
+        assert!(nfo.contains("<plot>A video</plot>"));
+        assert!(nfo.contains("<dateadded>2023-10-20 14:05:01</dateadded>"));
+        assert!(nfo.contains(&format!("<creator>{}</creator>", post.user)));
+    }
+
+    #[test]
+    fn test_post_to_markdown() {
+        let mut post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        post.title = "A post".to_string();
+        post.content = Some("<p>Hello <strong>there</strong></p>".to_string());
+        post.embed = Embed {
+            url: Some("https://example.com/video".to_string()),
+            subject: Some("A video".to_string()),
+            description: None,
+        };
+
+        let markdown = post_to_markdown(&post, &DownloadOptions::new("patreon", "12345"));
+        assert!(markdown.starts_with(&format!(
+            "# A post\n\nPublished: {}\n\nHello **there**\n",
+            post.published
+        )));
+        for attachment in post.all_attachments() {
+            assert!(markdown.contains(&format!(
+                "- [{}]",
+                attachment.name.clone().unwrap_or_default()
+            )));
+        }
+        assert!(markdown.ends_with("## Embed\n\n[A video](https://example.com/video)\n"));
+
+        post.poll = Some(Poll {
+            title: "Next?".to_string(),
+            choices: vec![PollChoice {
+                text: "More fish".to_string(),
+                votes: 2,
+            }],
+            ..Default::default()
+        });
+        assert!(
+            post_to_markdown(&post, &DownloadOptions::new("patreon", "12345"))
+                .contains("## Poll\n\nNext?\n\n- More fish (2 votes)\n")
+        );
+    }
+
+    #[test]
+    fn test_markdown_link_escaping() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let target_dir = tmp.path().join("my files (100%)");
+        let filepath = write_post_markdown(
+            &tmp.path().join("metadata"),
+            &target_dir,
+            &post,
+            &DownloadOptions::new("patreon", "12345"),
+        )
+        .expect("Failed to write markdown");
+        let markdown = std::fs::read_to_string(filepath).expect("Failed to read markdown");
+        let prefix = link_target(&target_dir.display().to_string());
+        assert!(prefix.ends_with("/my%20files%20%28100%25%29"));
+        for attachment in post.all_attachments() {
+            assert!(markdown.contains(&format!(
+                "- [{}]({}/",
+                attachment.name.clone().unwrap_or_default(),
+                prefix
+            )));
+        }
+    }
+
+    #[test]
+    fn test_write_captions() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let target_dir = tmp.path().join("captions");
+        let mut post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let video = Attachment {
+            name: Some("clip.mp4".to_string()),
+            path: Some("/aa/bb/clip.mp4".to_string()),
+        };
+        let image = Attachment {
+            name: Some("cover.jpg".to_string()),
+            path: Some("/aa/bb/cover.jpg".to_string()),
+        };
+        let file_path = target_dir.join("clip.mp4");
+
+        post.captions = None;
+        assert_eq!(captions_extension(&post), None);
+        assert_eq!(
+            write_captions(&file_path, &post, &video).expect("write"),
+            None
+        );
+
+        post.captions = Some(vec!["Hello".to_string()]);
+        assert_eq!(captions_extension(&post), Some("txt"));
+        assert_eq!(
+            write_captions(&file_path, &post, &image).expect("write"),
+            None
+        );
+
+        post.captions = Some(vec!["WEBVTT\n\n00:00.000 --> 00:01.000\nHi".to_string()]);
+        let written = write_captions(&file_path, &post, &video)
+            .expect("write")
+            .expect("Captions should be written");
+        assert_eq!(written, target_dir.join("clip.mp4.vtt"));
+        assert_eq!(
+            std::fs::read_to_string(&written).expect("read"),
+            "WEBVTT\n\n00:00.000 --> 00:01.000\nHi\n"
+        );
+        // unchanged captions aren't rewritten
+        assert_eq!(
+            write_captions(&file_path, &post, &video).expect("write"),
+            None
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! A `failures.json` per creator/service recording attachments which failed to download, so they can be retried.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::KemonoError;
+use crate::util::write_atomic;
+use crate::Attachment;
+
+pub static FAILURES_FILENAME: &str = "failures.json";
+
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct Failure {
+    pub post_id: String,
+    #[serde(flatten)]
+    pub attachment: Attachment,
+    pub url: String,
+    pub error: String,
+}
+
+pub fn failures_path(target_dir: &Path) -> PathBuf {
+    target_dir.join(FAILURES_FILENAME)
+}
+
+/// Reads the failures from the last run, if there are any
+pub fn read_failures(target_dir: &Path) -> Result<Vec<Failure>, KemonoError> {
+    let path = failures_path(target_dir);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = std::fs::read_to_string(&path).map_err(|err| {
+        KemonoError::from(format!(
+            "Failed to read failures {}: {:?}",
+            path.display(),
+            err
+        ))
+    })?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+/// Writes the failures file, or removes it if there's nothing left to record
+pub fn write_failures(target_dir: &Path, failures: &[Failure]) -> Result<(), KemonoError> {
+    let path = failures_path(target_dir);
+    if failures.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(&path).map_err(|err| {
+                KemonoError::from(format!("Failed to remove failures file: {:?}", err))
+            })?;
+        }
+        return Ok(());
+    }
+    std::fs::create_dir_all(target_dir)
+        .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    write_atomic(&path, serde_json::to_string_pretty(failures)?)
+        .map_err(|err| KemonoError::from(format!("Failed to write failures: {:?}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failures_roundtrip() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let target_dir = tmp.path().join("failures");
+        let failures = vec![Failure {
+            post_id: "12345".to_string(),
+            attachment: Attachment {
+                name: Some("foo.jpg".to_string()),
+                path: Some("/aa/bb/foo.jpg".to_string()),
+            },
+            url: "https://example.com/aa/bb/foo.jpg".to_string(),
+            error: "Generic error: nope".to_string(),
+        }];
+
+        write_failures(&target_dir, &failures).expect("Failed to write failures");
+        let read_back = read_failures(&target_dir).expect("Failed to read failures");
+        write_failures(&target_dir, &[]).expect("Failed to clear failures");
+        let exists = failures_path(&target_dir).exists();
+
+        assert_eq!(read_back, failures);
+        assert!(!exists);
+    }
+}
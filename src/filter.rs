@@ -0,0 +1,151 @@
+//! Choosing which posts and attachments to download, by their tags and names.
+
+use std::fmt::Display;
+
+use regex::Regex;
+
+use crate::Post;
+
+/// Matches attachment names containing any of `contains` or matching any of `patterns`, unless
+/// they match one of `exclude`. With no `contains` or `patterns` everything is included.
+#[derive(Clone, Debug, Default)]
+pub struct FilenameFilter {
+    pub contains: Vec<String>,
+    pub patterns: Vec<Regex>,
+    pub exclude: Vec<Regex>,
+}
+
+impl FilenameFilter {
+    pub fn is_empty(&self) -> bool {
+        self.contains.is_empty() && self.patterns.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let included = (self.contains.is_empty() && self.patterns.is_empty())
+            || self.contains.iter().any(|filename| name.contains(filename))
+            || self.patterns.iter().any(|pattern| pattern.is_match(name));
+        included && !self.exclude.iter().any(|pattern| pattern.is_match(name))
+    }
+}
+
+impl Display for FilenameFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts: Vec<String> = self
+            .contains
+            .iter()
+            .map(|filename| format!("'{}'", filename))
+            .chain(self.patterns.iter().map(|pattern| format!("/{}/", pattern)))
+            .collect();
+        if !self.exclude.is_empty() {
+            let exclude: Vec<String> = self
+                .exclude
+                .iter()
+                .map(|pattern| format!("/{}/", pattern))
+                .collect();
+            parts.push(format!("not {}", exclude.join(" or ")));
+        }
+        write!(f, "{}", parts.join(" or "))
+    }
+}
+
+/// Matches posts tagged with any of `include` (if there are any) and none of `exclude`, ignoring case.
+///
+/// The API can't filter a creator's posts by tag, so this is applied after they're fetched.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TagFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl TagFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Posts without tags only match if there's nothing to include
+    pub fn matches(&self, post: &Post) -> bool {
+        let tags = post.tags.as_deref().unwrap_or_default();
+        let tagged = |wanted: &String| {
+            tags.iter()
+                .any(|tag| tag.trim().eq_ignore_ascii_case(wanted.trim()))
+        };
+        (self.include.is_empty() || self.include.iter().any(tagged))
+            && !self.exclude.iter().any(tagged)
+    }
+}
+
+impl Display for TagFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let quoted = |tags: &[String]| -> String {
+            tags.iter()
+                .map(|tag| format!("'{}'", tag))
+                .collect::<Vec<_>>()
+                .join(" or ")
+        };
+        let mut parts = Vec::new();
+        if !self.include.is_empty() {
+            parts.push(format!("tagged {}", quoted(&self.include)));
+        }
+        if !self.exclude.is_empty() {
+            parts.push(format!("not tagged {}", quoted(&self.exclude)));
+        }
+        write!(f, "{}", parts.join(" and "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_filter() {
+        let mut post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        post.tags = Some(vec!["Series A".to_string(), "sketch".to_string()]);
+        let mut untagged = post.clone();
+        untagged.tags = None;
+
+        let mut filter = TagFilter::default();
+        assert!(filter.is_empty());
+        assert!(filter.matches(&post));
+        assert!(filter.matches(&untagged));
+
+        filter.exclude = vec!["SKETCH".to_string()];
+        assert!(!filter.matches(&post));
+        assert!(filter.matches(&untagged));
+
+        filter.exclude.clear();
+        filter.include = vec!["series a".to_string(), "series b".to_string()];
+        assert!(filter.matches(&post));
+        assert!(!filter.matches(&untagged));
+        filter.exclude = vec!["sketch".to_string()];
+        assert!(!filter.matches(&post));
+        assert_eq!(
+            filter.to_string(),
+            "tagged 'series a' or 'series b' and not tagged 'sketch'"
+        );
+    }
+
+    #[test]
+    fn test_filename_filter() {
+        let regex = |pattern: &str| Regex::new(pattern).expect("Invalid test regex");
+        let mut filter = FilenameFilter::default();
+        assert!(filter.is_empty());
+        assert!(filter.matches("foo.jpg"));
+
+        filter.contains = vec!["foo".to_string()];
+        filter.patterns = vec![regex(r"\.png$")];
+        assert!(filter.matches("foo.jpg"));
+        assert!(filter.matches("bar.png"));
+        assert!(!filter.matches("bar.jpg"));
+
+        filter.exclude = vec![regex("^foo_preview")];
+        assert!(!filter.matches("foo_preview.jpg"));
+        assert!(filter.matches("foo.jpg"));
+        assert_eq!(
+            filter.to_string(),
+            r"'foo' or /\.png$/ or not /^foo_preview/"
+        );
+    }
+}
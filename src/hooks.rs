@@ -0,0 +1,213 @@
+//! Hooks which run after each file is downloaded.
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, error};
+
+use crate::errors::KemonoError;
+use crate::Post;
+
+/// What a hook gets told about a downloaded file
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HookEvent {
+    pub file: PathBuf,
+    pub post_id: String,
+    pub creator: String,
+    pub service: String,
+    pub published: String,
+}
+
+impl HookEvent {
+    pub fn new(file: PathBuf, post: &Post, service: &str, creator: &str) -> Self {
+        Self {
+            file,
+            post_id: post.id.clone(),
+            creator: creator.to_string(),
+            service: service.to_string(),
+            published: post.published.clone(),
+        }
+    }
+
+    /// The environment variables passed to `--exec-after` commands
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("KEMONO_FILE", self.file.display().to_string()),
+            ("KEMONO_POST_ID", self.post_id.clone()),
+            ("KEMONO_CREATOR", self.creator.clone()),
+            ("KEMONO_SERVICE", self.service.clone()),
+            ("KEMONO_PUBLISHED", self.published.clone()),
+        ]
+    }
+}
+
+pub type HookFuture = Pin<Box<dyn Future<Output = Result<(), KemonoError>> + Send>>;
+
+/// Something to run once per successfully downloaded file
+#[derive(Clone)]
+pub enum DownloadHook {
+    /// A shell command, with the event in the environment
+    Command(String),
+    /// An async closure, for library users
+    Callback(Arc<dyn Fn(HookEvent) -> HookFuture + Send + Sync>),
+}
+
+impl Debug for DownloadHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadHook::Command(command) => f.debug_tuple("Command").field(command).finish(),
+            DownloadHook::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+impl DownloadHook {
+    /// Wraps an async closure as a hook
+    pub fn callback<F, Fut>(hook: F) -> Self
+    where
+        F: Fn(HookEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), KemonoError>> + Send + 'static,
+    {
+        DownloadHook::Callback(Arc::new(move |event| Box::pin(hook(event))))
+    }
+
+    /// Runs the hook and waits for it, a non-zero exit from a command is an error
+    pub fn run(&self, event: &HookEvent) -> Result<(), KemonoError> {
+        match self {
+            DownloadHook::Command(command) => {
+                let status = shell(command)
+                    .envs(event.env())
+                    .status()
+                    .map_err(|err| format!("Failed to run '{}': {}", command, err))?;
+                match status.success() {
+                    true => Ok(()),
+                    false => Err(KemonoError::from(format!(
+                        "'{}' exited with {}",
+                        command, status
+                    ))),
+                }
+            }
+            DownloadHook::Callback(hook) => tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(KemonoError::from_stringable)?
+                .block_on(hook(event.clone())),
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+/// Runs hooks for events off the queue with at most `concurrency` going at once, counting the failures
+pub fn run_hooks(
+    hook: &DownloadHook,
+    events: Receiver<HookEvent>,
+    concurrency: usize,
+    failed: &AtomicUsize,
+) {
+    let events = Mutex::new(events);
+    std::thread::scope(|scope| {
+        for _ in 0..concurrency.max(1) {
+            scope.spawn(|| loop {
+                // only hold the lock while waiting for the next event
+                let event = match events.lock() {
+                    Ok(events) => events.recv(),
+                    Err(_) => return,
+                };
+                let Ok(event) = event else {
+                    return;
+                };
+                match hook.run(&event) {
+                    Ok(()) => debug!("Ran hook for {}", event.file.display()),
+                    Err(err) => {
+                        error!("Hook failed for {}: {}", event.file.display(), err);
+                        failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(name: &str) -> HookEvent {
+        HookEvent {
+            file: PathBuf::from(name),
+            post_id: "123".to_string(),
+            creator: "456".to_string(),
+            service: "patreon".to_string(),
+            published: "2024-01-01T00:00:00".to_string(),
+        }
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_command_hook() {
+        let hook = DownloadHook::Command(
+            "test \"$KEMONO_POST_ID/$KEMONO_SERVICE\" = 123/patreon".to_string(),
+        );
+        assert!(hook.run(&event("a.jpg")).is_ok());
+        assert!(DownloadHook::Command("exit 3".to_string())
+            .run(&event("a.jpg"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_run_hooks() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        let hook = DownloadHook::callback(move |event: HookEvent| {
+            let seen = hook_seen.clone();
+            async move {
+                if event.file.as_os_str() == "bad.jpg" {
+                    return Err(KemonoError::from_stringable("nope"));
+                }
+                seen.lock().unwrap().push(event.file);
+                Ok(())
+            }
+        });
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(2);
+        let failed = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            scope.spawn(|| run_hooks(&hook, receiver, 2, &failed));
+            for name in ["a.jpg", "bad.jpg", "b.jpg", "c.jpg"] {
+                sender.send(event(name)).unwrap();
+            }
+            drop(sender);
+        });
+
+        assert_eq!(failed.load(Ordering::Relaxed), 1);
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(
+            seen,
+            vec![
+                PathBuf::from("a.jpg"),
+                PathBuf::from("b.jpg"),
+                PathBuf::from("c.jpg")
+            ]
+        );
+    }
+}
@@ -0,0 +1,357 @@
+//! How creator/service directories are laid out under the download path.
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::content::CONTENT_DIR;
+use crate::download::{METADATA_DIR, SHARED_DIR, THUMBS_DIR};
+use crate::errors::KemonoError;
+
+/// Directory layout under the download path
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Layout {
+    /// `{creator}/{service}`
+    #[default]
+    CreatorService,
+    /// `{service}/{creator}`
+    ServiceCreator,
+    /// `{service}/{creator name} [{creator}]`
+    ServiceCreatorName,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "creator-service" => Ok(Layout::CreatorService),
+            "service-creator" => Ok(Layout::ServiceCreator),
+            "service-creator-name" => Ok(Layout::ServiceCreatorName),
+            _ => Err(format!(
+                "Invalid layout '{}', expected creator-service, service-creator or service-creator-name",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layout::CreatorService => write!(f, "creator-service"),
+            Layout::ServiceCreator => write!(f, "service-creator"),
+            Layout::ServiceCreatorName => write!(f, "service-creator-name"),
+        }
+    }
+}
+
+/// A creator/service directory found on disk
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CreatorDir {
+    pub service: String,
+    pub creator: String,
+    /// The display name, only known for the named layout
+    pub name: Option<String>,
+    pub path: PathBuf,
+}
+
+/// A directory move needed to change from one layout to another
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct LayoutMove {
+    pub service: String,
+    pub creator: String,
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// Works out the moves to change `base_dir` from the `from` layout to `to`, `name` is asked for any missing creator names
+pub fn migration_moves<F>(base_dir: &Path, from: Layout, to: Layout, name: F) -> Vec<LayoutMove>
+where
+    F: Fn(&CreatorDir) -> Option<String>,
+{
+    from.walk(base_dir)
+        .into_iter()
+        .filter_map(|dir| {
+            let creator_name = dir.name.clone().or_else(|| name(&dir));
+            let target = base_dir.join(to.relative_dir(
+                base_dir,
+                &dir.service,
+                &dir.creator,
+                creator_name.as_deref(),
+            ));
+            (target != dir.path).then_some(LayoutMove {
+                service: dir.service,
+                creator: dir.creator,
+                from: dir.path,
+                to: target,
+            })
+        })
+        .collect()
+}
+
+/// Moves a creator/service directory, tidying up the old parent if that leaves it empty
+pub fn apply_move(layout_move: &LayoutMove) -> Result<(), KemonoError> {
+    if layout_move.to.exists() {
+        return Err(KemonoError::from(format!(
+            "Can't move {} to {} as it already exists",
+            layout_move.from.display(),
+            layout_move.to.display()
+        )));
+    }
+    if let Some(parent) = layout_move.to.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    }
+    std::fs::rename(&layout_move.from, &layout_move.to).map_err(|err| {
+        KemonoError::from(format!(
+            "Failed to move {} to {}: {:?}",
+            layout_move.from.display(),
+            layout_move.to.display(),
+            err
+        ))
+    })?;
+    if let Some(parent) = layout_move.from.parent() {
+        // fails if there's anything left in it, which is fine
+        let _ = std::fs::remove_dir(parent);
+    }
+    Ok(())
+}
+
+/// Makes a creator's name safe to use as a directory name
+pub fn sanitize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // leading dots would hide the directory, and we skip hidden directories
+    name.trim().trim_start_matches('.').to_string()
+}
+
+/// Directories which can't be a creator or service, hidden ones and the ones we write inside creator/service directories
+pub fn is_reserved_dir(name: &str) -> bool {
+    name.starts_with('.')
+        || [METADATA_DIR, CONTENT_DIR, SHARED_DIR, THUMBS_DIR, "posts"].contains(&name)
+}
+
+/// Pulls the name and ID out of a `name [id]` directory name
+fn parse_named_dir(dir_name: &str) -> Option<(String, String)> {
+    let (name, id) = dir_name.strip_suffix(']')?.rsplit_once('[')?;
+    Some((name.trim().to_string(), id.to_string()))
+}
+
+impl Layout {
+    /// Whether the layout needs the creator's display name
+    pub fn needs_name(&self) -> bool {
+        matches!(self, Layout::ServiceCreatorName)
+    }
+
+    /// The creator/service directory relative to the download path.
+    ///
+    /// If the named layout doesn't have a name, it looks for an existing `* [{creator}]` directory under `base_dir`.
+    pub fn relative_dir(
+        &self,
+        base_dir: &Path,
+        service: &str,
+        creator: &str,
+        name: Option<&str>,
+    ) -> PathBuf {
+        match self {
+            Layout::CreatorService => PathBuf::from(creator).join(service),
+            Layout::ServiceCreator => PathBuf::from(service).join(creator),
+            Layout::ServiceCreatorName => {
+                let dir_name = match name.map(sanitize_name) {
+                    Some(name) if !name.is_empty() => format!("{} [{}]", name, creator),
+                    _ => self
+                        .find_existing(base_dir, service, creator)
+                        .and_then(|dir| dir.path.file_name()?.to_str().map(str::to_string))
+                        .unwrap_or(format!("[{}]", creator)),
+                };
+                PathBuf::from(service).join(dir_name)
+            }
+        }
+    }
+
+    /// Looks for a creator's existing directory, for the named layout where we might not know the name
+    pub fn find_existing(
+        &self,
+        base_dir: &Path,
+        service: &str,
+        creator: &str,
+    ) -> Option<CreatorDir> {
+        self.walk(base_dir)
+            .into_iter()
+            .find(|dir| dir.service == service && dir.creator == creator)
+    }
+
+    /// Works out the creator and service from the two directory levels under the download path
+    pub fn parse(&self, first: &str, second: &str) -> Option<(String, String, Option<String>)> {
+        match self {
+            Layout::CreatorService => Some((second.to_string(), first.to_string(), None)),
+            Layout::ServiceCreator => Some((first.to_string(), second.to_string(), None)),
+            Layout::ServiceCreatorName => {
+                let (name, id) = parse_named_dir(second)?;
+                let name = (!name.is_empty()).then_some(name);
+                Some((first.to_string(), id, name))
+            }
+        }
+    }
+
+    /// Finds all the creator/service directories under the download path, skipping hidden, metadata and non-UTF8 ones
+    pub fn walk(&self, base_dir: &Path) -> Vec<CreatorDir> {
+        let subdirs = |path: &Path| -> Vec<(String, PathBuf)> {
+            let Ok(entries) = path.read_dir() else {
+                return Vec::new();
+            };
+            let mut dirs: Vec<(String, PathBuf)> = entries
+                .flatten()
+                .filter(|entry| entry.path().is_dir())
+                .filter_map(|entry| Some((entry.file_name().to_str()?.to_string(), entry.path())))
+                .filter(|(name, _)| !is_reserved_dir(name))
+                .collect();
+            dirs.sort();
+            dirs
+        };
+
+        let mut found = Vec::new();
+        for (first, first_path) in subdirs(base_dir) {
+            for (second, path) in subdirs(&first_path) {
+                if let Some((service, creator, name)) = self.parse(&first, &second) {
+                    found.push(CreatorDir {
+                        service,
+                        creator,
+                        name,
+                        path,
+                    });
+                }
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_dir() {
+        let base = Path::new("/nonexistent");
+        assert_eq!(
+            Layout::CreatorService.relative_dir(base, "patreon", "12345", None),
+            PathBuf::from("12345/patreon")
+        );
+        assert_eq!(
+            Layout::ServiceCreator.relative_dir(base, "patreon", "12345", None),
+            PathBuf::from("patreon/12345")
+        );
+        assert_eq!(
+            Layout::ServiceCreatorName.relative_dir(base, "patreon", "12345", Some("Some/One")),
+            PathBuf::from("patreon/Some_One [12345]")
+        );
+        assert_eq!(
+            Layout::ServiceCreatorName.relative_dir(base, "patreon", "12345", None),
+            PathBuf::from("patreon/[12345]")
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Layout::ServiceCreatorName.parse("patreon", "Some One [12345]"),
+            Some((
+                "patreon".to_string(),
+                "12345".to_string(),
+                Some("Some One".to_string())
+            ))
+        );
+        assert_eq!(
+            Layout::ServiceCreatorName.parse("patreon", "[12345]"),
+            Some(("patreon".to_string(), "12345".to_string(), None))
+        );
+        assert_eq!(
+            Layout::ServiceCreatorName.parse("patreon", "metadata"),
+            None
+        );
+        assert_eq!(
+            Layout::CreatorService.parse("12345", "patreon"),
+            Some(("patreon".to_string(), "12345".to_string(), None))
+        );
+    }
+
+    #[test]
+    fn test_migration() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let base_dir = tmp.path().join("migrate");
+        std::fs::create_dir_all(base_dir.join("12345").join("patreon"))
+            .expect("Failed to create test dir");
+        std::fs::create_dir_all(base_dir.join("12345").join("fanbox"))
+            .expect("Failed to create test dir");
+        std::fs::write(
+            base_dir.join("12345").join("patreon").join("a.jpg"),
+            "hello",
+        )
+        .expect("Failed to write test file");
+
+        let moves = migration_moves(
+            &base_dir,
+            Layout::CreatorService,
+            Layout::ServiceCreatorName,
+            |dir| (dir.service == "patreon").then_some("Some One".to_string()),
+        );
+        let results: Vec<_> = moves.iter().map(apply_move).collect();
+        let moved = base_dir
+            .join("patreon")
+            .join("Some One [12345]")
+            .join("a.jpg")
+            .exists();
+        let unnamed = base_dir.join("fanbox").join("[12345]").exists();
+        let old_removed = !base_dir.join("12345").exists();
+
+        assert_eq!(moves.len(), 2);
+        assert!(results.iter().all(|res| res.is_ok()));
+        assert!(moved);
+        assert!(unnamed);
+        assert!(old_removed);
+    }
+
+    #[test]
+    fn test_walk() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let base_dir = tmp.path().join("layout");
+        std::fs::create_dir_all(base_dir.join("patreon").join("Some One [12345]"))
+            .expect("Failed to create test dir");
+        std::fs::create_dir_all(base_dir.join(".objects").join("ab"))
+            .expect("Failed to create test dir");
+        std::fs::create_dir_all(base_dir.join(METADATA_DIR).join("patreon"))
+            .expect("Failed to create test dir");
+        std::fs::create_dir_all(base_dir.join("12345").join(CONTENT_DIR))
+            .expect("Failed to create test dir");
+        std::fs::create_dir_all(base_dir.join("12345").join("posts"))
+            .expect("Failed to create test dir");
+        std::fs::create_dir_all(base_dir.join("12345").join("fanbox"))
+            .expect("Failed to create test dir");
+        let creator_service = Layout::CreatorService.walk(&base_dir);
+
+        let found = Layout::ServiceCreatorName.walk(&base_dir);
+        let existing = Layout::ServiceCreatorName.relative_dir(&base_dir, "patreon", "12345", None);
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].creator, "12345");
+        // only the real service, not the directories we write
+        assert_eq!(
+            creator_service
+                .iter()
+                .map(|dir| dir.service.as_str())
+                .collect::<Vec<_>>(),
+            vec!["fanbox", "Some One [12345]"]
+        );
+        assert_eq!(existing, PathBuf::from("patreon/Some One [12345]"));
+    }
+}
@@ -1,35 +1,395 @@
 use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use discord::{DiscordChannel, DiscordMessage, DiscordServer, DISCORD_SERVICE};
 use errors::KemonoError;
-use log::debug;
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use log::{debug, warn};
+use lru::LruCache;
+use platform::Platform;
 use reqwest::cookie::Jar;
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use time::OffsetDateTime;
 
+pub mod archive;
+pub mod collection;
+pub mod config;
+pub mod content;
+pub mod cookies;
+pub mod dedup;
+pub mod discord;
+pub mod download;
 pub mod errors;
+pub mod export;
+pub mod failures;
+pub mod filter;
+pub mod hooks;
+pub mod layout;
+pub mod lock;
+pub mod manifest;
+pub mod missing;
+pub mod mtime;
+pub mod notify;
+pub mod output;
+pub mod platform;
+pub mod prune;
+pub mod schedule;
+pub mod state;
+pub mod stats;
+pub mod summary;
+pub mod util;
 
 pub static DEFAULT_DOWNLOAD_PATH: &str = "./download";
+pub static DEFAULT_THREADS: usize = 2;
+/// More concurrent downloads than this tend to get rate limited, so there's a warning above it
+pub static MAX_THREADS: usize = 8;
+/// How many creators `update` works on at once
+pub static DEFAULT_PARALLEL_CREATORS: usize = 2;
+
+/// Expands a leading `~` to `home` and makes relative paths absolute from `current_dir`
+fn expand_path(path: &str, home: Option<PathBuf>, current_dir: &Path) -> PathBuf {
+    let path = match (path.strip_prefix('~'), home) {
+        (Some(""), Some(home)) => home,
+        (Some(rest), Some(home)) if rest.starts_with('/') => home.join(&rest[1..]),
+        _ => PathBuf::from(path),
+    };
+    match path.is_absolute() {
+        true => path,
+        false => current_dir.join(path),
+    }
+}
+
+/// Turns a download path from the user into an absolute one, creating it if it doesn't exist
+pub fn prepare_download_path(path: &str) -> Result<PathBuf, KemonoError> {
+    let current_dir = std::env::current_dir()
+        .map_err(|err| format!("Failed to get the current directory: {:?}", err))?;
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let path = expand_path(path, home, &current_dir);
+    if path.exists() && !path.is_dir() {
+        return Err(KemonoError::Configuration(format!(
+            "Download path {} exists but isn't a directory",
+            path.display()
+        )));
+    }
+    std::fs::create_dir_all(&path).map_err(|err| {
+        KemonoError::Configuration(format!(
+            "Failed to create download path {}: {:?}",
+            path.display(),
+            err
+        ))
+    })?;
+    Ok(path)
+}
 
 #[derive(Deserialize, Debug, Serialize)]
 pub struct Creator {
-    pub favorited: usize,
+    /// How many users have favorited the creator, the API calls it `favorited`. It's not in the
+    /// favorites list, so it's zero there
+    #[serde(default, alias = "favorited")]
+    pub favorite_count: u64,
     pub id: String,
-    pub indexed: usize,
+    #[serde(with = "creator_time")]
+    pub indexed: OffsetDateTime,
     pub name: String,
     pub service: String,
-    pub updated: usize,
+    #[serde(with = "creator_time")]
+    pub updated: OffsetDateTime,
+}
+
+impl Creator {
+    /// When the site last saw a change to the creator, eg to find ones which have gone quiet
+    pub fn updated_at(&self) -> OffsetDateTime {
+        self.updated
+    }
+
+    /// When the site last checked the creator for new posts
+    pub fn indexed_at(&self) -> OffsetDateTime {
+        self.indexed
+    }
+}
+
+/// The logged-in account, from `/account`
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Account {
+    #[serde(default)]
+    pub id: Value,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<String>,
+}
+
+/// The cookie the site keeps the login in
+pub static SESSION_COOKIE: &str = "session";
+
+/// A message a creator sent to their subscribers, from `/{service}/user/{creator}/dms`
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DirectMessage {
+    /// Identifies the message, there's no ID
+    pub hash: String,
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub service: String,
+    pub content: Option<String>,
+    pub added: Option<String>,
+    pub published: Option<String>,
+    #[serde(default)]
+    pub embed: Value,
+    #[serde(default)]
+    pub file: Value,
+}
+
+/// A [Creator]'s times, which the creators list sends as unix timestamps and the favorites API as
+/// date strings. They're written out as RFC 3339 so they're readable.
+mod creator_time {
+    use log::debug;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_json::Value;
+    use time::format_description::well_known::Rfc3339;
+    use time::OffsetDateTime;
+
+    use crate::mtime;
+
+    pub fn serialize<S: Serializer>(
+        time: &OffsetDateTime,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let formatted = time.format(&Rfc3339).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&formatted)
+    }
+
+    /// Anything we can't make sense of is the epoch, rather than failing the whole list
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<OffsetDateTime, D::Error> {
+        let time = match Value::deserialize(deserializer)? {
+            Value::Number(number) => number
+                .as_i64()
+                .and_then(|seconds| OffsetDateTime::from_unix_timestamp(seconds).ok()),
+            Value::String(date) => mtime::parse_timestamp(&date)
+                .map(OffsetDateTime::from)
+                .or_else(|| OffsetDateTime::parse(&date, &Rfc3339).ok())
+                .or_else(|| {
+                    debug!("Ignoring unexpected timestamp {:?}", date);
+                    None
+                }),
+            _ => None,
+        };
+        Ok(time.unwrap_or(OffsetDateTime::UNIX_EPOCH))
+    }
 }
 
+/// The site only makes thumbnails for attachments with these extensions
+pub static THUMBNAIL_EXTENSIONS: [&str; 5] = ["jpg", "jpeg", "png", "gif", "webp"];
+
 #[derive(Deserialize, Debug, Serialize, Eq, PartialEq, Clone, Hash)]
 pub struct Attachment {
     pub name: Option<String>,
     pub path: Option<String>,
 }
 
+impl Attachment {
+    /// Returns true if the attachment has both a name and a path
+    pub fn is_complete(&self) -> bool {
+        self.name.is_some() && self.path.is_some()
+    }
+
+    /// The lowercased file extension from the name, if there is one
+    pub fn extension(&self) -> Option<String> {
+        let name = self.name.as_deref()?;
+        match name.rsplit_once('.') {
+            // dotfiles like ".hidden" don't have an extension
+            Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => Some(ext.to_lowercase()),
+            _ => None,
+        }
+    }
+
+    /// The URL of the attachment's thumbnail, `{scheme}://img.{hostname}/thumbnail/data{path}`, if it's an image
+    pub fn thumbnail_url(&self, client: &KemonoClient) -> Option<Url> {
+        let path = self.path.as_deref()?;
+        if !self
+            .extension()
+            .is_some_and(|ext| THUMBNAIL_EXTENSIONS.contains(&ext.as_str()))
+        {
+            return None;
+        }
+        let path = path.trim_start_matches('/');
+        Url::from_str(&format!(
+            "{}://img.{}/thumbnail/data/{}",
+            client.scheme, client.hostname, path
+        ))
+        .ok()
+    }
+}
+
+impl core::fmt::Display for Attachment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} at {}",
+            self.name.as_deref().unwrap_or("<unnamed>"),
+            self.path.as_deref().unwrap_or("<no path>")
+        )
+    }
+}
+
+/// An external link embedded in a post
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct Embed {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl Embed {
+    /// Returns true if there's nothing embedded, the API sends `{}` for most posts
+    pub fn is_empty(&self) -> bool {
+        self.url.is_none() && self.subject.is_none() && self.description.is_none()
+    }
+}
+
+/// A poll attached to a post
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct Poll {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub choices: Vec<PollChoice>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closes_at: Option<String>,
+    #[serde(default)]
+    pub allows_multiple: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_votes: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct PollChoice {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub votes: u64,
+}
+
+/// Deserializes a [Poll], anything other than a poll object (including the `false` older metadata has) is no poll
+fn deserialize_poll<'de, D>(deserializer: D) -> Result<Option<Poll>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Value::deserialize(deserializer)?;
+    if !value.is_object() {
+        return Ok(None);
+    }
+    Ok(serde_json::from_value(value.clone())
+        .map_err(|err| debug!("Ignoring unexpected poll {}: {:?}", value, err))
+        .ok())
+}
+
+/// Deserializes the edited timestamp, older metadata has a bool here which doesn't say when, so it's dropped
+fn deserialize_edited<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(edited) => Some(edited),
+        Value::Null => None,
+        value => {
+            debug!("Ignoring unexpected edited value {}", value);
+            None
+        }
+    })
+}
+
+/// Deserializes captions from whatever shape they come in, keeping every string in order
+fn deserialize_captions<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    fn strings(value: Value, captions: &mut Vec<String>) {
+        match value {
+            Value::String(caption) if !caption.trim().is_empty() => captions.push(caption),
+            Value::Array(values) => values.into_iter().for_each(|v| strings(v, captions)),
+            Value::Object(values) => values.into_iter().for_each(|(_, v)| strings(v, captions)),
+            _ => {}
+        }
+    }
+    let mut captions = Vec::new();
+    strings(Value::deserialize(deserializer)?, &mut captions);
+    Ok(match captions.is_empty() {
+        true => None,
+        false => Some(captions),
+    })
+}
+
+/// A post from the API which didn't parse
+#[derive(Debug)]
+pub struct BadPost {
+    /// The post's ID, if it had one
+    pub id: Option<String>,
+    pub error: serde_json::Error,
+}
+
+impl std::fmt::Display for BadPost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "post {} which failed to parse: {}",
+            self.id.as_deref().unwrap_or("with no ID"),
+            self.error
+        )
+    }
+}
+
+/// Parses each post on its own, so one odd post doesn't lose the rest of the page
+pub fn parse_posts(values: Vec<Value>) -> (Vec<Post>, Vec<BadPost>) {
+    let mut posts = Vec::new();
+    let mut bad_posts = Vec::new();
+    for value in values {
+        let id = match value.get("id") {
+            Some(Value::String(id)) => Some(id.clone()),
+            Some(Value::Number(id)) => Some(id.to_string()),
+            _ => None,
+        };
+        match serde_json::from_value::<Post>(value) {
+            Ok(post) => posts.push(post),
+            Err(error) => bad_posts.push(BadPost { id, error }),
+        }
+    }
+    (posts, bad_posts)
+}
+
+/// Deserializes an [Embed], falling back to an empty one if the API sends something unexpected
+fn deserialize_embed<'de, D>(deserializer: D) -> Result<Embed, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Value::deserialize(deserializer)?;
+    if value.is_null() {
+        return Ok(Embed::default());
+    }
+    Ok(serde_json::from_value(value.clone()).unwrap_or_else(|err| {
+        debug!("Ignoring unexpected embed {}: {:?}", value, err);
+        Embed::default()
+    }))
+}
+
 #[derive(Clone, Deserialize, Debug, Serialize)]
 pub struct Post {
     pub id: String,
@@ -37,67 +397,250 @@ pub struct Post {
     pub service: String,
     pub title: String,
     pub content: Option<String>,
-    pub embed: Value,
+    #[serde(default, deserialize_with = "deserialize_embed")]
+    pub embed: Embed,
     pub shared_file: Option<bool>,
     pub file: Attachment,
     pub added: String,     // should be an offsetdatetime
     pub published: String, // should be an offsetdatetime
-    pub edited: Option<bool>,
-    pub poll: Option<bool>,
+    /// When the post was last edited
+    #[serde(default, deserialize_with = "deserialize_edited")]
+    pub edited: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_poll")]
+    pub poll: Option<Poll>,
+    /// Subtitles for the post's video
+    #[serde(default, deserialize_with = "deserialize_captions")]
     pub captions: Option<Vec<String>>,
     pub tags: Option<Vec<String>>,
     pub attachments: Option<HashSet<Attachment>>,
 }
 
+/// Posts are the same post if they have the same service, creator and ID.
+///
+/// The rest of the fields (including `embed`) are the post's content, which changes when it's
+/// edited, so they're left out; use [download::post_changed] to check for edits.
+impl PartialEq for Post {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id && self.user == other.user && self.service == other.service
+    }
+}
+
+impl Eq for Post {}
+
+impl std::hash::Hash for Post {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.service.hash(state);
+        self.user.hash(state);
+        self.id.hash(state);
+    }
+}
+
+impl Post {
+    /// Everything there is to download for this post, the main `file` (if it's complete) followed by the attachments.
+    ///
+    /// Some services also list the main file in the attachments, so those are deduplicated by path.
+    pub fn all_attachments(&self) -> Vec<&Attachment> {
+        let mut res: Vec<&Attachment> = Vec::new();
+        if self.file.is_complete() {
+            res.push(&self.file);
+        }
+        if let Some(attachments) = &self.attachments {
+            for attachment in attachments {
+                if !res.iter().any(|a| a.path == attachment.path) {
+                    res.push(attachment);
+                }
+            }
+        }
+        res
+    }
+
+    /// Bytes across all the post's files, from `sizes` by attachment path, None if any of them isn't known.
+    ///
+    /// The API doesn't include sizes, see [stats::remote_sizes] for getting them.
+    pub fn total_size(&self, sizes: &HashMap<String, u64>) -> Option<u64> {
+        self.all_attachments()
+            .into_iter()
+            .map(|attachment| sizes.get(attachment.path.as_deref()?).copied())
+            .sum()
+    }
+}
+
 pub struct KemonoClient {
     pub hostname: String,
+    /// URL scheme used to talk to the host, defaults to https
+    pub scheme: String,
     pub download_path: Option<String>,
     pub session: Option<reqwest::blocking::Client>,
 
     pub cookies: Arc<Jar>,
+    /// Request timeout, defaults to 900 seconds for downloads and 30 seconds for API calls
+    pub timeout_seconds: Option<u64>,
+    /// Proxy to send all requests through
+    pub proxy: Option<reqwest::Proxy>,
+    /// Hosts to download attachments from, in order of preference, defaults to `hostname`
+    pub data_hosts: Vec<String>,
+    /// Index into `data_hosts` of the last one which worked, shared with clones
+    pub data_host_index: Arc<AtomicUsize>,
     #[allow(dead_code)]
     pub username: Option<String>,
     #[allow(dead_code)]
     pub password: Option<String>,
+    /// How long pages of posts are cached for, None turns the cache off
+    pub cache_ttl: Option<Duration>,
+    /// Fail the whole page if a post doesn't parse, rather than skipping it
+    pub strict: bool,
+    /// Pages of posts by (service, creator, offset), shared with clones
+    posts_cache: PostsCache,
 }
 
-impl KemonoClient {
-    pub fn new_from(client: &KemonoClient) -> Self {
+/// How many pages [KemonoClient::all_posts_parallel] fetches at once
+pub static PARALLEL_PAGES: usize = 4;
+
+/// How long pages of posts are cached for by default
+pub static DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+/// Most pages of posts to keep in the cache
+static POSTS_CACHE_PAGES: usize = 256;
+
+type PostsCache = Arc<Mutex<LruCache<(String, String, usize), (Instant, Vec<Post>)>>>;
+
+impl std::fmt::Debug for KemonoClient {
+    /// Leaves out the credentials so the client can be logged safely
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redact = |value: &Option<String>| value.as_ref().map(|_| "***");
+        f.debug_struct("KemonoClient")
+            .field("hostname", &self.hostname)
+            .field("scheme", &self.scheme)
+            .field("download_path", &self.download_path)
+            .field("session", &self.session.is_some())
+            .field("timeout_seconds", &self.timeout_seconds)
+            .field("data_hosts", &self.data_hosts)
+            .field("cache_ttl", &self.cache_ttl)
+            .field("strict", &self.strict)
+            .field("username", &redact(&self.username))
+            .field("password", &redact(&self.password))
+            .finish_non_exhaustive()
+    }
+}
+
+impl Clone for KemonoClient {
+    /// Shares the cookie jar, data host preference and posts cache with the original
+    fn clone(&self) -> Self {
         Self {
-            hostname: client.hostname.clone(),
-            download_path: client.download_path.clone(),
-            session: client.session.clone(),
-            cookies: Arc::new(Jar::default()),
-            username: client.username.clone(),
-            password: client.password.clone(),
+            hostname: self.hostname.clone(),
+            scheme: self.scheme.clone(),
+            download_path: self.download_path.clone(),
+            session: self.session.clone(),
+            cookies: Arc::clone(&self.cookies),
+            timeout_seconds: self.timeout_seconds,
+            proxy: self.proxy.clone(),
+            data_hosts: self.data_hosts.clone(),
+            data_host_index: Arc::clone(&self.data_host_index),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            cache_ttl: self.cache_ttl,
+            strict: self.strict,
+            posts_cache: Arc::clone(&self.posts_cache),
         }
     }
+}
+
+impl KemonoClient {
+    #[deprecated(note = "use KemonoClient::clone, which shares the cookie jar")]
+    pub fn new_from(client: &KemonoClient) -> Self {
+        client.clone()
+    }
+
+    /// Returns the scheme + hostname, eg `https://example.com`
+    pub fn host_url(&self) -> String {
+        format!("{}://{}", self.scheme, self.hostname)
+    }
 
     pub fn base_url(&self) -> String {
-        format!("https://{}/api/v1", self.hostname)
+        format!("{}/api/v1", self.host_url())
     }
 
     // pub fn user_agent(&self) -> String {
     //     format!("Rust Kemono Client v{}", env!("CARGO_PKG_VERSION"))
     // }
 
+    /// Returns the configured timeout, or `default` seconds if it's not set
+    pub fn timeout(&self, default: u64) -> Result<Duration, KemonoError> {
+        match self.timeout_seconds {
+            Some(0) => Err(KemonoError::from_stringable(
+                "Timeout must be greater than 0 seconds",
+            )),
+            Some(timeout) => Ok(Duration::from_secs(timeout)),
+            None => Ok(Duration::from_secs(default)),
+        }
+    }
+
+    /// Send all requests through a proxy, eg `http://proxy:3128` or `socks5://proxy:1080`
+    pub fn with_proxy(mut self, proxy_url: &str) -> Result<Self, KemonoError> {
+        self.proxy = Some(reqwest::Proxy::all(proxy_url)?);
+        Ok(self)
+    }
+
+    /// Download attachments from these hosts, trying the next one if a host fails
+    pub fn with_data_hosts(mut self, hosts: Vec<String>) -> Self {
+        self.data_hosts = hosts;
+        self.data_host_index.store(0, Ordering::Relaxed);
+        self
+    }
+
+    /// The data hosts as base URLs, starting with the last one that worked
+    pub fn data_host_urls(&self) -> Vec<String> {
+        if self.data_hosts.is_empty() {
+            return vec![self.host_url()];
+        }
+        let start = self.data_host_index.load(Ordering::Relaxed) % self.data_hosts.len();
+        self.data_hosts
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(self.data_hosts.len())
+            .map(|host| self.data_host_url(host))
+            .collect()
+    }
+
+    /// Hosts can be given as a bare hostname or a full base URL
+    fn data_host_url(&self, host: &str) -> String {
+        match host.contains("://") {
+            true => host.trim_end_matches('/').to_string(),
+            false => format!("{}://{}", self.scheme, host),
+        }
+    }
+
+    /// Remember that `host_url` worked so we try it first next time
+    pub fn prefer_data_host(&self, host_url: &str) {
+        if let Some(index) = self
+            .data_hosts
+            .iter()
+            .position(|host| self.data_host_url(host) == host_url)
+        {
+            self.data_host_index.store(index, Ordering::Relaxed);
+        }
+    }
+
     pub fn new_session(&mut self) -> Result<(), KemonoError> {
-        self.session = Some(
-            reqwest::blocking::Client::builder()
-                .timeout(Duration::from_secs(900))
-                .cookie_store(true)
-                .cookie_provider(self.cookies.clone())
-                .build()?,
-        );
+        let mut builder = reqwest::blocking::Client::builder()
+            .timeout(self.timeout(900)?)
+            .cookie_store(true)
+            .cookie_provider(self.cookies.clone());
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        self.session = Some(builder.build()?);
         Ok(())
     }
-    pub fn new_async_session(&mut self) -> Result<reqwest::Client, KemonoError> {
-        reqwest::Client::builder()
-            .timeout(Duration::from_secs(30))
+    pub fn new_async_session(&self) -> Result<reqwest::Client, KemonoError> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(self.timeout(30)?)
             .cookie_store(true)
-            .cookie_provider(self.cookies.clone())
-            .build()
-            .map_err(|err| err.into())
+            .cookie_provider(self.cookies.clone());
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        builder.build().map_err(|err| err.into())
     }
 
     pub fn get_base_download_path(&self) -> String {
@@ -117,15 +660,129 @@ impl KemonoClient {
 
     pub fn new(hostname: &str, download_path: Option<String>) -> Self {
         Self {
-            hostname: hostname.to_string(),
             download_path,
+            ..Self::new_with_jar(hostname, Arc::new(Jar::default()))
+        }
+    }
+
+    /// A client using a cookie jar managed elsewhere, eg one shared with other clients or already
+    /// holding a login session
+    pub fn new_with_jar(hostname: &str, jar: Arc<Jar>) -> Self {
+        Self {
+            hostname: hostname.to_string(),
+            scheme: "https".to_string(),
+            download_path: None,
             session: None,
             username: None,
             password: None,
-            cookies: Arc::new(Jar::default()),
+            cookies: jar,
+            timeout_seconds: None,
+            proxy: None,
+            data_hosts: Vec::new(),
+            data_host_index: Arc::new(AtomicUsize::new(0)),
+            cache_ttl: Some(DEFAULT_CACHE_TTL),
+            strict: false,
+            posts_cache: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(POSTS_CACHE_PAGES).unwrap_or(NonZeroUsize::MIN),
+            ))),
+        }
+    }
+
+    /// Forgets all the cached pages of posts
+    pub fn clear_cache(&mut self) {
+        if let Ok(mut cache) = self.posts_cache.lock() {
+            cache.clear();
+        }
+    }
+
+    /// Parses a page of posts, returning the ones which parsed and the (logged) ones which didn't,
+    /// or failing on the first bad post if we're strict
+    pub fn decode_posts(
+        &self,
+        values: Vec<Value>,
+    ) -> Result<(Vec<Post>, Vec<BadPost>), KemonoError> {
+        let (posts, bad_posts) = parse_posts(values);
+        if let Some(bad_post) = bad_posts.first().filter(|_| self.strict) {
+            return Err(KemonoError::from(bad_post.to_string()));
+        }
+        for bad_post in bad_posts.iter() {
+            warn!("Skipping {}", bad_post);
+        }
+        Ok((posts, bad_posts))
+    }
+
+    /// A cached page of posts, if there's one which hasn't expired
+    fn cached_posts(&self, key: &(String, String, usize)) -> Option<Vec<Post>> {
+        let ttl = self.cache_ttl?;
+        let mut cache = self.posts_cache.lock().ok()?;
+        match cache.get(key) {
+            Some((cached, posts)) if cached.elapsed() < ttl => Some(posts.clone()),
+            Some(_) => {
+                cache.pop(key);
+                None
+            }
+            None => None,
         }
     }
 
+    /// A client for one of the known sites
+    pub fn for_platform(platform: Platform, download_path: Option<String>) -> Self {
+        Self::new(platform.hostname(), download_path)
+    }
+
+    /// Which site we're talking to, if the hostname says
+    pub fn platform(&self) -> Option<Platform> {
+        Platform::from_hostname(&self.hostname)
+    }
+
+    /// Fails if `service` is archived on a different site to the one we're talking to.
+    ///
+    /// Services neither site is known to have are allowed, in case they've been added since.
+    pub fn validate_service(&self, service: &str) -> Result<(), KemonoError> {
+        let (Some(platform), Some(expected)) = (self.platform(), Platform::from_service(service))
+        else {
+            return Ok(());
+        };
+        match platform == expected {
+            true => Ok(()),
+            false => Err(KemonoError::Configuration(format!(
+                "{} is archived on {}, not {}, services on {} are {}",
+                service,
+                expected.hostname(),
+                self.hostname,
+                platform,
+                platform.services().join(", ")
+            ))),
+        }
+    }
+
+    /// A client for Coomer, which archives OnlyFans, Fansly and CandFans
+    pub fn new_coomer(download_path: Option<String>) -> Self {
+        Self::for_platform(Platform::Coomer, download_path)
+    }
+
+    /// Where the site serves a creator's avatar, `{host}/icons/{service}/{creator}`
+    pub fn icon_url(&self, service: &str, creator: &str) -> Result<Url, KemonoError> {
+        Url::from_str(&format!(
+            "{}/icons/{}/{}",
+            self.host_url(),
+            service,
+            creator
+        ))
+        .map_err(|e| KemonoError::from(e.to_string()))
+    }
+
+    /// Where the site serves a creator's banner, `{host}/banners/{service}/{creator}`
+    pub fn banner_url(&self, service: &str, creator: &str) -> Result<Url, KemonoError> {
+        Url::from_str(&format!(
+            "{}/banners/{}/{}",
+            self.host_url(),
+            service,
+            creator
+        ))
+        .map_err(|e| KemonoError::from(e.to_string()))
+    }
+
     pub fn make_url(&self, endpoint: &str) -> Result<Url, KemonoError> {
         Url::from_str(&format!("{}/{}", self.base_url(), endpoint))
             .map_err(|e| KemonoError::from(e.to_string()))
@@ -134,7 +791,9 @@ impl KemonoClient {
     /// Get the app version hash
     pub async fn app_version(&self) -> Result<String, KemonoError> {
         let endpoint_url = self.make_url("app_version")?;
-        reqwest::get(endpoint_url)
+        self.new_async_session()?
+            .get(endpoint_url)
+            .send()
             .await?
             .text()
             .await
@@ -145,12 +804,140 @@ impl KemonoClient {
     pub async fn creators(&self) -> Result<Vec<Creator>, KemonoError> {
         let endpoint_url = self.make_url("creators.txt")?;
         // println!("endpoint_url: {}", endpoint_url);
-        let res = reqwest::get(endpoint_url).await?;
+        let res = self.new_async_session()?.get(endpoint_url).send().await?;
         res.json::<Vec<Creator>>()
             .await
             .map_err(KemonoError::from_stringable)
     }
 
+    /// The logged-in user's favorite creators, [KemonoError::Unauthorized] if we're not logged in
+    /// The logged-in account, [KemonoError::Unauthorized] if we're not logged in
+    pub async fn account(&self) -> Result<Account, KemonoError> {
+        let endpoint_url = self.make_url("account")?;
+        let res = self.new_async_session()?.get(endpoint_url).send().await?;
+        match res.status().as_u16() {
+            401 | 403 => return Err(KemonoError::Unauthorized),
+            429 => return Err(KemonoError::RateLimited),
+            _ => {}
+        }
+        res.error_for_status()?
+            .json::<Account>()
+            .await
+            .map_err(KemonoError::from_stringable)
+    }
+
+    /// Uses an existing login session instead of logging in, eg one copied from a browser
+    pub fn set_session_token(&self, token: &str) -> Result<(), KemonoError> {
+        let url = Url::parse(&self.host_url()).map_err(KemonoError::from_stringable)?;
+        self.cookies.add_cookie_str(
+            &format!("{}={}; Path=/", SESSION_COOKIE, token.trim()),
+            &url,
+        );
+        Ok(())
+    }
+
+    /// Checks the session is still good, [KemonoError::SessionExpired] if the site rejects it
+    pub async fn validate_session(&self) -> Result<Account, KemonoError> {
+        self.account().await.map_err(|err| match err {
+            KemonoError::Unauthorized => KemonoError::SessionExpired,
+            err => err,
+        })
+    }
+
+    /// Whether the cookie jar has a login session for the site, which every session we make shares
+    pub fn has_session(&self) -> bool {
+        use reqwest::cookie::CookieStore;
+
+        let Ok(url) = Url::parse(&self.host_url()) else {
+            return false;
+        };
+        self.cookies
+            .cookies(&url)
+            .and_then(|cookies| cookies.to_str().map(str::to_string).ok())
+            .is_some_and(|cookies| {
+                cookies
+                    .split(';')
+                    .any(|cookie| cookie.trim().starts_with(&format!("{}=", SESSION_COOKIE)))
+            })
+    }
+
+    pub async fn favorites(&self) -> Result<Vec<Creator>, KemonoError> {
+        let mut endpoint_url = self.make_url("account/favorites")?;
+        endpoint_url.query_pairs_mut().append_pair("type", "artist");
+        let res = self.new_async_session()?.get(endpoint_url).send().await?;
+        match res.status().as_u16() {
+            401 | 403 => return Err(KemonoError::Unauthorized),
+            429 => return Err(KemonoError::RateLimited),
+            _ => {}
+        }
+        res.error_for_status()?
+            .json::<Vec<Creator>>()
+            .await
+            .map_err(KemonoError::from_stringable)
+    }
+
+    /// Adds a post to the logged-in user's favorites, [KemonoError::Unauthorized] if we're not logged in
+    pub async fn favorite_post(
+        &self,
+        service: &str,
+        creator: &str,
+        post_id: &str,
+    ) -> Result<(), KemonoError> {
+        self.change_favorite_post(reqwest::Method::POST, service, creator, post_id)
+            .await
+    }
+
+    /// Removes a post from the logged-in user's favorites, [KemonoError::Unauthorized] if we're not logged in
+    pub async fn unfavorite_post(
+        &self,
+        service: &str,
+        creator: &str,
+        post_id: &str,
+    ) -> Result<(), KemonoError> {
+        self.change_favorite_post(reqwest::Method::DELETE, service, creator, post_id)
+            .await
+    }
+
+    async fn change_favorite_post(
+        &self,
+        method: reqwest::Method,
+        service: &str,
+        creator: &str,
+        post_id: &str,
+    ) -> Result<(), KemonoError> {
+        let endpoint_url = self.make_url(&format!(
+            "favorites/post/{}/{}/{}",
+            service, creator, post_id
+        ))?;
+        let res = self
+            .new_async_session()?
+            .request(method, endpoint_url)
+            .send()
+            .await?;
+        match res.status().as_u16() {
+            401 | 403 => return Err(KemonoError::Unauthorized),
+            429 => return Err(KemonoError::RateLimited),
+            _ => {}
+        }
+        res.error_for_status()?;
+        Ok(())
+    }
+
+    /// Creators whose name contains `name`, ignoring case, most favorited first.
+    ///
+    /// There's no search endpoint in the API, so this filters the full creator list.
+    pub async fn search_creators(&self, name: &str) -> Result<Vec<Creator>, KemonoError> {
+        let name = name.trim().to_lowercase();
+        let mut creators: Vec<Creator> = self
+            .creators()
+            .await?
+            .into_iter()
+            .filter(|creator| creator.name.to_lowercase().contains(&name))
+            .collect();
+        creators.sort_by_key(|creator| std::cmp::Reverse(creator.favorite_count));
+        Ok(creators)
+    }
+
     /// Get a list of recent posts, filterable by query or offset
     pub async fn recent_posts(
         &self,
@@ -166,75 +953,471 @@ impl KemonoClient {
                 .query_pairs_mut()
                 .append_pair("o", offset.to_string().as_str());
         }
-        let res = reqwest::get(endpoint_url).await?;
-        res.json::<Vec<Post>>()
+        let res = self.new_async_session()?.get(endpoint_url).send().await?;
+        let values = res
+            .json::<Vec<Value>>()
             .await
-            .map_err(KemonoError::from_stringable)
+            .map_err(KemonoError::from_stringable)?;
+        self.decode_posts(values)
+            .map(|(posts, _)| posts)
+            .map_err(|err| KemonoError::from_stringable(format!("{:?}", err)))
     }
 
-    /// get *all* posts for a creator/service combination
-    pub async fn all_posts(
-        &mut self,
+    /// How many pages of posts the creator has, from the post count in their profile.
+    ///
+    /// Returns [KemonoError::Unknown] if the profile doesn't have a count, so callers can page
+    /// through sequentially instead.
+    pub async fn get_page_count(&self, service: &str, creator: &str) -> Result<usize, KemonoError> {
+        if self
+            .clone()
+            .posts(service, creator, None, Some(0))
+            .await?
+            .is_empty()
+        {
+            return Ok(0);
+        }
+        let post_count = self
+            .creator_profile(service, creator)
+            .await?
+            .get("post_count")
+            .and_then(Value::as_u64)
+            .ok_or(KemonoError::Unknown)? as usize;
+        Ok(post_count.div_ceil(self.max_per_page()).max(1))
+    }
+
+    /// Gets *all* posts for a creator/service combination, fetching up to [PARALLEL_PAGES] pages at once.
+    ///
+    /// Falls back to [KemonoClient::all_posts] if we can't tell how many pages there are.
+    pub async fn all_posts_parallel(
+        &self,
         service: &str,
         creator: &str,
     ) -> Result<Vec<Post>, KemonoError> {
-        let mut offset = 0;
-        let mut posts = Vec::new();
-        loop {
-            let res = self.posts(service, creator, None, Some(offset)).await?;
-            if res.is_empty() {
+        let pages = match self.get_page_count(service, creator).await {
+            Ok(pages) => pages,
+            Err(KemonoError::Unknown) => {
                 debug!(
-                    "Empty response from server {}/{} offset: {}",
-                    service, creator, offset
+                    "No post count for {}/{}, getting pages one at a time",
+                    service, creator
                 );
-                break;
+                return self.posts_stream(service, creator).try_collect().await;
             }
-            posts.extend(res);
-            offset += self.max_per_page();
+            Err(err) => return Err(err),
+        };
+        let mut pages: Vec<Vec<Post>> = stream::iter(0..pages)
+            .map(|page| {
+                let mut client = self.clone();
+                async move {
+                    client
+                        .posts(service, creator, None, Some(page * client.max_per_page()))
+                        .await
+                }
+            })
+            .buffered(PARALLEL_PAGES)
+            .try_collect()
+            .await?;
+        // the profile's count can be behind, so carry on while the pages are full
+        let mut client = self.clone();
+        while pages
+            .last()
+            .is_some_and(|page| page.len() >= self.max_per_page())
+        {
+            let offset = pages.len() * self.max_per_page();
+            debug!(
+                "Post count for {}/{} is out of date, getting offset {}",
+                service, creator, offset
+            );
+            pages.push(client.posts(service, creator, None, Some(offset)).await?);
         }
-        Ok(posts)
+        let mut seen = HashSet::new();
+        Ok(pages
+            .into_iter()
+            .flatten()
+            .filter(|post| seen.insert(post.id.clone()))
+            .collect())
     }
 
-    /// Gets a list of posts for a given service/creator, filterable by query or offset
-    pub async fn posts(
+    /// get *all* posts for a creator/service combination
+    pub async fn all_posts(
         &mut self,
         service: &str,
         creator: &str,
-        query: Option<&str>,
-        offset: Option<usize>,
     ) -> Result<Vec<Post>, KemonoError> {
-        let mut endpoint_url = self.make_url(&format!("{}/user/{}", service, creator))?;
-        if let Some(query) = query {
-            endpoint_url.query_pairs_mut().append_pair("q", query);
-        }
-        if let Some(offset) = offset {
-            endpoint_url
-                .query_pairs_mut()
-                .append_pair("o", offset.to_string().as_str());
-        }
-        let client = self.new_async_session()?;
+        self.posts_stream(service, creator).try_collect().await
+    }
 
-        let res = client.get(endpoint_url).send().await?;
-        if res.status().as_u16() == 429 {
-            return Err(KemonoError::RateLimited);
-        }
-        res.json::<Vec<Post>>()
+    /// get the `limit` newest posts for a creator/service combination, only fetching the pages
+    /// needed to find them
+    pub async fn all_posts_with_limit(
+        &mut self,
+        service: &str,
+        creator: &str,
+        limit: usize,
+    ) -> Result<Vec<Post>, KemonoError> {
+        self.posts_window(service, creator, 0, Some(limit))
+            .try_collect()
             .await
-            .map_err(|e| KemonoError::GetPostsError(format!("{:?}", e)))
     }
 
-    // TODO: /{service}/user/{creator_id}/announcements
-    /*
-    [
-        {
-        "service": "patreon",
-        "user_id": "blep",
-        "hash": "biglonghashnumber",
-        "content": "message content",
-        "added": "2023-01-31T05:16:15.462035"
-        }
-    ]
-     */
+    /// Streams *all* posts for a creator/service combination, fetching each page as the previous
+    /// one is used up, so callers don't need to hold every post in memory.
+    ///
+    /// Posts which turn up again on a later page are skipped, the stream ends after the first error.
+    pub fn posts_stream(
+        &self,
+        service: &str,
+        creator: &str,
+    ) -> impl Stream<Item = Result<Post, KemonoError>> + 'static {
+        self.posts_window(service, creator, 0, None)
+    }
+
+    /// Streams a creator/service's posts from the `offset`th newest, stopping after `max_posts`.
+    ///
+    /// Pagination stops as soon as enough posts have been fetched, so a small window of a big
+    /// creator only costs a page or two.
+    ///
+    /// The API only takes offsets which are a multiple of [KemonoClient::max_per_page], so any
+    /// other `offset` starts from the page it's on and skips the posts before it.
+    pub fn posts_window(
+        &self,
+        service: &str,
+        creator: &str,
+        offset: usize,
+        max_posts: Option<usize>,
+    ) -> impl Stream<Item = Result<Post, KemonoError>> + 'static {
+        struct Pages {
+            client: KemonoClient,
+            service: String,
+            creator: String,
+            offset: Option<usize>,
+            seen: HashSet<String>,
+        }
+
+        let pages = Pages {
+            client: self.clone(),
+            service: service.to_string(),
+            creator: creator.to_string(),
+            // the API only pages in whole pages
+            offset: Some(offset - offset % self.max_per_page()),
+            seen: HashSet::new(),
+        };
+        stream::unfold(pages, |mut pages| async move {
+            let offset = pages.offset?;
+            let res = match pages
+                .client
+                .posts(&pages.service, &pages.creator, None, Some(offset))
+                .await
+            {
+                Ok(res) => res,
+                Err(err) => {
+                    pages.offset = None;
+                    return Some((vec![Err(err)], pages));
+                }
+            };
+            if pages
+                .client
+                .last_page(&res, &pages.service, &pages.creator, offset)
+            {
+                return None;
+            }
+            pages.offset = Some(offset + pages.client.max_per_page());
+            let res: Vec<Result<Post, KemonoError>> = res
+                .into_iter()
+                .filter(|post| pages.seen.insert(post.id.clone()))
+                .map(Ok)
+                .collect();
+            Some((res, pages))
+        })
+        .flat_map(stream::iter)
+        .skip(offset % self.max_per_page())
+        .take(max_posts.unwrap_or(usize::MAX))
+    }
+
+    /// Whether pagination's finished, warns if the server's sent more than a page
+    fn last_page(&self, res: &[Post], service: &str, creator: &str, offset: usize) -> bool {
+        if res.is_empty() {
+            debug!(
+                "Empty response from server {}/{} offset: {}",
+                service, creator, offset
+            );
+            return true;
+        }
+        if res.len() > self.max_per_page() {
+            warn!(
+                "Got {} posts for {}/{} offset: {}, expected at most {}",
+                res.len(),
+                service,
+                creator,
+                offset,
+                self.max_per_page()
+            );
+        }
+        false
+    }
+
+    /// Paginates through a creator/service's posts until `stop` matches a post.
+    ///
+    /// Posts come back newest-first, so everything before the matching post is returned. The
+    /// bool is false if we ran out of posts without a match.
+    async fn posts_until<F>(
+        &self,
+        service: &str,
+        creator: &str,
+        stop: F,
+    ) -> Result<(Vec<Post>, bool), KemonoError>
+    where
+        F: Fn(&Post) -> bool,
+    {
+        let mut posts: Vec<Post> = Vec::new();
+        let mut stream = std::pin::pin!(self.posts_window(service, creator, 0, None));
+        while let Some(post) = stream.try_next().await? {
+            if stop(&post) {
+                debug!(
+                    "Stopping at post {} for {}/{} offset: {}",
+                    post.id,
+                    service,
+                    creator,
+                    posts.len()
+                );
+                return Ok((posts, true));
+            }
+            posts.push(post);
+        }
+        Ok((posts, false))
+    }
+
+    /// get all posts for a creator/service combination that are newer than `post_id`
+    pub async fn posts_after_id(
+        &mut self,
+        service: &str,
+        creator: &str,
+        post_id: &str,
+    ) -> Result<Vec<Post>, KemonoError> {
+        let (posts, found) = self
+            .posts_until(service, creator, |post| post.id == post_id)
+            .await?;
+        if !found {
+            warn!(
+                "Didn't find post {} for {}/{}, returning all posts",
+                post_id, service, creator
+            );
+        }
+        Ok(posts)
+    }
+
+    /// get all posts for a creator/service combination published after `published`
+    pub async fn posts_since(
+        &mut self,
+        service: &str,
+        creator: &str,
+        published: &str,
+    ) -> Result<Vec<Post>, KemonoError> {
+        let (posts, _) = self
+            .posts_until(service, creator, |post| {
+                post.published.as_str() <= published
+            })
+            .await?;
+        Ok(posts)
+    }
+
+    /// Gets a list of posts for a given service/creator, filterable by query or offset
+    ///
+    /// Errors are wrapped in [KemonoError::GetPostsError] to say which creator and offset failed.
+    pub async fn posts(
+        &mut self,
+        service: &str,
+        creator: &str,
+        query: Option<&str>,
+        offset: Option<usize>,
+    ) -> Result<Vec<Post>, KemonoError> {
+        self.fetch_posts(service, creator, query, offset)
+            .await
+            .map_err(|err| KemonoError::get_posts(service, creator, offset.unwrap_or(0), err))
+    }
+
+    async fn fetch_posts(
+        &mut self,
+        service: &str,
+        creator: &str,
+        query: Option<&str>,
+        offset: Option<usize>,
+    ) -> Result<Vec<Post>, KemonoError> {
+        // searches aren't cached, they're one-offs
+        let key = (
+            service.to_string(),
+            creator.to_string(),
+            offset.unwrap_or(0),
+        );
+        if query.is_none() {
+            if let Some(posts) = self.cached_posts(&key) {
+                debug!(
+                    "Using cached posts for {}/{} offset: {}",
+                    service, creator, key.2
+                );
+                return Ok(posts);
+            }
+        }
+        let mut endpoint_url = self.make_url(&format!("{}/user/{}", service, creator))?;
+        if let Some(query) = query {
+            endpoint_url.query_pairs_mut().append_pair("q", query);
+        }
+        if let Some(offset) = offset {
+            endpoint_url
+                .query_pairs_mut()
+                .append_pair("o", offset.to_string().as_str());
+        }
+        let client = self.new_async_session()?;
+
+        let res = client.get(endpoint_url).send().await?;
+        if res.status().as_u16() == 429 {
+            return Err(KemonoError::RateLimited);
+        }
+        let values = res
+            .json::<Vec<Value>>()
+            .await
+            .map_err(|e| KemonoError::from(format!("{:?}", e)))?;
+        let (posts, _) = self.decode_posts(values)?;
+        if query.is_none() && self.cache_ttl.is_some() {
+            if let Ok(mut cache) = self.posts_cache.lock() {
+                cache.put(key, (Instant::now(), posts.clone()));
+            }
+        }
+        Ok(posts)
+    }
+
+    /// An archived Discord server and its channels
+    pub async fn discord_server(&self, server_id: &str) -> Result<DiscordServer, KemonoError> {
+        let endpoint_url = self.make_url(&format!("discord/server/{}", server_id))?;
+        let res = self.new_async_session()?.get(endpoint_url).send().await?;
+        if res.status().as_u16() == 429 {
+            return Err(KemonoError::RateLimited);
+        }
+        let channels: Vec<DiscordChannel> = res
+            .error_for_status()?
+            .json()
+            .await
+            .map_err(KemonoError::from_stringable)?;
+        Ok(DiscordServer {
+            id: server_id.to_string(),
+            channels,
+        })
+    }
+
+    /// A page of messages from a Discord channel, newest first
+    pub async fn discord_channel_posts(
+        &self,
+        channel_id: &str,
+        offset: Option<usize>,
+    ) -> Result<Vec<DiscordMessage>, KemonoError> {
+        let mut endpoint_url = self.make_url(&format!("discord/channel/{}", channel_id))?;
+        if let Some(offset) = offset {
+            endpoint_url
+                .query_pairs_mut()
+                .append_pair("o", offset.to_string().as_str());
+        }
+        let res = self.new_async_session()?.get(endpoint_url).send().await?;
+        if res.status().as_u16() == 429 {
+            return Err(KemonoError::RateLimited);
+        }
+        res.error_for_status()?
+            .json::<Vec<DiscordMessage>>()
+            .await
+            .map_err(|e| {
+                KemonoError::get_posts(
+                    DISCORD_SERVICE,
+                    channel_id,
+                    offset.unwrap_or(0),
+                    KemonoError::from(format!("{:?}", e)),
+                )
+            })
+    }
+
+    /// Every message in every channel of a Discord server, as posts so they can be downloaded like any other
+    pub async fn discord_server_posts(&self, server_id: &str) -> Result<Vec<Post>, KemonoError> {
+        let server = self.discord_server(server_id).await?;
+        let mut posts = Vec::new();
+        for channel in server.channels.iter() {
+            let mut seen: HashSet<String> = HashSet::new();
+            loop {
+                let res = self
+                    .discord_channel_posts(&channel.id, Some(seen.len()))
+                    .await?;
+                let before = seen.len();
+                posts.extend(
+                    res.into_iter()
+                        .filter(|message| seen.insert(message.id.clone()))
+                        .map(|message| message.into_post(channel)),
+                );
+                if seen.len() == before {
+                    break;
+                }
+            }
+            debug!(
+                "Found {} messages in #{} of discord server {}",
+                seen.len(),
+                channel.name,
+                server_id
+            );
+        }
+        Ok(posts)
+    }
+
+    async fn creator_profile(&self, service: &str, creator: &str) -> Result<Value, KemonoError> {
+        let endpoint_url = self.make_url(&format!("{}/user/{}/profile", service, creator))?;
+        let res = self.new_async_session()?.get(endpoint_url).send().await?;
+        if res.status().as_u16() == 429 {
+            return Err(KemonoError::RateLimited);
+        }
+        Ok(res.error_for_status()?.json().await?)
+    }
+
+    /// Get a creator's display name from their profile
+    pub async fn creator_name(&self, service: &str, creator: &str) -> Result<String, KemonoError> {
+        self.creator_profile(service, creator)
+            .await?
+            .get("name")
+            .and_then(|name| name.as_str())
+            .map(str::to_string)
+            .ok_or(KemonoError::from(format!(
+                "No name in the profile for {}/{}",
+                service, creator
+            )))
+    }
+
+    /// A creator's DMs, empty if the service doesn't have them
+    pub async fn dms(
+        &self,
+        service: &str,
+        creator: &str,
+    ) -> Result<Vec<DirectMessage>, KemonoError> {
+        let endpoint_url = self.make_url(&format!("{}/user/{}/dms", service, creator))?;
+        let res = self.new_async_session()?.get(endpoint_url).send().await?;
+        match res.status().as_u16() {
+            404 => {
+                debug!("No DMs for {}/{}", service, creator);
+                return Ok(Vec::new());
+            }
+            429 => return Err(KemonoError::RateLimited),
+            _ => {}
+        }
+        res.error_for_status()?
+            .json::<Vec<DirectMessage>>()
+            .await
+            .map_err(KemonoError::from_stringable)
+    }
+
+    // TODO: /{service}/user/{creator_id}/announcements
+    /*
+    [
+        {
+        "service": "patreon",
+        "user_id": "blep",
+        "hash": "biglonghashnumber",
+        "content": "message content",
+        "added": "2023-01-31T05:16:15.462035"
+        }
+    ]
+     */
 
     // TODO: /fanbox/user/{creator_id}/fancards
     /*
@@ -258,8 +1441,10 @@ impl KemonoClient {
     // TODO: /{service}/user/{creator_id}/post/{post_id}
     // Get a specific post
 
+    /// Logs in with the username and password, leaving the session cookie in the shared jar so the
+    /// API and download sessions are both logged in
     pub async fn login(&mut self) -> Result<(), KemonoError> {
-        let endpoint_url = Url::from_str(&format!("https://{}/account/login", self.hostname))
+        let endpoint_url = Url::from_str(&format!("{}/account/login", self.host_url()))
             .map_err(|err| err.to_string())?;
 
         let mut form = HashMap::new();
@@ -275,10 +1460,7 @@ impl KemonoClient {
 
         let res = client
             .post(endpoint_url)
-            .header(
-                "Referer",
-                format!("https://{}/account/login", self.hostname),
-            )
+            .header("Referer", format!("{}/account/login", self.host_url()))
             .form(&form)
             .send()
             .await?
@@ -286,32 +1468,98 @@ impl KemonoClient {
         if res.url().as_str().contains("login") {
             return Err(KemonoError::from_stringable("Login failed"));
         }
+        if !self.has_session() {
+            return Err(KemonoError::from_stringable(
+                "Login failed, the site didn't send a session cookie",
+            ));
+        }
+        debug!(
+            "Logged in as {}",
+            self.username.as_deref().unwrap_or_default()
+        );
         Ok(())
     }
 }
 
-/// replace the extension in a filename with mkv
+/// Maps original file extensions to the ones they might have been converted to locally, eg `mp4=mkv,mov=mkv`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConvertedExtensions(Vec<(String, String)>);
+
+/// Video containers which are often remuxed to mkv
+pub static MKV_SOURCE_EXTENSIONS: [&str; 8] =
+    ["mp4", "m4v", "webm", "mov", "avi", "wmv", "flv", "ts"];
+
+impl Default for ConvertedExtensions {
+    fn default() -> Self {
+        Self(
+            MKV_SOURCE_EXTENSIONS
+                .iter()
+                .map(|ext| (ext.to_string(), "mkv".to_string()))
+                .collect(),
+        )
+    }
+}
+
+impl std::fmt::Display for ConvertedExtensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pairs: Vec<String> = self
+            .0
+            .iter()
+            .map(|(from, to)| format!("{}={}", from, to))
+            .collect();
+        write!(f, "{}", pairs.join(","))
+    }
+}
+
+impl FromStr for ConvertedExtensions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => Ok((
+                    from.trim().trim_start_matches('.').to_lowercase(),
+                    to.trim().trim_start_matches('.').to_string(),
+                )),
+                _ => Err(format!(
+                    "Invalid extension mapping '{}', expected from=to, eg mp4=mkv",
+                    pair
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self)
+    }
+}
+
+impl ConvertedExtensions {
+    /// The filename with its final extension converted, or None if there's no mapping for it
+    pub fn converted_filename(&self, filename: &str) -> Option<String> {
+        // only look at the last path component, so dots in directory names don't count
+        let name_start = filename.rfind('/').map(|i| i + 1).unwrap_or(0);
+        let (stem, ext) = filename[name_start..].rsplit_once('.')?;
+        if stem.is_empty() {
+            return None;
+        }
+        let (_, to) = self
+            .0
+            .iter()
+            .find(|(from, _)| from.eq_ignore_ascii_case(ext))?;
+        Some(format!("{}{}.{}", &filename[..name_start], stem, to))
+    }
+}
+
+/// replace the extension in a filename with mkv, if it's one of the [MKV_SOURCE_EXTENSIONS]
 ///
 /// ```
 /// use kemono::get_mkv_filename;
 /// assert_eq!(get_mkv_filename("test.mp4"), "test.mkv");
 ///  ```
 pub fn get_mkv_filename(filename: &str) -> String {
-    let parts = filename.split('.');
-    let mut new_filename = String::new();
-    let mut first = true;
-    for part in parts {
-        if !first {
-            new_filename.push('.');
-        }
-        if part == "mp4" || part == "m4v" {
-            new_filename.push_str("mkv");
-        } else {
-            new_filename.push_str(part);
-        }
-        first = false;
-    }
-    new_filename
+    ConvertedExtensions::default()
+        .converted_filename(filename)
+        .unwrap_or(filename.to_string())
 }
 
 #[cfg(test)]
@@ -328,6 +1576,1393 @@ mod tests {
         println!("number of results: {}", res.len());
     }
 
+    #[test]
+    fn test_deserialize_embed() {
+        let embed = |json: &str| -> Embed {
+            let mut post: Value =
+                serde_json::from_str::<Vec<Value>>(include_str!("../test_data.json"))
+                    .expect("Failed to deserialize data")
+                    .remove(0);
+            post["embed"] = serde_json::from_str(json).expect("Invalid test json");
+            serde_json::from_value::<Post>(post)
+                .expect("Failed to deserialize post")
+                .embed
+        };
+        assert!(embed("{}").is_empty());
+        assert!(embed("null").is_empty());
+        assert!(embed("[1, 2]").is_empty());
+        assert_eq!(
+            embed(r#"{"url": "https://example.com", "subject": "A link", "description": null}"#),
+            Embed {
+                url: Some("https://example.com".to_string()),
+                subject: Some("A link".to_string()),
+                description: None,
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&Embed::default()).expect("Failed to serialize"),
+            "{}"
+        );
+    }
+
+    #[test]
+    fn test_deserialize_edited_poll() {
+        let posts: Vec<Post> = serde_json::from_value(serde_json::json!([
+            {
+                "id": "1", "user": "2", "service": "patreon", "title": "A poll",
+                "content": "", "embed": {}, "shared_file": false,
+                "file": {"name": null, "path": null},
+                "added": "2023-01-02T00:00:00", "published": "2023-01-01T00:00:00",
+                "edited": "2023-01-03T12:34:56",
+                "poll": {"title": "Next?", "choices": [{"text": "A", "votes": 1}], "total_votes": 1},
+                "captions": null, "tags": null, "attachments": []
+            },
+            {
+                "id": "3", "user": "2", "service": "patreon", "title": "Older metadata",
+                "content": "", "embed": {}, "shared_file": false,
+                "file": {"name": null, "path": null},
+                "added": "2023-01-02T00:00:00", "published": "2023-01-01T00:00:00",
+                "edited": true, "poll": "unexpected",
+                "captions": null, "tags": null, "attachments": []
+            }
+        ]))
+        .expect("Failed to deserialize posts");
+        assert_eq!(posts[0].edited.as_deref(), Some("2023-01-03T12:34:56"));
+        assert_eq!(
+            posts[0].poll.as_ref().map(|poll| poll.total_votes),
+            Some(Some(1))
+        );
+        assert_eq!(posts[1].edited, None);
+        assert_eq!(posts[1].poll, None);
+    }
+
+    #[test]
+    fn test_deserialize_poll_and_captions() {
+        let post = |poll: &str, captions: &str| -> Post {
+            let mut post: Value =
+                serde_json::from_str::<Vec<Value>>(include_str!("../test_data.json"))
+                    .expect("Failed to deserialize data")
+                    .remove(0);
+            post["poll"] = serde_json::from_str(poll).expect("Invalid test json");
+            post["captions"] = serde_json::from_str(captions).expect("Invalid test json");
+            serde_json::from_value::<Post>(post).expect("Failed to deserialize post")
+        };
+        let plain = post("null", "null");
+        assert_eq!(plain.poll, None);
+        assert_eq!(plain.captions, None);
+        assert_eq!(post("false", "[]").poll, None);
+
+        let post = post(
+            r#"{"title": "Next?", "choices": [{"text": "A", "votes": 3}, {"text": "B"}], "allows_multiple": true}"#,
+            r#"{"en": "WEBVTT\n\n00:00.000 --> 00:01.000\nHi", "ja": ["", "こんにちは"]}"#,
+        );
+        let poll = post.poll.expect("Poll should deserialize");
+        assert_eq!(poll.title, "Next?");
+        assert!(poll.allows_multiple);
+        assert_eq!(
+            poll.choices,
+            vec![
+                PollChoice {
+                    text: "A".to_string(),
+                    votes: 3
+                },
+                PollChoice {
+                    text: "B".to_string(),
+                    votes: 0
+                }
+            ]
+        );
+        assert_eq!(
+            post.captions,
+            Some(vec![
+                "WEBVTT\n\n00:00.000 --> 00:01.000\nHi".to_string(),
+                "こんにちは".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_expand_path() {
+        let home = Some(PathBuf::from("/home/user"));
+        let cwd = Path::new("/work");
+        assert_eq!(
+            expand_path("~/kemono", home.clone(), cwd),
+            PathBuf::from("/home/user/kemono")
+        );
+        assert_eq!(
+            expand_path("~", home.clone(), cwd),
+            PathBuf::from("/home/user")
+        );
+        assert_eq!(
+            expand_path("./download", home.clone(), cwd),
+            PathBuf::from("/work/./download")
+        );
+        assert_eq!(
+            expand_path("/archive", home, cwd),
+            PathBuf::from("/archive")
+        );
+        assert_eq!(
+            expand_path("~/kemono", None, cwd),
+            PathBuf::from("/work/~/kemono")
+        );
+    }
+
+    #[test]
+    fn test_prepare_download_path() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let base = tmp.path().join("prepare");
+        let dir = base.join("download");
+        let file = base.join("file");
+        let created = prepare_download_path(&dir.display().to_string());
+        std::fs::write(&file, "hello").expect("Failed to write test file");
+        let not_dir = prepare_download_path(&file.display().to_string());
+
+        assert_eq!(created.expect("Failed to prepare path"), dir);
+        assert!(matches!(not_dir, Err(KemonoError::Configuration(_))));
+    }
+
+    #[test]
+    fn test_get_mkv_filename() {
+        assert_eq!(get_mkv_filename("test.MP4"), "test.mkv");
+        assert_eq!(get_mkv_filename("my.mp4.backup.txt"), "my.mp4.backup.txt");
+        assert_eq!(get_mkv_filename("my.video.m4v"), "my.video.mkv");
+        assert_eq!(get_mkv_filename("no_extension"), "no_extension");
+        assert_eq!(get_mkv_filename(".mp4"), ".mp4");
+        assert_eq!(get_mkv_filename("post.mp4/clip.jpg"), "post.mp4/clip.jpg");
+        for ext in MKV_SOURCE_EXTENSIONS {
+            assert_eq!(get_mkv_filename(&format!("clip.{}", ext)), "clip.mkv");
+        }
+        assert_eq!(get_mkv_filename("clip.mkv"), "clip.mkv");
+        assert_eq!(get_mkv_filename("file"), "file");
+        assert_eq!(get_mkv_filename("file.mp4"), "file.mkv");
+        assert_eq!(get_mkv_filename("archive.tar.gz"), "archive.tar.gz");
+        assert_eq!(get_mkv_filename(".hidden"), ".hidden");
+        assert_eq!(get_mkv_filename(".ts"), ".ts");
+    }
+
+    #[test]
+    fn test_converted_extensions() {
+        let converted =
+            ConvertedExtensions::from_str("mp4=mkv, .MOV=mkv,webm=mp4").expect("Failed to parse");
+        assert_eq!(
+            converted.converted_filename("a.mov"),
+            Some("a.mkv".to_string())
+        );
+        assert_eq!(
+            converted.converted_filename("a.WEBM"),
+            Some("a.mp4".to_string())
+        );
+        assert_eq!(converted.converted_filename("a.jpg"), None);
+        assert!(ConvertedExtensions::from_str("mp4").is_err());
+        assert!(ConvertedExtensions::from_str("mp4=").is_err());
+        assert_eq!(converted.to_string(), "mp4=mkv,mov=mkv,webm=mp4");
+        assert_eq!(
+            ConvertedExtensions::from_str(&ConvertedExtensions::default().to_string()),
+            Ok(ConvertedExtensions::default())
+        );
+    }
+
+    #[test]
+    fn test_attachment_display() {
+        let attachment = Attachment {
+            name: Some("test.jpg".to_string()),
+            path: Some("/data/test.jpg".to_string()),
+        };
+        assert!(attachment.is_complete());
+        assert_eq!(attachment.to_string(), "test.jpg at /data/test.jpg");
+
+        let attachment = Attachment {
+            name: None,
+            path: None,
+        };
+        assert!(!attachment.is_complete());
+        assert_eq!(attachment.to_string(), "<unnamed> at <no path>");
+    }
+
+    #[test]
+    fn test_attachment_extension() {
+        let attachment = |name: Option<&str>| Attachment {
+            name: name.map(|n| n.to_string()),
+            path: None,
+        };
+        assert_eq!(
+            attachment(Some("archive.tar.gz")).extension(),
+            Some("gz".to_string())
+        );
+        assert_eq!(
+            attachment(Some("Image.JPG")).extension(),
+            Some("jpg".to_string())
+        );
+        assert_eq!(attachment(Some("noext")).extension(), None);
+        assert_eq!(attachment(Some(".hidden")).extension(), None);
+        assert_eq!(attachment(Some("trailing.")).extension(), None);
+        assert_eq!(attachment(Some("")).extension(), None);
+        assert_eq!(attachment(None).extension(), None);
+    }
+
+    #[test]
+    fn test_thumbnail_url() {
+        let client = KemonoClient::new("example.com", None);
+        let attachment = |name: &str| Attachment {
+            name: Some(name.to_string()),
+            path: Some(format!("/aa/bb/abc{}", &name[name.len() - 4..])),
+        };
+        assert_eq!(
+            attachment("Image.JPG")
+                .thumbnail_url(&client)
+                .map(|url| url.to_string()),
+            Some("https://img.example.com/thumbnail/data/aa/bb/abc.JPG".to_string())
+        );
+        assert_eq!(attachment("video.mp4").thumbnail_url(&client), None);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut client = KemonoClient::new("example.com", None);
+        assert_eq!(client.timeout(30).unwrap(), Duration::from_secs(30));
+        client.timeout_seconds = Some(5);
+        assert_eq!(client.timeout(30).unwrap(), Duration::from_secs(5));
+        client.timeout_seconds = Some(0);
+        assert!(client.timeout(30).is_err());
+        assert!(client.new_async_session().is_err());
+    }
+
+    #[test]
+    fn test_profile_image_urls() {
+        let client = KemonoClient::new("example.com", None);
+        assert_eq!(
+            client.icon_url("patreon", "12345").unwrap().as_str(),
+            "https://example.com/icons/patreon/12345"
+        );
+        assert_eq!(
+            client.banner_url("patreon", "12345").unwrap().as_str(),
+            "https://example.com/banners/patreon/12345"
+        );
+    }
+
+    #[test]
+    fn test_post_identity() {
+        let posts = test_posts();
+        let mut edited = posts[0].clone();
+        edited.title = "Edited".to_string();
+        edited.embed.url = Some("https://example.com".to_string());
+        assert_eq!(posts[0], edited);
+        assert_ne!(posts[0], posts[1]);
+
+        let mut other_service = posts[0].clone();
+        other_service.service = "fanbox".to_string();
+        assert_ne!(posts[0], other_service);
+
+        let unique: HashSet<Post> = [posts[0].clone(), edited, other_service].into();
+        assert_eq!(unique.len(), 2);
+    }
+
+    #[test]
+    fn test_debug_redacts_credentials() {
+        let mut client = KemonoClient::new("example.com", None);
+        assert!(format!("{:?}", client).contains("password: None"));
+        client.username = Some("someone".to_string());
+        client.password = Some("hunter2".to_string());
+        let debug = format!("{:?}", client);
+        assert!(debug.contains("example.com"));
+        assert!(debug.contains("password: Some(\"***\")"));
+        assert!(!debug.contains("hunter2"));
+        assert!(!debug.contains("someone"));
+    }
+
+    #[test]
+    fn test_clone_shares_cookies() {
+        use reqwest::cookie::CookieStore;
+
+        let client = KemonoClient::new("example.com", None);
+        let cloned = client.clone();
+        let url = reqwest::Url::parse(&client.host_url()).expect("Failed to parse URL");
+        client.cookies.add_cookie_str("session=abc123", &url);
+        let cookies = cloned
+            .cookies
+            .cookies(&url)
+            .expect("Clone didn't see the cookie");
+        assert_eq!(cookies.to_str().unwrap(), "session=abc123");
+        assert!(Arc::ptr_eq(&client.cookies, &cloned.cookies));
+    }
+
+    #[test]
+    fn test_all_attachments() {
+        let mut post = test_posts().remove(0);
+        let expected = post.attachments.as_ref().map(|a| a.len()).unwrap_or(0) + 1;
+        assert_eq!(post.all_attachments().len(), expected);
+
+        // the main file showing up in the attachments shouldn't be counted twice
+        post.attachments
+            .get_or_insert_with(Default::default)
+            .insert(post.file.clone());
+        assert_eq!(post.all_attachments().len(), expected);
+        assert_eq!(post.all_attachments()[0], &post.file);
+
+        post.file = Attachment {
+            name: None,
+            path: None,
+        };
+        assert_eq!(post.all_attachments().len(), expected);
+    }
+
+    /// Points a client at a local mock server
+    #[tokio::test]
+    async fn test_mock_login() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/account/login"))
+            .respond_with(
+                ResponseTemplate::new(302)
+                    .insert_header("Location", "/artists")
+                    .insert_header("Set-Cookie", "session=abc123; Path=/"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/artists"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/account"))
+            .and(header("cookie", "session=abc123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": 1,
+                "username": "someone",
+                "created_at": "2023-01-01T00:00:00",
+                "role": "consumer"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/account"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/file.png"))
+            .and(header("cookie", "session=abc123"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("image"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut client = mock_client(&server);
+        assert!(!client.has_session());
+        assert!(matches!(
+            client.account().await,
+            Err(KemonoError::Unauthorized)
+        ));
+        client.username = Some("someone".to_string());
+        client.password = Some("hunter2".to_string());
+        client.login().await.expect("Failed to log in");
+        assert!(client.has_session());
+        let account = client.account().await.expect("Failed to get account");
+        assert_eq!(account.username, "someone");
+
+        // the blocking download session shares the login
+        let url = format!("{}/data/file.png", client.host_url());
+        let body = tokio::task::spawn_blocking(move || {
+            client.new_session().expect("Failed to make session");
+            client
+                .session
+                .as_ref()
+                .expect("No session")
+                .get(url)
+                .send()
+                .and_then(|res| res.error_for_status())
+                .and_then(|res| res.text())
+        })
+        .await
+        .expect("Download task panicked")
+        .expect("Download wasn't logged in");
+        assert_eq!(body, "image");
+    }
+
+    #[tokio::test]
+    async fn test_mock_session_token() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/account"))
+            .and(header("cookie", "session=abc123"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"username": "someone"})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/account"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+
+        let client = mock_client(&server);
+        client
+            .set_session_token(" abc123\n")
+            .expect("Failed to set session token");
+        assert!(client.has_session());
+        let account = client
+            .validate_session()
+            .await
+            .expect("Session should be valid");
+        assert_eq!(account.username, "someone");
+
+        let client = mock_client(&server);
+        client
+            .set_session_token("expired")
+            .expect("Failed to set session token");
+        assert!(matches!(
+            client.validate_session().await,
+            Err(KemonoError::SessionExpired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mock_login_without_session() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/account/login"))
+            .respond_with(ResponseTemplate::new(302).insert_header("Location", "/artists"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/artists"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let mut client = mock_client(&server);
+        client.username = Some("someone".to_string());
+        client.password = Some("hunter2".to_string());
+        assert!(client.login().await.is_err());
+        assert!(!client.has_session());
+    }
+
+    #[tokio::test]
+    async fn test_mock_new_with_jar() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/account/favorites"))
+            .and(header("cookie", "session=abc123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Vec::<Creator>::new()))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/account/favorites"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+
+        // a jar that's already logged in, without calling login()
+        let jar = Arc::new(Jar::default());
+        let url = reqwest::Url::parse(&format!("http://{}", server.address()))
+            .expect("Failed to parse URL");
+        jar.add_cookie_str("session=abc123", &url);
+        let mut client = KemonoClient::new_with_jar(&server.address().to_string(), jar.clone());
+        client.scheme = "http".to_string();
+        assert!(Arc::ptr_eq(&client.cookies, &jar));
+        assert!(client.favorites().await.is_ok());
+
+        let client = mock_client(&server);
+        assert!(matches!(
+            client.favorites().await,
+            Err(KemonoError::Unauthorized)
+        ));
+    }
+
+    fn mock_client(server: &wiremock::MockServer) -> KemonoClient {
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        client
+    }
+
+    fn test_posts() -> Vec<Post> {
+        serde_json::from_str(include_str!("../test_data.json")).expect("Failed to deserialize data")
+    }
+
+    #[tokio::test]
+    async fn test_mock_posts() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345"))
+            .and(query_param("o", "50"))
+            .and(query_param("q", "foo"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(include_str!("../test_data.json"), "application/json"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut client = mock_client(&server);
+        let res = client
+            .posts("patreon", "12345", Some("foo"), Some(50))
+            .await
+            .expect("Failed to query posts");
+        assert_eq!(res.len(), test_posts().len());
+    }
+
+    #[tokio::test]
+    async fn test_mock_posts_bad_post() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mut page: Vec<Value> =
+            serde_json::from_str(include_str!("../test_data.json")).expect("Invalid test data");
+        page[1]["title"] = Value::Null;
+        let bad_id = page[1]["id"].clone();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&page))
+            .mount(&server)
+            .await;
+
+        let (posts, bad_posts) = parse_posts(page.clone());
+        assert_eq!(posts.len(), page.len() - 1);
+        assert_eq!(bad_posts.len(), 1);
+        assert_eq!(
+            Some(Value::from(bad_posts[0].id.clone())),
+            Some(bad_id.clone())
+        );
+
+        let mut client = mock_client(&server);
+        let (posts, bad_posts) = client
+            .decode_posts(page.clone())
+            .expect("Bad post should be skipped");
+        assert_eq!(posts.len(), page.len() - 1);
+        assert_eq!(Some(Value::from(bad_posts[0].id.clone())), Some(bad_id));
+        client.cache_ttl = None;
+        let posts = client
+            .posts("patreon", "12345", None, None)
+            .await
+            .expect("Bad post should be skipped");
+        assert_eq!(posts.len(), page.len() - 1);
+        client.strict = true;
+        let err = client
+            .posts("patreon", "12345", None, None)
+            .await
+            .expect_err("Bad post should fail when strict");
+        assert!(matches!(
+            &err,
+            KemonoError::GetPostsError {
+                service,
+                creator,
+                offset: 0,
+                ..
+            } if service == "patreon" && creator == "12345"
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("Failed to get posts for patreon/12345 at offset 0: "));
+    }
+
+    #[tokio::test]
+    async fn test_mock_posts_rate_limited() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345"))
+            .respond_with(ResponseTemplate::new(429))
+            .mount(&server)
+            .await;
+
+        let mut client = mock_client(&server);
+        let res = client.posts("patreon", "12345", None, None).await;
+        assert!(matches!(res, Err(KemonoError::RateLimited)));
+    }
+
+    #[test]
+    fn test_validate_service() {
+        let coomer = KemonoClient::new_coomer(None);
+        assert_eq!(coomer.platform(), Some(Platform::Coomer));
+        assert!(coomer.validate_service("onlyfans").is_ok());
+        assert!(matches!(
+            coomer.validate_service("patreon"),
+            Err(KemonoError::Configuration(_))
+        ));
+        assert!(coomer.validate_service("newservice").is_ok());
+
+        let kemono = KemonoClient::for_platform(Platform::Kemono, None);
+        assert!(kemono.validate_service("fanbox").is_ok());
+        assert!(kemono.validate_service("fansly").is_err());
+
+        let mirror = KemonoClient::new("127.0.0.1:8080", None);
+        assert_eq!(mirror.platform(), None);
+        assert!(mirror.validate_service("onlyfans").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_mock_platform_urls() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        for (platform, service) in [
+            (Platform::Kemono, "patreon"),
+            (Platform::Coomer, "onlyfans"),
+        ] {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path(format!("/api/v1/{}/user/12345", service)))
+                .and(header("host", platform.hostname()))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_raw(include_str!("../test_data.json"), "application/json"),
+                )
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let mut client = KemonoClient::for_platform(platform, None)
+                .with_proxy(&format!("http://{}", server.address()))
+                .expect("Failed to set proxy");
+            client.scheme = "http".to_string();
+            let posts = client
+                .posts(service, "12345", None, None)
+                .await
+                .expect("Failed to get posts");
+            assert!(!posts.is_empty());
+
+            let host = Some(platform.hostname());
+            assert_eq!(
+                client
+                    .icon_url(service, "12345")
+                    .expect("Failed to make URL")
+                    .host_str(),
+                host
+            );
+            assert_eq!(
+                client
+                    .banner_url(service, "12345")
+                    .expect("Failed to make URL")
+                    .host_str(),
+                host
+            );
+            let attachment = &posts[0].file;
+            if attachment.path.is_some() {
+                assert_eq!(
+                    crate::download::attachment_url(&client, attachment)
+                        .expect("Failed to make URL")
+                        .host_str(),
+                    host
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_proxy() {
+        use wiremock::matchers::{header, header_exists, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/creators.txt"))
+            .and(header("host", "kemono.invalid"))
+            .and(header_exists("proxy-authorization"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Vec::<Creator>::new()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut client = KemonoClient::new("kemono.invalid", None)
+            .with_proxy(&format!("http://user:pass@{}", server.address()))
+            .expect("Failed to set proxy");
+        client.scheme = "http".to_string();
+        let res = client.creators().await.expect("Failed to query creators");
+        assert!(res.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mock_all_posts() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345"))
+            .and(query_param("o", "0"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(include_str!("../test_data.json"), "application/json"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345"))
+            .and(query_param("o", "50"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Vec::<Post>::new()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut client = mock_client(&server);
+        let res = client
+            .all_posts("patreon", "12345")
+            .await
+            .expect("Failed to query all posts");
+        assert_eq!(res.len(), test_posts().len());
+    }
+
+    #[tokio::test]
+    async fn test_mock_all_posts_pagination() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let template = test_posts().remove(0);
+        let make_page = |page: usize, count: usize| -> Vec<Post> {
+            (0..count)
+                .map(|i| Post {
+                    id: format!("{}-{}", page, i),
+                    ..template.clone()
+                })
+                .collect()
+        };
+        // the short last page repeats a couple of posts from the previous one
+        let mut last_page = make_page(2, 23);
+        last_page.extend(make_page(1, 2));
+
+        let server = MockServer::start().await;
+        for (offset, page) in [
+            ("0", make_page(0, 50)),
+            ("50", make_page(1, 50)),
+            ("100", last_page),
+            ("150", Vec::new()),
+        ] {
+            Mock::given(method("GET"))
+                .and(path("/api/v1/patreon/user/12345"))
+                .and(query_param("o", offset))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let mut client = mock_client(&server);
+        let res = client
+            .all_posts("patreon", "12345")
+            .await
+            .expect("Failed to query all posts");
+        assert_eq!(res.len(), 123);
+        let ids: HashSet<String> = res.iter().map(|post| post.id.clone()).collect();
+        assert_eq!(ids.len(), 123);
+    }
+
+    #[tokio::test]
+    async fn test_mock_all_posts_parallel() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let template = test_posts().remove(0);
+        let make_page = |page: usize, count: usize| -> Vec<Post> {
+            (0..count)
+                .map(|i| Post {
+                    id: format!("{}-{}", page, i),
+                    ..template.clone()
+                })
+                .collect()
+        };
+
+        let server = MockServer::start().await;
+        for (offset, page) in [
+            ("0", make_page(0, 50)),
+            ("50", make_page(1, 50)),
+            ("100", make_page(2, 23)),
+        ] {
+            Mock::given(method("GET"))
+                .and(path("/api/v1/patreon/user/12345"))
+                .and(query_param("o", offset))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page))
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345/profile"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({"post_count": 123})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/empty"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Vec::<Post>::new()))
+            .mount(&server)
+            .await;
+
+        let client = mock_client(&server);
+        assert_eq!(
+            client.get_page_count("patreon", "empty").await.ok(),
+            Some(0)
+        );
+        assert_eq!(
+            client.get_page_count("patreon", "12345").await.ok(),
+            Some(3)
+        );
+        let res = client
+            .all_posts_parallel("patreon", "12345")
+            .await
+            .expect("Failed to query all posts");
+        assert_eq!(res.len(), 123);
+        assert_eq!(res[0].id, "0-0");
+        assert_eq!(res[122].id, "2-22");
+
+        // a post count that's out of date doesn't lose the oldest posts
+        for (offset, page) in [
+            ("0", make_page(0, 50)),
+            ("50", make_page(1, 50)),
+            ("100", make_page(2, 23)),
+        ] {
+            Mock::given(method("GET"))
+                .and(path("/api/v1/patreon/user/stale"))
+                .and(query_param("o", offset))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page))
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/stale/profile"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({"post_count": 100})),
+            )
+            .mount(&server)
+            .await;
+        let res = client
+            .all_posts_parallel("patreon", "stale")
+            .await
+            .expect("Failed to query all posts");
+        assert_eq!(res.len(), 123);
+
+        // without a post count it's paged through one at a time
+        server.reset().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345/profile"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .mount(&server)
+            .await;
+        for (offset, page) in [("0", make_page(0, 20)), ("50", Vec::new())] {
+            Mock::given(method("GET"))
+                .and(path("/api/v1/patreon/user/12345"))
+                .and(query_param("o", offset))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page))
+                .mount(&server)
+                .await;
+        }
+        let mut client = mock_client(&server);
+        client.cache_ttl = None;
+        assert!(matches!(
+            client.get_page_count("patreon", "12345").await,
+            Err(KemonoError::Unknown)
+        ));
+        let res = client
+            .all_posts_parallel("patreon", "12345")
+            .await
+            .expect("Failed to query all posts");
+        assert_eq!(res.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_mock_posts_until() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let template = test_posts().remove(0);
+        let server = MockServer::start().await;
+        // post 49 was edited between pages so it's on both, and we stop before needing a third
+        for (offset, ids, expected) in [("0", 0..50, 1), ("50", 49..99, 1), ("100", 0..0, 0)] {
+            let page: Vec<Post> = ids
+                .map(|i| Post {
+                    id: i.to_string(),
+                    title: format!("{} on page {}", i, offset),
+                    ..template.clone()
+                })
+                .collect();
+            Mock::given(method("GET"))
+                .and(path("/api/v1/patreon/user/12345"))
+                .and(query_param("o", offset))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page))
+                .expect(expected)
+                .mount(&server)
+                .await;
+        }
+
+        let client = mock_client(&server);
+        let (posts, found) = client
+            .posts_until("patreon", "12345", |post| post.id == "60")
+            .await
+            .expect("Failed to page through posts");
+        assert!(found);
+        let ids: Vec<String> = posts.into_iter().map(|post| post.id).collect();
+        assert_eq!(ids, (0..60).map(|i| i.to_string()).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_mock_posts_stream() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let template = test_posts().remove(0);
+        let server = MockServer::start().await;
+        // the second page repeats the last post of the first, the third is never needed
+        for (offset, ids, expected) in [("0", 0..50, 1), ("50", 49..99, 1), ("100", 0..0, 0)] {
+            let page: Vec<Post> = ids
+                .map(|i| Post {
+                    id: i.to_string(),
+                    ..template.clone()
+                })
+                .collect();
+            Mock::given(method("GET"))
+                .and(path("/api/v1/patreon/user/12345"))
+                .and(query_param("o", offset))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page))
+                .expect(expected)
+                .mount(&server)
+                .await;
+        }
+
+        let client = mock_client(&server);
+        let posts: Vec<Post> = client
+            .posts_stream("patreon", "12345")
+            .take(60)
+            .try_collect()
+            .await
+            .expect("Failed to stream posts");
+        let ids: Vec<String> = posts.into_iter().map(|post| post.id).collect();
+        assert_eq!(ids, (0..60).map(|i| i.to_string()).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_mock_posts_window() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let template = test_posts().remove(0);
+        let server = MockServer::start().await;
+        // posts 60-109 only need the second and third pages
+        for (offset, expected) in [(0, 0), (50, 1), (100, 1), (150, 0)] {
+            let page: Vec<Post> = (offset..offset + 50)
+                .map(|i| Post {
+                    id: i.to_string(),
+                    ..template.clone()
+                })
+                .collect();
+            Mock::given(method("GET"))
+                .and(path("/api/v1/patreon/user/12345"))
+                .and(query_param("o", offset.to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page))
+                .expect(expected)
+                .mount(&server)
+                .await;
+        }
+
+        let client = mock_client(&server);
+        let posts: Vec<Post> = client
+            .posts_window("patreon", "12345", 60, Some(50))
+            .try_collect()
+            .await
+            .expect("Failed to stream posts");
+        let ids: Vec<String> = posts.into_iter().map(|post| post.id).collect();
+        assert_eq!(ids, (60..110).map(|i| i.to_string()).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_mock_all_posts_with_limit() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let template = test_posts().remove(0);
+        let server = MockServer::start().await;
+        // five posts fit on the first page, so the second's never fetched
+        for (offset, expected) in [(0, 1), (50, 0)] {
+            let page: Vec<Post> = (offset..offset + 50)
+                .map(|i| Post {
+                    id: i.to_string(),
+                    ..template.clone()
+                })
+                .collect();
+            Mock::given(method("GET"))
+                .and(path("/api/v1/patreon/user/12345"))
+                .and(query_param("o", offset.to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page))
+                .expect(expected)
+                .mount(&server)
+                .await;
+        }
+
+        let mut client = mock_client(&server);
+        let posts = client
+            .all_posts_with_limit("patreon", "12345", 5)
+            .await
+            .expect("Failed to get posts");
+        let ids: Vec<String> = posts.into_iter().map(|post| post.id).collect();
+        assert_eq!(ids, ["0", "1", "2", "3", "4"]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_posts_cache() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345"))
+            .and(query_param("o", "0"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(include_str!("../test_data.json"), "application/json"),
+            )
+            .expect(5)
+            .mount(&server)
+            .await;
+
+        let mut client = mock_client(&server);
+        let mut requests = Vec::new();
+        for step in ["cached", "cleared", "disabled"] {
+            match step {
+                "cleared" => client.clear_cache(),
+                "disabled" => client.cache_ttl = None,
+                _ => {}
+            }
+            for _ in 0..2 {
+                client
+                    .posts("patreon", "12345", None, Some(0))
+                    .await
+                    .expect("Failed to get posts");
+            }
+            requests.push(server.received_requests().await.unwrap_or_default().len());
+        }
+        // expired pages are fetched again
+        let mut clone = client.clone();
+        clone.cache_ttl = Some(Duration::ZERO);
+        clone
+            .posts("patreon", "12345", None, Some(0))
+            .await
+            .expect("Failed to get posts");
+        assert_eq!(requests, vec![1, 2, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_posts_since() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345"))
+            .and(query_param("o", "0"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(include_str!("../test_data.json"), "application/json"),
+            )
+            // the second query gets the page from the cache
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let posts = test_posts();
+        let mut client = mock_client(&server);
+        let res = client
+            .posts_since("patreon", "12345", &posts[10].published)
+            .await
+            .expect("Failed to query posts");
+        assert_eq!(res.len(), 10);
+
+        let res = client
+            .posts_after_id("patreon", "12345", &posts[10].id)
+            .await
+            .expect("Failed to query posts");
+        assert_eq!(res.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_mock_recent_posts() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/posts"))
+            .and(query_param("q", "hello world"))
+            .and(query_param("o", "100"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(include_str!("../test_data.json"), "application/json"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = mock_client(&server);
+        let res = client
+            .recent_posts(Some("hello world"), Some(100))
+            .await
+            .expect("Failed to query recent posts");
+        assert_eq!(res.len(), test_posts().len());
+    }
+
+    #[tokio::test]
+    async fn test_mock_creators() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/creators.txt"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {
+                    "favorited": 10,
+                    "id": "12345",
+                    "indexed": 1672531200,
+                    "name": "example",
+                    "service": "patreon",
+                    "updated": 1672617600
+                }
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = mock_client(&server);
+        let res = client.creators().await.expect("Failed to query creators");
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].name, "example");
+        assert_eq!(res[0].service, "patreon");
+        assert_eq!(res[0].indexed_at().unix_timestamp(), 1672531200);
+        assert!(res[0].updated_at() > res[0].indexed_at());
+        // written out readably, and read back the same
+        assert_eq!(res[0].favorite_count, 10);
+        let json = serde_json::to_value(&res[0]).expect("Failed to serialize creator");
+        assert_eq!(json["updated"], "2023-01-02T00:00:00Z");
+        assert_eq!(json["favorite_count"], 10);
+        let creator: Creator = serde_json::from_value(json).expect("Failed to deserialize creator");
+        assert_eq!(creator.updated, res[0].updated);
+    }
+
+    #[tokio::test]
+    async fn test_mock_dms() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345/dms"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {
+                    "hash": "abc",
+                    "user": "12345",
+                    "service": "patreon",
+                    "content": "hello",
+                    "embed": {},
+                    "file": {},
+                    "added": "2023-01-01T00:00:00",
+                    "published": null
+                }
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = mock_client(&server);
+        let res = client
+            .dms("patreon", "12345")
+            .await
+            .expect("Failed to get DMs");
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].hash, "abc");
+        assert_eq!(res[0].content.as_deref(), Some("hello"));
+        assert_eq!(res[0].published, None);
+
+        // services without DMs 404
+        let res = client
+            .dms("fanbox", "12345")
+            .await
+            .expect("Failed to get DMs");
+        assert!(res.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mock_favorites() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/account/favorites"))
+            .and(query_param("type", "artist"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {
+                    "faved_seq": 1,
+                    "id": "12345",
+                    "indexed": "2023-01-01T00:00:00.123456",
+                    "name": "example",
+                    "service": "patreon",
+                    "updated": "2023-01-02T00:00:00"
+                }
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = mock_client(&server);
+        let res = client.favorites().await.expect("Failed to get favorites");
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].id, "12345");
+        assert_eq!(res[0].indexed.unix_timestamp(), 1672531200);
+        assert_eq!(res[0].updated_at().unix_timestamp(), 1672617600);
+
+        let unauthorized = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/account/favorites"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&unauthorized)
+            .await;
+        assert!(matches!(
+            mock_client(&unauthorized).favorites().await,
+            Err(KemonoError::Unauthorized)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mock_discord_server_posts() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/discord/server/333"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"id": "444", "name": "art"}
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let message = |id: &str| {
+            serde_json::json!({
+                "id": id,
+                "author": {"id": "222", "username": "someone"},
+                "server": "333",
+                "channel": "444",
+                "content": "hello",
+                "published": "2023-01-01T00:00:00",
+                "edited": null,
+                "attachments": []
+            })
+        };
+        for (offset, body) in [
+            ("0", serde_json::json!([message("1"), message("2")])),
+            ("2", serde_json::json!([])),
+        ] {
+            Mock::given(method("GET"))
+                .and(path("/api/v1/discord/channel/444"))
+                .and(query_param("o", offset))
+                .respond_with(ResponseTemplate::new(200).set_body_json(body))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let client = mock_client(&server);
+        let posts = client
+            .discord_server_posts("333")
+            .await
+            .expect("Failed to get discord posts");
+        assert_eq!(posts.len(), 2);
+        assert!(posts.iter().all(|post| post.title == "art"));
+    }
+
+    #[tokio::test]
+    async fn test_mock_favorite_post() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        for verb in ["POST", "DELETE"] {
+            Mock::given(method(verb))
+                .and(path("/api/v1/favorites/post/patreon/12345/678"))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let client = mock_client(&server);
+        client
+            .favorite_post("patreon", "12345", "678")
+            .await
+            .expect("Failed to favorite post");
+        client
+            .unfavorite_post("patreon", "12345", "678")
+            .await
+            .expect("Failed to unfavorite post");
+
+        let unauthorized = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&unauthorized)
+            .await;
+        assert!(matches!(
+            mock_client(&unauthorized)
+                .favorite_post("patreon", "12345", "678")
+                .await,
+            Err(KemonoError::Unauthorized)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mock_search_creators() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let creator = |id: &str, name: &str, favorited: u64| {
+            serde_json::json!({
+                "favorited": favorited,
+                "id": id,
+                "indexed": 1672531200,
+                "name": name,
+                "service": "patreon",
+                "updated": 1672617600
+            })
+        };
+        Mock::given(method("GET"))
+            .and(path("/api/v1/creators.txt"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                creator("1", "Some Artist", 5),
+                creator("2", "someone else", 1),
+                creator("3", "ARTIST two", 50),
+            ])))
+            .mount(&server)
+            .await;
+
+        let client = mock_client(&server);
+        let res = client
+            .search_creators("artist")
+            .await
+            .expect("Failed to search creators");
+        let ids: Vec<&str> = res.iter().map(|creator| creator.id.as_str()).collect();
+        assert_eq!(ids, vec!["3", "1"]);
+    }
+
     #[cfg(feature = "test_live")]
     #[tokio::test]
     async fn test_live_creators() {
@@ -359,6 +2994,7 @@ mod tests {
         println!("res: {:?}", res);
     }
 
+    #[cfg(feature = "test_live")]
     #[tokio::test]
     async fn test_live_login() {
         let host = std::env::var("KEMONO_HOSTNAME").expect("Failed to get KEMONO_HOSTNAME env var");
@@ -0,0 +1,134 @@
+//! A lock file in the download root, so two runs don't write to the same archive at once.
+
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use crate::errors::KemonoError;
+
+pub static LOCK_FILENAME: &str = ".kemono.lock";
+
+/// Holds the lock until it's dropped
+#[derive(Debug)]
+pub struct DownloadLock {
+    path: PathBuf,
+}
+
+impl DownloadLock {
+    /// Takes the lock on `dir`, failing if another process has it.
+    ///
+    /// A lock left behind by a process which isn't running any more is taken over, where we can
+    /// tell (Linux only).
+    pub fn acquire(dir: &Path) -> Result<Self, KemonoError> {
+        let path = dir.join(LOCK_FILENAME);
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(std::process::id().to_string().as_bytes())
+                        .map_err(|err| {
+                            KemonoError::from(format!(
+                                "Failed to write lock file {}: {:?}",
+                                path.display(),
+                                err
+                            ))
+                        })?;
+                    debug!("Locked {}", dir.display());
+                    return Ok(Self { path });
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    let pid = std::fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|pid| pid.trim().parse::<u32>().ok());
+                    match pid {
+                        Some(pid) if !process_running(pid) => {
+                            warn!(
+                                "Removing stale lock {} left by process {}",
+                                path.display(),
+                                pid
+                            );
+                            std::fs::remove_file(&path).map_err(|err| {
+                                KemonoError::from(format!(
+                                    "Failed to remove stale lock {}: {:?}",
+                                    path.display(),
+                                    err
+                                ))
+                            })?;
+                        }
+                        pid => {
+                            return Err(KemonoError::Configuration(format!(
+                                "{} is in use by process {}, remove {} if that's not running",
+                                dir.display(),
+                                pid.map(|pid| pid.to_string())
+                                    .unwrap_or("unknown".to_string()),
+                                path.display()
+                            )))
+                        }
+                    }
+                }
+                Err(err) => {
+                    return Err(KemonoError::from(format!(
+                        "Failed to create lock file {}: {:?}",
+                        path.display(),
+                        err
+                    )))
+                }
+            }
+        }
+        Err(KemonoError::from(format!(
+            "Failed to lock {}",
+            dir.display()
+        )))
+    }
+}
+
+impl Drop for DownloadLock {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove lock {}: {:?}", self.path.display(), err);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Without a way to check, assume the lock's still held
+#[cfg(not(target_os = "linux"))]
+fn process_running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_download_lock() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = tmp.path().join("lock");
+        std::fs::create_dir_all(&dir).expect("Failed to create test dir");
+
+        let lock = DownloadLock::acquire(&dir).expect("Failed to lock");
+        assert!(dir.join(LOCK_FILENAME).exists());
+        let err = DownloadLock::acquire(&dir).expect_err("Lock should be held");
+        assert!(matches!(err, KemonoError::Configuration(_)));
+        assert!(err
+            .to_string()
+            .contains(&format!("process {}", std::process::id())));
+        drop(lock);
+        assert!(!dir.join(LOCK_FILENAME).exists());
+
+        // nothing's running with a PID this big
+        #[cfg(target_os = "linux")]
+        {
+            std::fs::write(dir.join(LOCK_FILENAME), u32::MAX.to_string())
+                .expect("Failed to write stale lock");
+            let lock = DownloadLock::acquire(&dir).expect("Stale lock should be taken over");
+            drop(lock);
+        }
+    }
+}
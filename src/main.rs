@@ -1,18 +1,61 @@
-use log::{debug, error, info};
+use log::{debug, error, info, log_enabled, warn, Level, LevelFilter};
 use structured_logger::{async_json::new_writer, Builder};
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use clap::{Parser, Subcommand};
+use clap::builder::PossibleValuesParser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use futures::{future, stream, StreamExt, TryStreamExt};
+use kemono::archive::{export_archive, ArchiveFilter, ArchiveFormat};
+use kemono::collection::PostStats;
+use kemono::config::{read_targets, KemonoConfig, CONFIG_FILENAME};
+use kemono::content::ContentFormat;
+use kemono::dedup::dedup_archive;
+use kemono::download::{
+    clear_stop, download, remove_partial_files, request_stop, select_posts, stop_requested,
+    DownloadOptions,
+};
 use kemono::errors::KemonoError;
-use kemono::{get_mkv_filename, Attachment, KemonoClient, Post, DEFAULT_DOWNLOAD_PATH};
-use rayon::{prelude::*, ThreadPoolBuilder};
+use kemono::filter::{FilenameFilter, TagFilter};
+use kemono::hooks::DownloadHook;
+use kemono::layout::{apply_move, migration_moves, Layout};
+use kemono::lock::DownloadLock;
+use kemono::missing::{find_missing, MissingFile};
+use kemono::mtime::MtimeSource;
+use kemono::notify::notify;
+use kemono::output::{print_action, select_fields, to_csv, OutputFormat, POST_FIELDS};
+use kemono::platform::Platform;
+use kemono::prune::{find_orphans, remove_orphan, Orphans, PruneMode};
+use kemono::schedule::Interval;
+use kemono::stats::{remote_sizes, stored_posts, LocalStats, DEFAULT_TOP_FILES};
+use kemono::summary::UpdateSummary;
+use kemono::util::FilenameTemplate;
+use kemono::{
+    prepare_download_path, ConvertedExtensions, KemonoClient, Post, DEFAULT_DOWNLOAD_PATH,
+    DEFAULT_PARALLEL_CREATORS, DEFAULT_THREADS, MAX_THREADS,
+};
 
-use reqwest::Url;
+use regex::Regex;
 use serde_json::json;
 
+/// Held while downloading, so it can be let go of before exiting
+static DOWNLOAD_LOCK: Mutex<Option<DownloadLock>> = Mutex::new(None);
+
+/// Exits with `code`, removing the download lock first as exiting skips destructors
+fn exit_releasing_lock(code: i32) -> ! {
+    if let Ok(mut lock) = DOWNLOAD_LOCK.lock() {
+        lock.take();
+    }
+    std::process::exit(code);
+}
+
 #[derive(Parser)]
 struct CreatorAndService {
     #[arg(env = "KEMONO_CREATOR")]
@@ -20,12 +63,50 @@ struct CreatorAndService {
     #[arg(env = "KEMONO_SERVICE")]
     service: String,
 }
+
+#[derive(Clone, Parser)]
+struct PostWindow {
+    /// Skip this many of the newest posts, any number works, not only whole pages
+    #[arg(long, env = "KEMONO_OFFSET", default_value_t = 0)]
+    offset: usize,
+    /// Stop fetching once this many posts have been found, eg `--limit 10` for the ten newest
+    #[arg(long, visible_alias = "limit", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_posts: Option<usize>,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Write a config file with every option commented out
+    Init {
+        /// Where to write it
+        #[arg(default_value = CONFIG_FILENAME)]
+        path: PathBuf,
+        /// Overwrite the file if it exists
+        #[arg(long)]
+        force: bool,
+    },
+}
+
 #[derive(Subcommand)]
 enum Commands {
-    /// Dumps a list of posts in JSON format
+    /// Dumps a list of posts in JSON, TOML or CSV format
     Query {
         #[clap(flatten)]
         creatorandservice: CreatorAndService,
+        /// Output format: jsonl (streamed as each page arrives), json, toml or csv
+        #[arg(long, default_value = "jsonl")]
+        format: OutputFormat,
+        /// Same as --format jsonl
+        #[arg(long, conflicts_with = "format")]
+        ndjson: bool,
+        /// Only output these fields, comma separated
+        #[arg(long, value_delimiter = ',', value_parser = PossibleValuesParser::new(POST_FIELDS))]
+        fields: Vec<String>,
+        /// Pretty-print the JSON array
+        #[arg(long)]
+        pretty: bool,
+        #[clap(flatten)]
+        window: PostWindow,
 
         #[clap(flatten)]
         copt: SharedCliOpts,
@@ -34,26 +115,200 @@ enum Commands {
     Download {
         #[clap(flatten)]
         creatorandservice: CreatorAndService,
+        /// Only retry the downloads which failed last time
+        #[arg(long)]
+        retry_failed: bool,
+        /// Also write each post as Markdown to metadata/{id}.md
+        #[arg(long)]
+        export_markdown: bool,
+        /// Write a Kodi/Jellyfin .nfo file next to each video
+        #[arg(long)]
+        nfo: bool,
+        /// Download files again even if they're already there, replacing them
+        #[arg(long)]
+        force: bool,
+        /// Only download this post
+        #[arg(long)]
+        post_id: Option<String>,
+        /// Only download posts newer than this post ID
+        #[arg(long, env = "KEMONO_SINCE_POST_ID")]
+        since_post_id: Option<String>,
+        /// Also save the creator's DMs to metadata/dms.json, if the service has them
+        #[arg(long)]
+        include_dms: bool,
+        #[clap(flatten)]
+        window: PostWindow,
         #[clap(flatten)]
         copt: SharedCliOpts,
     },
+    /// Counts a creator's files, or with no creator everything that's been downloaded
     Stats {
-        #[arg(env = "KEMONO_SERVICE")]
-        service: String,
+        #[arg(env = "KEMONO_SERVICE", requires = "creator")]
+        service: Option<String>,
         #[arg(env = "KEMONO_CREATOR")]
-        creator: String,
+        creator: Option<String>,
+        /// Output format: json, toml or csv
+        #[arg(long, default_value = "json")]
+        format: OutputFormat,
+        /// Also report disk usage of the files already downloaded, implied without a creator
+        #[arg(long)]
+        local: bool,
+        /// How many of the largest downloaded files to list
+        #[arg(long, default_value_t = DEFAULT_TOP_FILES)]
+        top: usize,
+        /// Also total the size of every file by asking the server with a HEAD request, which is slow for big creators
+        #[arg(long)]
+        remote_sizes: bool,
+        #[clap(flatten)]
+        window: PostWindow,
+        #[clap(flatten)]
+        copt: SharedCliOpts,
+    },
+    /// Keep checking a creator/service for new posts and download them, until Ctrl+C
+    Watch {
+        #[clap(flatten)]
+        creatorandservice: CreatorAndService,
+        /// How long to wait between checks, eg 90s, 15m or 1h, plain numbers are seconds
+        #[arg(long, env = "KEMONO_WATCH_INTERVAL", default_value = "15m")]
+        interval: Interval,
+        #[clap(flatten)]
+        copt: SharedCliOpts,
+    },
+    /// Hash everything in the download dir and convert duplicate files into links to a shared object store
+    Dedup {
+        #[clap(flatten)]
+        copt: SharedCliOpts,
+    },
+    /// Favorites a post for the logged-in user, or unfavorites it with --remove
+    Favorite {
+        #[clap(flatten)]
+        creatorandservice: CreatorAndService,
+        /// The post to favorite
+        #[arg(long)]
+        post_id: String,
+        /// Remove the post from the favorites instead
+        #[arg(long)]
+        remove: bool,
+        #[clap(flatten)]
+        copt: SharedCliOpts,
+    },
+    /// Lists the logged-in user's favorite creators, as a JSON array
+    Favorites {
+        #[clap(flatten)]
+        copt: SharedCliOpts,
+    },
+    /// Shows the logged-in account, to check the username and password work
+    #[command(visible_alias = "whoami")]
+    Account {
+        #[clap(flatten)]
+        copt: SharedCliOpts,
+    },
+    /// Lists the creators on the site, as a JSON array
+    ListCreators {
+        /// Only list creators whose name contains this, ignoring case, most favorited first
+        #[arg(long)]
+        search: Option<String>,
+        #[clap(flatten)]
+        copt: SharedCliOpts,
+    },
+    /// Package a creator's downloads and metadata into a zip or tar.zst file
+    Export {
+        #[clap(flatten)]
+        creatorandservice: CreatorAndService,
+        /// Archive format: zip or tar.zst
+        #[arg(long, default_value = "zip")]
+        format: ArchiveFormat,
+        /// Where to write the archive
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Only include files with these extensions, comma-separated
+        #[arg(long, value_delimiter = ',')]
+        extensions: Vec<String>,
+        /// Only include posts published on or after this date, eg 2023-01-01
+        #[arg(long)]
+        since: Option<String>,
+        /// Only include posts published on or before this date
+        #[arg(long)]
+        until: Option<String>,
+        /// Overwrite the output file if it exists
+        #[arg(long)]
+        force: bool,
+        #[clap(flatten)]
+        copt: SharedCliOpts,
+    },
+    /// Report local files which aren't in any current post, and optionally remove them
+    Prune {
+        #[clap(flatten)]
+        creatorandservice: CreatorAndService,
+        /// Move the orphaned files into .trash/ in the creator/service dir
+        #[arg(long)]
+        delete: bool,
+        /// With --delete, remove the files instead of moving them to .trash/
+        #[arg(long, requires = "delete")]
+        hard_delete: bool,
+        #[clap(flatten)]
+        copt: SharedCliOpts,
+    },
+    /// List the files a download would fetch without downloading anything, exits 1 if there are any
+    Missing {
+        #[clap(flatten)]
+        creatorandservice: CreatorAndService,
+        /// Only check posts newer than this post ID
+        #[arg(long, env = "KEMONO_SINCE_POST_ID")]
+        since_post_id: Option<String>,
+        #[clap(flatten)]
+        window: PostWindow,
+        #[clap(flatten)]
+        copt: SharedCliOpts,
+    },
+    /// Move an existing download dir from one layout to another
+    MigrateLayout {
+        /// The layout the download dir is in now
+        #[arg(long, default_value = "creator-service")]
+        from: Layout,
+        /// The layout to move to
+        #[arg(long)]
+        to: Layout,
+        /// Print the moves without doing them
+        #[arg(long)]
+        dry_run: bool,
         #[clap(flatten)]
         copt: SharedCliOpts,
     },
     /// Iterate through creator/service dirs and download all the filew we don't have.
     Update {
+        /// Only update these creators, comma-separated
         #[arg(env = "KEMONO_CREATOR", short, long)]
         creator: Option<String>,
+        /// Only update these services, comma-separated
         #[arg(env = "KEMONO_SERVICE", short, long)]
         service: Option<String>,
+        /// Ignore the sync state and re-crawl every post
+        #[arg(long)]
+        full: bool,
+        /// Also download the logged-in user's favorite creators, even if they're not in the download path yet
+        #[arg(long)]
+        download_favorites: bool,
+        /// Also update the creators listed in this file, one `service creator` per line, even if they're not in the download path yet
+        #[arg(long, env = "KEMONO_TARGETS")]
+        targets: Option<PathBuf>,
+        /// How many creators to update at once, --threads is split between them
+        #[arg(long, env = "KEMONO_PARALLEL_CREATORS", default_value_t = DEFAULT_PARALLEL_CREATORS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        parallel_creators: usize,
+        /// Keep running, updating again every --interval until Ctrl+C or SIGTERM
+        #[arg(long)]
+        watch: bool,
+        /// How long to wait between updates with --watch, eg 30m, 6h or 1d, plus up to a tenth more
+        #[arg(long, env = "KEMONO_UPDATE_INTERVAL", default_value_t = Interval::default(), requires = "watch")]
+        interval: Interval,
         #[clap(flatten)]
         copt: SharedCliOpts,
     },
+    /// Manage the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
 }
 
 #[derive(Clone, Parser)]
@@ -63,28 +318,200 @@ struct SharedCliOpts {}
 #[command(author, version, about, long_about = None)]
 struct CliOpts {
     #[arg(short = 'H', long, env = "KEMONO_HOSTNAME")]
-    hostname: String,
-    #[arg(env = "KEMONO_THREADS", short, long, default_value = "2")]
-    threads: usize,
+    hostname: Option<String>,
+    #[arg(long, visible_alias = "site", env = "KEMONO_PLATFORM")]
+    /// Which site to use if --hostname isn't set: kemono or coomer
+    platform: Option<Platform>,
+    #[arg(env = "KEMONO_THREADS", short, long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    /// Number of concurrent downloads, more than 8 usually gets rate limited [default: 2]
+    threads: Option<usize>,
+    #[arg(long, env = "KEMONO_PER_CREATOR_THREADS", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    /// Limit how many concurrent downloads a single creator gets
+    per_creator_threads: Option<usize>,
+
+    #[arg(long = "data-host", env = "KEMONO_DATA_HOSTS", value_delimiter = ',')]
+    /// Host to download attachments from, repeat to fall back to others, defaults to the hostname
+    data_hosts: Vec<String>,
+
+    #[arg(long, env = "KEMONO_CONFIG")]
+    /// Load settings from a TOML config file, command line options take precedence.
+    /// Defaults to kemono.toml, then ~/.config/kemono/config.toml
+    config: Option<PathBuf>,
+
+    #[arg(skip)]
+    /// Service/creator pairs from the config file for update to fetch
+    creators: Vec<(String, String)>,
 
     #[arg(env = "KEMONO_USERNAME")]
     username: Option<String>,
     #[arg(env = "KEMONO_PASSWORD")]
     password: Option<String>,
+    #[arg(long, env = "KEMONO_SESSION", hide_env_values = true)]
+    /// Use this session cookie from a logged-in browser instead of logging in with a username and password
+    session_token: Option<String>,
     #[arg(env = "KEMONO_DEBUG", short, long)]
     debug: bool,
 
+    #[arg(long, env = "KEMONO_QUIET", short, conflicts_with_all = ["debug", "log_level"])]
+    /// Only log errors, action lines and the final summary are still printed
+    quiet: bool,
+
+    #[arg(long, env = "KEMONO_LOG_LEVEL")]
+    /// One of error, warn, info, debug or trace, overrides --debug
+    log_level: Option<LevelFilter>,
+
     #[arg(env = "KEMONO_MKVS", short, long)]
-    /// If the "original" file is an mp4 or m4v then we might have a mkv file and that's OK
+    /// If the "original" file is a video (mp4, webm, mov and so on) then we might have a mkv file and that's OK
     mkvs: bool,
 
+    #[arg(long, env = "KEMONO_CONVERTED_EXT", default_value_t = ConvertedExtensions::default())]
+    /// With --mkvs, the extensions files might have been converted to, eg mp4=mkv,mov=mkv,webm=mkv
+    converted_ext: ConvertedExtensions,
+
     #[arg(short, long)]
-    filename: Option<String>,
+    /// Only download files whose name contains this, repeat to allow others
+    filename: Vec<String>,
+
+    #[arg(long)]
+    /// Only download files whose name matches this regex, repeat to allow others
+    filename_regex: Vec<Regex>,
+
+    #[arg(long)]
+    /// Don't download files whose name matches this regex, repeat to exclude others
+    exclude_filename_regex: Vec<Regex>,
+
+    #[arg(long)]
+    /// Only download or query posts with this tag (ignoring case), repeat to allow others.
+    /// The API can't filter by tag, so every post is still fetched
+    tag: Vec<String>,
+
+    #[arg(long)]
+    /// Skip posts with this tag (ignoring case), repeat to skip others
+    exclude_tag: Vec<String>,
+
+    #[arg(long, env = "KEMONO_CONTENT_FORMAT", default_value = "none")]
+    /// Save each post's content as a file: html, markdown or none
+    content_format: ContentFormat,
+
+    #[arg(long, env = "KEMONO_MTIME", default_value = "published")]
+    /// Set downloaded files' modified time to the post's date: published, added or now
+    mtime: MtimeSource,
+
+    #[arg(long, env = "KEMONO_INLINE_MEDIA")]
+    /// Also download images and files linked from the post content
+    inline_media: bool,
+
+    #[arg(long, env = "KEMONO_REWRITE_MEDIA", requires = "inline_media")]
+    /// Point saved content files at the downloaded inline media, for offline viewing
+    rewrite_media: bool,
+
+    #[arg(long, env = "KEMONO_IGNORE_EDITS")]
+    /// Don't refresh metadata for posts which have been edited upstream
+    ignore_edits: bool,
+
+    #[arg(long, env = "KEMONO_MANIFEST")]
+    /// Append newly downloaded files to SHA256SUMS in the creator/service directory
+    manifest: bool,
+
+    #[arg(long, env = "KEMONO_DEDUP")]
+    /// Store each file once under .objects in the download dir and hardlink it into place
+    dedup: bool,
 
     #[command(subcommand)]
     command: Commands,
 
+    #[arg(long, env = "KEMONO_TIMEOUT", value_parser = clap::value_parser!(u64).range(1..))]
+    /// Request timeout in seconds, defaults to 30 for API calls and 900 for downloads
+    timeout: Option<u64>,
+
+    #[arg(long, env = "KEMONO_CACHE_TTL", default_value_t = 300)]
+    /// Seconds to reuse pages of posts for within a run
+    cache_ttl: u64,
+
+    #[arg(long, env = "KEMONO_NO_CACHE")]
+    /// Always ask the server for pages of posts, even if they were just fetched
+    no_cache: bool,
+
+    #[arg(long, env = "KEMONO_STRICT")]
+    /// Fail if any post doesn't parse, instead of skipping it with a warning
+    strict: bool,
+
+    #[arg(long, env = "KEMONO_PROXY")]
+    /// Proxy to send requests through, eg http://proxy:3128 or socks5://proxy:1080
+    proxy: Option<String>,
+
+    #[arg(long, env = "KEMONO_LAYOUT", default_value = "creator-service")]
+    /// Directory layout: creator-service, service-creator or service-creator-name
+    layout: Layout,
+
+    #[arg(long, env = "KEMONO_POST_DIRS")]
+    /// Put each post's files in a {published}-{id}-{title} directory
+    post_dirs: bool,
+
+    #[arg(long, env = "KEMONO_FILENAME_TEMPLATE", default_value_t = FilenameTemplate::default())]
+    /// How files are named, using {published}, {id}, {title}, {name}, {service} and {user}, {name} is required
+    filename_template: FilenameTemplate,
+
+    #[arg(long, env = "KEMONO_EXEC_AFTER")]
+    /// Run this shell command for each downloaded file, with KEMONO_FILE, KEMONO_POST_ID, KEMONO_CREATOR, KEMONO_SERVICE and KEMONO_PUBLISHED set
+    exec_after: Option<String>,
+
+    #[arg(long, env = "KEMONO_COOKIES_FILE")]
+    /// Keep the login session in this file between runs, so it's only logged in again when it expires
+    cookie_file: Option<String>,
+
+    #[arg(long, env = "KEMONO_NOTIFY_WEBHOOK", value_parser = parse_webhook)]
+    /// POST a JSON summary to this URL when new files are downloaded, per creator for update
+    notify_webhook: Option<String>,
+
+    #[arg(long, env = "KEMONO_EXEC_AFTER_JOBS", default_value = "1", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    /// How many --exec-after commands can run at once
+    exec_after_jobs: usize,
+
+    #[arg(long, env = "KEMONO_SUSPECT_THRESHOLD")]
+    /// Download files smaller than this many bytes again, empty files and saved error pages always are
+    suspect_threshold: Option<u64>,
+
+    #[arg(long, env = "KEMONO_THUMBNAILS")]
+    /// Download thumbnails of images into thumbs/ instead of the originals, other files are downloaded as usual
+    thumbnails: bool,
+
+    #[arg(long, overrides_with = "no_profile_images")]
+    /// Save the creator's icon and banner in their directory, this is the default
+    profile_images: bool,
+
+    #[arg(
+        long,
+        env = "KEMONO_NO_PROFILE_IMAGES",
+        overrides_with = "profile_images"
+    )]
+    /// Don't save the creator's icon and banner
+    no_profile_images: bool,
+
+    #[arg(
+        long,
+        visible_alias = "skip-metadata",
+        env = "KEMONO_NO_METADATA",
+        conflicts_with = "metadata_only"
+    )]
+    /// Don't write the per-post metadata JSON, for media-only mirrors. Without it there's no
+    /// incremental sync state, so every run checks all the posts, and --retry-failed can't work
+    no_metadata: bool,
+
+    #[arg(long, env = "KEMONO_METADATA_ONLY")]
+    /// Write or refresh the per-post metadata JSON but don't download any media
+    metadata_only: bool,
+
+    #[arg(long, env = "KEMONO_KEEP_PARTIAL")]
+    /// Don't remove the .part files left by interrupted downloads before downloading
+    keep_partial: bool,
+
+    #[arg(long, env = "KEMONO_METADATA_PATH")]
+    /// Keep post metadata under this directory instead of alongside the downloads
+    metadata_path: Option<String>,
+
     #[arg(short = 'D', long, env = "KEMONO_DOWNLOAD_PATH")]
+    /// Where to save downloads, created if it doesn't exist [default: ./download]
     download_path: Option<String>,
 }
 
@@ -97,8 +524,29 @@ impl CliOpts {
             Commands::Download {
                 creatorandservice, ..
             } => creatorandservice.service.clone(),
-            Commands::Stats { service, .. } => service.clone(),
+            Commands::Watch {
+                creatorandservice, ..
+            }
+            | Commands::Prune {
+                creatorandservice, ..
+            }
+            | Commands::Missing {
+                creatorandservice, ..
+            }
+            | Commands::Export {
+                creatorandservice, ..
+            }
+            | Commands::Favorite {
+                creatorandservice, ..
+            } => creatorandservice.service.clone(),
+            Commands::Stats { service, .. } => service.clone().unwrap_or_default(),
             Commands::Update { service, .. } => service.clone().unwrap_or("".to_string()),
+            Commands::Dedup { .. }
+            | Commands::MigrateLayout { .. }
+            | Commands::ListCreators { .. }
+            | Commands::Favorites { .. }
+            | Commands::Account { .. }
+            | Commands::Config { .. } => "".to_string(),
         }
     }
 
@@ -110,400 +558,1143 @@ impl CliOpts {
             Commands::Download {
                 creatorandservice, ..
             } => creatorandservice.creator.clone(),
-            Commands::Stats { creator, .. } => creator.clone(),
+            Commands::Watch {
+                creatorandservice, ..
+            }
+            | Commands::Prune {
+                creatorandservice, ..
+            }
+            | Commands::Missing {
+                creatorandservice, ..
+            }
+            | Commands::Export {
+                creatorandservice, ..
+            }
+            | Commands::Favorite {
+                creatorandservice, ..
+            } => creatorandservice.creator.clone(),
+            Commands::Stats { creator, .. } => creator.clone().unwrap_or_default(),
             Commands::Update { creator, .. } => creator.clone().unwrap_or("".to_string()),
+            Commands::Dedup { .. }
+            | Commands::MigrateLayout { .. }
+            | Commands::ListCreators { .. }
+            | Commands::Favorites { .. }
+            | Commands::Account { .. }
+            | Commands::Config { .. } => "".to_string(),
         }
     }
-}
 
-/// download a given file
-fn download_content(
-    cli: &CliOpts,
-    client: &mut KemonoClient,
-    post: &Post,
-    attachment: &Attachment,
-) -> Result<(), KemonoError> {
-    if attachment.name.is_none() {
-        return Err(KemonoError::from(format!(
-            "Attachment has no name! {:?}",
-            attachment
-        )));
+    /// Whether the command talks to the site, rather than only looking at what's downloaded
+    fn uses_site(&self) -> bool {
+        !matches!(
+            self.command,
+            Commands::Dedup { .. }
+                | Commands::Export { .. }
+                | Commands::MigrateLayout { .. }
+                | Commands::Config { .. }
+        )
     }
-    let attachment_path = match &attachment.path {
-        None => {
-            return Err(KemonoError::from(format!(
-                "Attachment has no path! {:?}",
-                attachment
-            )));
-        }
-        Some(ap) => {
-            let mut ap = ap.to_owned();
 
-            if !ap.starts_with('/') {
-                ap = format!("/{}", ap);
-            }
-            ap
-        }
-    };
-    let download_filename = format!(
-        "{}-{}",
-        post.published.replace(':', "-"),
-        attachment.name.clone().unwrap()
-    );
-    let download_path = PathBuf::from(format!(
-        "{}/{}",
-        client.get_download_path(&cli.service(), &cli.creator()),
-        download_filename
-    ));
-    // check
-    if download_path.exists() {
-        if cli.debug {
-            debug!(
-                "Skipping {} because it already exists",
-                download_path.display()
-            );
+    fn output_format(&self) -> OutputFormat {
+        match &self.command {
+            Commands::Query { ndjson: true, .. } => OutputFormat::Jsonl,
+            Commands::Query { format, .. } | Commands::Stats { format, .. } => *format,
+            _ => OutputFormat::default(),
         }
-        return Ok(());
     }
 
-    if cli.mkvs {
-        let mkv_path = PathBuf::from(get_mkv_filename(&download_filename));
-        let full_mkv_path = PathBuf::from(client.get_download_path(&cli.service(), &cli.creator()))
-            .join(mkv_path.clone());
-        if full_mkv_path.exists() {
-            debug!(
-                "Skipping mkv {} because it already exists",
-                full_mkv_path.display()
-            );
-            return Ok(());
+    /// --quiet wins, then --log-level, then --debug
+    fn log_level(&self) -> LevelFilter {
+        if self.quiet {
+            LevelFilter::Error
+        } else if let Some(level) = self.log_level {
+            level
+        } else if self.debug {
+            LevelFilter::Debug
         } else {
-            debug!("Couldn't find mkv {}", full_mkv_path.display());
+            LevelFilter::Info
         }
     }
 
-    let url = Url::from_str(&format!("https://{}{}", client.hostname, attachment_path,))
-        .map_err(KemonoError::from_stringable)?;
-    let jsonmsg = json!({
-        "action" : "download",
-        "filename" : download_path.display().to_string(),
-        "url" :url.to_string(),}
-    );
-    println!("{}", serde_json::to_string(&jsonmsg)?);
-
-    if client.session.is_none() {
-        client.new_session()?;
+    fn hostname(&self) -> String {
+        self.hostname.clone().unwrap_or_default()
     }
 
-    let response = client
-        .session
-        .as_mut()
-        .unwrap()
-        .get(url)
-        .send()?
-        .error_for_status()?;
-    match response.bytes() {
-        Ok(data) => {
-            if !download_path.parent().unwrap().exists() {
-                std::fs::create_dir_all(download_path.parent().unwrap())
-                    .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    /// Loads the config file if there is one and fills in anything not set on the command line
+    /// or in the environment, `matches` are what the options were parsed from
+    fn load_config(&mut self, matches: &ArgMatches) -> Result<(), KemonoError> {
+        let file_config = match self.config.clone().or_else(KemonoConfig::find) {
+            Some(path) => {
+                debug!("Loading config from {}", path.display());
+                KemonoConfig::from_file(&path)?
             }
-            std::fs::write(download_path, data)
-                .map_err(|err| KemonoError::from(format!("Failed to write image data: {:?}", err)))
+            None => KemonoConfig::default(),
+        };
+        let config = file_config.merge(KemonoConfig {
+            hostname: self.hostname.clone(),
+            download_path: self.download_path.clone(),
+            threads: self.threads,
+            proxy: self.proxy.clone(),
+            timeout: self.timeout,
+            ..Default::default()
+        });
+        config.validate()?;
+        if let Some(threads) = config.threads.filter(|threads| *threads > MAX_THREADS) {
+            warn!(
+                "{} threads is more than {}, expect the site to rate limit downloads",
+                threads, MAX_THREADS
+            );
         }
-        Err(err) => Err(KemonoError::from(err)),
-    }
-}
+        self.creators = config.creator_pairs();
 
-async fn do_query(cli: CliOpts, client: &mut KemonoClient) -> Result<(), KemonoError> {
-    let posts = client.all_posts(&cli.service(), &cli.creator()).await?;
-    for post in posts {
-        println!("{}", serde_json::to_string_pretty(&post)?);
-    }
-    Ok(())
-}
-
-async fn do_download(cli: CliOpts, client: &mut KemonoClient) -> Result<(), KemonoError> {
-    let mut files = Vec::new();
-
-    let all_posts = client.all_posts(&cli.service(), &cli.creator()).await?;
-    if all_posts.is_empty() {
-        return Err(KemonoError::from(format!(
-            "No posts found for {}/{}",
-            cli.service(),
-            cli.creator()
-        )));
-    }
-
-    for post in all_posts {
-        let post_data_filepath = PathBuf::from(&format!(
-            "{}/metadata/{}.json",
-            client.get_download_path(&cli.service(), &cli.creator()),
-            post.id
-        ));
+        let explicit = |id: &str| {
+            matches
+                .value_source(id)
+                .is_some_and(|source| source != ValueSource::DefaultValue)
+        };
+        fill(
+            explicit("platform"),
+            &mut self.platform,
+            parse_config(config.platform, "platform")?.map(Some),
+        );
+        fill(
+            explicit("metadata_path"),
+            &mut self.metadata_path,
+            config.metadata_path.map(Some),
+        );
+        fill(
+            explicit("data_hosts"),
+            &mut self.data_hosts,
+            config.data_hosts,
+        );
+        fill(
+            explicit("per_creator_threads"),
+            &mut self.per_creator_threads,
+            config.per_creator_threads.map(Some),
+        );
+        fill(explicit("cache_ttl"), &mut self.cache_ttl, config.cache_ttl);
+        fill(explicit("strict"), &mut self.strict, config.strict);
+        fill(explicit("mkvs"), &mut self.mkvs, config.mkvs);
+        fill(
+            explicit("layout"),
+            &mut self.layout,
+            parse_config(config.layout, "layout")?,
+        );
+        fill(explicit("post_dirs"), &mut self.post_dirs, config.post_dirs);
+        fill(
+            explicit("filename_template"),
+            &mut self.filename_template,
+            parse_config(config.filename_template, "filename_template")?,
+        );
+        fill(
+            explicit("content_format"),
+            &mut self.content_format,
+            parse_config(config.content_format, "content_format")?,
+        );
+        fill(
+            explicit("mtime"),
+            &mut self.mtime,
+            parse_config(config.mtime, "mtime")?,
+        );
+        fill(
+            explicit("inline_media"),
+            &mut self.inline_media,
+            config.inline_media,
+        );
+        fill(
+            explicit("thumbnails"),
+            &mut self.thumbnails,
+            config.thumbnails,
+        );
+        fill(explicit("dedup"), &mut self.dedup, config.dedup);
+        fill(explicit("manifest"), &mut self.manifest, config.manifest);
+        fill(
+            explicit("ignore_edits"),
+            &mut self.ignore_edits,
+            config.ignore_edits,
+        );
+        fill(
+            explicit("suspect_threshold"),
+            &mut self.suspect_threshold,
+            config.suspect_threshold.map(Some),
+        );
+        fill(
+            explicit("exec_after"),
+            &mut self.exec_after,
+            config.exec_after.map(Some),
+        );
+        fill(
+            explicit("cookie_file"),
+            &mut self.cookie_file,
+            config.cookies_file.map(Some),
+        );
+        fill(
+            explicit("notify_webhook"),
+            &mut self.notify_webhook,
+            config.notify_webhook.map(Some),
+        );
+        self.hostname = config.hostname.or(self
+            .platform
+            .map(|platform| platform.hostname().to_string()));
+        self.download_path = config.download_path;
+        self.threads = config.threads;
+        self.proxy = config.proxy;
+        self.timeout = config.timeout;
 
-        if !post_data_filepath.parent().unwrap().exists() {
-            std::fs::create_dir_all(post_data_filepath.parent().unwrap())
-                .expect("Failed to create parent dirs");
+        if self.hostname.is_none() {
+            return Err(KemonoError::from_stringable(
+                "No hostname set, use --hostname, --platform, KEMONO_HOSTNAME or the config file",
+            ));
         }
+        Ok(())
+    }
 
-        if !post_data_filepath.exists() {
-            std::fs::write(post_data_filepath, serde_json::to_string_pretty(&post)?)
-                .expect("Failed to write post data");
-        }
-        if post.file.name.is_some() && post.file.path.is_some() {
-            files.push((post.clone(), post.file.clone()));
-        }
-        if let Some(attachments) = post.attachments.clone() {
-            for attachment in attachments {
-                files.push((post.clone(), attachment));
-            }
+    /// The --offset and --max-posts options, for the commands which have them
+    fn post_window(&self) -> PostWindow {
+        match &self.command {
+            Commands::Query { window, .. }
+            | Commands::Download { window, .. }
+            | Commands::Stats { window, .. }
+            | Commands::Missing { window, .. } => window.clone(),
+            _ => PostWindow {
+                offset: 0,
+                max_posts: None,
+            },
         }
     }
 
-    info!("Found {} objects", files.len());
-    let res = files.par_iter().map(|image| {
-        if let Some(filename) = cli.filename.clone() {
-            if let Some(post_file_name) = image.1.name.clone() {
-                if !post_file_name.contains(&filename) {
-                    if cli.debug {
-                        debug!("Skipping {} as doesn't match {}", post_file_name, filename);
-                    }
-                    return Ok(());
+    fn download_options(&self) -> DownloadOptions {
+        let window = self.post_window();
+        DownloadOptions {
+            threads: self.threads.unwrap_or(DEFAULT_THREADS),
+            per_creator_threads: self.per_creator_threads,
+            filename: FilenameFilter {
+                contains: self.filename.clone(),
+                patterns: self.filename_regex.clone(),
+                exclude: self.exclude_filename_regex.clone(),
+            },
+            tags: TagFilter {
+                include: self.tag.clone(),
+                exclude: self.exclude_tag.clone(),
+            },
+            mkvs: self.mkvs,
+            converted_extensions: self.converted_ext.clone(),
+            download_path: self.download_path.clone(),
+            content_format: self.content_format,
+            mtime: self.mtime,
+            inline_media: self.inline_media,
+            rewrite_media: self.rewrite_media,
+            layout: self.layout,
+            metadata_path: self.metadata_path.clone(),
+            post_dirs: self.post_dirs,
+            filename_template: self.filename_template.clone(),
+            suspect_threshold: self.suspect_threshold,
+            profile_images: !self.no_profile_images,
+            no_metadata: self.no_metadata,
+            metadata_only: self.metadata_only,
+            exec_after: self.exec_after.clone().map(DownloadHook::Command),
+            hook_concurrency: self.exec_after_jobs,
+            thumbnails: self.thumbnails,
+            nfo: matches!(self.command, Commands::Download { nfo: true, .. }),
+            force: matches!(self.command, Commands::Download { force: true, .. }),
+            post_id: match &self.command {
+                Commands::Download { post_id, .. } => post_id.clone(),
+                _ => None,
+            },
+            export_markdown: matches!(
+                self.command,
+                Commands::Download {
+                    export_markdown: true,
+                    ..
                 }
-            }
+            ),
+            include_dms: matches!(
+                self.command,
+                Commands::Download {
+                    include_dms: true,
+                    ..
+                }
+            ),
+            // a post ID only means something for one creator, so this isn't used by update
+            since_post_id: match &self.command {
+                Commands::Download { since_post_id, .. }
+                | Commands::Missing { since_post_id, .. } => since_post_id.clone(),
+                _ => None,
+            },
+            offset: window.offset,
+            max_posts: window.max_posts,
+            ignore_edits: self.ignore_edits,
+            sha256sums: self.manifest,
+            dedup: self.dedup,
+            incremental: matches!(
+                self.command,
+                Commands::Update { full: false, .. } | Commands::Watch { .. }
+            ),
+            ..DownloadOptions::new(&self.service(), &self.creator())
         }
-        let (post, attachment) = image;
-        let mut client = KemonoClient::new_from(client);
+    }
+}
 
-        if let Err(err) = download_content(&cli, &mut client, post, attachment)
-        // })
-        {
-            match err {
-                KemonoError::Reqwest(req_error) => {
-                    if let Some(status_code) = req_error.status() {
-                        if status_code.as_u16() == 429 {
-                            eprintln!("Got rate limited, bailing for now!");
-                            error!("Got rate limited, bailing for now!");
-                            return Err(KemonoError::RateLimited);
-                        }
-                    } else {
-                        error!("Failed to download {:?} {:?}", attachment, req_error);
-                    }
-                }
-                KemonoError::RateLimited => {
-                    return Err(KemonoError::RateLimited);
-                }
-                _ => error!("Failed to download {:?} {:?}", attachment, err), // KemonoError::Generic(_) => todo!(),
-                                                                              // KemonoError::SerdeJson(_) => todo!(),
-            }
-        };
-        Ok(())
-    });
-    // handle any errors
-    res.collect::<Result<Vec<_>, _>>()?;
+/// Checks --notify-webhook is a URL
+fn parse_webhook(value: &str) -> Result<String, String> {
+    reqwest::Url::parse(value)
+        .map(|_| value.to_string())
+        .map_err(|err| err.to_string())
+}
+
+/// Sets `field` to the config file's `value`, unless it was set on the command line or in the environment
+fn fill<T>(explicit: bool, field: &mut T, value: Option<T>) {
+    if let Some(value) = value.filter(|_| !explicit) {
+        *field = value;
+    }
+}
 
+/// Parses a value from the config file, the same way the command line option is parsed
+fn parse_config<T: FromStr<Err = String>>(
+    value: Option<String>,
+    name: &str,
+) -> Result<Option<T>, KemonoError> {
+    value
+        .map(|value| value.parse())
+        .transpose()
+        .map_err(|err| KemonoError::Configuration(format!("{}: {}", name, err)))
+}
+
+async fn do_query(cli: CliOpts, client: &mut KemonoClient) -> Result<(), KemonoError> {
+    let (fields, pretty) = match &cli.command {
+        Commands::Query { fields, pretty, .. } => (fields.clone(), *pretty),
+        _ => (Vec::new(), false),
+    };
+    let tags = cli.download_options().tags;
+    let window = cli.post_window();
+    let posts = client
+        .posts_window(
+            &cli.service(),
+            &cli.creator(),
+            window.offset,
+            window.max_posts,
+        )
+        .try_filter(move |post| future::ready(tags.matches(post)));
+    futures::pin_mut!(posts);
+    if cli.output_format() == OutputFormat::Jsonl {
+        // write each post as it arrives, so we're not holding every post in memory
+        let mut stdout = std::io::stdout().lock();
+        while let Some(post) = posts.try_next().await? {
+            writeln!(stdout, "{}", select_fields(&post, &fields)?)
+                .and_then(|_| stdout.flush())
+                .map_err(KemonoError::from_stringable)?;
+        }
+        return Ok(());
+    }
+    // the other formats need everything before they can write anything
+    let posts: Vec<Post> = posts.try_collect().await?;
+    println!("{}", cli.output_format().posts(&posts, &fields, pretty)?);
     Ok(())
 }
 
+/// Stats from the API for a creator, or from the stored metadata for every creator if one isn't given
 async fn do_stats(client: &mut KemonoClient, cli: &CliOpts) -> Result<(), KemonoError> {
-    let posts = client.all_posts(&cli.service(), &cli.creator()).await?;
-
-    let post_count = posts.len();
-    let mut filetypes: HashMap<String, usize> = HashMap::new();
-    let mut file_count = 0;
-
-    for post in posts {
-        if let Some(attachments) = post.attachments {
-            for attachment in attachments {
-                if let Some(name) = attachment.name {
-                    let ext = name.split('.').last().unwrap().to_string();
-                    let count = filetypes.entry(ext).or_insert(0);
-                    *count += 1;
-                    file_count += 1;
-                }
+    let (local, top, with_sizes) = match &cli.command {
+        Commands::Stats {
+            local,
+            top,
+            remote_sizes,
+            ..
+        } => (*local, *top, *remote_sizes),
+        _ => (false, DEFAULT_TOP_FILES, false),
+    };
+    let mut stats = PostStats::default();
+    let mut local_stats = LocalStats::new(top);
+    // only kept around if we need their sizes
+    let mut all_posts: Vec<Post> = Vec::new();
+    if cli.creator().is_empty() {
+        let base_path = PathBuf::from(&client.get_base_download_path());
+        for dir in cli.layout.walk(&base_path) {
+            let options = DownloadOptions {
+                service: dir.service,
+                creator: dir.creator,
+                creator_name: dir.name,
+                ..cli.download_options()
+            };
+            let posts = stored_posts(&options.metadata_dir(client));
+            posts.iter().for_each(|post| stats.add(post));
+            if with_sizes {
+                all_posts.extend(posts);
             }
+            local_stats.add_dir(&dir.path)?;
         }
-        if let Some(name) = post.file.name {
-            let ext = name.split('.').last().unwrap().to_string();
-            let count = filetypes.entry(ext).or_insert(0);
-            *count += 1;
-            file_count += 1;
+    } else {
+        let window = cli.post_window();
+        let posts = client.posts_window(
+            &cli.service(),
+            &cli.creator(),
+            window.offset,
+            window.max_posts,
+        );
+        futures::pin_mut!(posts);
+        while let Some(post) = posts.try_next().await? {
+            stats.add(&post);
+            if with_sizes {
+                all_posts.push(post);
+            }
+        }
+        if local {
+            let options = cli.download_options().resolve_creator_name(client).await;
+            let target_dir = options.target_dir(client);
+            if target_dir.is_dir() {
+                local_stats.add_dir(&target_dir)?;
+            }
         }
     }
+    if with_sizes {
+        info!("Getting the size of {} files", stats.file_count);
+        let threads = cli.threads.unwrap_or(DEFAULT_THREADS);
+        let sizes = remote_sizes(client, &all_posts, threads).await?;
+        all_posts
+            .iter()
+            .for_each(|post| stats.add_sizes(post, &sizes));
+    }
+    let local = local || cli.creator().is_empty();
+    let filetypes = &stats.extensions;
 
-    let stats = json!({
-        "post_count": post_count,
-        "file_count" : file_count,
+    let mut output = json!({
+        "post_count": stats.post_count,
+        "file_count" : stats.file_count,
         "filetypes": filetypes,
+        "posts_per_year": &stats.posts_per_year,
+        "posts_per_month": &stats.posts_per_month,
         "service": cli.service(),
         "creator": cli.creator(),
     });
+    if local {
+        output["local"] = serde_json::to_value(&local_stats)?;
+    }
+    if with_sizes {
+        output["total_size"] = json!(stats.total_size);
+        output["size_by_extension"] = json!(&stats.size_by_extension);
+        output["unknown_sizes"] = json!(stats.unknown_sizes);
+    }
 
-    println!("{}", serde_json::to_string_pretty(&stats)?);
+    let output = match cli.output_format() {
+        OutputFormat::Csv => {
+            #[derive(serde::Serialize)]
+            struct Row<'a> {
+                extension: &'a str,
+                count: usize,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                bytes: Option<u64>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                size: Option<u64>,
+            }
+            let extensions: BTreeSet<&String> = filetypes
+                .keys()
+                .chain(local_stats.bytes_by_extension.keys())
+                .collect();
+            let rows = extensions.into_iter().map(|extension| Row {
+                extension,
+                count: filetypes.get(extension).copied().unwrap_or_default(),
+                bytes: local.then(|| {
+                    local_stats
+                        .bytes_by_extension
+                        .get(extension)
+                        .copied()
+                        .unwrap_or_default()
+                }),
+                size: with_sizes.then(|| {
+                    stats
+                        .size_by_extension
+                        .get(extension)
+                        .copied()
+                        .unwrap_or_default()
+                }),
+            });
+            to_csv(rows)?
+        }
+        format => format.value(&output)?,
+    };
+    println!("{}", output);
 
     Ok(())
 }
 
 /// Update everything based on the file paths in the download dir
-async fn do_update(client: &mut KemonoClient, cli: &CliOpts) -> Result<(), KemonoError> {
+async fn do_update(client: &mut KemonoClient, cli: &CliOpts) -> Result<UpdateSummary, KemonoError> {
+    let start = Instant::now();
+    let mut summary = UpdateSummary::default();
     // get the targets
     //
     let base_path = PathBuf::from(&client.get_base_download_path());
+    let layout = cli.layout;
 
-    eprintln!("Checking {}", base_path.display());
+    info!("Checking {} with layout {}", base_path.display(), layout);
 
-    for creator in base_path.read_dir().map_err(|err| err.to_string())? {
-        let creator = creator.map_err(|err| err.to_string())?;
-        // find the services
-        let creator_name = creator.file_name();
+    // (service, creator, name)
+    let mut targets: Vec<(String, String, Option<String>)> = layout
+        .walk(&base_path)
+        .into_iter()
+        // the metadata might be kept inside the download path
+        .filter(|dir| {
+            !cli.metadata_path
+                .as_ref()
+                .is_some_and(|metadata_path| dir.path.starts_with(metadata_path))
+        })
+        .map(|dir| (dir.service, dir.creator, dir.name))
+        .collect();
+    // creators from the config and --targets might not have been downloaded yet
+    let mut listed = cli.creators.clone();
+    if let Commands::Update {
+        targets: Some(path),
+        ..
+    } = &cli.command
+    {
+        listed.extend(read_targets(path)?);
+    }
+    for (service, creator) in listed {
+        if !targets.iter().any(|(known_service, known_creator, _)| {
+            known_service == &service && known_creator == &creator
+        }) {
+            targets.push((service, creator, None));
+        }
+    }
+    if matches!(
+        cli.command,
+        Commands::Update {
+            download_favorites: true,
+            ..
+        }
+    ) {
+        for favorite in client.favorites().await? {
+            if !targets.iter().any(|(service, creator, _)| {
+                service == &favorite.service && creator == &favorite.id
+            }) {
+                targets.push((favorite.service, favorite.id, Some(favorite.name)));
+            }
+        }
+    }
 
-        let creator_name = creator_name.to_str().expect("Failed to string-ify creator");
+    let parallel_creators = match cli.command {
+        Commands::Update {
+            parallel_creators, ..
+        } => parallel_creators,
+        _ => 1,
+    };
+    let base_options = cli.download_options();
+    // the creators share the download threads, so running more of them doesn't hit the site harder
+    let threads = (base_options.threads / parallel_creators).max(1);
 
-        if !cli.creator().is_empty() && creator_name != cli.creator() {
-            debug!(
-                "Skipping {} as doesn't match {}",
-                creator_name,
-                cli.creator()
+    let mut jobs: Vec<DownloadOptions> = Vec::new();
+    for (service, creator, name) in targets.iter() {
+        let (creator, service) = (creator.as_str(), service.as_str());
+        print_action("update", json!({"creator": creator, "service": service}));
+
+        // the name layout can be filtered on the name as well as the ID
+        let creator_matches = matches_list(&cli.creator(), creator)
+            || name
+                .as_deref()
+                .is_some_and(|name| matches_list(&cli.creator(), name));
+        if !creator_matches || !matches_list(&cli.service(), service) {
+            info!(
+                "Skipping creator {} service {} as didn't match {}/{}",
+                creator,
+                service,
+                cli.creator(),
+                cli.service()
+            );
+            summary.skip(creator, service);
+            continue;
+        }
+        if let Err(err) = client.validate_service(service) {
+            error!(
+                "Not updating creator: {} service: {} {}",
+                creator, service, err
             );
+            summary.error(creator, service, err);
             continue;
         }
-        debug!("Updating {}", creator_name);
+        jobs.push(DownloadOptions {
+            service: service.to_string(),
+            creator: creator.to_string(),
+            creator_name: name.clone(),
+            threads,
+            ..base_options.clone()
+        });
+    }
 
-        if creator.path().is_dir() {
-            for service in creator.path().read_dir().map_err(|err| err.to_string())? {
-                let service = service
-                    .map_err(|err| format!("failed to get direntry: {}", err))?
-                    .path();
-                if !service.is_dir() {
-                    eprintln!("Skipping service {:?}", service);
-                    continue;
+    // once the site starts refusing requests there's no point starting more creators
+    let rate_limited = Arc::new(AtomicBool::new(false));
+    let mut results = stream::iter(jobs)
+        .map(|options| {
+            let mut client = client.clone();
+            let rate_limited = rate_limited.clone();
+            async move {
+                let (creator, service) = (options.creator.clone(), options.service.clone());
+                if rate_limited.load(Ordering::SeqCst) || stop_requested() {
+                    return (creator, service, Ok(None));
+                }
+                info!(
+                    creator = creator.as_str(), service = service.as_str();
+                    "Continuing with creator: {} service: {}", creator, service
+                );
+                let res =
+                    tokio::spawn(async move { download(&mut client, &options).await.map(Some) })
+                        .await
+                        .unwrap_or_else(|err| {
+                            Err(KemonoError::from(format!("Update task failed: {:?}", err)))
+                        });
+                if matches!(&res, Err(err) if err.is_rate_limited()) {
+                    rate_limited.store(true, Ordering::SeqCst);
                 }
-                let service = service
-                    .file_name()
-                    .map(|s| s.to_str().expect("Failed to string-ify service"))
-                    .expect("Failed to get service name");
+                (creator, service, res)
+            }
+        })
+        .buffer_unordered(parallel_creators);
 
-                eprintln!(
-                    "{}",
-                    serde_json::to_string(&json!({"creator": creator_name,"service" : service}))?
+    let mut interrupted = false;
+    while let Some((creator, service, res)) = results.next().await {
+        match res {
+            Ok(Some(creator_summary)) => {
+                if let Some(webhook) = &cli.notify_webhook {
+                    notify(client, webhook, &creator_summary).await;
+                }
+                summary.add(creator_summary)
+            }
+            Ok(None) => {
+                info!(
+                    "Not starting creator {} service {}, stopping early",
+                    creator, service
                 );
+                summary.skip(&creator, &service);
+            }
+            Err(err) if err.is_rate_limited() => {
+                error!(
+                    creator = creator.as_str(), service = service.as_str();
+                    "Rate limited updating creator: {} service: {} {}", creator, service, err
+                );
+                summary.rate_limited(&creator, &service, err);
+            }
+            Err(KemonoError::Interrupted) => interrupted = true,
+            Err(err) => {
+                error!(
+                    creator = creator.as_str(), service = service.as_str();
+                    "Failed to update creator: {} service: {} {:?}", creator, service, err
+                );
+                summary.error(&creator, &service, err);
+            }
+        }
+    }
+    if interrupted {
+        return Err(KemonoError::Interrupted);
+    }
+    if summary.rate_limited {
+        error!("Got rate limited, the rest will be updated next time");
+    }
 
-                if !cli.service().is_empty() && cli.service() != service {
-                    info!(
-                        "Skipping service {} for creator {} as didn't match {}",
-                        service,
-                        creator_name,
-                        cli.service()
-                    );
-                    continue;
-                } else {
-                    eprintln!(
-                        "Continuing with creator: {} service: {}",
-                        creator_name, service
-                    );
+    summary.total.finish(start.elapsed());
+    info!(
+        "Updated {} creator/service dirs, skipped {}, {} failed",
+        summary.creators.len(),
+        summary.skipped.len(),
+        summary.errors.len()
+    );
+    Ok(summary)
+}
+
+/// Moves the download dir between layouts, looking up creator names if the new layout needs them
+async fn do_migrate_layout(
+    client: &KemonoClient,
+    from: Layout,
+    to: Layout,
+    dry_run: bool,
+) -> Result<usize, KemonoError> {
+    let base_path = PathBuf::from(client.get_base_download_path());
+    let mut names: HashMap<(String, String), String> = HashMap::new();
+    if to.needs_name() {
+        for dir in from.walk(&base_path) {
+            if dir.name.is_some() {
+                continue;
+            }
+            match client.creator_name(&dir.service, &dir.creator).await {
+                Ok(name) => {
+                    names.insert((dir.service, dir.creator), name);
                 }
+                Err(KemonoError::RateLimited) => return Err(KemonoError::RateLimited),
+                Err(err) => error!(
+                    "Couldn't get the name of {}/{}, using the ID: {:?}",
+                    dir.service, dir.creator, err
+                ),
+            }
+        }
+    }
 
-                if let Err(err) = do_download(
-                    CliOpts {
-                        command: Commands::Download {
-                            copt: SharedCliOpts {},
-                            creatorandservice: CreatorAndService {
-                                creator: creator_name.to_string(),
-                                service: service.to_string(),
-                            },
-                        },
-                        debug: cli.debug,
-                        mkvs: cli.mkvs,
-                        hostname: cli.hostname.clone(),
-                        username: cli.username.clone(),
-                        password: cli.password.clone(),
-                        threads: cli.threads,
-                        filename: cli.filename.clone(),
-                        download_path: cli.download_path.clone(),
-                    },
-                    client,
-                )
-                .await
-                {
-                    match err {
-                        KemonoError::RateLimited => {
-                            error!("Got rate limited, bailing for now!");
-                            return Err(KemonoError::RateLimited);
-                        }
-                        _ => {
-                            eprintln!(
-                                "Failed to update creator: {} service: {} {:?}",
-                                creator_name, service, err
-                            );
-                        }
-                    }
-                };
+    let moves = migration_moves(&base_path, from, to, |dir| {
+        names
+            .get(&(dir.service.clone(), dir.creator.clone()))
+            .cloned()
+    });
+    for layout_move in moves.iter() {
+        print_action(
+            if dry_run { "would_move" } else { "move" },
+            json!({"from": layout_move.from, "to": layout_move.to}),
+        );
+        if !dry_run {
+            apply_move(layout_move)?;
+        }
+    }
+    Ok(moves.len())
+}
+
+/// Logs in if there's a username and password set, the session is shared by everything the client does
+async fn login_if_configured(client: &mut KemonoClient) -> Result<(), KemonoError> {
+    if client.username.is_some() && client.password.is_some() {
+        client.login().await?;
+        info!("Logged in");
+    }
+    Ok(())
+}
+
+/// Sets up the login session from --session-token, then a session saved in --cookie-file, then
+/// the username and password, and saves it to --cookie-file for the next run
+async fn log_in(client: &mut KemonoClient, cli: &CliOpts) -> Result<(), KemonoError> {
+    let cookie_file = cli.cookie_file.as_ref().map(PathBuf::from);
+    if let Some(token) = &cli.session_token {
+        client.set_session_token(token)?;
+        let account = client.validate_session().await?;
+        info!("Using the session for {}", account.username);
+    } else if let Some(path) = cookie_file.as_ref().filter(|path| path.exists()) {
+        client.load_cookies(path)?;
+        let res = match client.has_session() {
+            true => client.validate_session().await.map(|account| {
+                info!("Using the saved session for {}", account.username);
+            }),
+            false => Err(KemonoError::SessionExpired),
+        };
+        match res {
+            Err(KemonoError::SessionExpired)
+                if client.username.is_some() && client.password.is_some() =>
+            {
+                info!("The saved session has expired, logging in again");
+                login_if_configured(client).await?;
             }
+            // without a login there's nothing saved to check
+            Err(KemonoError::SessionExpired) if !client.has_session() => {}
+            res => res?,
         }
+    } else {
+        login_if_configured(client).await?;
+    }
+    if let Some(path) = cookie_file.filter(|_| client.has_session()) {
+        client.save_cookies(&path)?;
+    }
+    Ok(())
+}
+
+/// Finds files which aren't in the current posts, trashing or deleting them if asked to
+async fn do_prune(
+    client: &mut KemonoClient,
+    cli: &CliOpts,
+    mode: PruneMode,
+) -> Result<Orphans, KemonoError> {
+    let options = cli.download_options().resolve_creator_name(client).await;
+    // a post which was skipped because it didn't parse would make its files look like orphans
+    client.strict = true;
+    client.clear_cache();
+    let posts = client.all_posts(&options.service, &options.creator).await?;
+    if posts.is_empty() {
+        // an empty list is more likely an API problem than everything being deleted
+        return Err(KemonoError::from(format!(
+            "No posts found for {}/{}, not pruning",
+            options.service, options.creator
+        )));
+    }
+    let orphans = find_orphans(client, &options, &posts)?;
+    let target_dir = options.target_dir(client);
+    for file in orphans.files.iter() {
+        let action = match mode {
+            PruneMode::Report => "orphan",
+            PruneMode::Trash => "trash",
+            PruneMode::Delete => "delete",
+        };
+        let trashed = remove_orphan(&target_dir, file, mode)?;
+        print_action(action, json!({"path": file, "trash_path": trashed}));
+    }
+    for file in orphans.metadata.iter() {
+        print_action("orphan_metadata", json!({"path": file}));
     }
+    Ok(orphans)
+}
+
+/// Prints the files which aren't downloaded yet, and a count of them
+async fn do_missing(
+    client: &mut KemonoClient,
+    cli: &CliOpts,
+) -> Result<Vec<MissingFile>, KemonoError> {
+    let options = cli.download_options().resolve_creator_name(client).await;
+    // the same posts a download would look at
+    let posts = select_posts(client, &options).await?.posts;
+    let missing = find_missing(client, &options, &posts);
+    for file in missing.iter() {
+        print_action("missing", json!(file));
+    }
+    print_action(
+        "missing_summary",
+        json!({
+            "service": options.service,
+            "creator": options.creator,
+            "posts": posts.len(),
+            "missing": missing.len(),
+        }),
+    );
+    Ok(missing)
+}
 
+/// Checks `value` against a comma-separated list of names, ignoring case and whitespace. An empty list matches everything.
+fn matches_list(list: &str, value: &str) -> bool {
+    let value = value.trim();
+    let mut names = list
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .peekable();
+    names.peek().is_none() || names.any(|name| name.eq_ignore_ascii_case(value))
+}
+
+/// The command failed
+static EXIT_FAILED: i32 = 1;
+/// The run finished, but some downloads or creators failed
+static EXIT_PARTIAL: i32 = 2;
+/// The site rate limited us, try again later
+static EXIT_RATE_LIMITED: i32 = 3;
+/// `missing` found files which haven't been downloaded
+static EXIT_MISSING: i32 = 4;
+/// Stopped early by Ctrl+C
+static EXIT_INTERRUPTED: i32 = 130;
+
+/// The exit code for a command which failed with `err`
+fn exit_code(err: &KemonoError) -> i32 {
+    match err {
+        err if err.is_rate_limited() => EXIT_RATE_LIMITED,
+        KemonoError::Interrupted => EXIT_INTERRUPTED,
+        _ => EXIT_FAILED,
+    }
+}
+
+/// Prints a pretty JSON value, returning the exit code
+fn print_json(value: &impl serde::Serialize) -> i32 {
+    match serde_json::to_string_pretty(value) {
+        Ok(value) => {
+            println!("{}", value);
+            0
+        }
+        Err(err) => {
+            error!("Failed to serialize output: {:?}", err);
+            EXIT_FAILED
+        }
+    }
+}
+
+/// The first Ctrl+C or SIGTERM lets the downloads in progress finish and stops new ones starting, the second quits
+fn stop_on_signal() {
+    // the downloads block this thread, so listen for signals on another one
+    tokio::spawn(async {
+        shutdown_signal().await;
+        eprintln!("Stopping after the in-progress downloads finish, Ctrl+C again to quit now");
+        request_stop();
+        shutdown_signal().await;
+        exit_releasing_lock(130);
+    });
+}
+
+/// Waits for Ctrl+C, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {:?}", err);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// One run of `watch` or `update --watch`
+async fn watch_cycle(
+    client: &mut KemonoClient,
+    cli: &CliOpts,
+    cycle: u64,
+) -> Result<(), KemonoError> {
+    match cli.command {
+        Commands::Update { .. } => {
+            let summary = do_update(client, cli).await?;
+            if summary.rate_limited {
+                error!("Got rate limited, waiting until the next update")
+            }
+            print_action("updated", json!({"cycle": cycle, "summary": summary}));
+        }
+        _ => {
+            let summary = download(client, &cli.download_options()).await?;
+            if let Some(webhook) = &cli.notify_webhook {
+                notify(client, webhook, &summary).await;
+            }
+            print_action("checked", json!({"cycle": cycle, "summary": summary}));
+        }
+    }
     Ok(())
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let cli = CliOpts::parse();
+/// Runs `watch` or `update --watch` every `interval` until Ctrl+C or SIGTERM, letting the downloads in
+/// progress finish first
+async fn do_watch(client: &mut KemonoClient, cli: &CliOpts, interval: Interval) {
+    stop_on_signal();
 
-    let log_level = match cli.debug {
-        false => "info",
-        true => "debug",
-    };
+    let mut cycle: u64 = 0;
+    // each run starts afresh, a stop asked for during the last one ends the watch instead
+    while !clear_stop() {
+        cycle += 1;
+        // each run needs to see what's new since the last one
+        client.clear_cache();
+        match watch_cycle(client, cli, cycle).await {
+            Ok(()) | Err(KemonoError::Interrupted) => {}
+            Err(err) if err.is_rate_limited() => {
+                error!("{}, waiting until the next run", err)
+            }
+            Err(err) => error!("Failed to complete run: {:?}", err),
+        }
+        if stop_requested() {
+            continue;
+        }
+        let wait = interval.with_jitter();
+        info!("Running again in {} seconds", wait.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown_signal() => request_stop(),
+        }
+    }
+    eprintln!("Stopped watching after {} runs", cycle);
+}
 
-    Builder::with_level(log_level)
-        .with_target_writer("*", new_writer(tokio::io::stdout()))
+#[tokio::main]
+async fn main() {
+    let matches = CliOpts::command().get_matches();
+    let mut cli = CliOpts::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    // logs go to stderr so stdout is only machine-readable output
+    Builder::with_level(cli.log_level().as_str())
+        .with_target_writer("*", new_writer(tokio::io::stderr()))
         .init();
 
-    let mut client = KemonoClient::new(&cli.hostname.clone(), cli.download_path.clone());
+    if let Commands::Config {
+        command: ConfigCommand::Init { path, force },
+    } = &cli.command
+    {
+        match KemonoConfig::write_template(path, *force) {
+            Ok(()) => print_action("config_init", json!({"path": path})),
+            Err(err) => {
+                eprintln!("Failed to write config: {}", err);
+                std::process::exit(EXIT_FAILED);
+            }
+        }
+        return;
+    }
+    if let Err(err) = cli.load_config(&matches) {
+        eprintln!("Failed to load config: {}", err);
+        std::process::exit(EXIT_FAILED);
+    }
+
+    let download_path = cli
+        .download_path
+        .clone()
+        .unwrap_or(DEFAULT_DOWNLOAD_PATH.to_string());
+    match prepare_download_path(&download_path) {
+        Ok(path) => cli.download_path = Some(path.display().to_string()),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(EXIT_FAILED);
+        }
+    }
+    if let Some(metadata_path) = &cli.metadata_path {
+        match prepare_download_path(metadata_path) {
+            Ok(path) => cli.metadata_path = Some(path.display().to_string()),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(EXIT_FAILED);
+            }
+        }
+    }
+
+    let mut client = KemonoClient::new(&cli.hostname(), cli.download_path.clone())
+        .with_data_hosts(cli.data_hosts.clone());
     client.username = cli.username.clone();
     client.password = cli.password.clone();
-    if cli.mkvs && cli.debug {
+    client.timeout_seconds = cli.timeout;
+    client.cache_ttl = match cli.no_cache {
+        true => None,
+        false => Some(Duration::from_secs(cli.cache_ttl)),
+    };
+    client.strict = cli.strict;
+    if let Some(proxy) = &cli.proxy {
+        client = match client.with_proxy(proxy) {
+            Ok(client) => client,
+            Err(err) => {
+                eprintln!("Invalid proxy {}: {:?}", proxy, err);
+                std::process::exit(EXIT_FAILED);
+            }
+        };
+    }
+    if cli.mkvs {
         debug!("MKV checking mode enabled");
     }
+    // update takes a list of services to filter on
+    for service in cli.service().split(',').map(str::trim) {
+        if let Err(err) = client.validate_service(service) {
+            eprintln!("{}", err);
+            std::process::exit(EXIT_FAILED);
+        }
+    }
 
-    // build the threadpool for rayon so we don't get rate limited
-    ThreadPoolBuilder::new()
-        .num_threads(cli.threads)
-        .build_global()
-        .unwrap();
+    if cli.uses_site() {
+        if let Err(err) = log_in(&mut client, &cli).await {
+            eprintln!("Failed to log in: {}", err);
+            std::process::exit(exit_code(&err));
+        }
+    }
 
-    match cli.command {
+    let writes_downloads = matches!(
+        cli.command,
+        Commands::Download { .. } | Commands::Update { .. } | Commands::Watch { .. }
+    );
+    // held until we exit, so two runs can't download into the same place at once
+    if writes_downloads {
+        match cli
+            .download_path
+            .as_ref()
+            .map(|dir| DownloadLock::acquire(&PathBuf::from(dir)))
+            .transpose()
+        {
+            Ok(lock) => {
+                if let Ok(mut held) = DOWNLOAD_LOCK.lock() {
+                    *held = lock;
+                }
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(EXIT_FAILED);
+            }
+        }
+    }
+
+    if !cli.keep_partial && writes_downloads {
+        for dir in cli.download_path.iter().chain(cli.metadata_path.iter()) {
+            match remove_partial_files(&PathBuf::from(dir)) {
+                Ok(0) => {}
+                Ok(removed) => info!("Removed {} partial files from {}", removed, dir),
+                Err(err) => warn!("Failed to remove partial files from {}: {:?}", dir, err),
+            }
+        }
+    }
+
+    let code = match cli.command {
         Commands::Stats { .. } => {
             info!(
                 "Pulling stats for {}/{}/{}",
-                cli.hostname,
+                cli.hostname(),
                 cli.service(),
                 cli.creator()
             );
-            if let Err(err) = do_stats(&mut client, &cli).await {
-                error!("Failed to complete stats: {:?}", err);
-            };
+            match do_stats(&mut client, &cli).await {
+                Err(err) => {
+                    error!("Failed to complete stats: {:?}", err);
+                    exit_code(&err)
+                }
+                Ok(()) => 0,
+            }
         }
         Commands::Query { .. } => {
             info!(
                 "Pulling API data for {}/{}/{}",
-                cli.hostname,
+                cli.hostname(),
                 cli.service(),
                 cli.creator()
             );
-            if let Err(err) = do_query(cli, &mut client).await {
-                error!("Failed to complete query: {:?}", err);
+            match do_query(cli, &mut client).await {
+                Err(err) => {
+                    error!("Failed to complete query: {:?}", err);
+                    exit_code(&err)
+                }
+                Ok(()) => 0,
+            }
+        }
+        Commands::Download { retry_failed, .. } => {
+            stop_on_signal();
+            let res = match retry_failed {
+                true => {
+                    info!(
+                        "Retrying failed downloads for {}/{}/{}",
+                        cli.hostname(),
+                        cli.service(),
+                        cli.creator()
+                    );
+                    kemono::download::retry_failed(&client, &cli.download_options()).await
+                }
+                false => {
+                    info!(
+                        "Downloading all content for {}/{}/{}",
+                        cli.hostname(),
+                        cli.service(),
+                        cli.creator()
+                    );
+                    download(&mut client, &cli.download_options()).await
+                }
             };
+            match res {
+                Err(err) => {
+                    error!("Failed to complete download: {:?}", err);
+                    exit_code(&err)
+                }
+                Ok(summary) => {
+                    if let Some(webhook) = &cli.notify_webhook {
+                        notify(&client, webhook, &summary).await;
+                    }
+                    match serde_json::to_string(&summary) {
+                        Ok(summary) => println!("{}", summary),
+                        Err(err) => error!("Failed to serialize summary: {:?}", err),
+                    }
+                    if summary.interrupted {
+                        eprintln!("Stopped early, run again to download the rest");
+                        EXIT_INTERRUPTED
+                    } else if summary.failed > 0 {
+                        eprintln!("{} downloads failed", summary.failed);
+                        EXIT_PARTIAL
+                    } else {
+                        0
+                    }
+                }
+            }
         }
-        Commands::Download { .. } => {
+        Commands::Update {
+            watch: true,
+            interval,
+            ..
+        } => {
             info!(
-                "Downloading all content for {}/{}/{}",
-                cli.hostname,
-                cli.service(),
-                cli.creator()
+                "Updating all content in {} every {}",
+                client
+                    .download_path
+                    .clone()
+                    .unwrap_or(DEFAULT_DOWNLOAD_PATH.to_string()),
+                interval
             );
-            if let Err(err) = do_download(cli, &mut client).await {
-                error!("Failed to complete download: {:?}", err);
-            };
+            do_watch(&mut client, &cli, interval).await;
+            0
         }
         Commands::Update { .. } => {
             info!(
@@ -514,10 +1705,651 @@ async fn main() {
                     .unwrap_or(DEFAULT_DOWNLOAD_PATH.to_string()),
                 client.hostname
             );
+            stop_on_signal();
             match do_update(&mut client, &cli).await {
-                Err(err) => eprintln!("Failed to complete update: {:?}", err),
-                Ok(()) => eprintln!("Update complete"),
+                Err(err) => {
+                    eprintln!("Failed to complete update: {:?}", err);
+                    exit_code(&err)
+                }
+                Ok(summary) => {
+                    match serde_json::to_string(&summary) {
+                        Ok(summary) => println!("{}", summary),
+                        Err(err) => error!("Failed to serialize summary: {:?}", err),
+                    }
+                    if stop_requested() {
+                        eprintln!("Update stopped early, run again to download the rest");
+                        EXIT_INTERRUPTED
+                    } else if summary.rate_limited {
+                        eprintln!("Update rate limited, run again later to download the rest");
+                        EXIT_RATE_LIMITED
+                    } else if summary.total.failed > 0 || !summary.errors.is_empty() {
+                        eprintln!(
+                            "Update complete, {} downloads failed, {} creators failed",
+                            summary.total.failed,
+                            summary.errors.len()
+                        );
+                        EXIT_PARTIAL
+                    } else {
+                        if log_enabled!(Level::Info) {
+                            eprintln!("Update complete");
+                        }
+                        0
+                    }
+                }
+            }
+        }
+        Commands::Watch { interval, .. } => {
+            info!(
+                "Watching {}/{}/{} every {}",
+                cli.hostname(),
+                cli.service(),
+                cli.creator(),
+                interval
+            );
+            do_watch(&mut client, &cli, interval).await;
+            0
+        }
+        Commands::MigrateLayout {
+            from, to, dry_run, ..
+        } => match do_migrate_layout(&client, from, to, dry_run).await {
+            Ok(moved) => {
+                eprintln!("Migrated {} directories from {} to {}", moved, from, to);
+                0
+            }
+            Err(err) => {
+                eprintln!("Failed to migrate layout: {:?}", err);
+                exit_code(&err)
+            }
+        },
+        Commands::Favorite {
+            ref post_id,
+            remove,
+            ..
+        } => {
+            let (service, creator) = (cli.service(), cli.creator());
+            let res = match remove {
+                true => client.unfavorite_post(&service, &creator, post_id).await,
+                false => client.favorite_post(&service, &creator, post_id).await,
             };
+            match res {
+                Err(KemonoError::Unauthorized) => {
+                    eprintln!("Changing favorites needs a username and password");
+                    EXIT_FAILED
+                }
+                Err(err) => {
+                    eprintln!("Failed to change favorite: {:?}", err);
+                    exit_code(&err)
+                }
+                Ok(()) => {
+                    print_action(
+                        match remove {
+                            true => "unfavorite",
+                            false => "favorite",
+                        },
+                        json!({"service": service, "creator": creator, "post_id": post_id}),
+                    );
+                    0
+                }
+            }
+        }
+        Commands::Account { .. } => match client.account().await {
+            Err(KemonoError::Unauthorized) => {
+                eprintln!(
+                    "Not logged in, set KEMONO_SESSION, or KEMONO_USERNAME and KEMONO_PASSWORD"
+                );
+                EXIT_FAILED
+            }
+            Err(err) => {
+                eprintln!("Failed to get the account: {:?}", err);
+                exit_code(&err)
+            }
+            Ok(account) => print_json(&account),
+        },
+        Commands::Favorites { .. } => match client.favorites().await {
+            Err(KemonoError::Unauthorized) => {
+                eprintln!("Listing favorites needs a username and password");
+                EXIT_FAILED
+            }
+            Err(err) => {
+                eprintln!("Failed to list favorites: {:?}", err);
+                exit_code(&err)
+            }
+            Ok(favorites) => print_json(&favorites),
+        },
+        Commands::ListCreators { ref search, .. } => {
+            let creators = match search {
+                Some(search) => client.search_creators(search).await,
+                None => client.creators().await,
+            };
+            match creators {
+                Err(err) => {
+                    eprintln!("Failed to list creators: {:?}", err);
+                    exit_code(&err)
+                }
+                Ok(creators) => print_json(&creators),
+            }
+        }
+        Commands::Export {
+            format,
+            ref output,
+            ref extensions,
+            ref since,
+            ref until,
+            force,
+            ..
+        } => {
+            let filter = ArchiveFilter {
+                extensions: extensions.clone(),
+                since: since.clone(),
+                until: until.clone(),
+            };
+            let options = cli.download_options().resolve_creator_name(&client).await;
+            match export_archive(&client, &options, output, format, &filter, force) {
+                Err(err) => {
+                    eprintln!("Failed to export: {}", err);
+                    exit_code(&err)
+                }
+                Ok(summary) => match serde_json::to_string(&summary) {
+                    Ok(summary) => {
+                        println!("{}", summary);
+                        0
+                    }
+                    Err(err) => {
+                        error!("Failed to serialize summary: {:?}", err);
+                        EXIT_FAILED
+                    }
+                },
+            }
+        }
+        Commands::Prune {
+            delete,
+            hard_delete,
+            ..
+        } => {
+            let mode = match (delete, hard_delete) {
+                (false, _) => PruneMode::Report,
+                (true, false) => PruneMode::Trash,
+                (true, true) => PruneMode::Delete,
+            };
+            info!(
+                "Pruning {}/{}/{} ({:?})",
+                cli.hostname(),
+                cli.service(),
+                cli.creator(),
+                mode
+            );
+            match do_prune(&mut client, &cli, mode).await {
+                Err(err) => {
+                    eprintln!("Failed to complete prune: {:?}", err);
+                    exit_code(&err)
+                }
+                Ok(_) => 0,
+            }
+        }
+        Commands::Missing { .. } => {
+            info!(
+                "Checking for missing files from {}/{}/{}",
+                cli.hostname(),
+                cli.service(),
+                cli.creator()
+            );
+            match do_missing(&mut client, &cli).await {
+                Ok(missing) if missing.is_empty() => 0,
+                Ok(_) => EXIT_MISSING,
+                Err(err) => {
+                    eprintln!("Failed to check for missing files: {}", err);
+                    exit_code(&err)
+                }
+            }
         }
+        // handled before the config is loaded
+        Commands::Config { .. } => 0,
+        Commands::Dedup { .. } => {
+            let base_path = PathBuf::from(client.get_base_download_path());
+            info!("Deduplicating {}", base_path.display());
+            match dedup_archive(&base_path) {
+                Err(err) => {
+                    eprintln!("Failed to complete dedup: {:?}", err);
+                    exit_code(&err)
+                }
+                Ok(summary) => match serde_json::to_string(&summary) {
+                    Ok(summary) => {
+                        println!("{}", summary);
+                        0
+                    }
+                    Err(err) => {
+                        error!("Failed to serialize summary: {:?}", err);
+                        EXIT_FAILED
+                    }
+                },
+            }
+        }
+    };
+    exit_releasing_lock(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_list() {
+        assert!(matches_list("", "12345"));
+        assert!(matches_list(" , ", "12345"));
+        assert!(matches_list("12345", "12345"));
+        assert!(matches_list("Patreon", "patreon"));
+        assert!(matches_list("fanbox, PATREON ", "patreon"));
+        assert!(!matches_list("fanbox,gumroad", "patreon"));
+    }
+
+    #[test]
+    fn test_log_level() {
+        let cli = CliOpts::parse_from(["kemono", "dedup"]);
+        assert_eq!(cli.log_level(), LevelFilter::Info);
+        let cli = CliOpts::parse_from(["kemono", "--debug", "dedup"]);
+        assert_eq!(cli.log_level(), LevelFilter::Debug);
+        let cli = CliOpts::parse_from(["kemono", "--debug", "--log-level", "trace", "dedup"]);
+        assert_eq!(cli.log_level(), LevelFilter::Trace);
+        let cli = CliOpts::parse_from(["kemono", "--quiet", "dedup"]);
+        assert_eq!(cli.log_level(), LevelFilter::Error);
+        assert!(CliOpts::try_parse_from(["kemono", "--quiet", "--debug", "dedup"]).is_err());
+        assert!(CliOpts::try_parse_from(["kemono", "--log-level", "loud", "dedup"]).is_err());
+    }
+
+    /// Parses the options and loads the config the way main does
+    fn load(args: &[&str]) -> Result<CliOpts, KemonoError> {
+        let matches = CliOpts::command().get_matches_from(args);
+        let mut cli = CliOpts::from_arg_matches(&matches).expect("Failed to parse options");
+        cli.load_config(&matches)?;
+        Ok(cli)
+    }
+
+    #[test]
+    fn test_post_window() {
+        let cli = load(&[
+            "kemono",
+            "-H",
+            "example.com",
+            "download",
+            "12345",
+            "patreon",
+            "--offset",
+            "520",
+            "--max-posts",
+            "100",
+        ])
+        .expect("Failed to load config");
+        let options = cli.download_options();
+        assert_eq!(options.offset, 520);
+        assert_eq!(options.max_posts, Some(100));
+        assert!(options.windowed());
+
+        let options = load(&[
+            "kemono",
+            "-H",
+            "example.com",
+            "query",
+            "12345",
+            "patreon",
+            "--limit",
+            "10",
+        ])
+        .expect("Failed to load config")
+        .download_options();
+        assert_eq!(options.max_posts, Some(10));
+
+        let options = load(&["kemono", "-H", "example.com", "query", "12345", "patreon"])
+            .expect("Failed to load config")
+            .download_options();
+        assert_eq!(options.offset, 0);
+        assert!(!options.windowed());
+    }
+
+    #[test]
+    fn test_platform_hostname() {
+        let cli =
+            load(&["kemono", "--platform", "coomer", "dedup"]).expect("Failed to load config");
+        assert_eq!(cli.hostname(), "coomer.su");
+        let cli = load(&[
+            "kemono",
+            "--platform",
+            "coomer",
+            "-H",
+            "example.com",
+            "dedup",
+        ])
+        .expect("Failed to load config");
+        assert_eq!(cli.hostname(), "example.com");
+        let cli = load(&["kemono", "--site", "coomer", "dedup"]).expect("Failed to load config");
+        assert_eq!(cli.hostname(), "coomer.su");
+    }
+
+    #[tokio::test]
+    async fn test_update_targets() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // every creator is empty, so each listed one should be attempted and reported
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Vec::<Post>::new()))
+            .mount(&server)
+            .await;
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = tmp.path().join("targets");
+        std::fs::create_dir_all(&dir).expect("Failed to create test dir");
+        let targets = dir.join("targets.txt");
+        std::fs::write(
+            &targets,
+            "# subscriptions\npatreon 12345\nfanbox\nfanbox 67890\n",
+        )
+        .expect("Failed to write targets");
+
+        let cli = CliOpts::parse_from([
+            "kemono",
+            "-H",
+            &server.address().to_string(),
+            "-D",
+            &dir.join("download").to_string_lossy(),
+            "update",
+            "--targets",
+            &targets.to_string_lossy(),
+        ]);
+        let mut client = KemonoClient::new(&cli.hostname(), cli.download_path.clone());
+        client.scheme = "http".to_string();
+        let summary = do_update(&mut client, &cli).await;
+
+        let summary = summary.expect("Update failed");
+        assert!(summary.creators.is_empty());
+        let mut errors = summary.errors.clone();
+        errors.sort();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("12345/patreon: "));
+        assert!(errors[1].starts_with("67890/fanbox: "));
+    }
+
+    #[tokio::test]
+    async fn test_update_rate_limited() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429))
+            .mount(&server)
+            .await;
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = tmp.path().join("update-429");
+        std::fs::create_dir_all(&dir).expect("Failed to create test dir");
+        let targets = dir.join("targets.txt");
+        std::fs::write(
+            &targets,
+            "patreon 12345
+fanbox 67890
+",
+        )
+        .expect("Failed to write targets");
+
+        let cli = CliOpts::parse_from([
+            "kemono",
+            "-H",
+            &server.address().to_string(),
+            "-D",
+            &dir.join("download").to_string_lossy(),
+            "update",
+            "--parallel-creators",
+            "1",
+            "--targets",
+            &targets.to_string_lossy(),
+        ]);
+        let mut client = KemonoClient::new(&cli.hostname(), cli.download_path.clone());
+        client.scheme = "http".to_string();
+        let summary = do_update(&mut client, &cli).await;
+
+        // the summary still comes back, with the first creator's error and the second not started
+        let summary = summary.expect("Update failed");
+        assert!(summary.rate_limited);
+        assert_eq!(summary.errors.len(), 1);
+        assert_eq!(summary.skipped.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&summary).expect("Failed to serialize")["rate_limited"],
+            true
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prune_bad_post() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mut page: Vec<serde_json::Value> =
+            serde_json::from_str(include_str!("../test_data.json")).expect("Invalid test data");
+        page[1]["title"] = serde_json::Value::Null;
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/patreon/user/12345"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&page))
+            .mount(&server)
+            .await;
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+
+        let cli = CliOpts::parse_from([
+            "kemono",
+            "-H",
+            &server.address().to_string(),
+            "-D",
+            &tmp.path().to_string_lossy(),
+            "prune",
+            "12345",
+            "patreon",
+        ]);
+        let mut client = KemonoClient::new(&cli.hostname(), cli.download_path.clone());
+        client.scheme = "http".to_string();
+        let res = do_prune(&mut client, &cli, PruneMode::Report).await;
+
+        // the bad post's files would look like orphans, so it mustn't be skipped
+        let err = res.expect_err("Prune should fail on a bad post");
+        assert!(err.to_string().contains("failed to parse"), "{}", err);
+    }
+
+    #[test]
+    fn test_watch_interval() {
+        let interval = |args: &[&str]| match CliOpts::parse_from(args).command {
+            Commands::Watch { interval, .. } => interval,
+            _ => panic!("Expected watch"),
+        };
+        assert_eq!(
+            interval(&["kemono", "watch", "12345", "patreon"]).to_string(),
+            "15m"
+        );
+        // plain numbers are still seconds
+        assert_eq!(
+            interval(&["kemono", "watch", "12345", "patreon", "--interval", "90"]).0,
+            Duration::from_secs(90)
+        );
+    }
+
+    #[test]
+    fn test_config_file() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = tmp.path().join("config.toml");
+        std::fs::write(
+            &path,
+            r#"
+hostname = "file.example"
+threads = 4
+mkvs = true
+layout = "service-creator"
+
+[creators]
+patreon = ["12345"]
+"#,
+        )
+        .expect("Failed to write config");
+        let config = path.to_string_lossy().to_string();
+
+        let cli = load(&["kemono", "--config", &config, "dedup"]);
+        let overridden = load(&[
+            "kemono",
+            "--config",
+            &config,
+            "-H",
+            "cli.example",
+            "--threads",
+            "8",
+            "--layout",
+            "creator-service",
+            "dedup",
+        ]);
+
+        let cli = cli.expect("Failed to load config");
+        assert_eq!(cli.hostname(), "file.example");
+        assert_eq!(cli.threads, Some(4));
+        assert!(cli.mkvs);
+        assert_eq!(cli.layout, Layout::ServiceCreator);
+        assert_eq!(
+            cli.creators,
+            vec![("patreon".to_string(), "12345".to_string())]
+        );
+        // the command line wins
+        let cli = overridden.expect("Failed to load config");
+        assert_eq!(cli.hostname(), "cli.example");
+        assert_eq!(cli.threads, Some(8));
+        assert_eq!(cli.layout, Layout::CreatorService);
+        assert!(cli.mkvs);
+
+        assert!(load(&["kemono", "--config", "/nonexistent/kemono.toml", "dedup"]).is_err());
+        assert!(CliOpts::try_parse_from(["kemono", "--threads", "0", "dedup"]).is_err());
+    }
+
+    #[test]
+    fn test_force_flags() {
+        let cli = CliOpts::parse_from([
+            "kemono",
+            "download",
+            "12345",
+            "patreon",
+            "--force",
+            "--post-id",
+            "42",
+        ]);
+        let options = cli.download_options();
+        assert!(options.force);
+        assert_eq!(options.post_id, Some("42".to_string()));
+        let options = CliOpts::parse_from([
+            "kemono",
+            "download",
+            "12345",
+            "patreon",
+            "--since-post-id",
+            "41",
+        ])
+        .download_options();
+        assert_eq!(options.since_post_id, Some("41".to_string()));
+        assert!(CliOpts::try_parse_from(["kemono", "--since-post-id", "41", "update"]).is_err());
+        assert!(CliOpts::try_parse_from(["kemono", "update", "--since-post-id", "41"]).is_err());
+        assert!(
+            !CliOpts::parse_from(["kemono", "update"])
+                .download_options()
+                .force
+        );
+    }
+
+    #[test]
+    fn test_filename_flags() {
+        let cli = CliOpts::parse_from([
+            "kemono",
+            "-f",
+            "foo",
+            "--filename-regex",
+            r"\.png$",
+            "--filename-regex",
+            r"\.gif$",
+            "--exclude-filename-regex",
+            "preview",
+            "update",
+        ]);
+        let filter = cli.download_options().filename;
+        assert_eq!(filter.contains, vec!["foo".to_string()]);
+        assert_eq!(filter.patterns.len(), 2);
+        assert!(filter.matches("a.gif"));
+        assert!(!filter.matches("preview.png"));
+        let err = CliOpts::try_parse_from(["kemono", "--filename-regex", "(", "update"])
+            .err()
+            .expect("Invalid regex parsed");
+        assert!(err.to_string().contains("regex parse error"));
+    }
+
+    #[test]
+    fn test_query_flags() {
+        let cli = CliOpts::parse_from(["kemono", "query", "patreon", "1234"]);
+        assert_eq!(cli.output_format(), OutputFormat::Jsonl);
+        let cli = CliOpts::parse_from([
+            "kemono",
+            "query",
+            "patreon",
+            "1234",
+            "--format",
+            "csv",
+            "--fields",
+            "id,title,attachments",
+        ]);
+        assert_eq!(cli.output_format(), OutputFormat::Csv);
+        assert!(matches!(
+            cli.command,
+            Commands::Query { ref fields, .. } if fields == &["id", "title", "attachments"]
+        ));
+        let cli = CliOpts::parse_from([
+            "kemono",
+            "--tag",
+            "Series A",
+            "--tag",
+            "series b",
+            "--exclude-tag",
+            "sketch",
+            "query",
+            "patreon",
+            "1234",
+        ]);
+        assert_eq!(
+            cli.download_options().tags,
+            TagFilter {
+                include: vec!["Series A".to_string(), "series b".to_string()],
+                exclude: vec!["sketch".to_string()],
+            }
+        );
+        let cli = CliOpts::parse_from(["kemono", "query", "patreon", "1234", "--ndjson"]);
+        assert_eq!(cli.output_format(), OutputFormat::Jsonl);
+        assert!(CliOpts::try_parse_from([
+            "kemono", "query", "patreon", "1234", "--fields", "id,nope"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_profile_images_flag() {
+        let cli = CliOpts::parse_from(["kemono", "update"]);
+        assert!(cli.download_options().profile_images);
+        let cli = CliOpts::parse_from(["kemono", "--no-profile-images", "update"]);
+        assert!(!cli.download_options().profile_images);
+        let cli = CliOpts::parse_from([
+            "kemono",
+            "--no-profile-images",
+            "--profile-images",
+            "update",
+        ]);
+        assert!(cli.download_options().profile_images);
+    }
+
+    #[test]
+    fn test_metadata_flags_conflict() {
+        assert!(
+            CliOpts::try_parse_from(["kemono", "--no-metadata", "--metadata-only", "update"])
+                .is_err()
+        );
+        let cli = CliOpts::parse_from(["kemono", "--skip-metadata", "update"]);
+        assert!(cli.download_options().no_metadata);
+        let cli = CliOpts::parse_from(["kemono", "--metadata-only", "update"]);
+        assert!(cli.download_options().metadata_only);
     }
 }
@@ -0,0 +1,467 @@
+//! A `manifest.json` per creator/service listing everything downloaded locally.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::download::{
+    collect_files, converted_relative_path, download_relative_path, DownloadOptions,
+};
+use crate::errors::KemonoError;
+use crate::util::write_atomic;
+use crate::{Attachment, Post};
+
+pub static MANIFEST_FILENAME: &str = "manifest.json";
+pub static SHA256SUMS_FILENAME: &str = "SHA256SUMS";
+
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct ManifestEntry {
+    pub post_id: String,
+    pub published: String,
+    pub filename: String,
+    pub sha256: String,
+    /// The attachment's path on the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+/// The `ETag` and `Last-Modified` the server sent with a file, so next time we can ask whether it's changed
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Validator {
+    /// Where the file was saved, relative to the creator/service directory
+    pub filename: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Validators by attachment path, shared between the download threads
+#[derive(Debug, Default)]
+pub struct Validators(Mutex<HashMap<String, Validator>>);
+
+impl Validators {
+    pub fn from_manifest(entries: &[ManifestEntry]) -> Self {
+        Self(Mutex::new(
+            entries
+                .iter()
+                .filter(|entry| entry.etag.is_some() || entry.last_modified.is_some())
+                .filter_map(|entry| {
+                    let validator = Validator {
+                        filename: entry.filename.clone(),
+                        etag: entry.etag.clone(),
+                        last_modified: entry.last_modified.clone(),
+                    };
+                    Some((entry.path.clone()?, validator))
+                })
+                .collect(),
+        ))
+    }
+
+    pub fn get(&self, path: &str) -> Option<Validator> {
+        self.0.lock().ok()?.get(path).cloned()
+    }
+
+    pub fn insert(&self, path: &str, validator: Validator) {
+        if let Ok(mut validators) = self.0.lock() {
+            validators.insert(path.to_string(), validator);
+        }
+    }
+}
+
+/// Returns the hex-encoded SHA256 of a file
+pub fn sha256_file(path: &Path) -> Result<String, KemonoError> {
+    let mut file = File::open(path).map_err(|err| {
+        KemonoError::from(format!("Failed to open {}: {:?}", path.display(), err))
+    })?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(|err| {
+        KemonoError::from(format!("Failed to hash {}: {:?}", path.display(), err))
+    })?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Files are stored by hash, eg `/5c/50/5c50c6b8...8fee7.jpg`, so pull the SHA256 out of the attachment's path
+pub fn hash_from_path(attachment: &Attachment) -> Option<String> {
+    let stem = Path::new(attachment.path.as_deref()?)
+        .file_stem()?
+        .to_str()?;
+    match stem.len() == 64 && stem.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Some(stem.to_lowercase()),
+        false => None,
+    }
+}
+
+/// Hashes a downloaded file, logging an error if it doesn't match the hash in the attachment's path as it's probably
+/// been corrupted
+pub fn checked_sha256(path: &Path, attachment: &Attachment) -> Result<String, KemonoError> {
+    let sha256 = sha256_file(path)?;
+    if let Some(expected) = hash_from_path(attachment).filter(|expected| *expected != sha256) {
+        error!(
+            "{} has hash {} but the server has {}, it might be corrupt",
+            path.display(),
+            sha256,
+            expected
+        );
+    }
+    Ok(sha256)
+}
+
+/// Adds `{hash}  {filename}` lines to `SHA256SUMS` in `target_dir` for the files sent to it, once all the senders are
+/// dropped. A file which is already listed, eg because it was repaired, has its line replaced so `sha256sum -c` still
+/// passes.
+///
+/// Having a single writer means parallel downloads can't interleave their lines. Returns the number of lines written.
+pub fn sha256sums_writer(
+    target_dir: &Path,
+    receiver: Receiver<(String, String)>,
+) -> Result<usize, KemonoError> {
+    // keyed by filename, so a file that's sent twice only keeps its last hash
+    let sums: HashMap<String, String> = receiver
+        .into_iter()
+        .map(|(hash, filename)| (filename, hash))
+        .collect();
+    if sums.is_empty() {
+        return Ok(0);
+    }
+    let path = target_dir.join(SHA256SUMS_FILENAME);
+    let existing = match std::fs::read_to_string(&path) {
+        Ok(existing) => existing,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            return Err(KemonoError::from(format!(
+                "Failed to read SHA256SUMS: {:?}",
+                err
+            )))
+        }
+    };
+    let mut contents: String = existing
+        .lines()
+        .filter(|line| {
+            line.split_once("  ")
+                .is_none_or(|(_, filename)| !sums.contains_key(filename))
+        })
+        .map(|line| format!("{}\n", line))
+        .collect();
+    let mut filenames: Vec<&String> = sums.keys().collect();
+    filenames.sort();
+    for filename in filenames {
+        contents.push_str(&format!("{}  {}\n", sums[filename], filename));
+    }
+    std::fs::create_dir_all(target_dir)
+        .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    write_atomic(&path, contents)
+        .map_err(|err| KemonoError::from(format!("Failed to write SHA256SUMS: {:?}", err)))?;
+    Ok(sums.len())
+}
+
+/// Reads the existing manifest entries, if there are any
+pub fn read_manifest(target_dir: &Path) -> Result<Vec<ManifestEntry>, KemonoError> {
+    let path = target_dir.join(MANIFEST_FILENAME);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = std::fs::read_to_string(&path).map_err(|err| {
+        KemonoError::from(format!(
+            "Failed to read manifest {}: {:?}",
+            path.display(),
+            err
+        ))
+    })?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+/// Writes `manifest.json` in the creator/service's `target_dir`, appending any downloaded files which aren't listed yet.
+///
+/// Files in `written` were downloaded this run, so ones which were already listed, eg repaired or forced downloads,
+/// get their hashes refreshed.
+pub fn write_manifest(
+    target_dir: &Path,
+    options: &DownloadOptions,
+    posts: &[Post],
+    validators: &Validators,
+    written: &HashSet<String>,
+) -> Result<(), KemonoError> {
+    let (service, creator) = (&options.service, &options.creator);
+    let mut entries = read_manifest(target_dir)?;
+    let known: HashSet<String> = entries.iter().map(|e| e.filename.clone()).collect();
+
+    let mut added = 0;
+    // pick up validators from this run for files we already knew about
+    for entry in entries.iter_mut() {
+        let Some(validator) = entry.path.as_deref().and_then(|path| validators.get(path)) else {
+            continue;
+        };
+        if validator.filename == entry.filename
+            && (entry.etag != validator.etag || entry.last_modified != validator.last_modified)
+        {
+            entry.etag = validator.etag;
+            entry.last_modified = validator.last_modified;
+            added += 1;
+        }
+    }
+    // the file could be flat or in its post's directory, and we might have a remuxed copy instead of the original
+    let layouts = [false, true].map(|post_dirs| DownloadOptions {
+        post_dirs,
+        ..options.clone()
+    });
+    for (post, attachment) in collect_files(posts) {
+        let filename = layouts
+            .iter()
+            .flat_map(|options| {
+                let filename = download_relative_path(&post, &attachment, options).ok();
+                let converted = converted_relative_path(&post, &attachment, options)
+                    .ok()
+                    .flatten();
+                filename.into_iter().chain(converted)
+            })
+            .find(|filename| target_dir.join(filename).exists());
+        let Some(filename) = filename else {
+            continue;
+        };
+        let filepath = target_dir.join(&filename);
+        if known.contains(&filename) {
+            if written.contains(&filename) {
+                let sha256 = checked_sha256(&filepath, &attachment)?;
+                if let Some(entry) = entries
+                    .iter_mut()
+                    .find(|entry| entry.filename == filename && entry.sha256 != sha256)
+                {
+                    entry.sha256 = sha256;
+                    added += 1;
+                }
+            }
+            continue;
+        }
+        let validator = attachment
+            .path
+            .as_deref()
+            .and_then(|path| validators.get(path))
+            .unwrap_or_default();
+        entries.push(ManifestEntry {
+            post_id: post.id.clone(),
+            published: post.published.clone(),
+            sha256: checked_sha256(&filepath, &attachment)?,
+            filename,
+            path: attachment.path.clone(),
+            etag: validator.etag,
+            last_modified: validator.last_modified,
+        });
+        added += 1;
+    }
+    debug!(
+        "Adding or updating {} entries in the manifest for {}/{}",
+        added, service, creator
+    );
+    if added == 0 && target_dir.join(MANIFEST_FILENAME).exists() {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(target_dir)
+        .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+    write_atomic(
+        target_dir.join(MANIFEST_FILENAME),
+        serde_json::to_string_pretty(&entries)?,
+    )
+    .map_err(|err| KemonoError::from(format!("Failed to write manifest: {:?}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_from_path() {
+        let attachment = |path: &str| Attachment {
+            name: None,
+            path: Some(path.to_string()),
+        };
+        assert_eq!(
+            hash_from_path(&attachment(
+                "/5c/50/5c50c6b8935398de2a6d82ee436f28a595e81b33d825b895a57c92314ad8fee7.jpg"
+            )),
+            Some("5c50c6b8935398de2a6d82ee436f28a595e81b33d825b895a57c92314ad8fee7".to_string())
+        );
+        assert_eq!(hash_from_path(&attachment("/data/foo.jpg")), None);
+    }
+
+    #[test]
+    fn test_checked_sha256() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = tmp.path().join("file.txt");
+        std::fs::write(&path, "hello world").expect("Failed to write file");
+        let good = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let attachment = |hash: &str| Attachment {
+            name: Some("file.txt".to_string()),
+            path: Some(format!("/b9/4d/{}.txt", hash)),
+        };
+        assert_eq!(
+            checked_sha256(&path, &attachment(good)).expect("Failed to hash"),
+            good
+        );
+        // a corrupt file gets its own hash, not the one it should have had
+        std::fs::write(&path, "hello w0rld").expect("Failed to write file");
+        let corrupt = checked_sha256(&path, &attachment(good)).expect("Failed to hash");
+        assert_ne!(corrupt, good);
+        assert_eq!(corrupt, sha256_file(&path).expect("Failed to hash"));
+    }
+
+    #[test]
+    fn test_sha256sums_writer() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let target_dir = tmp.path().join("sha256sums");
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let writer = {
+            let target_dir = target_dir.clone();
+            std::thread::spawn(move || sha256sums_writer(&target_dir, receiver))
+        };
+        sender
+            .send(("aaaa".to_string(), "one.jpg".to_string()))
+            .expect("Failed to send");
+        sender
+            .send(("bbbb".to_string(), "two.jpg".to_string()))
+            .expect("Failed to send");
+        drop(sender);
+        let written = writer.join().expect("Writer panicked");
+        let contents = std::fs::read_to_string(target_dir.join(SHA256SUMS_FILENAME));
+
+        // a repaired file replaces its line rather than adding another
+        let (sender, receiver) = std::sync::mpsc::channel();
+        sender
+            .send(("cccc".to_string(), "one.jpg".to_string()))
+            .expect("Failed to send");
+        drop(sender);
+        let rewritten = sha256sums_writer(&target_dir, receiver);
+        let replaced = std::fs::read_to_string(target_dir.join(SHA256SUMS_FILENAME));
+
+        assert_eq!(written.expect("Failed to write"), 2);
+        assert_eq!(
+            contents.expect("Failed to read SHA256SUMS"),
+            "aaaa  one.jpg\nbbbb  two.jpg\n"
+        );
+        assert_eq!(rewritten.expect("Failed to write"), 1);
+        assert_eq!(
+            replaced.expect("Failed to read SHA256SUMS"),
+            "bbbb  two.jpg\ncccc  one.jpg\n"
+        );
+    }
+
+    #[test]
+    fn test_write_manifest() {
+        let posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("manifest");
+        let target_dir = download_path.join("12345").join("patreon");
+        std::fs::create_dir_all(&target_dir).expect("Failed to create test dir");
+
+        let (post, attachment) = collect_files(&posts).remove(0);
+        let options = DownloadOptions::new("patreon", "12345");
+        let filename =
+            download_relative_path(&post, &attachment, &options).expect("Failed to get path");
+        std::fs::write(target_dir.join(&filename), "hello").expect("Failed to write test file");
+
+        write_manifest(
+            &target_dir,
+            &options,
+            &posts,
+            &Validators::default(),
+            &HashSet::new(),
+        )
+        .expect("Failed to write manifest");
+        // a second run shouldn't duplicate entries, but does pick up new validators
+        let validators = Validators::default();
+        validators.insert(
+            attachment
+                .path
+                .as_deref()
+                .expect("No path in test attachment"),
+            Validator {
+                filename: filename.clone(),
+                etag: Some("\"abc\"".to_string()),
+                last_modified: None,
+            },
+        );
+        write_manifest(&target_dir, &options, &posts, &validators, &HashSet::new())
+            .expect("Failed to write manifest");
+        let entries = read_manifest(&target_dir).expect("Failed to read manifest");
+
+        // a repaired file gets its hash refreshed
+        std::fs::write(target_dir.join(&filename), "fixed").expect("Failed to repair test file");
+        write_manifest(
+            &target_dir,
+            &options,
+            &posts,
+            &validators,
+            &HashSet::from([filename.clone()]),
+        )
+        .expect("Failed to write manifest");
+        let repaired = read_manifest(&target_dir).expect("Failed to read manifest");
+        let repaired_sha256 = sha256_file(&target_dir.join(&filename));
+        assert_eq!(repaired.len(), 1);
+        assert_eq!(
+            repaired[0].sha256,
+            repaired_sha256.expect("Failed to hash test file")
+        );
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].post_id, post.id);
+        assert_eq!(
+            entries[0].sha256,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(entries[0].etag, Some("\"abc\"".to_string()));
+        assert_eq!(
+            Validators::from_manifest(&entries).get(attachment.path.as_deref().unwrap()),
+            validators.get(attachment.path.as_deref().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_manifest_converted_extensions() {
+        let posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let download_path = tmp.path().join("manifest-converted");
+        let target_dir = download_path.join("12345").join("patreon");
+        std::fs::create_dir_all(&target_dir).expect("Failed to create test dir");
+
+        let (post, attachment) = collect_files(&posts).remove(0);
+        let mut options = DownloadOptions::new("patreon", "12345");
+        let filename =
+            download_relative_path(&post, &attachment, &options).expect("Failed to get path");
+        let (stem, extension) = filename.rsplit_once('.').expect("No extension");
+        // only the remuxed copy's on disk, with a mapping which isn't one of the defaults
+        options.converted_extensions = format!("{}=remux", extension)
+            .parse()
+            .expect("Failed to parse converted extensions");
+        let converted = format!("{}.remux", stem);
+        std::fs::write(target_dir.join(&converted), "hello").expect("Failed to write test file");
+
+        let res = write_manifest(
+            &target_dir,
+            &options,
+            &posts,
+            &Validators::default(),
+            &HashSet::new(),
+        );
+        let entries = read_manifest(&target_dir);
+
+        res.expect("Failed to write manifest");
+        let entries = entries.expect("Failed to read manifest");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].filename, converted);
+    }
+}
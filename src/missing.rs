@@ -0,0 +1,158 @@
+//! Finding the files a download would fetch, without downloading anything.
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::dedup::object_path;
+use crate::download::{
+    collect_files, converted_relative_path, download_relative_path, suspect_reason, thumbnail_path,
+    DownloadOptions,
+};
+use crate::manifest::hash_from_path;
+use crate::{KemonoClient, Post};
+
+/// An attachment which isn't on disk yet
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct MissingFile {
+    pub post_id: String,
+    pub name: String,
+    /// Where the downloader would save it
+    pub path: PathBuf,
+}
+
+/// Whether there's a good copy of the file at `path`, the same check the downloader does before skipping it
+fn present(path: &Path, options: &DownloadOptions) -> bool {
+    path.exists() && suspect_reason(path, options.suspect_threshold).is_none()
+}
+
+/// The attachments of `posts` which a download with `options` would fetch, because there isn't
+/// a good local copy, an mkv of it (with `mkvs`), a thumbnail (with `thumbnails`) or an object to link to (with `dedup`)
+pub fn find_missing(
+    client: &KemonoClient,
+    options: &DownloadOptions,
+    posts: &[Post],
+) -> Vec<MissingFile> {
+    let target_dir = options.target_dir(client);
+    let mut missing = Vec::new();
+    for (post, attachment) in collect_files(posts) {
+        if !attachment.is_complete() || !options.matches_filter(&attachment) {
+            continue;
+        }
+        // anything that can't be named can't be downloaded either
+        let Ok(relative_path) = download_relative_path(&post, &attachment, options) else {
+            continue;
+        };
+        let download_path = target_dir.join(&relative_path);
+        if present(&download_path, options) {
+            continue;
+        }
+        if options.thumbnails
+            && thumbnail_path(&target_dir, &post, &attachment, options)
+                .is_ok_and(|thumbnail| present(&thumbnail, options))
+        {
+            continue;
+        }
+        if let Some(converted) = converted_relative_path(&post, &attachment, options)
+            .ok()
+            .flatten()
+            .filter(|_| options.mkvs)
+        {
+            if present(&target_dir.join(converted), options) {
+                continue;
+            }
+        }
+        if hash_from_path(&attachment)
+            .filter(|_| options.dedup)
+            .is_some_and(|hash| object_path(&options.base_dir(client), &hash).exists())
+        {
+            continue;
+        }
+        missing.push(MissingFile {
+            post_id: post.id,
+            name: attachment.name.unwrap_or_default(),
+            path: download_path,
+        });
+    }
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_mkv(path: &Path) -> PathBuf {
+        PathBuf::from(crate::get_mkv_filename(&path.to_string_lossy()))
+    }
+
+    #[test]
+    fn test_find_missing() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let base_dir = tmp.path().join("missing");
+        let client = KemonoClient::new("example.com", Some(base_dir.to_string_lossy().to_string()));
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.suspect_threshold = None;
+        let mut post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        post.file = crate::Attachment {
+            name: Some("clip.mp4".to_string()),
+            path: Some("/aa/bb/clip.mp4".to_string()),
+        };
+        post.attachments = Some(
+            [
+                crate::Attachment {
+                    name: Some("a.jpg".to_string()),
+                    path: Some("/aa/bb/a.jpg".to_string()),
+                },
+                crate::Attachment {
+                    name: Some("b.jpg".to_string()),
+                    path: Some("/aa/bb/b.jpg".to_string()),
+                },
+            ]
+            .into(),
+        );
+        let posts = vec![post.clone()];
+        let target_dir = options.target_dir(&client);
+        let relative = |name: &str| {
+            let attachment = post
+                .all_attachments()
+                .into_iter()
+                .find(|attachment| attachment.name.as_deref() == Some(name))
+                .cloned()
+                .expect("Missing test attachment");
+            target_dir.join(
+                download_relative_path(&post, &attachment, &options)
+                    .expect("Failed to name test attachment"),
+            )
+        };
+        let names = |missing: Vec<MissingFile>| -> Vec<String> {
+            let mut names: Vec<String> = missing.into_iter().map(|file| file.name).collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(
+            names(find_missing(&client, &options, &posts)),
+            vec!["a.jpg", "b.jpg", "clip.mp4"]
+        );
+
+        std::fs::create_dir_all(relative("a.jpg").parent().expect("No parent"))
+            .expect("Failed to create test dir");
+        std::fs::write(relative("a.jpg"), b"image").expect("Failed to write");
+        std::fs::write(get_mkv(&relative("clip.mp4")), b"video").expect("Failed to write");
+        assert_eq!(
+            names(find_missing(&client, &options, &posts)),
+            vec!["b.jpg", "clip.mp4"]
+        );
+        // the mkv counts as the mp4
+        options.mkvs = true;
+        assert_eq!(
+            names(find_missing(&client, &options, &posts)),
+            vec!["b.jpg"]
+        );
+        // as do the filters
+        options.filename.contains = vec!["a.".to_string()];
+        assert!(find_missing(&client, &options, &posts).is_empty());
+    }
+}
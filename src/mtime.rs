@@ -0,0 +1,128 @@
+//! Setting file modification times from post dates, so sorting a folder by date means something.
+
+use std::fmt::Display;
+use std::fs::{FileTimes, OpenOptions};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use log::{debug, warn};
+use time::macros::format_description;
+use time::PrimitiveDateTime;
+
+use crate::Post;
+
+/// Which timestamp to give downloaded files
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MtimeSource {
+    /// When the creator published the post
+    #[default]
+    Published,
+    /// When the post was added to the archive
+    Added,
+    /// Leave it as the time we downloaded it
+    Now,
+}
+
+impl FromStr for MtimeSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "published" => Ok(MtimeSource::Published),
+            "added" => Ok(MtimeSource::Added),
+            "now" => Ok(MtimeSource::Now),
+            _ => Err(format!(
+                "Invalid mtime source '{}', expected published, added or now",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for MtimeSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MtimeSource::Published => write!(f, "published"),
+            MtimeSource::Added => write!(f, "added"),
+            MtimeSource::Now => write!(f, "now"),
+        }
+    }
+}
+
+/// Parses the API's timestamps, eg `2023-10-20T14:05:01` or `2023-10-20T04:26:54.507651`, which are in UTC
+pub fn parse_timestamp(timestamp: &str) -> Option<SystemTime> {
+    let format = format_description!(
+        "[year]-[month]-[day]T[hour]:[minute]:[second][optional [.[subsecond]]]"
+    );
+    PrimitiveDateTime::parse(timestamp.trim(), format)
+        .ok()
+        .map(|datetime| datetime.assume_utc().into())
+}
+
+/// Sets the modified and accessed times of `path` from the post, based on `source`.
+///
+/// Failures are logged rather than returned, a wrong mtime isn't worth failing a download over.
+pub fn set_file_times(path: &Path, source: MtimeSource, post: &Post) {
+    let timestamp = match source {
+        MtimeSource::Published => &post.published,
+        MtimeSource::Added => &post.added,
+        MtimeSource::Now => return,
+    };
+    let Some(time) = parse_timestamp(timestamp) else {
+        debug!(
+            "Couldn't parse {} date {:?} for post {}, leaving the mtime of {} alone",
+            source,
+            timestamp,
+            post.id,
+            path.display()
+        );
+        return;
+    };
+    let res = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_times(FileTimes::new().set_accessed(time).set_modified(time)));
+    if let Err(err) = res {
+        warn!("Failed to set the mtime of {}: {:?}", path.display(), err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(
+            parse_timestamp("2023-10-20T14:05:01"),
+            Some(UNIX_EPOCH + Duration::from_secs(1697810701))
+        );
+        assert_eq!(
+            parse_timestamp("2023-10-20T14:05:01.500000"),
+            Some(UNIX_EPOCH + Duration::from_millis(1697810701500))
+        );
+        assert_eq!(parse_timestamp("yesterday"), None);
+        assert_eq!(parse_timestamp(""), None);
+    }
+
+    #[test]
+    fn test_set_file_times() {
+        let mut post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        post.published = "2020-01-02T03:04:05".to_string();
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = tmp.path().join("mtime");
+        std::fs::write(&path, "hello").expect("Failed to write test file");
+
+        set_file_times(&path, MtimeSource::Published, &post);
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified());
+
+        assert_eq!(
+            modified.expect("Failed to get mtime"),
+            parse_timestamp(&post.published).expect("Failed to parse")
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Webhook notifications when a run downloads new files.
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::KemonoError;
+use crate::summary::DownloadSummary;
+use crate::KemonoClient;
+
+/// How many filenames a notification lists
+pub static NOTIFY_FILES: usize = 5;
+
+/// What's POSTed to `--notify-webhook` as JSON.
+///
+/// `content` and `text` hold the same human-readable message, so Discord and Slack webhooks can
+/// take it as it is, the rest is there for anything else.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct NotificationPayload {
+    pub content: String,
+    pub text: String,
+    pub service: String,
+    pub creator: String,
+    pub downloaded: usize,
+    pub downloaded_bytes: u64,
+    pub failed: usize,
+    /// The first [NOTIFY_FILES] files which were downloaded
+    pub files: Vec<String>,
+}
+
+impl NotificationPayload {
+    /// The notification for a creator's download, if anything new was downloaded
+    pub fn from_summary(summary: &DownloadSummary) -> Option<Self> {
+        if summary.downloaded == 0 {
+            return None;
+        }
+        let service = summary.service.clone().unwrap_or_default();
+        let creator = summary.creator.clone().unwrap_or_default();
+        let mut message = format!(
+            "Downloaded {} new files ({} bytes) for {}/{}",
+            summary.downloaded, summary.downloaded_bytes, service, creator
+        );
+        if summary.failed > 0 {
+            message.push_str(&format!(", {} failed", summary.failed));
+        }
+        let files: Vec<String> = summary
+            .new_files
+            .iter()
+            .take(NOTIFY_FILES)
+            .cloned()
+            .collect();
+        if !files.is_empty() {
+            message.push_str(&format!(": {}", files.join(", ")));
+        }
+        Some(Self {
+            content: message.clone(),
+            text: message,
+            service,
+            creator,
+            downloaded: summary.downloaded,
+            downloaded_bytes: summary.downloaded_bytes,
+            failed: summary.failed,
+            files,
+        })
+    }
+}
+
+/// POSTs the payload to the webhook
+pub async fn send_notification(
+    client: &KemonoClient,
+    url: &str,
+    payload: &NotificationPayload,
+) -> Result<(), KemonoError> {
+    client
+        .new_async_session()?
+        .post(url)
+        .json(payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Sends a notification for the summary if anything new was downloaded, a webhook that fails is
+/// only logged so it never fails the run
+pub async fn notify(client: &KemonoClient, url: &str, summary: &DownloadSummary) {
+    let Some(payload) = NotificationPayload::from_summary(summary) else {
+        return;
+    };
+    // the URL usually has a token in it, so it's not logged
+    match send_notification(client, url, &payload).await {
+        Ok(()) => debug!(
+            "Sent notification for {}/{}",
+            payload.service, payload.creator
+        ),
+        Err(err) => warn!(
+            "Failed to send notification for {}/{}: {}",
+            payload.service, payload.creator, err
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_summary() -> DownloadSummary {
+        DownloadSummary {
+            downloaded: 7,
+            downloaded_bytes: 1234,
+            new_files: (0..7).map(|i| format!("file{}.png", i)).collect(),
+            ..DownloadSummary::new("patreon", "12345")
+        }
+    }
+
+    #[test]
+    fn test_notification_payload() {
+        assert!(
+            NotificationPayload::from_summary(&DownloadSummary::new("patreon", "12345")).is_none()
+        );
+
+        let payload = NotificationPayload::from_summary(&test_summary())
+            .expect("Should notify about new files");
+        assert_eq!(payload.service, "patreon");
+        assert_eq!(payload.creator, "12345");
+        assert_eq!(payload.downloaded, 7);
+        assert_eq!(payload.downloaded_bytes, 1234);
+        assert_eq!(payload.files.len(), NOTIFY_FILES);
+        assert_eq!(payload.content, payload.text);
+        assert!(payload
+            .content
+            .starts_with("Downloaded 7 new files (1234 bytes) for patreon/12345: file0.png"));
+    }
+
+    #[tokio::test]
+    async fn test_mock_notify() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .and(body_partial_json(
+                serde_json::json!({"service": "patreon", "creator": "12345", "downloaded": 7}),
+            ))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/broken"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let client = KemonoClient::new(&server.address().to_string(), None);
+        let url = format!("{}/hook", server.uri());
+        notify(&client, &url, &test_summary()).await;
+        // nothing new, so nothing's sent
+        notify(&client, &url, &DownloadSummary::new("patreon", "12345")).await;
+        // failures are only logged
+        notify(
+            &client,
+            &format!("{}/broken", server.uri()),
+            &test_summary(),
+        )
+        .await;
+        let payload = NotificationPayload::from_summary(&test_summary()).expect("Should notify");
+        assert!(
+            send_notification(&client, &format!("{}/broken", server.uri()), &payload)
+                .await
+                .is_err()
+        );
+    }
+}
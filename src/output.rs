@@ -0,0 +1,373 @@
+//! Formatting the output of the query commands.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::errors::KemonoError;
+use crate::Post;
+
+/// How to print command output
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    /// One compact JSON object per line
+    Jsonl,
+    Toml,
+    Csv,
+}
+
+/// The post fields which can be picked with `--fields`, `attachment_count` is worked out from the attachments
+pub static POST_FIELDS: &[&str] = &[
+    "id",
+    "user",
+    "service",
+    "title",
+    "content",
+    "embed",
+    "shared_file",
+    "file",
+    "added",
+    "published",
+    "edited",
+    "poll",
+    "captions",
+    "tags",
+    "attachments",
+    "attachment_count",
+];
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" | "ndjson" => Ok(OutputFormat::Jsonl),
+            "toml" => Ok(OutputFormat::Toml),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!(
+                "Invalid output format '{}', expected json, jsonl, toml or csv",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Jsonl => write!(f, "jsonl"),
+            OutputFormat::Toml => write!(f, "toml"),
+            OutputFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+/// TOML documents have to be a table, so posts go in a `[[posts]]` array
+#[derive(Serialize)]
+struct TomlPosts {
+    posts: Vec<Value>,
+}
+
+/// A row of the CSV post listing, the API doesn't tell us file sizes so `total_size` is blank for now
+#[derive(Serialize)]
+struct PostRow<'a> {
+    id: &'a str,
+    published: &'a str,
+    title: &'a str,
+    attachment_count: usize,
+    total_size: Option<u64>,
+}
+
+/// Picks `fields` out of a post, or the whole post if there aren't any
+pub fn select_fields(post: &Post, fields: &[String]) -> Result<Value, KemonoError> {
+    let value = serde_json::to_value(post)?;
+    if fields.is_empty() {
+        return Ok(value);
+    }
+    Ok(Value::Object(
+        fields
+            .iter()
+            .map(|field| {
+                let selected = match field.as_str() {
+                    "attachment_count" => Value::from(post.all_attachments().len()),
+                    _ => value.get(field).cloned().unwrap_or_default(),
+                };
+                (field.clone(), selected)
+            })
+            .collect(),
+    ))
+}
+
+/// TOML has no null, so missing values are left out like serde does for `None`
+fn strip_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, strip_nulls(value)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(strip_nulls).collect()),
+        other => other,
+    }
+}
+
+/// Flattens a field into a CSV cell, `attachments` is the count and `file` is the first file's name
+fn csv_cell(post: &Post, value: &Value, field: &str) -> String {
+    match field {
+        "attachments" | "attachment_count" => post.all_attachments().len().to_string(),
+        "file" => post
+            .all_attachments()
+            .first()
+            .and_then(|attachment| attachment.name.clone())
+            .unwrap_or_default(),
+        _ => match value.get(field) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(cell)) => cell.clone(),
+            Some(Value::Array(values)) if values.iter().all(Value::is_string) => values
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join(", "),
+            Some(other) => other.to_string(),
+        },
+    }
+}
+
+/// Writes rows as CSV with a header line
+pub fn to_csv<T: Serialize>(rows: impl IntoIterator<Item = T>) -> Result<String, KemonoError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer
+            .serialize(row)
+            .map_err(|err| KemonoError::from(format!("Failed to write CSV: {}", err)))?;
+    }
+    let data = writer
+        .into_inner()
+        .map_err(|err| KemonoError::from(format!("Failed to write CSV: {}", err)))?;
+    String::from_utf8(data).map_err(KemonoError::from_stringable)
+}
+
+/// Adds the `action` field to an object, anything else is wrapped as `details`
+fn action_line(action: &str, details: Value) -> Value {
+    let mut line = match details {
+        Value::Object(map) => map,
+        Value::Null => Default::default(),
+        other => [("details".to_string(), other)].into_iter().collect(),
+    };
+    line.insert("action".to_string(), Value::String(action.to_string()));
+    Value::Object(line)
+}
+
+/// Prints a machine-readable action line to stdout, whatever the log level.
+///
+/// Logs go to stderr, so stdout only ever has action lines, summaries and query output on it.
+pub fn print_action(action: &str, details: Value) {
+    println!("{}", action_line(action, details));
+}
+
+impl OutputFormat {
+    /// Renders a list of posts with only `fields` (or everything if it's empty).
+    ///
+    /// JSON is a single array, only pretty-printed if `pretty` is set, and JSONL is an object per line.
+    pub fn posts(
+        &self,
+        posts: &[Post],
+        fields: &[String],
+        pretty: bool,
+    ) -> Result<String, KemonoError> {
+        if *self == OutputFormat::Csv {
+            return Self::posts_csv(posts, fields);
+        }
+        let values = posts
+            .iter()
+            .map(|post| select_fields(post, fields))
+            .collect::<Result<Vec<_>, _>>()?;
+        match self {
+            OutputFormat::Json if pretty => Ok(serde_json::to_string_pretty(&values)?),
+            OutputFormat::Json => Ok(serde_json::to_string(&values)?),
+            OutputFormat::Jsonl => Ok(values
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<Result<Vec<_>, _>>()?
+                .join("\n")),
+            _ => self.value(&TomlPosts {
+                posts: values.into_iter().map(strip_nulls).collect(),
+            }),
+        }
+    }
+
+    fn posts_csv(posts: &[Post], fields: &[String]) -> Result<String, KemonoError> {
+        if fields.is_empty() {
+            return to_csv(posts.iter().map(|post| PostRow {
+                id: &post.id,
+                published: &post.published,
+                title: &post.title,
+                attachment_count: post.all_attachments().len(),
+                total_size: None,
+            }));
+        }
+        let mut rows = vec![fields.to_vec()];
+        for post in posts {
+            let value = serde_json::to_value(post)?;
+            rows.push(
+                fields
+                    .iter()
+                    .map(|field| csv_cell(post, &value, field))
+                    .collect(),
+            );
+        }
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Vec::new());
+        for row in rows {
+            writer
+                .write_record(row)
+                .map_err(|err| KemonoError::from(format!("Failed to write CSV: {}", err)))?;
+        }
+        let data = writer
+            .into_inner()
+            .map_err(|err| KemonoError::from(format!("Failed to write CSV: {}", err)))?;
+        String::from_utf8(data).map_err(KemonoError::from_stringable)
+    }
+
+    /// Renders anything which serializes to a map, CSV needs rows so use [to_csv] for that
+    pub fn value<T: Serialize>(&self, value: &T) -> Result<String, KemonoError> {
+        match self {
+            OutputFormat::Json => Ok(serde_json::to_string_pretty(value)?),
+            OutputFormat::Jsonl => Ok(serde_json::to_string(value)?),
+            OutputFormat::Toml => toml::to_string(value)
+                .map_err(|err| KemonoError::from(format!("Failed to serialize to TOML: {}", err))),
+            OutputFormat::Csv => Err(KemonoError::from_stringable(
+                "CSV output isn't supported here",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toml_posts() {
+        let posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        let output = OutputFormat::Toml
+            .posts(&posts[0..2], &[], false)
+            .expect("Failed to serialize posts");
+        assert_eq!(output.matches("[[posts]]").count(), 2);
+
+        #[derive(serde::Deserialize)]
+        struct Parsed {
+            posts: Vec<Post>,
+        }
+        let parsed: Parsed = toml::from_str(&output).expect("Failed to parse TOML");
+        assert_eq!(parsed.posts[1].id, posts[1].id);
+    }
+
+    #[test]
+    fn test_csv_posts() {
+        let mut posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        posts[0].title = "Hello, world".to_string();
+        let output = OutputFormat::Csv
+            .posts(&posts[0..2], &[], false)
+            .expect("Failed to serialize posts");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "id,published,title,attachment_count,total_size");
+        assert_eq!(
+            lines[1],
+            format!(
+                "{},{},\"Hello, world\",{},",
+                posts[0].id,
+                posts[0].published,
+                posts[0].all_attachments().len()
+            )
+        );
+    }
+
+    #[test]
+    fn test_posts_fields() {
+        let mut posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        posts[0].tags = Some(vec!["a".to_string(), "b".to_string()]);
+        let fields: Vec<String> = ["id", "tags", "attachment_count"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let output = OutputFormat::Json
+            .posts(&posts[0..2], &fields, false)
+            .expect("Failed to serialize posts");
+        let parsed: Vec<Value> = serde_json::from_str(&output).expect("Not a JSON array");
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0]["id"], posts[0].id);
+        assert_eq!(parsed[0]["tags"], serde_json::json!(["a", "b"]));
+        assert_eq!(
+            parsed[0]["attachment_count"],
+            posts[0].all_attachments().len()
+        );
+        assert!(parsed[0].get("title").is_none());
+        assert_eq!(output.lines().count(), 1);
+        assert!(OutputFormat::Json
+            .posts(&posts[0..2], &fields, true)
+            .expect("Failed to serialize posts")
+            .starts_with("[\n"));
+
+        let output = OutputFormat::Jsonl
+            .posts(&posts[0..2], &[], false)
+            .expect("Failed to serialize posts");
+        let lines: Vec<Post> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Not a post"))
+            .collect();
+        assert_eq!(lines, posts[0..2]);
+
+        let fields: Vec<String> = ["id", "tags", "file", "attachments"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let output = OutputFormat::Csv
+            .posts(&posts[0..1], &fields, false)
+            .expect("Failed to serialize posts");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "id,tags,file,attachments");
+        assert_eq!(
+            lines[1],
+            format!(
+                "{},\"a, b\",{},{}",
+                posts[0].id,
+                posts[0].all_attachments()[0]
+                    .name
+                    .clone()
+                    .unwrap_or_default(),
+                posts[0].all_attachments().len()
+            )
+        );
+    }
+
+    #[test]
+    fn test_action_line() {
+        let line = action_line("download", serde_json::json!({"filename": "a.jpg"}));
+        assert_eq!(line["action"], "download");
+        assert_eq!(line["filename"], "a.jpg");
+        let line = action_line("moved", serde_json::json!(3));
+        assert_eq!(line["details"], 3);
+    }
+
+    #[test]
+    fn test_output_format_from_str() {
+        assert_eq!(OutputFormat::from_str("TOML"), Ok(OutputFormat::Toml));
+        assert_eq!(OutputFormat::from_str("json"), Ok(OutputFormat::Json));
+        assert_eq!(OutputFormat::from_str("ndjson"), Ok(OutputFormat::Jsonl));
+        assert!(OutputFormat::from_str("yaml").is_err());
+    }
+}
@@ -0,0 +1,104 @@
+//! The sites which run the same software, so the same client works against any of them.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// A known site, for when you'd rather not remember the hostname
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Platform {
+    #[default]
+    Kemono,
+    Coomer,
+}
+
+impl Platform {
+    pub fn hostname(&self) -> &'static str {
+        match self {
+            Platform::Kemono => "kemono.su",
+            Platform::Coomer => "coomer.su",
+        }
+    }
+
+    /// The services the site archives
+    pub fn services(&self) -> &'static [&'static str] {
+        match self {
+            Platform::Kemono => &[
+                "patreon",
+                "fanbox",
+                "discord",
+                "fantia",
+                "afdian",
+                "boosty",
+                "gumroad",
+                "subscribestar",
+                "dlsite",
+            ],
+            Platform::Coomer => &["onlyfans", "fansly", "candfans"],
+        }
+    }
+
+    /// Which site a hostname belongs to, by whether it mentions kemono or coomer
+    pub fn from_hostname(hostname: &str) -> Option<Self> {
+        let hostname = hostname.to_lowercase();
+        [Platform::Coomer, Platform::Kemono]
+            .into_iter()
+            .find(|platform| hostname.contains(&platform.to_string()))
+    }
+
+    /// Which site archives `service`, if it's one we know about
+    pub fn from_service(service: &str) -> Option<Self> {
+        [Platform::Kemono, Platform::Coomer]
+            .into_iter()
+            .find(|platform| {
+                platform
+                    .services()
+                    .contains(&service.to_lowercase().as_str())
+            })
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "kemono" => Ok(Platform::Kemono),
+            "coomer" => Ok(Platform::Coomer),
+            _ => Err(format!(
+                "Invalid platform '{}', expected kemono or coomer",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Platform::Kemono => write!(f, "kemono"),
+            Platform::Coomer => write!(f, "coomer"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_platform() {
+        assert_eq!(Platform::from_str("Coomer"), Ok(Platform::Coomer));
+        assert!(Platform::from_str("example").is_err());
+        assert_eq!(Platform::Coomer.to_string(), "coomer");
+        assert_eq!(Platform::Coomer.hostname(), "coomer.su");
+        assert_eq!(Platform::from_service("OnlyFans"), Some(Platform::Coomer));
+        assert_eq!(Platform::from_service("patreon"), Some(Platform::Kemono));
+        assert_eq!(Platform::from_service("example"), None);
+        assert_eq!(Platform::from_hostname("Coomer.su"), Some(Platform::Coomer));
+        assert_eq!(
+            Platform::from_hostname("n2.kemono.su"),
+            Some(Platform::Kemono)
+        );
+        assert_eq!(Platform::from_hostname("127.0.0.1:8080"), None);
+    }
+}
@@ -0,0 +1,212 @@
+//! Finding local files which aren't upstream any more, because the post was deleted or the file was removed from it.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::dedup::downloaded_files;
+use crate::download::{
+    collect_files, collect_inline_media, converted_relative_path, download_relative_path,
+    is_profile_image, thumbnail_path, DownloadOptions,
+};
+use crate::errors::KemonoError;
+use crate::export::captions_extension;
+use crate::{KemonoClient, Post};
+
+/// Where pruned files go unless they're being deleted for real, under the creator/service directory
+pub static TRASH_DIR: &str = ".trash";
+
+/// What to do with orphaned files
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PruneMode {
+    /// Just report them
+    #[default]
+    Report,
+    /// Move them into [TRASH_DIR]
+    Trash,
+    /// Unlink them
+    Delete,
+}
+
+/// Local files which don't belong to any current post
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Orphans {
+    pub files: Vec<PathBuf>,
+    /// Metadata for posts which have gone, these are only ever reported
+    pub metadata: Vec<PathBuf>,
+}
+
+/// Every file the downloader would write for these posts, including mkv, NFO, captions and thumbnail variants
+pub fn expected_files(
+    client: &KemonoClient,
+    options: &DownloadOptions,
+    posts: &[Post],
+) -> HashSet<PathBuf> {
+    let target_dir = options.target_dir(client);
+    let mut files = collect_files(posts);
+    if options.inline_media {
+        for post in posts {
+            files.extend(
+                collect_inline_media(client, post)
+                    .into_iter()
+                    .map(|(_, attachment)| (post.clone(), attachment)),
+            );
+        }
+    }
+
+    let mut expected = HashSet::new();
+    for (post, attachment) in files {
+        let Ok(relative_path) = download_relative_path(&post, &attachment, options) else {
+            continue;
+        };
+        let converted = converted_relative_path(&post, &attachment, options)
+            .ok()
+            .flatten()
+            .filter(|_| options.mkvs);
+        for name in std::iter::once(relative_path).chain(converted) {
+            expected.insert(target_dir.join(format!("{}.nfo", name)));
+            if let Some(extension) = captions_extension(&post) {
+                expected.insert(target_dir.join(format!("{}.{}", name, extension)));
+            }
+            expected.insert(target_dir.join(name));
+        }
+        if attachment.thumbnail_url(client).is_some() {
+            expected.extend(thumbnail_path(&target_dir, &post, &attachment, options).ok());
+        }
+    }
+    expected
+}
+
+/// Compares what's on disk against the current posts
+pub fn find_orphans(
+    client: &KemonoClient,
+    options: &DownloadOptions,
+    posts: &[Post],
+) -> Result<Orphans, KemonoError> {
+    let target_dir = options.target_dir(client);
+    let expected = expected_files(client, options, posts);
+    let mut orphans = Orphans {
+        files: downloaded_files(&target_dir)?
+            .into_iter()
+            .filter(|path| !expected.contains(path))
+            .filter(|path| {
+                // the profile images are at the top level and aren't part of any post
+                path.parent() != Some(target_dir.as_path())
+                    || !path
+                        .file_name()
+                        .is_some_and(|name| is_profile_image(&name.to_string_lossy()))
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    let metadata_dir = options.metadata_dir(client);
+    if metadata_dir.is_dir() {
+        let post_ids: HashSet<&str> = posts.iter().map(|post| post.id.as_str()).collect();
+        let entries = metadata_dir.read_dir().map_err(|err| {
+            KemonoError::from(format!(
+                "Failed to read {}: {:?}",
+                metadata_dir.display(),
+                err
+            ))
+        })?;
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some((post_id, _)) = name.split_once('.') else {
+                continue;
+            };
+            if !post_id.is_empty() && !post_ids.contains(post_id) && entry.path().is_file() {
+                orphans.metadata.push(entry.path());
+            }
+        }
+    }
+
+    orphans.files.sort();
+    orphans.metadata.sort();
+    Ok(orphans)
+}
+
+/// Trashes or deletes an orphaned file, returns where it went if it was trashed
+pub fn remove_orphan(
+    target_dir: &Path,
+    path: &Path,
+    mode: PruneMode,
+) -> Result<Option<PathBuf>, KemonoError> {
+    match mode {
+        PruneMode::Report => Ok(None),
+        PruneMode::Delete => {
+            std::fs::remove_file(path).map_err(|err| {
+                KemonoError::from(format!("Failed to delete {}: {:?}", path.display(), err))
+            })?;
+            Ok(None)
+        }
+        PruneMode::Trash => {
+            let relative = path.strip_prefix(target_dir).map_err(|_| {
+                KemonoError::from(format!(
+                    "{} isn't in {}",
+                    path.display(),
+                    target_dir.display()
+                ))
+            })?;
+            let trash_path = target_dir.join(TRASH_DIR).join(relative);
+            if let Some(parent) = trash_path.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+            }
+            std::fs::rename(path, &trash_path).map_err(|err| {
+                KemonoError::from(format!("Failed to trash {}: {:?}", path.display(), err))
+            })?;
+            Ok(Some(trash_path))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let base_dir = tmp.path().join("prune");
+        let client = KemonoClient::new("example.com", Some(base_dir.display().to_string()));
+        let mut options = DownloadOptions::new("patreon", "12345");
+        options.mkvs = true;
+
+        let posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        let (kept, gone) = (&posts[0], &posts[1]);
+        let target_dir = options.target_dir(&client);
+        let metadata_dir = options.metadata_dir(&client);
+        std::fs::create_dir_all(&metadata_dir).expect("Failed to create test dir");
+
+        let (_, attachment) = collect_files(std::slice::from_ref(kept))
+            .pop()
+            .expect("No attachments in test post");
+        let kept_file = target_dir
+            .join(download_relative_path(kept, &attachment, &options).expect("Failed to get path"));
+        let orphan_file = target_dir.join("deleted.jpg");
+        std::fs::write(&kept_file, "hello").expect("Failed to write");
+        std::fs::write(&orphan_file, "hello").expect("Failed to write");
+        std::fs::write(target_dir.join("icon.png"), "hello").expect("Failed to write");
+        std::fs::write(metadata_dir.join(format!("{}.json", kept.id)), "{}")
+            .expect("Failed to write");
+        let orphan_metadata = metadata_dir.join(format!("{}.json", gone.id));
+        std::fs::write(&orphan_metadata, "{}").expect("Failed to write");
+
+        let orphans = find_orphans(&client, &options, std::slice::from_ref(kept));
+        let trashed = remove_orphan(&target_dir, &orphan_file, PruneMode::Trash);
+        let trash_exists = target_dir.join(TRASH_DIR).join("deleted.jpg").exists();
+        let again = find_orphans(&client, &options, std::slice::from_ref(kept));
+
+        let orphans = orphans.expect("Failed to find orphans");
+        assert_eq!(orphans.files, vec![orphan_file]);
+        assert_eq!(orphans.metadata, vec![orphan_metadata]);
+        assert!(trashed.expect("Failed to trash").is_some());
+        assert!(trash_exists);
+        let again = again.expect("Failed to find orphans");
+        assert!(again.files.is_empty());
+        assert_eq!(again.metadata, orphans.metadata);
+    }
+}
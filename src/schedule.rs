@@ -0,0 +1,107 @@
+//! Timing for `watch` and `update --watch`, how long to wait between runs.
+
+use std::collections::hash_map::RandomState;
+use std::fmt::Display;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long to wait between runs, eg `90s`, `30m`, `6h` or `1d`, plain numbers are seconds
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Interval(pub Duration);
+
+impl Default for Interval {
+    fn default() -> Self {
+        Self(Duration::from_secs(6 * 60 * 60))
+    }
+}
+
+impl FromStr for Interval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+            Some(index) => s.split_at(index),
+            None => (s, "s"),
+        };
+        let multiplier = match unit.to_lowercase().as_str() {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => {
+                return Err(format!(
+                    "Unknown unit in interval '{}', use s, m, h or d",
+                    s
+                ))
+            }
+        };
+        let seconds = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(multiplier))
+            .filter(|seconds| *seconds > 0)
+            .ok_or(format!("Invalid interval '{}'", s))?;
+        Ok(Self(Duration::from_secs(seconds)))
+    }
+}
+
+impl Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.0.as_secs();
+        match seconds {
+            s if s % (24 * 60 * 60) == 0 => write!(f, "{}d", s / (24 * 60 * 60)),
+            s if s % (60 * 60) == 0 => write!(f, "{}h", s / (60 * 60)),
+            s if s % 60 == 0 => write!(f, "{}m", s / 60),
+            s => write!(f, "{}s", s),
+        }
+    }
+}
+
+impl Interval {
+    /// The interval plus up to a tenth again, so runs from several machines drift apart
+    pub fn with_jitter(&self) -> Duration {
+        let max_jitter = self.0.as_millis() as u64 / 10;
+        if max_jitter == 0 {
+            return self.0;
+        }
+        // a fresh RandomState is randomly seeded, which is all the randomness we need
+        let random = RandomState::new().build_hasher().finish();
+        self.0 + Duration::from_millis(random % max_jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval() {
+        for (input, seconds, display) in [
+            ("6h", 21600, "6h"),
+            ("30m", 1800, "30m"),
+            ("90", 90, "90s"),
+            ("120s", 120, "2m"),
+            ("1D", 86400, "1d"),
+        ] {
+            let interval = Interval::from_str(input).expect("Failed to parse interval");
+            assert_eq!(interval.0, Duration::from_secs(seconds));
+            assert_eq!(interval.to_string(), display);
+        }
+        for input in ["", "0h", "6w", "h", "-1h"] {
+            assert!(Interval::from_str(input).is_err(), "{} should fail", input);
+        }
+        assert_eq!(Interval::default().to_string(), "6h");
+    }
+
+    #[test]
+    fn test_jitter() {
+        let interval = Interval(Duration::from_secs(600));
+        for _ in 0..10 {
+            let wait = interval.with_jitter();
+            assert!(wait >= interval.0);
+            assert!(wait < Duration::from_secs(660));
+        }
+    }
+}
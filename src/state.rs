@@ -0,0 +1,98 @@
+//! Per creator/service sync state, so updates only need to fetch new posts.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::KemonoError;
+use crate::util::write_atomic;
+use crate::Post;
+
+pub static SYNC_STATE_FILENAME: &str = ".sync_state.json";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct SyncState {
+    /// ID of the newest post we've seen
+    pub newest_post_id: Option<String>,
+    /// Published date of the newest post we've seen, this is the high-water mark
+    pub newest_published: Option<String>,
+    /// Unix timestamp of the last completed run
+    pub last_run: u64,
+}
+
+impl SyncState {
+    /// Where the state file lives for a given creator/service metadata directory
+    pub fn path(metadata_dir: &Path) -> PathBuf {
+        metadata_dir.join(SYNC_STATE_FILENAME)
+    }
+
+    /// Load the state file, returns None if it doesn't exist yet
+    pub fn load(metadata_dir: &Path) -> Result<Option<Self>, KemonoError> {
+        let path = Self::path(metadata_dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&path).map_err(|err| {
+            KemonoError::from(format!(
+                "Failed to read sync state {}: {:?}",
+                path.display(),
+                err
+            ))
+        })?;
+        Ok(Some(serde_json::from_str(&contents)?))
+    }
+
+    pub fn save(&self, metadata_dir: &Path) -> Result<(), KemonoError> {
+        let path = Self::path(metadata_dir);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|err| format!("Failed to create parent dirs: {:?}", err))?;
+        }
+        write_atomic(&path, serde_json::to_string_pretty(self)?)
+            .map_err(|err| KemonoError::from(format!("Failed to write sync state: {:?}", err)))
+    }
+
+    /// Moves the high-water mark forward to the newest of `posts`, and bumps the run timestamp
+    pub fn update(&mut self, posts: &[Post]) {
+        if let Some(newest) = posts.iter().max_by(|a, b| a.published.cmp(&b.published)) {
+            let is_newer = match &self.newest_published {
+                Some(published) => newest.published > *published,
+                None => true,
+            };
+            if is_newer {
+                self.newest_post_id = Some(newest.id.clone());
+                self.newest_published = Some(newest.published.clone());
+            }
+        }
+        self.last_run = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_state_update() {
+        let posts: Vec<Post> = serde_json::from_str(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data");
+        let newest = posts
+            .iter()
+            .map(|post| post.published.clone())
+            .max()
+            .expect("No posts in test data");
+
+        let mut state = SyncState::default();
+        state.update(&posts);
+        assert_eq!(state.newest_published, Some(newest.clone()));
+        assert!(state.last_run > 0);
+
+        // older posts don't move the mark backwards
+        state.update(&posts[posts.len() - 1..]);
+        assert_eq!(state.newest_published, Some(newest));
+    }
+}
@@ -0,0 +1,241 @@
+//! What's already been downloaded for `stats --local`, and how big the files are for `stats --remote-sizes`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use futures::stream::{self, StreamExt};
+use log::debug;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::dedup::downloaded_files;
+use crate::download::attachment_url;
+use crate::errors::KemonoError;
+use crate::{Attachment, KemonoClient, Post};
+
+/// How many of the largest files are listed by default
+pub static DEFAULT_TOP_FILES: usize = 10;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct LocalFile {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Disk usage of the downloaded files in one or more creator/service directories
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LocalStats {
+    pub file_count: usize,
+    pub total_bytes: u64,
+    /// Bytes by lowercased extension, files without one are counted under `(none)`
+    pub bytes_by_extension: BTreeMap<String, u64>,
+    /// The `top` largest files, biggest first
+    pub largest_files: Vec<LocalFile>,
+    #[serde(skip)]
+    top: usize,
+}
+
+impl LocalStats {
+    /// Keeps track of the `top` largest files
+    pub fn new(top: usize) -> Self {
+        Self {
+            top,
+            ..Default::default()
+        }
+    }
+
+    /// Adds the downloaded files in a creator/service directory
+    pub fn add_dir(&mut self, dir: &Path) -> Result<(), KemonoError> {
+        for path in downloaded_files(dir)? {
+            let size = match path.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(err) => {
+                    debug!("Failed to get the size of {}: {:?}", path.display(), err);
+                    continue;
+                }
+            };
+            let extension = path
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_else(|| "(none)".to_string());
+            self.file_count += 1;
+            self.total_bytes += size;
+            *self.bytes_by_extension.entry(extension).or_insert(0) += size;
+            self.largest_files.push(LocalFile { path, size });
+        }
+        self.largest_files
+            .sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+        self.largest_files.truncate(self.top);
+        Ok(())
+    }
+}
+
+/// The posts saved in a metadata directory, anything which doesn't parse as a post is skipped
+pub fn stored_posts(metadata_dir: &Path) -> Vec<Post> {
+    let Ok(entries) = metadata_dir.read_dir() else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| {
+            let data = std::fs::read_to_string(&path).ok()?;
+            serde_json::from_str::<Post>(&data)
+                .map_err(|err| debug!("Skipping {}: {:?}", path.display(), err))
+                .ok()
+        })
+        .collect()
+}
+
+/// The size the server gives for an attachment with a HEAD request, None if it won't say
+async fn remote_size(
+    client: &KemonoClient,
+    session: &reqwest::Client,
+    attachment: &Attachment,
+) -> Result<Option<u64>, KemonoError> {
+    let url = attachment_url(client, attachment)?;
+    let response = session.head(url.clone()).send().await?;
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(KemonoError::RateLimited);
+    }
+    if !response.status().is_success() {
+        debug!("Got {} for the size of {}", response.status(), url);
+        return Ok(None);
+    }
+    // content_length() is the body's length, which is always 0 for a HEAD request
+    Ok(response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok()))
+}
+
+/// Sizes of the files in `posts` by attachment path, asking the server for each one with up to
+/// `concurrency` HEAD requests at a time.
+///
+/// The API doesn't include sizes, so this is slow for big creators. Files the server won't give
+/// a size for are left out, only being rate limited is an error.
+pub async fn remote_sizes(
+    client: &KemonoClient,
+    posts: &[Post],
+    concurrency: usize,
+) -> Result<HashMap<String, u64>, KemonoError> {
+    let session = client.new_async_session()?;
+    let mut attachments: HashMap<&str, &Attachment> = HashMap::new();
+    for attachment in posts.iter().flat_map(|post| post.all_attachments()) {
+        if let Some(path) = attachment.path.as_deref() {
+            attachments.entry(path).or_insert(attachment);
+        }
+    }
+    let results: Vec<(&str, Result<Option<u64>, KemonoError>)> = stream::iter(attachments)
+        .map(|(path, attachment)| {
+            let session = &session;
+            async move { (path, remote_size(client, session, attachment).await) }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut sizes = HashMap::new();
+    for (path, result) in results {
+        match result {
+            Ok(Some(size)) => {
+                sizes.insert(path.to_string(), size);
+            }
+            Ok(None) => {}
+            Err(KemonoError::RateLimited) => return Err(KemonoError::RateLimited),
+            Err(err) => debug!("Failed to get the size of {}: {:?}", path, err),
+        }
+    }
+    Ok(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_stats() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let target_dir = tmp.path().join("stats");
+        let metadata_dir = target_dir.join(crate::download::METADATA_DIR);
+        std::fs::create_dir_all(&metadata_dir).expect("Failed to create test dir");
+        std::fs::create_dir_all(target_dir.join("post")).expect("Failed to create test dir");
+        for (name, size) in [("a.JPG", 30), ("b.jpg", 10), ("post/c.mp4", 50), ("d", 5)] {
+            std::fs::write(target_dir.join(name), vec![0u8; size]).expect("Failed to write");
+        }
+        let post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        std::fs::write(
+            metadata_dir.join(format!("{}.json", post.id)),
+            serde_json::to_string(&post).expect("Failed to serialize"),
+        )
+        .expect("Failed to write");
+        std::fs::write(metadata_dir.join("state.json"), "{}").expect("Failed to write");
+
+        let mut stats = LocalStats::new(2);
+        stats.add_dir(&target_dir).expect("Failed to add dir");
+        assert_eq!((stats.file_count, stats.total_bytes), (4, 95));
+        assert_eq!(stats.bytes_by_extension.get("jpg"), Some(&40));
+        assert_eq!(stats.bytes_by_extension.get("(none)"), Some(&5));
+        assert_eq!(
+            stats.largest_files,
+            vec![
+                LocalFile {
+                    path: target_dir.join("post/c.mp4"),
+                    size: 50
+                },
+                LocalFile {
+                    path: target_dir.join("a.JPG"),
+                    size: 30
+                }
+            ]
+        );
+
+        let posts = stored_posts(&metadata_dir);
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].id, post.id);
+        assert!(stored_posts(&target_dir.join("nope")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_remote_sizes() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("HEAD"))
+            .and(path("/aa/bb/a.jpg"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0u8; 1234]))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .and(path("/aa/bb/b.mp4"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let mut client = KemonoClient::new(&server.address().to_string(), None);
+        client.scheme = "http".to_string();
+        let mut post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        let attachment = |name: &str| Attachment {
+            name: Some(name.to_string()),
+            path: Some(format!("/aa/bb/{}", name)),
+        };
+        post.file = attachment("a.jpg");
+        // listed twice, but only asked about once
+        post.attachments = Some([attachment("a.jpg"), attachment("b.mp4")].into());
+
+        let sizes = remote_sizes(&client, &[post.clone()], 2)
+            .await
+            .expect("Failed to get sizes");
+        assert_eq!(sizes, [("/aa/bb/a.jpg".to_string(), 1234)].into());
+        assert_eq!(post.total_size(&sizes), None);
+    }
+}
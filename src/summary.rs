@@ -0,0 +1,243 @@
+//! Counters describing what happened during a download run.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::failures::Failure;
+use crate::notify::NOTIFY_FILES;
+
+/// What happened when we tried to download a single attachment
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DownloadOutcome {
+    /// Downloaded this many bytes
+    Downloaded(u64),
+    /// We already had the file
+    Exists,
+    /// We already had the bytes in the object store, so linked to them
+    Linked,
+    /// The file on disk looked broken, so we downloaded it again, this many bytes
+    Repaired(u64),
+}
+
+/// Counters shared between the download threads
+#[derive(Debug, Default)]
+pub struct DownloadCounters {
+    pub downloaded: AtomicUsize,
+    pub downloaded_bytes: AtomicU64,
+    pub skipped_exists: AtomicUsize,
+    pub skipped_filtered: AtomicUsize,
+    pub linked: AtomicUsize,
+    pub repaired: AtomicUsize,
+    pub hooks_failed: AtomicUsize,
+    /// The first few files which were downloaded, for notifications
+    pub new_files: Mutex<Vec<String>>,
+    /// Every file written this run, relative to the creator/service directory, so the manifest
+    /// can refresh hashes of ones which were repaired or downloaded again
+    pub written: Mutex<HashSet<String>>,
+}
+
+impl DownloadCounters {
+    pub fn record(&self, outcome: DownloadOutcome) {
+        match outcome {
+            DownloadOutcome::Downloaded(bytes) => {
+                self.downloaded.fetch_add(1, Ordering::Relaxed);
+                self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+            }
+            DownloadOutcome::Exists => {
+                self.skipped_exists.fetch_add(1, Ordering::Relaxed);
+            }
+            DownloadOutcome::Linked => {
+                self.linked.fetch_add(1, Ordering::Relaxed);
+            }
+            DownloadOutcome::Repaired(bytes) => {
+                self.downloaded.fetch_add(1, Ordering::Relaxed);
+                self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+                self.repaired.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn filtered(&self) {
+        self.skipped_filtered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Remembers a file was written, `filename` is relative to the creator/service directory
+    pub fn written(&self, filename: &str) {
+        if let Ok(mut written) = self.written.lock() {
+            written.insert(filename.to_string());
+        }
+    }
+
+    /// The files recorded by [DownloadCounters::written]
+    pub fn written_files(&self) -> HashSet<String> {
+        self.written
+            .lock()
+            .map(|written| written.clone())
+            .unwrap_or_default()
+    }
+
+    /// Remembers a downloaded file's name, up to [NOTIFY_FILES] of them
+    pub fn new_file(&self, name: &str) {
+        if let Ok(mut new_files) = self.new_files.lock() {
+            if new_files.len() < NOTIFY_FILES {
+                new_files.push(name.to_string());
+            }
+        }
+    }
+}
+
+/// Summary of a download run, the creator and service are unset for totals
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DownloadSummary {
+    pub service: Option<String>,
+    pub creator: Option<String>,
+    pub posts: usize,
+    pub attachments: usize,
+    pub downloaded: usize,
+    pub downloaded_bytes: u64,
+    pub skipped_exists: usize,
+    pub skipped_filtered: usize,
+    pub linked: usize,
+    /// Downloaded again because the file on disk was empty or an error page, these are also counted in `downloaded`
+    pub repaired: usize,
+    /// Downloads which failed this run
+    pub failed: usize,
+    /// Failures from earlier runs still waiting in `failures.json`, which aren't counted in `failed`
+    pub previous_failures: usize,
+    /// How many `--exec-after` hooks failed, these don't count as failed downloads
+    pub hooks_failed: usize,
+    pub elapsed_seconds: f64,
+    pub bytes_per_second: f64,
+    #[serde(skip)]
+    pub failures: Vec<Failure>,
+    /// The first few files which were downloaded
+    #[serde(skip)]
+    pub new_files: Vec<String>,
+    /// Stopped early by Ctrl+C, so not everything was downloaded
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub interrupted: bool,
+}
+
+impl DownloadSummary {
+    pub fn new(service: &str, creator: &str) -> Self {
+        Self {
+            service: Some(service.to_string()),
+            creator: Some(creator.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Pulls the totals out of the shared counters
+    pub fn add_counters(&mut self, counters: &DownloadCounters) {
+        self.downloaded += counters.downloaded.load(Ordering::Relaxed);
+        self.downloaded_bytes += counters.downloaded_bytes.load(Ordering::Relaxed);
+        self.skipped_exists += counters.skipped_exists.load(Ordering::Relaxed);
+        self.skipped_filtered += counters.skipped_filtered.load(Ordering::Relaxed);
+        self.linked += counters.linked.load(Ordering::Relaxed);
+        self.repaired += counters.repaired.load(Ordering::Relaxed);
+        self.hooks_failed += counters.hooks_failed.load(Ordering::Relaxed);
+        if let Ok(new_files) = counters.new_files.lock() {
+            self.new_files.extend(new_files.iter().cloned());
+        }
+    }
+
+    /// Adds another summary's counts to this one, used for totals
+    pub fn add(&mut self, other: &DownloadSummary) {
+        self.posts += other.posts;
+        self.attachments += other.attachments;
+        self.downloaded += other.downloaded;
+        self.downloaded_bytes += other.downloaded_bytes;
+        self.skipped_exists += other.skipped_exists;
+        self.skipped_filtered += other.skipped_filtered;
+        self.linked += other.linked;
+        self.repaired += other.repaired;
+        self.hooks_failed += other.hooks_failed;
+        self.previous_failures += other.previous_failures;
+        self.failures.extend(other.failures.iter().cloned());
+        self.interrupted |= other.interrupted;
+    }
+
+    /// Sets the elapsed time, and works out the failure count and throughput
+    pub fn finish(&mut self, elapsed: Duration) {
+        self.failed = self.failures.len();
+        self.elapsed_seconds = elapsed.as_secs_f64();
+        self.bytes_per_second = match self.elapsed_seconds > 0.0 {
+            true => self.downloaded_bytes as f64 / self.elapsed_seconds,
+            false => 0.0,
+        };
+    }
+}
+
+/// Summary of an update run across multiple creators/services
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct UpdateSummary {
+    pub creators: Vec<DownloadSummary>,
+    /// `creator/service` pairs which didn't match the filters
+    pub skipped: Vec<String>,
+    /// `creator/service: error` for the ones which couldn't be updated, eg because they don't exist
+    pub errors: Vec<String>,
+    /// The site rate limited us, so some creators weren't finished, see `errors` for how far they got
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub rate_limited: bool,
+    pub total: DownloadSummary,
+}
+
+impl UpdateSummary {
+    pub fn add(&mut self, summary: DownloadSummary) {
+        self.total.add(&summary);
+        self.creators.push(summary);
+    }
+
+    pub fn skip(&mut self, creator: &str, service: &str) {
+        self.skipped.push(format!("{}/{}", creator, service));
+    }
+
+    pub fn error(&mut self, creator: &str, service: &str, err: impl std::fmt::Display) {
+        self.errors
+            .push(format!("{}/{}: {}", creator, service, err));
+    }
+
+    /// Records a creator which was rate limited, with how far it got if we know
+    pub fn rate_limited(&mut self, creator: &str, service: &str, err: impl std::fmt::Display) {
+        self.rate_limited = true;
+        self.error(creator, service, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_totals() {
+        let counters = DownloadCounters::default();
+        counters.record(DownloadOutcome::Downloaded(100));
+        counters.record(DownloadOutcome::Downloaded(300));
+        counters.record(DownloadOutcome::Exists);
+        counters.record(DownloadOutcome::Repaired(100));
+        counters.filtered();
+
+        let mut summary = DownloadSummary::new("patreon", "12345");
+        summary.add_counters(&counters);
+        summary.finish(Duration::from_secs(2));
+        assert_eq!(summary.downloaded, 3);
+        assert_eq!(summary.downloaded_bytes, 500);
+        assert_eq!(summary.repaired, 1);
+        assert_eq!(summary.skipped_exists, 1);
+        assert_eq!(summary.skipped_filtered, 1);
+        assert_eq!(summary.bytes_per_second, 250.0);
+
+        let mut total = DownloadSummary::default();
+        total.add(&summary);
+        total.add(&summary);
+        total.finish(Duration::from_secs(4));
+        assert_eq!(total.downloaded_bytes, 1000);
+        assert_eq!(total.repaired, 2);
+        assert_eq!(total.bytes_per_second, 250.0);
+        assert!(total.service.is_none());
+    }
+}
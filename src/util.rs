@@ -0,0 +1,318 @@
+//! Naming downloaded files, so every part of the crate agrees on where an attachment lives.
+
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::download::{DownloadOptions, PART_EXTENSION};
+use crate::errors::KemonoError;
+use crate::layout::sanitize_name;
+use crate::{Attachment, Post};
+
+/// Longest a filename can be in bytes, most filesystems won't take any more
+pub static MAX_FILENAME_BYTES: usize = 255;
+
+/// The placeholders a [FilenameTemplate] can use
+pub static FILENAME_TEMPLATE_FIELDS: [&str; 6] =
+    ["published", "id", "title", "name", "service", "user"];
+
+/// How downloaded files are named, eg `{published}-{name}`.
+///
+/// `{name}` is the attachment's name and has to be there, otherwise a post's files would all get
+/// the same name. `{published}` has its colons replaced and `{title}` is sanitized.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FilenameTemplate(String);
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        Self("{published}-{name}".to_string())
+    }
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(['/', '\\']) {
+            return Err(format!(
+                "Invalid filename template '{}', it can't contain directories",
+                s
+            ));
+        }
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                return Err(format!("Invalid filename template '{}', unclosed {{", s));
+            };
+            let field = &rest[start + 1..start + end];
+            if !FILENAME_TEMPLATE_FIELDS.contains(&field) {
+                return Err(format!(
+                    "Invalid filename template '{}', unknown field {{{}}}, expected one of {}",
+                    s,
+                    field,
+                    FILENAME_TEMPLATE_FIELDS.join(", ")
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if !s.contains("{name}") {
+            return Err(format!(
+                "Invalid filename template '{}', it needs {{name}}",
+                s
+            ));
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl Display for FilenameTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FilenameTemplate {
+    /// Fills in the template for a post, `name` is the already-sanitized attachment name
+    pub fn render(&self, post: &Post, name: &str) -> String {
+        self.0
+            .replace("{published}", &post.published.replace(':', "-"))
+            .replace("{id}", &sanitize_filename(&post.id))
+            .replace("{title}", &sanitize_name(&post.title))
+            .replace("{service}", &sanitize_filename(&post.service))
+            .replace("{user}", &sanitize_filename(&post.user))
+            .replace("{name}", name)
+    }
+}
+
+/// Replaces the characters which would take a filename out of its directory, or can't be written at all
+pub fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Shortens a filename to at most `max_bytes`, keeping its extension if it's a sensible length
+pub fn truncate_filename(filename: &str, max_bytes: usize) -> String {
+    if filename.len() <= max_bytes {
+        return filename.to_string();
+    }
+    let (stem, ext) = match filename.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() + 1 < max_bytes / 2 => {
+            (stem, format!(".{}", ext))
+        }
+        _ => (filename, String::new()),
+    };
+    let mut end = max_bytes - ext.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], ext)
+}
+
+/// The name an attachment is saved under, without any directories.
+///
+/// It's the filename template filled in with the attachment's sanitized name, except for a shared
+/// file post's main file which is named after the post's title, then truncated to [MAX_FILENAME_BYTES].
+///
+/// Remuxed copies are looked for under [get_converted_filename]'s name, the download itself always
+/// keeps the original extension.
+pub fn get_canonical_filename(
+    post: &Post,
+    attachment: &Attachment,
+    opts: &DownloadOptions,
+) -> Result<String, KemonoError> {
+    let name = attachment
+        .name
+        .as_deref()
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| KemonoError::from(format!("Attachment has no name: {}", attachment)))?;
+    let title = sanitize_name(&post.title);
+    let name = match post.shared_file == Some(true) && *attachment == post.file && !title.is_empty()
+    {
+        // shared files are standalone uploads, so the title is what they're called
+        true => match attachment.extension() {
+            Some(ext) => format!("{}.{}", title, ext),
+            None => title,
+        },
+        false => sanitize_filename(name),
+    };
+    let filename = truncate_filename(
+        &opts.filename_template.render(post, &name),
+        MAX_FILENAME_BYTES,
+    );
+    match filename.trim_matches('.').is_empty() {
+        true => Err(KemonoError::from(format!(
+            "Invalid filename '{}' for {}",
+            filename, attachment
+        ))),
+        false => Ok(filename),
+    }
+}
+
+/// The name a remuxed copy of the attachment would have (see [crate::ConvertedExtensions]), or
+/// None if its extension isn't one that gets converted
+pub fn get_converted_filename(
+    post: &Post,
+    attachment: &Attachment,
+    opts: &DownloadOptions,
+) -> Result<Option<String>, KemonoError> {
+    let filename = get_canonical_filename(post, attachment, opts)?;
+    Ok(opts.converted_extensions.converted_filename(&filename))
+}
+
+/// Where a file is written before it's moved into place, `{path}.part`
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(format!(".{}", PART_EXTENSION));
+    PathBuf::from(part_path)
+}
+
+/// Like [std::fs::write], but writes to [part_path] and renames it into place, so a crash never
+/// leaves half a file behind that looks complete
+pub fn write_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let part_path = part_path(path);
+    let res = File::create(&part_path)
+        .and_then(|mut file| {
+            file.write_all(contents.as_ref())?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&part_path, path));
+    if res.is_err() {
+        let _ = std::fs::remove_file(&part_path);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_post() -> Post {
+        let mut post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+            .expect("Failed to deserialize data")
+            .remove(0);
+        post.id = "42".to_string();
+        post.title = "A: post".to_string();
+        post.published = "2023-10-20T14:05:01".to_string();
+        post.shared_file = Some(false);
+        post
+    }
+
+    #[test]
+    fn test_filename_template() {
+        assert_eq!(
+            FilenameTemplate::default().to_string(),
+            "{published}-{name}"
+        );
+        let template =
+            FilenameTemplate::from_str("{id}-{title}-{name}").expect("Failed to parse template");
+        assert_eq!(template.render(&test_post(), "a.jpg"), "42-A_ post-a.jpg");
+        assert!(FilenameTemplate::from_str("{published}").is_err());
+        assert!(FilenameTemplate::from_str("{nope}-{name}").is_err());
+        assert!(FilenameTemplate::from_str("{name").is_err());
+        assert!(FilenameTemplate::from_str("{id}/{name}").is_err());
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = tmp.path().join("atomic");
+        std::fs::create_dir_all(&dir).expect("Failed to create test dir");
+        let path = dir.join("a.json");
+        assert_eq!(part_path(&path), dir.join("a.json.part"));
+
+        write_atomic(&path, "first").expect("Failed to write");
+        write_atomic(&path, "second").expect("Failed to write");
+        assert_eq!(
+            std::fs::read_to_string(&path).expect("Failed to read"),
+            "second"
+        );
+        assert!(!part_path(&path).exists());
+        // nothing's left behind when the write fails
+        assert!(write_atomic(dir.join("nope").join("b.json"), "x").is_err());
+        assert!(!dir.join("nope").exists());
+    }
+
+    #[test]
+    fn test_truncate_filename() {
+        assert_eq!(truncate_filename("short.jpg", 20), "short.jpg");
+        assert_eq!(truncate_filename("abcdefghij.jpg", 10), "abcdef.jpg");
+        assert_eq!(truncate_filename("abcdefghijklmnop", 10), "abcdefghij");
+        // never splits a character
+        assert_eq!(truncate_filename("ééééé.jpg", 11), "ééé.jpg");
+    }
+
+    #[test]
+    fn test_get_canonical_filename() {
+        let mut post = test_post();
+        let mut opts = DownloadOptions::new("patreon", "12345");
+        let attachment = Attachment {
+            name: Some("a/b.jpg".to_string()),
+            path: Some("/aa/bb/ab.jpg".to_string()),
+        };
+        let filename = |post: &Post, attachment: &Attachment, opts: &DownloadOptions| {
+            get_canonical_filename(post, attachment, opts).expect("Failed to get filename")
+        };
+
+        // the default is the date prefix and the sanitized name
+        assert_eq!(
+            filename(&post, &attachment, &opts),
+            "2023-10-20T14-05-01-a_b.jpg"
+        );
+
+        // shared files take the post's title, other attachments don't
+        post.shared_file = Some(true);
+        post.file = attachment.clone();
+        assert_eq!(
+            filename(&post, &attachment, &opts),
+            "2023-10-20T14-05-01-A_ post.jpg"
+        );
+        let other = Attachment {
+            name: Some("c.png".to_string()),
+            path: Some("/aa/bb/c.png".to_string()),
+        };
+        assert_eq!(filename(&post, &other, &opts), "2023-10-20T14-05-01-c.png");
+
+        opts.filename_template =
+            FilenameTemplate::from_str("{service}-{id}-{name}").expect("Failed to parse template");
+        assert_eq!(
+            filename(&post, &other, &opts),
+            format!("{}-42-c.png", post.service)
+        );
+
+        // long names are cut down to fit, keeping the extension
+        let long = Attachment {
+            name: Some(format!("{}.mp4", "x".repeat(300))),
+            path: Some("/aa/bb/long.mp4".to_string()),
+        };
+        let long_name = filename(&post, &long, &opts);
+        assert_eq!(long_name.len(), MAX_FILENAME_BYTES);
+        assert!(long_name.ends_with("x.mp4"));
+
+        // a remuxed copy's the same name with the converted extension
+        let converted = get_converted_filename(&post, &long, &opts)
+            .expect("Failed to get filename")
+            .expect("mp4 should be converted");
+        assert_eq!(
+            converted,
+            format!("{}.mkv", &long_name[..long_name.len() - 4])
+        );
+        assert_eq!(
+            get_converted_filename(&post, &other, &opts).expect("Failed to get filename"),
+            None
+        );
+
+        let unnamed = Attachment {
+            name: None,
+            path: Some("/aa/bb/c.png".to_string()),
+        };
+        assert!(get_canonical_filename(&post, &unnamed, &opts).is_err());
+    }
+}
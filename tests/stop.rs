@@ -0,0 +1,102 @@
+//! [request_stop] is process wide, so this has its own test binary where it can't stop anyone
+//! else's downloads.
+
+use kemono::download::{clear_stop, download_files, request_stop, stop_requested, DownloadOptions};
+use kemono::errors::KemonoError;
+use kemono::manifest::Validators;
+use kemono::summary::DownloadCounters;
+use kemono::{Attachment, KemonoClient, Post};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+#[tokio::test]
+async fn test_stop_keeps_failures() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/aa/bb/file0.jpg"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&server)
+        .await;
+    // Ctrl+C while the second file's downloading
+    Mock::given(method("GET"))
+        .and(path("/aa/bb/file1.jpg"))
+        .respond_with(|_: &wiremock::Request| {
+            request_stop();
+            ResponseTemplate::new(200).set_body_string("hello")
+        })
+        .expect(1)
+        .mount(&server)
+        .await;
+    // only fetched once the stop's been cleared
+    for index in 2..4 {
+        Mock::given(method("GET"))
+            .and(path(format!("/aa/bb/file{}.jpg", index)))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    let mut client = KemonoClient::new(&server.address().to_string(), None);
+    client.scheme = "http".to_string();
+    let tmp = tempfile::tempdir().expect("Failed to create temp dir");
+    let download_path = tmp.path().join("stop");
+    let mut options = DownloadOptions::new("patreon", "12345");
+    options.download_path = Some(download_path.display().to_string());
+    options.threads = 1;
+    let post: Post = serde_json::from_str::<Vec<Post>>(include_str!("../test_data.json"))
+        .expect("Failed to deserialize data")
+        .remove(0);
+    let files: Vec<(Post, Attachment)> = (0..4)
+        .map(|index| {
+            (
+                post.clone(),
+                Attachment {
+                    name: Some(format!("file{}.jpg", index)),
+                    path: Some(format!("/aa/bb/file{}.jpg", index)),
+                },
+            )
+        })
+        .collect();
+
+    let download = |files: Vec<(Post, Attachment)>| {
+        let (client, options) = (client.clone(), options.clone());
+        tokio::task::spawn_blocking(move || {
+            let counters = DownloadCounters::default();
+            download_files(&client, &options, &files, &counters, &Validators::default())
+                .map(|downloaded| (downloaded, counters))
+        })
+    };
+    let res = download(files.clone())
+        .await
+        .expect("Download thread panicked");
+    let (downloaded, counters) = res.expect("Failed to download");
+    assert!(matches!(downloaded.stopped, Some(KemonoError::Interrupted)));
+    assert_eq!(downloaded.failures.len(), 1);
+    assert_eq!(
+        downloaded.failures[0].attachment.name,
+        Some("file0.jpg".to_string())
+    );
+    assert_eq!(
+        counters
+            .downloaded
+            .load(std::sync::atomic::Ordering::Relaxed),
+        1
+    );
+
+    // later downloads in the same process carry on once the stop's cleared
+    assert!(clear_stop());
+    assert!(!stop_requested());
+    let res = download(files[2..].to_vec())
+        .await
+        .expect("Download thread panicked");
+    let (downloaded, counters) = res.expect("Failed to download");
+    assert!(downloaded.stopped.is_none());
+    assert_eq!(
+        counters
+            .downloaded
+            .load(std::sync::atomic::Ordering::Relaxed),
+        2
+    );
+}